@@ -0,0 +1,31 @@
+//! Prints "Hello, world!" through `ConsolePeripheral`, one `SYSCALL_WRITE_BYTE` per character:
+//!
+//! ```text
+//! cargo run --example hello_console
+//! ```
+
+use tinyvm::{run_vm_with_syscalls, tinyvm_asm, ConsolePeripheral, Segment, VirtualMachine};
+
+fn main() {
+    let mut data = Segment::new_zeroed();
+    for (i, byte) in "Hello, world!\n".bytes().enumerate() {
+        data[0x0100 + i as u16] = byte as u16;
+    }
+    let instructions = tinyvm_asm! {
+        lw r2, 0x100;
+        loop_start:
+        lwd r2, r1;
+        b r1, print;
+        ret;
+        print:
+        lw r0, 1; // SYSCALL_WRITE_BYTE
+        debug_dump;
+        incr r2, r2;
+        j loop_start;
+    };
+    let mut console = ConsolePeripheral::new();
+    let mut vm = VirtualMachine::new(instructions, data);
+    let outcome = run_vm_with_syscalls(&mut vm, 1000, &mut console);
+    print!("{}", console.take_text());
+    println!("{:?}", outcome);
+}
@@ -0,0 +1,47 @@
+//! Measures how many instructions per second `VirtualMachine::run` executes, on the nested loop of the
+//! `test_time_very_long` test. Run it in release mode:
+//!
+//! ```text
+//! cargo run --release --example step_throughput [OUTER_ITERATIONS]
+//! ```
+//!
+//! The default of 0xB505 outer iterations executes about 2^32 instructions.
+
+use std::env;
+use std::time::Instant;
+use tinyvm::{Segment, VirtualMachine};
+
+fn main() {
+    let iterations = match env::args().nth(1) {
+        Some(arg) => arg.parse::<u16>().expect("OUTER_ITERATIONS must be a u16"),
+        None => 0xB505,
+    };
+    let mut instructions = Segment::new_zeroed();
+    instructions.copy_from_slice_at(
+        0,
+        &[
+            0x3700 | (iterations & 0x00FF), // lw r7, iterations (low byte)
+            0x4700 | (iterations >> 8),     // lhi r7, iterations (high byte)
+            0x5F71,                         // mv r1, r7
+            0x5F72,                         // outer_loop: mv r2, r7
+            0x5822,                         // inner_loop: decr r2
+            0x9280,                         // b r2 inner_loop
+            0x5811,                         // decr r1
+            0x9183,                         // b r1 outer_loop
+            0x102A,                         // ret
+        ],
+    );
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+
+    let start = Instant::now();
+    let outcome = vm.run(u64::MAX);
+    let elapsed = start.elapsed();
+
+    println!(
+        "{:?}: {} steps in {:.3} s, {:.1} MHz",
+        outcome.reason,
+        outcome.steps,
+        elapsed.as_secs_f64(),
+        outcome.steps as f64 / elapsed.as_secs_f64() / 1e6
+    );
+}
@@ -0,0 +1,556 @@
+use crate::vm::{encode_branch, encode_jump_imm, Segment};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// What is wrong with which line of the source, counting from 1.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl Display for AsmError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        write!(f, "Line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+/// Assembles a program written in the syntax of the comments in the tests, and returns it as an instruction
+/// segment, padded with zeros. `disasm::disassemble` produces the same syntax.
+///
+/// Each line holds at most one instruction or label, and `//` starts a comment. Operands may be separated by commas
+/// or just spaces. Labels are `.label name` or `name:`, and branch or jump targets are either a label name or a
+/// signed relative offset like `-0x6`. Immediates may be decimal, `0x` hex, or `0b` binary.
+///
+/// ```text
+/// lw r0, 24           // lw picks the shortest encoding, lw or lw + lhi
+/// .label start
+/// decr r0             // same as decr r0, r0
+/// b r0 start
+/// eq r1, 0            // compare to zero
+/// j r7 + 0x10
+/// lw r2, r1 + 3       // with an offset, needs the memory offset extension
+/// ret
+/// ```
+pub fn assemble(source: &str) -> Result<Segment, AsmError> {
+    let mut segment = Segment::new_zeroed();
+    for (i, word) in assemble_words(source)?.into_iter().enumerate() {
+        segment[i as u16] = word;
+    }
+    Ok(segment)
+}
+
+enum Fixup {
+    Branch { register: u16 },
+    JumpImm,
+}
+
+#[derive(Default)]
+struct Assembler {
+    words: Vec<u16>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<(usize, usize, String, Fixup)>,
+}
+
+fn assemble_words(source: &str) -> Result<Vec<u16>, AsmError> {
+    let mut assembler = Assembler::default();
+    let mut last_line = 0;
+    for (index, line) in source.lines().enumerate() {
+        last_line = index + 1;
+        assembler
+            .line(index + 1, line)
+            .map_err(|message| AsmError {
+                line: index + 1,
+                message,
+            })?;
+    }
+    if assembler.words.len() > 1 << 16 {
+        return Err(AsmError {
+            line: last_line,
+            message: format!(
+                "Program has {} words, but a segment only holds 65536.",
+                assembler.words.len()
+            ),
+        });
+    }
+    assembler.resolve()
+}
+
+impl Assembler {
+    fn line(&mut self, line_number: usize, line: &str) -> Result<(), String> {
+        let code = line.split("//").next().unwrap_or_default().trim();
+        if code.is_empty() {
+            return Ok(());
+        }
+        if let Some(label) = code.strip_prefix(".label") {
+            return self.label(label.trim().trim_end_matches(':'));
+        }
+        if let Some(label) = code.strip_suffix(':') {
+            return self.label(label.trim());
+        }
+
+        let tokens: Vec<&str> = code
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|token| !token.is_empty())
+            .collect();
+        let (mnemonic, operands) = tokens.split_first().expect("not empty");
+        let expect = |count: usize| {
+            if operands.len() == count {
+                Ok(())
+            } else {
+                Err(format!(
+                    "'{}' takes {} operand(s), got {}.",
+                    mnemonic,
+                    count,
+                    operands.len()
+                ))
+            }
+        };
+
+        match *mnemonic {
+            "ret" | "cpuid" | "debug-dump" | "time" => {
+                expect(0)?;
+                self.words.push(match *mnemonic {
+                    "ret" => 0x102A,
+                    "cpuid" => 0x102B,
+                    "debug-dump" => 0x102C,
+                    _ => 0x102D,
+                });
+            }
+            "sw" if operands.get(1) == Some(&"+") => {
+                expect(4)?;
+                let (address, offset) = (register(operands[0])?, memory_offset(operands[2])?);
+                let value = register(operands[3])?;
+                self.words
+                    .push(0x7800 | (offset << 8) | (address << 4) | value);
+            }
+            "lw" if operands.get(2) == Some(&"+") => {
+                expect(4)?;
+                let (dest, address) = (register(operands[0])?, register(operands[1])?);
+                let offset = memory_offset(operands[3])?;
+                self.words
+                    .push(0x7000 | (offset << 8) | (address << 4) | dest);
+            }
+            "sw" | "swi" => {
+                expect(2)?;
+                let (address, value) = (register(operands[0])?, register(operands[1])?);
+                let prefix = if *mnemonic == "sw" { 0x2000 } else { 0x2300 };
+                self.words.push(prefix | (address << 4) | value);
+            }
+            "lw" | "lwi" => {
+                expect(2)?;
+                let dest = register(operands[0])?;
+                if let Ok(address) = register(operands[1]) {
+                    let prefix = if *mnemonic == "lw" { 0x2100 } else { 0x2200 };
+                    self.words.push(prefix | (address << 4) | dest);
+                } else if *mnemonic == "lw" {
+                    let value = immediate_u16(operands[1])?;
+                    self.words.push(0x3000 | (dest << 8) | (value & 0x00FF));
+                    if value != value as u8 as i8 as u16 {
+                        self.words.push(0x4000 | (dest << 8) | (value >> 8));
+                    }
+                } else {
+                    return Err(format!("'{}' is not a register.", operands[1]));
+                }
+            }
+            "lhi" => {
+                expect(2)?;
+                let register = register(operands[0])?;
+                let value = immediate_u16(operands[1])?;
+                if value & 0x00FF != 0 {
+                    return Err(format!(
+                        "lhi only sets the high byte, but 0x{:04X} has a low byte.",
+                        value
+                    ));
+                }
+                self.words.push(0x4000 | (register << 8) | (value >> 8));
+            }
+            "b" => {
+                expect(2)?;
+                let register = register(operands[0])?;
+                self.target(line_number, operands[1], Fixup::Branch { register })?;
+            }
+            "j" => self.jump(line_number, operands)?,
+            "ill" | ".word" => {
+                expect(1)?;
+                let word = immediate_u16(operands[0])?;
+                self.words.push(word);
+            }
+            _ => {
+                if let Some(function) = unary_function(mnemonic) {
+                    if operands.is_empty() || operands.len() > 2 {
+                        return Err(format!(
+                            "'{}' takes 1 or 2 operand(s), got {}.",
+                            mnemonic,
+                            operands.len()
+                        ));
+                    }
+                    let dest = register(operands[0])?;
+                    let src = match operands.get(1) {
+                        Some(src) => register(src)?,
+                        None => dest,
+                    };
+                    self.words
+                        .push(0x5000 | (function << 8) | (src << 4) | dest);
+                } else if let Some(function) = binary_function(mnemonic) {
+                    expect(2)?;
+                    let (lhs, rhs) = (register(operands[0])?, register(operands[1])?);
+                    self.words.push(0x6000 | (function << 8) | (lhs << 4) | rhs);
+                } else if let Some(flags) = compare_flags(mnemonic) {
+                    expect(2)?;
+                    let lhs = register(operands[0])?;
+                    if operands[1] == "0" {
+                        self.words.push(0x1100 | (flags << 4) | lhs);
+                    } else {
+                        let rhs = register(operands[1])?;
+                        self.words.push(0x8000 | (flags << 8) | (lhs << 4) | rhs);
+                    }
+                } else {
+                    return Err(format!("Unknown mnemonic '{}'.", mnemonic));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn label(&mut self, label: &str) -> Result<(), String> {
+        if label.is_empty() || !label.chars().all(|c| c.is_alphanumeric() || c == '_') {
+            return Err(format!("'{}' is not a valid label name.", label));
+        }
+        if self.labels.insert(label.into(), self.words.len()).is_some() {
+            return Err(format!("Label '{}' is defined more than once.", label));
+        }
+        Ok(())
+    }
+
+    /// `j label`, `j -0x6`, `j r7`, `j r7 + 0x10`, or `j r7 -0x10`.
+    fn jump(&mut self, line_number: usize, operands: &[&str]) -> Result<(), String> {
+        let Some((first, rest)) = operands.split_first() else {
+            return Err("'j' takes a target.".into());
+        };
+        let Ok(register) = register(first) else {
+            if !rest.is_empty() {
+                return Err(format!(
+                    "'j {}' takes 1 operand(s), got {}.",
+                    first,
+                    operands.len()
+                ));
+            }
+            return self.target(line_number, first, Fixup::JumpImm);
+        };
+        let offset = match rest {
+            [] => 0,
+            [offset] => immediate(offset)?,
+            ["+", offset] => immediate(offset)?,
+            ["-", offset] => -immediate(offset)?,
+            _ => return Err(format!("Cannot parse the offset '{}'.", rest.join(" "))),
+        };
+        if !(-0x80..=0x7F).contains(&offset) {
+            return Err(format!(
+                "Offset {} is out of range, must be between -128 and 127.",
+                offset
+            ));
+        }
+        self.words
+            .push(0xB000 | (register << 8) | (offset as i8 as u8 as u16));
+        Ok(())
+    }
+
+    /// Emits a branch or jump by immediate, either to a label or by a relative offset.
+    fn target(&mut self, line_number: usize, target: &str, fixup: Fixup) -> Result<(), String> {
+        if target.starts_with(|c: char| c.is_ascii_digit() || c == '-' || c == '+') {
+            let relative = immediate(target)?;
+            let relative = i32::try_from(relative)
+                .map_err(|_| format!("Offset {} is out of range.", relative))?;
+            let word = match fixup {
+                Fixup::Branch { register } => encode_branch(register, relative),
+                Fixup::JumpImm => encode_jump_imm(relative),
+            };
+            self.words.push(word.map_err(|err| err.to_string())?);
+        } else {
+            self.fixups
+                .push((self.words.len(), line_number, target.into(), fixup));
+            // Placeholder, overwritten by resolve.
+            self.words.push(0x0000);
+        }
+        Ok(())
+    }
+
+    fn resolve(mut self) -> Result<Vec<u16>, AsmError> {
+        for (address, line, label, fixup) in &self.fixups {
+            let Some(target) = self.labels.get(label) else {
+                return Err(AsmError {
+                    line: *line,
+                    message: format!("Label '{}' is never defined.", label),
+                });
+            };
+            let relative = *target as i32 - *address as i32;
+            let encoded = match fixup {
+                Fixup::Branch { register } => encode_branch(*register, relative),
+                Fixup::JumpImm => encode_jump_imm(relative),
+            };
+            self.words[*address] = encoded.map_err(|err| AsmError {
+                line: *line,
+                message: format!("Cannot reach label '{}': {}", label, err),
+            })?;
+        }
+        Ok(self.words)
+    }
+}
+
+/// Parses `r0` to `r15`, but not `r01`.
+fn register(token: &str) -> Result<u16, String> {
+    let index = token.strip_prefix('r').unwrap_or_default();
+    let canonical = index.bytes().all(|b| b.is_ascii_digit()) && !index.starts_with('0');
+    match index.parse::<u16>() {
+        Ok(index) if index < 16 && (canonical || index == 0 && token == "r0") => Ok(index),
+        _ => Err(format!("'{}' is not a register.", token)),
+    }
+}
+
+/// Parses a decimal, `0x` hex, or `0b` binary number, with an optional sign.
+fn immediate(token: &str) -> Result<i64, String> {
+    let (negative, digits) = match token.as_bytes().first() {
+        Some(b'-') => (true, &token[1..]),
+        Some(b'+') => (false, &token[1..]),
+        _ => (false, token),
+    };
+    let parsed = if let Some(hex) = digits.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = digits.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        digits.parse::<i64>()
+    };
+    let value = parsed.map_err(|_| format!("'{}' is not a number.", token))?;
+    Ok(if negative { -value } else { value })
+}
+
+/// The offset of `lw rD, rA + N` and `sw rA + N, rV`, from 0 to 7.
+fn memory_offset(token: &str) -> Result<u16, String> {
+    let value = immediate(token)?;
+    if !(0..=7).contains(&value) {
+        return Err(format!(
+            "Offset {} is out of range, must be between 0 and 7.",
+            value
+        ));
+    }
+    Ok(value as u16)
+}
+
+/// Like `immediate`, but must fit into a word, either signed or unsigned.
+fn immediate_u16(token: &str) -> Result<u16, String> {
+    let value = immediate(token)?;
+    if !(-0x8000..=0xFFFF).contains(&value) {
+        return Err(format!("{} does not fit into 16 bits.", token));
+    }
+    Ok(value as u16)
+}
+
+fn unary_function(mnemonic: &str) -> Option<u16> {
+    Some(match mnemonic {
+        "decr" => 0b1000,
+        "incr" => 0b1001,
+        "not" => 0b1010,
+        "popcnt" => 0b1011,
+        "clz" => 0b1100,
+        "ctz" => 0b1101,
+        "rnd" => 0b1110,
+        "mov" | "mv" => 0b1111,
+        _ => return None,
+    })
+}
+
+fn binary_function(mnemonic: &str) -> Option<u16> {
+    Some(match mnemonic {
+        "add" => 0b0000,
+        "sub" => 0b0001,
+        "mul" => 0b0010,
+        "mulh" => 0b0011,
+        "div.u" => 0b0100,
+        "div.s" => 0b0101,
+        "mod.u" => 0b0110,
+        "mod.s" => 0b0111,
+        "and" => 0b1000,
+        "or" => 0b1001,
+        "xor" => 0b1010,
+        "sl" => 0b1011,
+        "srl" => 0b1100,
+        "sra" => 0b1101,
+        "exp" => 0b1110,
+        "root" => 0b1111,
+        _ => return None,
+    })
+}
+
+/// The LEGS flags of a compare mnemonic, see `disasm`.
+fn compare_flags(mnemonic: &str) -> Option<u16> {
+    let (name, signed) = match mnemonic.strip_suffix(".s") {
+        Some(name) => (name, 0b0001),
+        None => (mnemonic, 0b0000),
+    };
+    let flags = match name {
+        "false" => 0b0000,
+        "gt" => 0b0010,
+        "eq" => 0b0100,
+        "ge" => 0b0110,
+        "lt" => 0b1000,
+        "ne" => 0b1010,
+        "le" => 0b1100,
+        "true" => 0b1110,
+        _ => return None,
+    };
+    Some(flags | signed)
+}
+
+#[cfg(test)]
+mod test_asm {
+    use super::*;
+    use crate::disasm::disassemble;
+    use crate::selftest::FIBONACCI;
+
+    fn error(line: usize, message: &str) -> AsmError {
+        AsmError {
+            line,
+            message: message.into(),
+        }
+    }
+
+    #[test]
+    fn test_fibonacci_listing() {
+        // The comments of selftest::FIBONACCI.
+        let source = "
+            lw r0, 24
+            lw r1, 1
+            .label start:
+            add r1 r2
+            decr r0
+            sw r0, r2
+            add r2 r1
+            decr r0
+            sw r0, r1
+            b r0 start // (offset is -0x6)
+            ret
+        ";
+        assert_eq!(assemble_words(source).unwrap(), FIBONACCI.instructions);
+    }
+
+    #[test]
+    fn test_time_long_listing() {
+        // The comments of test_time_long in tests/instructions.rs.
+        let source = "
+            lw r7, 0xFFAB
+            decr r7
+            b r7 -0x1
+            time
+            ret
+        ";
+        assert_eq!(
+            assemble_words(source).unwrap(),
+            vec![0x37AB, 0x5877, 0x9780, 0x102D, 0x102A]
+        );
+    }
+
+    #[test]
+    fn test_segment() {
+        let segment = assemble("lw r2, 0x1234\nj r2 - 0x80\n").unwrap();
+        assert_eq!(segment[0], 0x3234);
+        assert_eq!(segment[1], 0x4212);
+        assert_eq!(segment[2], 0xB280);
+        assert_eq!(segment[3], 0x0000);
+    }
+
+    #[test]
+    fn test_memory_offset() {
+        let source = "
+            lw r2, r1 + 3
+            sw r1 + 0x7, r2
+            sw r15 + 0, r0
+        ";
+        assert_eq!(
+            assemble_words(source).unwrap(),
+            vec![0x7312, 0x7F12, 0x78F0]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let source = "
+            top:
+            j end
+            b r3, top
+            .label end
+            j top
+        ";
+        assert_eq!(
+            assemble_words(source).unwrap(),
+            vec![0xA000, 0x9380, 0xA801]
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            assemble_words("ret\nfrobnicate r0\n"),
+            Err(error(2, "Unknown mnemonic 'frobnicate'."))
+        );
+        assert_eq!(
+            assemble_words("lw r16, 5"),
+            Err(error(1, "'r16' is not a register."))
+        );
+        assert_eq!(
+            assemble_words("lw r01, 5"),
+            Err(error(1, "'r01' is not a register."))
+        );
+        assert_eq!(
+            assemble_words("lw r1, 0x10000"),
+            Err(error(1, "0x10000 does not fit into 16 bits."))
+        );
+        assert_eq!(
+            assemble_words("\n\nadd r1"),
+            Err(error(3, "'add' takes 2 operand(s), got 1."))
+        );
+        assert_eq!(
+            assemble_words("a:\nret\na:"),
+            Err(error(3, "Label 'a' is defined more than once."))
+        );
+        assert_eq!(
+            assemble_words("ret\nj nowhere\n"),
+            Err(error(2, "Label 'nowhere' is never defined."))
+        );
+        assert_eq!(
+            assemble_words("here:\nb r0 here"),
+            Err(error(
+                2,
+                "Cannot reach label 'here': Relative offset 0 cannot be encoded, it would be an infinite loop or a no-op."
+            ))
+        );
+        assert_eq!(
+            assemble_words("lw r1, r2 + 8"),
+            Err(error(
+                1,
+                "Offset 8 is out of range, must be between 0 and 7."
+            ))
+        );
+        assert_eq!(
+            assemble_words("sw r1 + 2"),
+            Err(error(1, "'sw' takes 4 operand(s), got 3."))
+        );
+        assert_eq!(
+            assemble_words("lhi r1, 0x1234").unwrap_err().to_string(),
+            "Line 1: lhi only sets the high byte, but 0x1234 has a low byte."
+        );
+    }
+
+    #[test]
+    fn test_disassembly_round_trip() {
+        for word in 0..=0xFFFF {
+            let text = disassemble(word);
+            // Both legal and illegal words.
+            assert_eq!(assemble_words(&text), Ok(vec![word]), "{}", text);
+        }
+    }
+}
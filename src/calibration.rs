@@ -0,0 +1,81 @@
+use crate::vm::{Segment, StepResult, VirtualMachine};
+use std::time::Duration;
+
+/// How many steps the calibration benchmark runs. This takes a few milliseconds on a typical host.
+pub const CALIBRATION_STEPS: u64 = 1_000_000;
+
+/// Runs a short busy loop and returns how many steps this host executes per millisecond.
+///
+/// `elapsed` must return the time since some fixed point in the past, e.g. `|| start.elapsed()`.
+/// It is called exactly twice: right before and right after the benchmark.
+pub fn measure_steps_per_ms(mut elapsed: impl FnMut() -> Duration) -> u64 {
+    let mut instructions = Segment::new_zeroed();
+    instructions[0] = 0x5911; // incr r1
+    instructions[1] = 0xA800; // j -0x1
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+
+    let before = elapsed();
+    for _ in 0..CALIBRATION_STEPS {
+        let step_result = vm.step();
+        debug_assert_eq!(step_result, StepResult::Continue);
+    }
+    let after = elapsed();
+
+    steps_per_ms(CALIBRATION_STEPS, after.saturating_sub(before))
+}
+
+/// Converts a measured number of steps in the given duration into steps per millisecond.
+///
+/// The result is always at least 1, even on absurdly slow hosts or when the duration is zero.
+#[must_use]
+pub fn steps_per_ms(steps: u64, duration: Duration) -> u64 {
+    let nanos = duration.as_nanos().max(1);
+    let per_ms = (steps as u128) * 1_000_000 / nanos;
+    per_ms.clamp(1, u64::MAX as u128) as u64
+}
+
+/// Converts a wall-clock time limit into an instruction budget, given a calibrated speed.
+#[must_use]
+pub fn budget_for_time_limit(time_limit_ms: u64, steps_per_ms: u64) -> u64 {
+    time_limit_ms.saturating_mul(steps_per_ms).max(1)
+}
+
+#[cfg(test)]
+mod test_calibration {
+    use super::*;
+
+    #[test]
+    fn test_steps_per_ms() {
+        assert_eq!(steps_per_ms(1_000_000, Duration::from_millis(250)), 4000);
+        assert_eq!(steps_per_ms(1_000_000, Duration::from_millis(1)), 1_000_000);
+        assert_eq!(steps_per_ms(3, Duration::from_micros(1500)), 2);
+    }
+
+    #[test]
+    fn test_steps_per_ms_extreme() {
+        assert_eq!(steps_per_ms(1, Duration::from_secs(10)), 1);
+        assert_eq!(
+            steps_per_ms(1_000_000, Duration::ZERO),
+            1_000_000 * 1_000_000
+        );
+        assert_eq!(steps_per_ms(u64::MAX, Duration::ZERO), u64::MAX);
+    }
+
+    #[test]
+    fn test_budget() {
+        assert_eq!(budget_for_time_limit(100, 4000), 400_000);
+        assert_eq!(budget_for_time_limit(0, 4000), 1);
+        assert_eq!(budget_for_time_limit(u64::MAX, 2), u64::MAX);
+    }
+
+    #[test]
+    fn test_measure_mocked() {
+        let mut calls = 0;
+        let per_ms = measure_steps_per_ms(|| {
+            calls += 1;
+            Duration::from_millis(if calls == 1 { 1000 } else { 1500 })
+        });
+        assert_eq!(calls, 2);
+        assert_eq!(per_ms, CALIBRATION_STEPS / 500);
+    }
+}
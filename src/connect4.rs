@@ -1,5 +1,23 @@
-use crate::vm::{Segment, StepResult, VirtualMachine};
-
+use crate::vm::{
+    run_stepping, CostModel, CpuFeatures, DebugDumpHandler, Fnv1a, InsnStats, Protection,
+    ReplaySource, RunOutcome, Segment, SharedRandomSource, SplitMix64, StepResult, StopReason,
+    Tracer, VirtualMachine,
+};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+mod checkpoint;
+pub mod layout;
+#[cfg(feature = "serde")]
+mod serde_impl;
+
+use checkpoint::Checkpoints;
+pub use checkpoint::GameCheckpoint;
+use layout::{Layout, MoveInfo};
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Player {
     One,
@@ -16,6 +34,7 @@ impl Player {
 }
 
 #[repr(u8)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum SlotState {
     Token(Player),
@@ -30,6 +49,98 @@ pub enum PlacementResult {
     Connect4,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BoardError {
+    /// Each dimension must be in `MIN_DIMENSION..=MAX_DIMENSION`.
+    SillyDimensions {
+        width: usize,
+        height: usize,
+    },
+    /// The board must fit into the data segment, below the words at 0xFF80.
+    TooManySlots {
+        width: usize,
+        height: usize,
+    },
+    /// The compact representation must have exactly one character per slot.
+    WrongSlotCount {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidSlotCharacter {
+        index: usize,
+        character: char,
+    },
+    /// A token must not sit on top of an empty slot.
+    FloatingToken {
+        x: usize,
+        y: usize,
+    },
+    /// Player one always moves first, so it has as many tokens as player two, or exactly one more.
+    ImplausibleTokenCounts {
+        one: usize,
+        two: usize,
+    },
+}
+
+impl Display for BoardError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            BoardError::SillyDimensions { width, height } => write!(
+                f,
+                "{}x{} are silly dimensions! Width and height must be between {} and {}.",
+                width, height, MIN_DIMENSION, MAX_DIMENSION
+            ),
+            BoardError::TooManySlots { width, height } => write!(
+                f,
+                "A {}x{} board has {} slots, but at most {} fit into the data segment.",
+                width,
+                height,
+                width * height,
+                MAX_SLOTS
+            ),
+            BoardError::WrongSlotCount { expected, actual } => {
+                write!(f, "Expected {} slots, got {} instead.", expected, actual)
+            }
+            BoardError::InvalidSlotCharacter { index, character } => write!(
+                f,
+                "Slot {} is '{}', but must be one of '{}', '{}', or '{}'.",
+                index, character, SLOT_CHAR_EMPTY, SLOT_CHAR_ONE, SLOT_CHAR_TWO
+            ),
+            BoardError::FloatingToken { x, y } => {
+                write!(
+                    f,
+                    "The token at ({}, {}) is floating above an empty slot.",
+                    x, y
+                )
+            }
+            BoardError::ImplausibleTokenCounts { one, two } => write!(
+                f,
+                "Player one has {} tokens and player two has {}, which cannot happen in a game.",
+                one, two
+            ),
+        }
+    }
+}
+
+impl Error for BoardError {}
+
+pub const MIN_DIMENSION: usize = 4;
+pub const MAX_DIMENSION: usize = 0x3FF;
+/// The board is encoded at the beginning of the data segment, and must not overlap the words starting at 0xFF80.
+pub const MAX_SLOTS: usize = 0xFF80;
+
+pub const SLOT_CHAR_EMPTY: char = '.';
+pub const SLOT_CHAR_ONE: char = 'X';
+pub const SLOT_CHAR_TWO: char = 'O';
+
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        try_from = "serde_impl::CompactBoard",
+        into = "serde_impl::CompactBoard"
+    )
+)]
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Board {
     slots: Vec<SlotState>,
@@ -38,29 +149,43 @@ pub struct Board {
 }
 
 impl Board {
-    pub fn new_custom(width: usize, height: usize) -> Board {
-        assert!(
-            3 < width && width < 0x400 && 3 < height && height < 0x400,
-            "{}x{} are silly dimensions!",
-            width,
-            height
-        );
-        Board {
+    pub fn try_new(width: usize, height: usize) -> Result<Board, BoardError> {
+        let dimensions = MIN_DIMENSION..=MAX_DIMENSION;
+        if !dimensions.contains(&width) || !dimensions.contains(&height) {
+            return Err(BoardError::SillyDimensions { width, height });
+        }
+        if width * height > MAX_SLOTS {
+            return Err(BoardError::TooManySlots { width, height });
+        }
+        Ok(Board {
             slots: vec![SlotState::Empty; width * height],
             width,
             height,
+        })
+    }
+
+    /// Like `try_new`, but panics on invalid dimensions.
+    pub fn new_custom(width: usize, height: usize) -> Board {
+        match Board::try_new(width, height) {
+            Ok(board) => board,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    fn try_index(&self, x: usize, y: usize) -> Option<usize> {
+        if x < self.width && y < self.height {
+            // Same "weird" order as in the data segment layout.
+            Some(x * self.height + y)
+        } else {
+            None
         }
     }
 
     fn index(&self, x: usize, y: usize) -> usize {
-        assert!(
-            x < self.width && y < self.height,
-            "({}, {}) out of bounds",
-            x,
-            y
-        );
-        // Same "weird" order as in the data segment layout.
-        x * self.height + y
+        match self.try_index(x, y) {
+            Some(index) => index,
+            None => panic!("({}, {}) out of bounds", x, y),
+        }
     }
 
     pub fn get_width(&self) -> usize {
@@ -75,6 +200,68 @@ impl Board {
         self.slots[self.index(x, y)]
     }
 
+    /// Like `get_slot`, but returns `None` for out-of-bounds coordinates.
+    pub fn try_get_slot(&self, x: usize, y: usize) -> Option<SlotState> {
+        self.try_index(x, y).map(|index| self.slots[index])
+    }
+
+    /// Returns one character per slot, in the same order as in the data segment layout: First the bottom-most slot
+    /// of the left-most column, then the rest of that column, then the next column, and so on.
+    pub fn to_compact_string(&self) -> String {
+        self.slots
+            .iter()
+            .map(|slot| match slot {
+                SlotState::Empty => SLOT_CHAR_EMPTY,
+                SlotState::Token(Player::One) => SLOT_CHAR_ONE,
+                SlotState::Token(Player::Two) => SLOT_CHAR_TWO,
+            })
+            .collect()
+    }
+
+    /// Inverse of `to_compact_string`. Rejects boards that cannot occur in a game.
+    pub fn from_compact_string(
+        width: usize,
+        height: usize,
+        compact: &str,
+    ) -> Result<Board, BoardError> {
+        let mut board = Board::try_new(width, height)?;
+        let actual = compact.chars().count();
+        if actual != board.slots.len() {
+            return Err(BoardError::WrongSlotCount {
+                expected: board.slots.len(),
+                actual,
+            });
+        }
+        let (mut one, mut two) = (0, 0);
+        for (index, character) in compact.chars().enumerate() {
+            board.slots[index] = match character {
+                SLOT_CHAR_EMPTY => SlotState::Empty,
+                SLOT_CHAR_ONE => {
+                    one += 1;
+                    SlotState::Token(Player::One)
+                }
+                SLOT_CHAR_TWO => {
+                    two += 1;
+                    SlotState::Token(Player::Two)
+                }
+                _ => return Err(BoardError::InvalidSlotCharacter { index, character }),
+            };
+        }
+        for x in 0..width {
+            for y in 1..height {
+                if board.get_slot(x, y) != SlotState::Empty
+                    && board.get_slot(x, y - 1) == SlotState::Empty
+                {
+                    return Err(BoardError::FloatingToken { x, y });
+                }
+            }
+        }
+        if one != two && one != two + 1 {
+            return Err(BoardError::ImplausibleTokenCounts { one, two });
+        }
+        Ok(board)
+    }
+
     fn count_towards(&self, x: usize, y: usize, dx: isize, dy: isize) -> usize {
         let expect_slot = self.get_slot(x, y);
         assert!(
@@ -149,13 +336,16 @@ impl Board {
     }
 
     fn encode_onto(&self, current_player: Player, segment: &mut Segment) {
-        for (i, slot_state) in self.slots.iter().enumerate() {
-            segment[i as u16] = match slot_state {
+        let words = self
+            .slots
+            .iter()
+            .map(|slot_state| match slot_state {
                 SlotState::Empty => 0,
                 SlotState::Token(token_player) if *token_player == current_player => 1,
                 SlotState::Token(_) => 2,
-            };
-        }
+            })
+            .collect::<Vec<_>>();
+        segment.copy_from_slice_at(0, &words);
     }
 
     pub fn is_full(&self) -> bool {
@@ -200,6 +390,117 @@ mod test_board {
         assert_eq!(b.index(2, 0), 2 * DEFAULT_HEIGHT);
     }
 
+    #[test]
+    fn test_try_new() {
+        assert!(Board::try_new(4, 4).is_ok());
+        assert!(Board::try_new(MAX_DIMENSION, 4).is_ok());
+        assert!(Board::try_new(4, MAX_DIMENSION).is_ok());
+        assert!(Board::try_new(0xFF, 0xFF).is_ok());
+        assert!(Board::try_new(0x100, 0xFF).is_ok());
+        assert!(Board::try_new(0x100, 0xFF80 / 0x100).is_ok());
+    }
+
+    #[test]
+    fn test_try_new_silly() {
+        for (width, height) in [
+            (0, 0),
+            (3, 6),
+            (7, 3),
+            (MAX_DIMENSION + 1, 6),
+            (7, MAX_DIMENSION + 1),
+            (usize::MAX, usize::MAX),
+        ] {
+            assert_eq!(
+                Board::try_new(width, height),
+                Err(BoardError::SillyDimensions { width, height })
+            );
+        }
+        assert_eq!(
+            Board::try_new(3, 6).unwrap_err().to_string(),
+            "3x6 are silly dimensions! Width and height must be between 4 and 1023."
+        );
+    }
+
+    #[test]
+    fn test_try_new_too_many_slots() {
+        for (width, height) in [
+            (0x100, 0x100),
+            (0xFF, 0x101),
+            (MAX_DIMENSION, MAX_DIMENSION),
+        ] {
+            assert_eq!(
+                Board::try_new(width, height),
+                Err(BoardError::TooManySlots { width, height })
+            );
+        }
+        assert_eq!(
+            Board::try_new(0x100, 0x100).unwrap_err().to_string(),
+            "A 256x256 board has 65536 slots, but at most 65408 fit into the data segment."
+        );
+    }
+
+    #[test]
+    fn test_compact_string() {
+        let mut b = Board::new_custom(4, 4);
+        assert_eq!(b.to_compact_string(), "................");
+        b.place_into_unsanitized_column(1, Player::One);
+        b.place_into_unsanitized_column(1, Player::Two);
+        b.place_into_unsanitized_column(3, Player::One);
+        assert_eq!(b.to_compact_string(), "....XO......X...");
+        assert_eq!(Board::from_compact_string(4, 4, "....XO......X..."), Ok(b));
+    }
+
+    #[test]
+    fn test_compact_string_reject() {
+        assert_eq!(
+            Board::from_compact_string(4, 4, "....XO......X.."),
+            Err(BoardError::WrongSlotCount {
+                expected: 16,
+                actual: 15
+            })
+        );
+        assert_eq!(
+            Board::from_compact_string(4, 4, "....Xo......X..."),
+            Err(BoardError::InvalidSlotCharacter {
+                index: 5,
+                character: 'o'
+            })
+        );
+        assert_eq!(
+            Board::from_compact_string(4, 4, "....X.O.....X..."),
+            Err(BoardError::FloatingToken { x: 1, y: 2 })
+        );
+        assert_eq!(
+            Board::from_compact_string(4, 4, "....OO......X..."),
+            Err(BoardError::ImplausibleTokenCounts { one: 1, two: 2 })
+        );
+        assert_eq!(
+            Board::from_compact_string(4, 4, "....XX......X..."),
+            Err(BoardError::ImplausibleTokenCounts { one: 3, two: 0 })
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "3x6 are silly dimensions!")]
+    fn test_new_custom_panics() {
+        let _ = Board::new_custom(3, 6);
+    }
+
+    #[test]
+    fn test_try_get_slot() {
+        let mut b = Board::default();
+        b.place_into_unsanitized_column(2, Player::Two);
+        assert_eq!(b.try_get_slot(2, 0), Some(SlotState::Token(Player::Two)));
+        assert_eq!(b.try_get_slot(0, 0), Some(SlotState::Empty));
+        assert_eq!(
+            b.try_get_slot(DEFAULT_WIDTH - 1, DEFAULT_HEIGHT - 1),
+            Some(SlotState::Empty)
+        );
+        assert_eq!(b.try_get_slot(DEFAULT_WIDTH, 0), None);
+        assert_eq!(b.try_get_slot(0, DEFAULT_HEIGHT), None);
+        assert_eq!(b.try_get_slot(usize::MAX, usize::MAX), None);
+    }
+
     #[test]
     fn test_encoding_empty() {
         let segment_expect = Segment::new_zeroed();
@@ -298,9 +599,9 @@ mod test_board {
         fn fill_column(col: u16, board: &mut Board, starting_with: Player) {
             assert_eq!(board.get_height(), 6);
             for _ in 0..3 {
-                assert_eq!(board.is_full(), false);
+                assert!(!board.is_full());
                 assert_place_success(board, col, starting_with);
-                assert_eq!(board.is_full(), false);
+                assert!(!board.is_full());
                 assert_place_success(board, col, starting_with.other());
             }
         }
@@ -314,7 +615,7 @@ mod test_board {
         fill_column(5, &mut b, Player::One);
         fill_column(6, &mut b, Player::One);
 
-        assert_eq!(b.is_full(), true);
+        assert!(b.is_full());
     }
 
     #[test]
@@ -322,19 +623,19 @@ mod test_board {
         let mut board = Board::default();
 
         assert_place_success(&mut board, 0, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 2, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 6, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 5, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 4, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 3, Player::One);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
     }
 
     #[test]
@@ -342,11 +643,11 @@ mod test_board {
         let mut board = Board::default();
 
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 2, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 4, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_eq!(
             board.place_into_unsanitized_column(3, Player::Two),
             PlacementResult::Connect4
@@ -358,13 +659,13 @@ mod test_board {
         let mut board = Board::default();
 
         assert_place_success(&mut board, 1, Player::One);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_eq!(
             board.place_into_unsanitized_column(1, Player::Two),
             PlacementResult::Connect4
@@ -376,15 +677,15 @@ mod test_board {
         let mut board = Board::default();
 
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::One);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
-        assert_eq!(board.is_full(), false);
+        assert!(!board.is_full());
         assert_place_success(&mut board, 1, Player::Two);
         assert_eq!(
             board.place_into_unsanitized_column(1, Player::Two),
@@ -439,31 +740,120 @@ mod test_board {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct PlayerData {
-    instructions: Segment,
+    instructions: Arc<Segment>,
     data: Segment,
     last_move: u16,
     total_moves: u16,
+    deterministic_so_far: bool,
+    last_move_deterministic: bool,
+    random_calls: u64,
+    last_vm: Option<VirtualMachine>,
+    layout: Layout,
+    total_insns: u64,
+    insn_mix: Option<InsnStats>,
+    seed: Option<u64>,
+    tracer: Option<Tracer>,
+    debug_dump_handler: Option<DebugDumpHandler>,
+    features: CpuFeatures,
+    random_source: Option<SharedRandomSource>,
+    random_trace: Option<Vec<u16>>,
+    cost_model: Option<CostModel>,
+    detect_loops: bool,
 }
 
+// Written to 0xFF80 and 0xFF81 by `update_data` (see `layout::Layout::V1`) before *every* move, not just once. The program cannot overwrite
+// them (see `Layout::read_only_data`), but the host may have changed the data segment in between.
 pub const GAME_VERSION_MAJOR: u16 = 0x0001;
 pub const GAME_VERSION_MINOR: u16 = 0x0000;
 
+/// How many steps a program may run between two looks at the clock, see `PlayerData::determine_answer_until`.
+/// Looking at the clock costs about as much as a few hundred steps, so this keeps the overhead negligible, while
+/// still stopping within a millisecond or so of the deadline.
+const WALL_CLOCK_CHECK_STEPS: u64 = 1 << 16;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum AlgorithmResult {
     Column(u16),
-    IllegalInstruction(u16),
+    IllegalInstruction {
+        insn: u16,
+        pc: u16,
+    },
     Timeout,
+    /// The program would have timed out, because it repeats the same `period` instructions forever, see
+    /// `PlayerData::set_detect_loops`.
+    LoopDetected {
+        period: u64,
+    },
+    /// The host could not provide entropy for `rnd`, see `StepResult::RandomnessUnavailable`.
+    RandomnessUnavailable,
+    /// The deadline of `PlayerData::determine_answer_until` passed before the program returned.
+    WallClockTimeout,
+    /// The program tried to overwrite the read-only address `addr`, see `Layout::read_only_data`.
+    ProtectionFault {
+        addr: u16,
+        pc: u16,
+    },
+}
+
+/// Summarizes the segments instead of dumping them, see `Segment::summary`.
+impl Debug for PlayerData {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("PlayerData")
+            .field("instructions", &self.instructions.summary())
+            .field("data", &self.data.summary())
+            .field("last_move", &self.last_move)
+            .field("total_moves", &self.total_moves)
+            .field("deterministic_so_far", &self.deterministic_so_far)
+            .field("last_move_deterministic", &self.last_move_deterministic)
+            .field("last_vm", &self.last_vm)
+            .field("layout", &self.layout)
+            .field("total_insns", &self.total_insns)
+            .field("insn_mix", &self.insn_mix)
+            .field("seed", &self.seed)
+            .field("features", &self.features)
+            .finish()
+    }
 }
 
 impl PlayerData {
+    /// Creates a player that has not moved yet. This is also the supported way to drive a single program outside
+    /// of a `Game`: Call `update_data` and then `determine_answer` for each move.
+    ///
+    /// The version words of the layout are read-only for the program, see `Layout::read_only_data`, so a program that
+    /// stores to them loses with `AlgorithmResult::ProtectionFault` instead of confusing itself later.
     pub fn new(instructions: Segment) -> PlayerData {
+        PlayerData::new_with_layout(instructions, Layout::default())
+    }
+
+    pub fn new_with_layout(instructions: Segment, layout: Layout) -> PlayerData {
+        PlayerData::new_shared(Arc::new(instructions), layout)
+    }
+
+    /// Like `new_with_layout`, but shares the instruction segment, e.g. across many games of the same programs.
+    /// Neither creating the player nor any move copies the instructions, see `VirtualMachine::new_shared`.
+    pub fn new_shared(instructions: Arc<Segment>, layout: Layout) -> PlayerData {
         PlayerData {
             instructions,
             data: Segment::new_zeroed(),
             last_move: 0xFFFF,
             total_moves: 0,
+            deterministic_so_far: true,
+            last_move_deterministic: true,
+            random_calls: 0,
+            last_vm: None,
+            layout,
+            total_insns: 0,
+            insn_mix: None,
+            seed: None,
+            tracer: None,
+            debug_dump_handler: None,
+            features: CpuFeatures::default(),
+            random_source: None,
+            random_trace: None,
+            cost_model: None,
+            detect_loops: false,
         }
     }
 
@@ -471,6 +861,152 @@ impl PlayerData {
         self.total_moves
     }
 
+    /// Returns the column chosen in the last successful move, or 0xFFFF if there was none yet.
+    pub fn get_last_move(&self) -> u16 {
+        self.last_move
+    }
+
+    pub fn get_instructions(&self) -> &Segment {
+        &self.instructions
+    }
+
+    /// Returns the data segment as it will be presented at the start of the next move (before `update_data`).
+    pub fn get_data(&self) -> &Segment {
+        &self.data
+    }
+
+    /// Returns the VM of the most recent call to `determine_answer`, in its final state. This is also available if
+    /// the move failed, e.g. to inspect the registers after a timeout, or `VirtualMachine::recent_pcs` after an
+    /// illegal instruction.
+    pub fn get_vm(&self) -> Option<&VirtualMachine> {
+        self.last_vm.as_ref()
+    }
+
+    /// Returns false if any move so far has drawn randomness, see `VirtualMachine::was_deterministic_so_far`.
+    pub fn was_deterministic_so_far(&self) -> bool {
+        self.deterministic_so_far
+    }
+
+    /// Returns false if the most recent move has drawn randomness. True if there was no move yet.
+    pub fn last_move_was_deterministic(&self) -> bool {
+        self.last_move_deterministic
+    }
+
+    /// Returns how many `rnd` instructions the program executed over all moves so far, including failed moves, see
+    /// `VirtualMachine::random_calls`. A program that draws once per move has as many calls as moves.
+    pub fn get_random_calls(&self) -> u64 {
+        self.random_calls
+    }
+
+    /// Returns a hash of everything the player carries from move to move: the data segment, the move counters, the
+    /// last move, and the state of the VM of the last move, see `VirtualMachine::state_hash`. Two players that
+    /// behaved identically have the same hash.
+    #[must_use]
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        hash.write_u64(self.data.fingerprint());
+        hash.write_u16(self.total_moves);
+        hash.write_u16(self.last_move);
+        hash.write_u64(self.total_insns);
+        hash.write_u64(self.last_vm.as_ref().map_or(0, VirtualMachine::state_hash));
+        hash.finish()
+    }
+
+    /// Returns the number of instructions executed over all moves so far, including failed moves. With a cost model,
+    /// this is their total cost instead.
+    pub fn get_total_insns(&self) -> u64 {
+        self.total_insns
+    }
+
+    /// Enables or disables counting executed instructions by class, see `get_insn_mix`. Enabling resets the counts.
+    /// This is off by default, and costs nothing when off.
+    pub fn set_collect_insn_mix(&mut self, collect: bool) {
+        self.insn_mix = collect.then(InsnStats::new);
+    }
+
+    /// Returns the instruction mix since it was enabled, or `None` if it is disabled.
+    pub fn get_insn_mix(&self) -> Option<&InsnStats> {
+        self.insn_mix.as_ref()
+    }
+
+    /// Switches the data segment to the sparse or dense representation, see `Segment::new_sparse`. This only changes
+    /// the memory footprint, never the behavior.
+    pub fn set_sparse_data(&mut self, sparse: bool) {
+        if sparse != self.data.is_sparse() {
+            self.data = if sparse {
+                self.data.to_sparse()
+            } else {
+                self.data.to_dense()
+            };
+        }
+    }
+
+    /// Makes `rnd` reproducible: With a seed, every move runs on `VirtualMachine::new_with_seed`, with a seed
+    /// derived from this one and the move number. `None` restores the operating system's entropy.
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.seed = seed;
+    }
+
+    pub fn get_seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Installs `tracer` on the VM of every future move, see `VirtualMachine::set_tracer`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Installs `handler` on the VM of every future move, see `VirtualMachine::set_debug_dump_handler`.
+    pub fn set_debug_dump_handler(&mut self, handler: Option<DebugDumpHandler>) {
+        self.debug_dump_handler = handler;
+    }
+
+    /// Makes `rnd` draw from `source` in every future move, see `VirtualMachine::set_random_source`. The source
+    /// takes precedence over `set_seed`, and continues where the previous move left off.
+    pub fn set_random_source(&mut self, source: Option<SharedRandomSource>) {
+        self.random_source = source;
+    }
+
+    /// Enables or disables recording the values drawn by `rnd` over all future moves, see `get_random_trace`.
+    /// Enabling discards the previous trace. This is off by default.
+    pub fn set_record_randomness(&mut self, record: bool) {
+        self.random_trace = record.then(Vec::new);
+    }
+
+    /// Returns the values drawn by `rnd` since recording was enabled, in order and over all moves, or `None` if it
+    /// is disabled.
+    pub fn get_random_trace(&self) -> Option<&[u16]> {
+        self.random_trace.as_deref()
+    }
+
+    /// Makes `rnd` return exactly `values` over all future moves, e.g. a trace from `get_random_trace`, see
+    /// `VirtualMachine::replay_randomness`. This replaces any random source.
+    pub fn replay_randomness(&mut self, values: Vec<u16>) {
+        self.random_source = Some(SharedRandomSource::new(Box::new(ReplaySource::new(values))));
+    }
+
+    /// Restricts the instruction set of every future move, see `VirtualMachine::with_features`.
+    pub fn set_cpu_features(&mut self, features: CpuFeatures) {
+        self.features = features;
+    }
+
+    pub fn get_cpu_features(&self) -> CpuFeatures {
+        self.features
+    }
+
+    /// Makes every future move charge instructions by `cost_model`, see `VirtualMachine::set_cost_model`. The
+    /// budget of a move then limits the total cost, which is also what the Time instruction reports.
+    pub fn set_cost_model(&mut self, cost_model: Option<CostModel>) {
+        self.cost_model = cost_model;
+    }
+
+    /// Makes every future move end as soon as the program is stuck in an endless loop, instead of running until the
+    /// budget is exhausted, see `VirtualMachine::enable_loop_detection`. The move then fails with
+    /// `AlgorithmResult::LoopDetected`, which a `Game` treats like a timeout. This is off by default.
+    pub fn set_detect_loops(&mut self, detect: bool) {
+        self.detect_loops = detect;
+    }
+
     pub fn update_data(
         &mut self,
         own_identity: Player,
@@ -478,60 +1014,178 @@ impl PlayerData {
         board: &Board,
         other: &PlayerData,
     ) {
-        // https://github.com/BenWiederhake/tinyvm/blob/master/data-layout/connect4.md#data-segment-content-and-layout-for-connect4
-        // - starting at 0x0000, size N words:
-        //     * Contains the entire board.
-        board.encode_onto(own_identity, &mut self.data);
-        // - 0xFF80: Major version of the game and data: Must always be 0x0001, to distinguish it from other games. (In case someone wants to write a multi-game algorithm.)
-        self.data[0xFF80] = GAME_VERSION_MAJOR;
-        // - 0xFF81: Minor version of the game and data: Should be 0x0000 for the version in this document.
-        self.data[0xFF81] = GAME_VERSION_MINOR;
-        // - 0xFF82: Total time available for this move, in 4 words, most significant word first, similar to the returned value of the Time instruction.
-        self.data[0xFF82] = (max_steps >> 48) as u16;
-        self.data[0xFF83] = (max_steps >> 32) as u16;
-        self.data[0xFF84] = (max_steps >> 16) as u16;
-        self.data[0xFF85] = max_steps as u16;
-        // - 0xFF86: Width of the board.
-        self.data[0xFF86] = board.get_width() as u16;
-        // - 0xFF87: Height of the board.
-        self.data[0xFF87] = board.get_height() as u16;
-        // - 0xFF88: Total number of moves made by the other player.
-        self.data[0xFF88] = other.total_moves;
-        // - 0xFF89: Total number of moves made by this player.
-        self.data[0xFF89] = self.total_moves;
-        // - 0xFF8A: Last move by other player. Again, 0-indexed. If this is the first move (and there is no previous move), this contains the value 0xFFFF.
-        self.data[0xFF8A] = other.last_move;
-        // - 0xFF8B-0xFFFF: These words may be overwritten arbitrarily on each turn by the game. If the game version is 0x0001.0x0000, then these words shall be overwritten with 0x0000.
-        for i in 0xFF8B..=0xFFFF {
-            self.data[i] = 0x0000;
-        }
+        let info = MoveInfo {
+            own_identity,
+            max_steps,
+            board,
+            own_total_moves: self.total_moves,
+            other_total_moves: other.total_moves,
+            other_last_move: other.last_move,
+        };
+        self.layout.write_move(&info, &mut self.data);
     }
 
+    /// Runs the player's program on a fresh VM. In particular, the time counter starts at zero for every move,
+    /// so the value of the Time instruction can be compared directly against the time available for this move.
     pub fn determine_answer(&mut self, max_steps: u64) -> AlgorithmResult {
-        let mut vm = VirtualMachine::new(self.instructions.clone(), self.data.clone());
-        for _ in 0..max_steps {
-            let last_step_result = vm.step();
-            match last_step_result {
-                StepResult::Continue => {}
-                StepResult::DebugDump => {}
-                StepResult::IllegalInstruction(insn) => {
-                    return AlgorithmResult::IllegalInstruction(insn);
-                }
-                StepResult::Return(column_index) => {
-                    self.data = vm.release_to_data_segment();
-                    self.last_move = column_index;
-                    self.total_moves += 1;
-                    return AlgorithmResult::Column(column_index);
-                }
+        self.determine_answer_until(max_steps, None)
+    }
+
+    /// Like `determine_answer`, but also gives up once the wall clock passes `deadline`. The clock is only checked
+    /// every `WALL_CLOCK_CHECK_STEPS` (65536) steps, so the program may overrun the deadline slightly.
+    pub fn determine_answer_until(
+        &mut self,
+        max_steps: u64,
+        deadline: Option<Instant>,
+    ) -> AlgorithmResult {
+        let instructions = Arc::clone(&self.instructions);
+        let data = self.data.clone();
+        let mut vm = match self.seed {
+            None => VirtualMachine::new_shared(instructions, data),
+            Some(seed) => {
+                // Every move gets its own seed, so that the program does not see the same numbers every time.
+                let move_seed =
+                    SplitMix64::new(seed.wrapping_add(self.total_moves as u64)).next_u64();
+                let mut vm = VirtualMachine::new_shared(instructions, data);
+                vm.set_seed(Some(move_seed));
+                vm
+            }
+        };
+        vm.set_features(self.features);
+        vm.protect_data(self.layout.read_only_data(), Protection::ReadOnly);
+        vm.set_cost_model(self.cost_model.clone());
+        vm.set_random_source(self.random_source.clone());
+        if self.random_trace.is_some() {
+            vm.record_randomness();
+        }
+        vm.set_tracer(self.tracer.clone());
+        vm.set_debug_dump_handler(self.debug_dump_handler.clone());
+        // Cheap, and tells how the program got to an illegal instruction.
+        vm.enable_recent_pcs();
+        if self.detect_loops {
+            vm.enable_loop_detection();
+        }
+        let mut outcome = RunOutcome {
+            steps: 0,
+            reason: StopReason::OutOfBudget,
+        };
+        let mut out_of_wall_clock = false;
+        loop {
+            let chunk = match deadline {
+                None => max_steps - outcome.steps,
+                Some(_) => (max_steps - outcome.steps).min(WALL_CLOCK_CHECK_STEPS),
+            };
+            let chunk_outcome = self.run_counting(&mut vm, chunk);
+            outcome.steps += chunk_outcome.steps;
+            outcome.reason = chunk_outcome.reason;
+            // With a cost model, the next instruction may not fit into the rest of the budget.
+            if outcome.reason != StopReason::OutOfBudget
+                || outcome.steps + vm.next_cost() > max_steps
+            {
+                break;
+            }
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                out_of_wall_clock = true;
+                break;
+            }
+        }
+        let result = match outcome.reason {
+            _ if out_of_wall_clock => AlgorithmResult::WallClockTimeout,
+            StopReason::Returned(column_index) => {
+                self.data = vm.get_data().clone();
+                self.last_move = column_index;
+                self.total_moves += 1;
+                AlgorithmResult::Column(column_index)
             }
+            StopReason::IllegalInstruction(insn) => AlgorithmResult::IllegalInstruction {
+                insn,
+                pc: vm.get_program_counter(),
+            },
+            // DebugDump does not stop this run, and a fresh VM has no breakpoints.
+            StopReason::DebugDump | StopReason::Breakpoint(_) | StopReason::OutOfBudget => {
+                AlgorithmResult::Timeout
+            }
+            StopReason::LoopDetected { period } => AlgorithmResult::LoopDetected { period },
+            StopReason::RandomnessUnavailable => AlgorithmResult::RandomnessUnavailable,
+            StopReason::ProtectionFault { addr, pc } => {
+                AlgorithmResult::ProtectionFault { addr, pc }
+            }
+        };
+        if let Some(random_trace) = &mut self.random_trace {
+            random_trace.extend(vm.take_random_trace());
+        }
+        self.total_insns += outcome.steps;
+        self.last_move_deterministic = vm.was_deterministic_so_far();
+        self.deterministic_so_far &= self.last_move_deterministic;
+        self.random_calls += vm.random_calls();
+        self.last_vm = Some(vm);
+        result
+    }
+
+    /// Runs `vm` for up to `max_steps` steps, and counts the instruction mix if enabled.
+    fn run_counting(&mut self, vm: &mut VirtualMachine, max_steps: u64) -> RunOutcome {
+        match &mut self.insn_mix {
+            None => vm.run(max_steps),
+            Some(insn_mix) => run_stepping(vm, max_steps, false, |vm| {
+                let instruction = vm.get_instructions()[vm.get_program_counter()];
+                let step_result = vm.step();
+                if let StepResult::Continue | StepResult::DebugDump = step_result {
+                    insn_mix.record(instruction);
+                }
+                step_result
+            }),
         }
-        AlgorithmResult::Timeout
     }
 }
 
+/// Describes the state of `player`'s program at a Debug-dump, e.g. for a `DebugDumpHandler`: the registers, and the
+/// board as the program currently sees it, see `Layout::describe_board`. This does not look at the game itself, so
+/// it also works outside of a `Game`.
+pub fn describe_debug_dump(player: Player, layout: Layout, vm: &VirtualMachine) -> String {
+    let name = match player {
+        Player::One => "one",
+        Player::Two => "two",
+    };
+    let registers = vm
+        .get_registers()
+        .iter()
+        .map(|register| format!("{:04X}", register))
+        .collect::<Vec<_>>();
+    format!(
+        "Debug dump by player {} at pc 0x{:04X}, time {}:\nRegisters: {}\n{}",
+        name,
+        vm.get_program_counter(),
+        vm.get_time(),
+        registers.join(" "),
+        layout.describe_board(vm.get_data())
+    )
+}
+
 #[cfg(test)]
 mod test_player_data {
     use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{BinaryFunction, ProgramBuilder};
+
+    #[test]
+    fn test_describe_debug_dump() {
+        let mut vm = VirtualMachine::new(Segment::new_zeroed(), Segment::new_zeroed());
+        vm.set_register(0, 0x1234);
+        vm.set_register(15, 0xABCD);
+        let text = describe_debug_dump(Player::Two, Layout::default(), &vm);
+        let mut lines = text.lines();
+        assert_eq!(
+            lines.next(),
+            Some("Debug dump by player two at pc 0x0000, time 0:")
+        );
+        assert_eq!(
+            lines.next(),
+            Some("Registers: 1234 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 0000 ABCD")
+        );
+        // An all-zero data segment has no valid board dimensions.
+        assert!(lines.next().unwrap().starts_with("(no board: "), "{}", text);
+        assert_eq!(lines.next(), None);
+    }
 
     #[test]
     fn test_update_data() {
@@ -545,11 +1199,15 @@ mod test_player_data {
         let mut other_player_data = PlayerData::new(Segment::new_zeroed());
         other_player_data.total_moves = 0x34;
 
+        // Simulate a previous move that clobbered the version words.
+        player_data.data[0xFF80] = 0xDEAD;
+        player_data.data[0xFF81] = 0xBEEF;
+
         player_data.update_data(Player::Two, 0x123456789ABCDEF0, &b, &other_player_data);
 
         let data_segment = &player_data.data;
         assert_eq!(data_segment[0], 0);
-        assert_eq!(data_segment[3 * 6 + 0], 2);
+        assert_eq!(data_segment[3 * 6], 2);
         assert_eq!(data_segment[3 * 6 + 1], 0);
 
         assert_eq!(data_segment[0x1234], 0);
@@ -579,56 +1237,280 @@ mod test_player_data {
 
     #[test]
     fn test_determine_answer() {
-        let mut instructions = Segment::new_zeroed();
-        instructions[0] = 0x3037; // ↓
-        instructions[1] = 0x4013; // lw r0, 0x1337
-        instructions[2] = 0x37CD; // ↓
-        instructions[3] = 0x47AB; // lw r7, 0xABCD
-        instructions[4] = 0x2077; // sw r7, r7
-        instructions[5] = 0x102A; // ret
+        let instructions = ProgramBuilder::new()
+            .lw(0, 0x1337)
+            .lw(7, 0xABCD)
+            .sw(7, 7)
+            .ret()
+            .build_segment()
+            .unwrap();
         let mut player_data = PlayerData::new(instructions);
-        assert_eq!(player_data.last_move, 0xFFFF);
-        assert_eq!(player_data.total_moves, 0);
+        assert_eq!(player_data.get_last_move(), 0xFFFF);
+        assert_eq!(player_data.get_total_moves(), 0);
 
         let result = player_data.determine_answer(0xFFFF);
 
-        let data_segment = &player_data.data;
+        let data_segment = player_data.get_data();
         assert_eq!(data_segment[0], 0);
         assert_eq!(data_segment[0xABCD], 0xABCD);
         assert_eq!(result, AlgorithmResult::Column(0x1337));
-        assert_eq!(player_data.last_move, 0x1337);
-        assert_eq!(player_data.total_moves, 1);
+        assert_eq!(player_data.get_last_move(), 0x1337);
+        assert_eq!(player_data.get_total_moves(), 1);
+    }
+
+    #[test]
+    fn test_time_resets_each_move() {
+        let instructions = ProgramBuilder::new()
+            .incr(1, 1)
+            .time()
+            .mov(0, 3)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut player_data = PlayerData::new(instructions);
+        let other_player_data = PlayerData::new(Segment::new_zeroed());
+        let board = Board::default();
+
+        for _ in 0..3 {
+            player_data.update_data(Player::One, 100, &board, &other_player_data);
+            // The program has executed a single instruction before "time", no matter how many moves came before.
+            assert_eq!(
+                player_data.determine_answer(100),
+                AlgorithmResult::Column(1)
+            );
+        }
+        assert_eq!(player_data.get_total_moves(), 3);
+    }
+
+    #[test]
+    fn test_single_bot() {
+        let instructions = ProgramBuilder::new()
+            .lw(1, 0xFF89)
+            .lwd(1, 1) // Total number of moves made by this player.
+            .b(1, "later_move")
+            .ret()
+            .label("later_move")
+            .lw(2, 1)
+            .rnd(0, 2)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut player_data = PlayerData::new(instructions);
+        let other_player_data = PlayerData::new(Segment::new_zeroed());
+        let board = Board::default();
+        assert_eq!(player_data.get_vm(), None);
+        assert!(player_data.last_move_was_deterministic());
+
+        player_data.update_data(Player::One, 100, &board, &other_player_data);
+        assert_eq!(
+            player_data.determine_answer(100),
+            AlgorithmResult::Column(0)
+        );
+        assert_eq!(player_data.get_last_move(), 0);
+        assert!(player_data.last_move_was_deterministic());
+        assert_eq!(player_data.get_vm().unwrap().get_program_counter(), 3);
+
+        player_data.update_data(Player::One, 100, &board, &other_player_data);
+        let result = player_data.determine_answer(100);
+        assert!(
+            matches!(result, AlgorithmResult::Column(0 | 1)),
+            "{:?}",
+            result
+        );
+        assert!(!player_data.last_move_was_deterministic());
+        assert_eq!(player_data.get_vm().unwrap().get_program_counter(), 6);
+
+        player_data.update_data(Player::One, 2, &board, &other_player_data);
+        assert_eq!(player_data.determine_answer(2), AlgorithmResult::Timeout);
+        assert_eq!(player_data.get_total_moves(), 2);
+        assert_eq!(player_data.get_vm().unwrap().get_time(), 2);
+        assert!(player_data.last_move_was_deterministic());
+        assert!(!player_data.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_deadline() {
+        let instructions = ProgramBuilder::new()
+            .label("loop_start")
+            .incr(1, 1)
+            .j("loop_start")
+            .build_segment()
+            .unwrap();
+        let mut player_data = PlayerData::new(instructions);
+        // The deadline has already passed, so the program only gets to run until the first look at the clock.
+        assert_eq!(
+            player_data.determine_answer_until(u64::MAX, Some(Instant::now())),
+            AlgorithmResult::WallClockTimeout
+        );
+        assert_eq!(player_data.get_total_insns(), WALL_CLOCK_CHECK_STEPS);
+        // A short budget ends before the clock matters.
+        assert_eq!(
+            player_data.determine_answer_until(10, Some(Instant::now())),
+            AlgorithmResult::Timeout
+        );
+        assert_eq!(player_data.get_total_moves(), 0);
+    }
+
+    #[test]
+    fn test_recent_pcs() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x1234;
+            jr r1, 0;
+        };
+        let mut player_data = PlayerData::new(instructions);
+        assert_eq!(
+            player_data.determine_answer(10),
+            AlgorithmResult::IllegalInstruction {
+                insn: 0x0000,
+                pc: 0x1234
+            }
+        );
+        let vm = player_data.get_vm().unwrap();
+        assert_eq!(vm.recent_pcs(), &[0x0000, 0x0001, 0x0002, 0x1234]);
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let instructions = tinyvm_asm! {
+            lw r1, 2;
+            mul r1, r1;
+            mul r1, r1;
+            lw r0, 3;
+            ret;
+        };
+        let mut player_data = PlayerData::new(instructions);
+        assert_eq!(player_data.determine_answer(10), AlgorithmResult::Column(3));
+        assert_eq!(player_data.get_total_insns(), 4);
+
+        let mut cost_model = CostModel::new();
+        cost_model.set_binary(BinaryFunction::Mul, 5);
+        player_data.set_cost_model(Some(cost_model));
+        assert_eq!(player_data.determine_answer(10), AlgorithmResult::Timeout);
+        assert_eq!(player_data.get_total_insns(), 4 + 6);
+        assert_eq!(player_data.determine_answer(13), AlgorithmResult::Column(3));
+        assert_eq!(player_data.get_total_insns(), 4 + 6 + 12);
     }
 }
 
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum WinReason {
     Connect4,
     Timeout,
-    IllegalInstruction(u16),
+    /// The opponent executed the illegal instruction `insn` at address `pc`.
+    IllegalInstruction {
+        insn: u16,
+        pc: u16,
+    },
     IllegalColumn(u16),
     FullColumn(u16),
+    /// The opponent executed `rnd`, but the host could not provide entropy. The move cannot be completed, so the
+    /// opponent loses, even though it is arguably not their fault.
+    RandomnessUnavailable,
+    /// The opponent was still running when the wall-clock limit of the game expired, see
+    /// `Game::set_wall_clock_limit`.
+    WallClockTimeout,
+    /// The opponent would have timed out, but was stopped early because it repeats the same `period` instructions
+    /// forever, see `Game::set_detect_loops`.
+    LoopDetected {
+        period: u64,
+    },
+    /// The opponent's instruction at address `pc` tried to overwrite the read-only address `addr`, see
+    /// `Layout::read_only_data`.
+    ProtectionFault {
+        addr: u16,
+        pc: u16,
+    },
 }
 
+/// Completes "Player 1 won …", from the winner's point of view.
+impl Display for WinReason {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            WinReason::Connect4 => write!(f, "by connect4"),
+            WinReason::Timeout => write!(f, "by timeout of the opponent"),
+            WinReason::IllegalInstruction { insn, pc } => write!(
+                f,
+                "by illegal instruction 0x{:04X} at pc 0x{:04X} of the opponent",
+                insn, pc
+            ),
+            WinReason::IllegalColumn(col) => write!(
+                f,
+                "by opponent's attempt to move at non-existent column {}",
+                col
+            ),
+            WinReason::FullColumn(col) => {
+                write!(f, "by opponent's attempt to move at full column {}", col)
+            }
+            WinReason::RandomnessUnavailable => {
+                write!(f, "because the host had no randomness for the opponent")
+            }
+            WinReason::WallClockTimeout => write!(f, "by wall-clock timeout of the opponent"),
+            WinReason::LoopDetected { period } => write!(
+                f,
+                "by timeout of the opponent, which was stuck in a loop of {} instructions",
+                period
+            ),
+            WinReason::ProtectionFault { addr, pc } => write!(
+                f,
+                "by opponent's store to read-only address 0x{:04X} at pc 0x{:04X}",
+                addr, pc
+            ),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum GameResult {
     Won(Player, WinReason),
     Draw,
 }
 
+/// E.g. "Player 1 won by connect4", or "draw".
+impl Display for GameResult {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            GameResult::Won(Player::One, reason) => write!(f, "Player 1 won {}", reason),
+            GameResult::Won(Player::Two, reason) => write!(f, "Player 2 won {}", reason),
+            GameResult::Draw => write!(f, "draw"),
+        }
+    }
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum GameState {
     RunningNextIs(Player),
     Ended(GameResult),
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(PartialEq, Eq, Clone)]
 pub struct Game {
     player_one: PlayerData,
     player_two: PlayerData,
     board: Board,
     state: GameState,
     max_steps: u64,
+    checkpoints: Checkpoints,
+    wall_clock_limit: Option<Duration>,
+    /// When the wall-clock limit expires, set by the first move after `set_wall_clock_limit`.
+    deadline: Option<Instant>,
+}
+
+/// Shows the board as its compact string, and only the number of retained checkpoints.
+impl Debug for Game {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.debug_struct("Game")
+            .field("state", &self.state)
+            .field("total_moves", &self.get_total_moves())
+            .field("max_steps", &self.max_steps)
+            .field("board", &self.board.to_compact_string())
+            .field("checkpoints", &self.checkpoints.len())
+            .field("player_one", &self.player_one)
+            .field("player_two", &self.player_two)
+            .finish()
+    }
 }
 
 impl Game {
@@ -636,24 +1518,59 @@ impl Game {
         instructions_player_one: Segment,
         instructions_player_two: Segment,
         max_steps: u64,
+    ) -> Game {
+        Game::new_shared(
+            Arc::new(instructions_player_one),
+            Arc::new(instructions_player_two),
+            max_steps,
+        )
+    }
+
+    /// Like `new`, but shares the instruction segments, so that running many games of the same programs does not
+    /// copy them for every game, see `PlayerData::new_shared`.
+    pub fn new_shared(
+        instructions_player_one: Arc<Segment>,
+        instructions_player_two: Arc<Segment>,
+        max_steps: u64,
     ) -> Game {
         Game {
-            player_one: PlayerData::new(instructions_player_one),
-            player_two: PlayerData::new(instructions_player_two),
+            player_one: PlayerData::new_shared(instructions_player_one, Layout::default()),
+            player_two: PlayerData::new_shared(instructions_player_two, Layout::default()),
             board: Default::default(),
             state: GameState::RunningNextIs(Player::One),
             max_steps,
+            checkpoints: Checkpoints::default(),
+            wall_clock_limit: None,
+            deadline: None,
         }
     }
 
-    pub fn do_move(&mut self) {
-        // Determine whose turn it is.
-        let moving_player = match self.state {
+    /// Like `new`, but `rnd` is reproducible, so the whole game can be replayed from the same seed. Each player
+    /// gets their own seed derived from `seed`, see `PlayerData::set_seed`.
+    pub fn new_with_seed(
+        instructions_player_one: Segment,
+        instructions_player_two: Segment,
+        max_steps: u64,
+        seed: u64,
+    ) -> Game {
+        let mut game = Game::new(instructions_player_one, instructions_player_two, max_steps);
+        let mut rng = SplitMix64::new(seed);
+        game.player_one.set_seed(Some(rng.next_u64()));
+        game.player_two.set_seed(Some(rng.next_u64()));
+        game
+    }
+
+    pub fn do_move(&mut self) {
+        // Determine whose turn it is.
+        let moving_player = match self.state {
             GameState::RunningNextIs(player) => player,
             GameState::Ended(_) => {
                 return;
             }
         };
+        let mut checkpoints = std::mem::take(&mut self.checkpoints);
+        checkpoints.maybe_take(self);
+        self.checkpoints = checkpoints;
         let moving_player_data;
         let other_player_data;
         match moving_player {
@@ -674,14 +1591,17 @@ impl Game {
             &self.board,
             other_player_data,
         );
-        let step_result = moving_player_data.determine_answer(self.max_steps);
+        if let (Some(limit), None) = (self.wall_clock_limit, self.deadline) {
+            self.deadline = Some(Instant::now() + limit);
+        }
+        let step_result = moving_player_data.determine_answer_until(self.max_steps, self.deadline);
         let column_index = match step_result {
             AlgorithmResult::Column(column_index) => column_index,
-            AlgorithmResult::IllegalInstruction(insn) => {
+            AlgorithmResult::IllegalInstruction { insn, pc } => {
                 // Loss by failure to produce a decision.
                 self.state = GameState::Ended(GameResult::Won(
                     moving_player.other(),
-                    WinReason::IllegalInstruction(insn),
+                    WinReason::IllegalInstruction { insn, pc },
                 ));
                 return;
             }
@@ -691,6 +1611,34 @@ impl Game {
                     GameState::Ended(GameResult::Won(moving_player.other(), WinReason::Timeout));
                 return;
             }
+            AlgorithmResult::LoopDetected { period } => {
+                self.state = GameState::Ended(GameResult::Won(
+                    moving_player.other(),
+                    WinReason::LoopDetected { period },
+                ));
+                return;
+            }
+            AlgorithmResult::RandomnessUnavailable => {
+                self.state = GameState::Ended(GameResult::Won(
+                    moving_player.other(),
+                    WinReason::RandomnessUnavailable,
+                ));
+                return;
+            }
+            AlgorithmResult::WallClockTimeout => {
+                self.state = GameState::Ended(GameResult::Won(
+                    moving_player.other(),
+                    WinReason::WallClockTimeout,
+                ));
+                return;
+            }
+            AlgorithmResult::ProtectionFault { addr, pc } => {
+                self.state = GameState::Ended(GameResult::Won(
+                    moving_player.other(),
+                    WinReason::ProtectionFault { addr, pc },
+                ));
+                return;
+            }
         };
 
         // Do the move, check the result.
@@ -747,19 +1695,168 @@ impl Game {
         self.player_one.get_total_moves() + self.player_two.get_total_moves()
     }
 
+    /// Combines the hashes of both players, see `PlayerData::state_hash`, and the board. Comparing this after a game
+    /// is a cheap way to check that two runs of the same match stayed in lockstep.
+    #[must_use]
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        hash.write_u64(self.player_one.state_hash());
+        hash.write_u64(self.player_two.state_hash());
+        hash.write_u64(self.board.width as u64);
+        hash.write_u64(self.board.height as u64);
+        for slot in &self.board.slots {
+            hash.write(&[match slot {
+                SlotState::Empty => 0,
+                SlotState::Token(Player::One) => 1,
+                SlotState::Token(Player::Two) => 2,
+            }]);
+        }
+        hash.finish()
+    }
+
     pub fn get_board(&self) -> &Board {
         &self.board
     }
+
+    pub fn get_player_data(&self, player: Player) -> &PlayerData {
+        match player {
+            Player::One => &self.player_one,
+            Player::Two => &self.player_two,
+        }
+    }
+
+    /// Enables or disables counting executed instructions by class for both players, see `get_insn_mix`.
+    pub fn set_collect_insn_mix(&mut self, collect: bool) {
+        self.player_one.set_collect_insn_mix(collect);
+        self.player_two.set_collect_insn_mix(collect);
+    }
+
+    /// Switches both players' data segments to the sparse or dense representation, see `PlayerData::set_sparse_data`.
+    pub fn set_sparse_data(&mut self, sparse: bool) {
+        self.player_one.set_sparse_data(sparse);
+        self.player_two.set_sparse_data(sparse);
+    }
+
+    /// Restricts the instruction set of both players, see `PlayerData::set_cpu_features`. Setting the same
+    /// features for both keeps the game fair.
+    pub fn set_cpu_features(&mut self, features: CpuFeatures) {
+        self.player_one.set_cpu_features(features);
+        self.player_two.set_cpu_features(features);
+    }
+
+    /// Charges the instructions of both players by `cost_model`, see `PlayerData::set_cost_model`.
+    pub fn set_cost_model(&mut self, cost_model: Option<CostModel>) {
+        self.player_one.set_cost_model(cost_model.clone());
+        self.player_two.set_cost_model(cost_model);
+    }
+
+    /// Ends a move early if the program is stuck in an endless loop, see `PlayerData::set_detect_loops`.
+    pub fn set_detect_loops(&mut self, detect: bool) {
+        self.player_one.set_detect_loops(detect);
+        self.player_two.set_detect_loops(detect);
+    }
+
+    /// Enables or disables recording the values drawn by `rnd` for both players, see `get_random_traces`.
+    pub fn set_record_randomness(&mut self, record: bool) {
+        self.player_one.set_record_randomness(record);
+        self.player_two.set_record_randomness(record);
+    }
+
+    /// Returns the values drawn by `rnd` of player one and player two, if recording is enabled. Pass them to
+    /// `replay_randomness` of a new game with the same programs to reproduce a nondeterministic game exactly.
+    pub fn get_random_traces(&self) -> Option<(&[u16], &[u16])> {
+        Some((
+            self.player_one.get_random_trace()?,
+            self.player_two.get_random_trace()?,
+        ))
+    }
+
+    /// Makes `rnd` of each player return exactly the given values, see `PlayerData::replay_randomness`.
+    pub fn replay_randomness(&mut self, player_one: Vec<u16>, player_two: Vec<u16>) {
+        self.player_one.replay_randomness(player_one);
+        self.player_two.replay_randomness(player_two);
+    }
+
+    /// Makes `rnd` of both players draw from the same `source`, see `PlayerData::set_random_source`.
+    pub fn set_random_source(&mut self, source: Option<SharedRandomSource>) {
+        self.player_one.set_random_source(source.clone());
+        self.player_two.set_random_source(source);
+    }
+
+    /// Makes `rnd` an illegal instruction for both players, so that a program that uses it loses, see
+    /// `VirtualMachine::set_forbid_random`.
+    pub fn set_forbid_random(&mut self, forbid: bool) {
+        for player_data in [&mut self.player_one, &mut self.player_two] {
+            let features = player_data.get_cpu_features();
+            player_data.set_cpu_features(if forbid {
+                features.without(CpuFeatures::RND)
+            } else {
+                features.with(CpuFeatures::RND)
+            });
+        }
+    }
+
+    pub fn get_insn_mix(&self, player: Player) -> Option<&InsnStats> {
+        self.get_player_data(player).get_insn_mix()
+    }
+
+    /// Traces every step of `player`'s future moves, see `PlayerData::set_tracer`. To trace both players with the
+    /// same callback, install clones of the same tracer.
+    pub fn set_tracer(&mut self, player: Player, tracer: Option<Tracer>) {
+        match player {
+            Player::One => self.player_one.set_tracer(tracer),
+            Player::Two => self.player_two.set_tracer(tracer),
+        }
+    }
+
+    /// Calls `handler` whenever `player`'s program executes Debug-dump, see `PlayerData::set_debug_dump_handler`
+    /// and `describe_debug_dump`.
+    pub fn set_debug_dump_handler(&mut self, player: Player, handler: Option<DebugDumpHandler>) {
+        match player {
+            Player::One => self.player_one.set_debug_dump_handler(handler),
+            Player::Two => self.player_two.set_debug_dump_handler(handler),
+        }
+    }
+
+    /// Limits the real time that the rest of the game may take, in addition to the step budget of each move. The
+    /// clock starts with the next move. Whoever is running when it expires loses by `WinReason::WallClockTimeout`.
+    /// This is a safety net for unattended runs, and makes the outcome depend on the speed of the host, so
+    /// tournaments should rely on the step budget instead. `None` removes the limit, which is the default.
+    pub fn set_wall_clock_limit(&mut self, limit: Option<Duration>) {
+        self.wall_clock_limit = limit;
+        self.deadline = None;
+    }
+
+    /// Keeps a checkpoint right before every `every_n_moves`-th move (starting with the first move), retaining
+    /// only the most recent `capacity` checkpoints. Zero for either value disables checkpoints, which is the
+    /// default. Discards all previously taken checkpoints.
+    ///
+    /// Each checkpoint costs roughly 512 KiB, see `GameCheckpoint`.
+    pub fn set_checkpoints(&mut self, every_n_moves: u16, capacity: usize) {
+        self.checkpoints = Checkpoints::new(every_n_moves, capacity);
+    }
+
+    /// Returns the checkpoint taken right before move `move_index` (zero-based), if it is still retained.
+    /// Use `GameCheckpoint::resume` to continue the game from there.
+    pub fn checkpoint_at(&self, move_index: u16) -> Option<&GameCheckpoint> {
+        self.checkpoints.get(move_index)
+    }
+
+    /// Returns true if neither player has drawn randomness so far, i.e. replaying this game would yield the same result.
+    pub fn was_deterministic_so_far(&self) -> bool {
+        self.player_one.was_deterministic_so_far() && self.player_two.was_deterministic_so_far()
+    }
 }
 
 #[cfg(test)]
 mod test_game {
     use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{CountingSource, InsnClass, ProgramBuilder, RandomSource};
 
     #[test]
     fn test_full_column() {
-        let mut instructions = Segment::new_zeroed();
-        instructions[0] = 0x102A; // ret
+        let instructions = ProgramBuilder::new().ret().build_segment().unwrap();
         let mut game = Game::new(instructions.clone(), instructions, 0x12345);
         assert_eq!(game.get_state(), GameState::RunningNextIs(Player::One));
         game.do_move();
@@ -794,9 +1891,11 @@ mod test_game {
 
     #[test]
     fn test_illegal_column() {
-        let mut instructions = Segment::new_zeroed();
-        instructions[0] = 0x30FF; // lw r3, 0xFFFF
-        instructions[1] = 0x102A; // ret
+        let instructions = ProgramBuilder::new()
+            .lw(0, 0xFFFF)
+            .ret()
+            .build_segment()
+            .unwrap();
         let mut game = Game::new(instructions.clone(), instructions, 0x12345);
         assert_eq!(game.get_state(), GameState::RunningNextIs(Player::One));
         // Next, player 1 attempts to insert into column 0xFFFF, which is an invalid column,
@@ -823,8 +1922,7 @@ mod test_game {
 
     #[test]
     fn test_timeout() {
-        let mut instructions = Segment::new_zeroed();
-        instructions[0] = 0xB000; // j r0, +0x0000
+        let instructions = ProgramBuilder::new().jr(0, 0).build_segment().unwrap();
         let mut game = Game::new(instructions.clone(), instructions, 123);
         assert_eq!(game.get_state(), GameState::RunningNextIs(Player::One));
         // Next, player 1 times out, thus losing the game.
@@ -835,13 +1933,85 @@ mod test_game {
         );
     }
 
+    #[test]
+    fn test_protected_version() {
+        // Player 1 overwrites scratch space next to the version words, which is fine.
+        let one = ProgramBuilder::new()
+            .lw(1, 0xFF82)
+            .sw(1, 1)
+            .lw(0, 0)
+            .ret()
+            .build_segment()
+            .unwrap();
+        // Player 2 overwrites the minor version.
+        let two = ProgramBuilder::new()
+            .lw(1, 0xFF81)
+            .sw(1, 0)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut game = Game::new(one, two, 100);
+        game.do_move();
+        assert_eq!(game.get_state(), GameState::RunningNextIs(Player::Two));
+        game.do_move();
+        assert_eq!(
+            game.get_state(),
+            GameState::Ended(GameResult::Won(
+                Player::One,
+                WinReason::ProtectionFault {
+                    addr: 0xFF81,
+                    pc: 0x0001
+                }
+            ))
+        );
+        let data = game.get_player_data(Player::Two);
+        let vm = data.get_vm().unwrap();
+        assert_eq!(vm.get_data()[0xFF81], GAME_VERSION_MINOR);
+    }
+
+    #[test]
+    fn test_detect_loops() {
+        let instructions = ProgramBuilder::new().jr(0, 0).build_segment().unwrap();
+        let mut game = Game::new(instructions.clone(), instructions, 30_000);
+        game.set_detect_loops(true);
+        game.do_move();
+        assert_eq!(
+            game.get_state(),
+            GameState::Ended(GameResult::Won(
+                Player::Two,
+                WinReason::LoopDetected { period: 1 }
+            ))
+        );
+        assert!(game.get_player_data(Player::One).get_total_insns() < 300);
+    }
+
+    #[test]
+    fn test_detect_loops_long_move() {
+        // Counts down from 10000 before answering, which is long, but not endless.
+        let instructions = tinyvm_asm! {
+            lw r1, 10000;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            lw r0, 3;
+            ret;
+        };
+        let mut player_data = PlayerData::new(instructions);
+        player_data.set_detect_loops(true);
+        assert_eq!(
+            player_data.determine_answer(30_000),
+            AlgorithmResult::Column(3)
+        );
+    }
+
     #[test]
     fn test_two_illegal_column() {
-        let mut instructions_one = Segment::new_zeroed();
-        instructions_one[0] = 0x102A; // ret
-        let mut instructions_two = Segment::new_zeroed();
-        instructions_two[0] = 0x30FF; // lw r0, 0xFFFF
-        instructions_two[1] = 0x102A; // ret
+        let instructions_one = ProgramBuilder::new().ret().build_segment().unwrap();
+        let instructions_two = ProgramBuilder::new()
+            .lw(0, 0xFFFF)
+            .ret()
+            .build_segment()
+            .unwrap();
         let mut game = Game::new(instructions_one, instructions_two, 123);
 
         // Player 2 tries to play into an illegal column, losing the game.
@@ -850,35 +2020,326 @@ mod test_game {
             GameResult::Won(Player::One, WinReason::IllegalColumn(0xFFFF))
         );
 
-        assert_eq!(game.player_one.total_moves, 1);
-        assert_eq!(game.player_two.total_moves, 1);
+        assert_eq!(game.get_player_data(Player::One).get_total_moves(), 1);
+        assert_eq!(game.get_player_data(Player::Two).get_total_moves(), 1);
     }
 
     #[test]
     fn test_two_illegal_instruction() {
-        let mut instructions_one = Segment::new_zeroed();
-        instructions_one[0] = 0x102A; // ret
-        let mut instructions_two = Segment::new_zeroed();
-        instructions_two[0] = 0x0000; // ill 0x0000
+        let instructions_one = ProgramBuilder::new().ret().build_segment().unwrap();
+        let instructions_two = ProgramBuilder::new().ill(0x0000).build_segment().unwrap();
         let mut game = Game::new(instructions_one, instructions_two, 123);
 
         // Player 2 terminates with an illegal instruction, losing the game.
         assert_eq!(
             game.conclude(),
-            GameResult::Won(Player::One, WinReason::IllegalInstruction(0x0000))
+            GameResult::Won(
+                Player::One,
+                WinReason::IllegalInstruction {
+                    insn: 0x0000,
+                    pc: 0x0000
+                }
+            )
+        );
+
+        assert_eq!(game.get_player_data(Player::One).get_total_moves(), 1);
+        assert_eq!(game.get_player_data(Player::Two).get_total_moves(), 0);
+    }
+
+    #[test]
+    fn test_illegal_instruction_pc() {
+        let instructions_one = ProgramBuilder::new().ret().build_segment().unwrap();
+        let instructions_two = tinyvm_asm! {
+            lw r0, 0x1FFE;
+            jr r0, 0;
+        };
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+        let GameResult::Won(Player::One, reason) = game.conclude() else {
+            panic!("Player 2 should have lost");
+        };
+        assert_eq!(
+            reason,
+            WinReason::IllegalInstruction {
+                insn: 0x0000,
+                pc: 0x1FFE
+            }
+        );
+        assert_eq!(
+            reason.to_string(),
+            "by illegal instruction 0x0000 at pc 0x1FFE of the opponent"
+        );
+    }
+
+    #[test]
+    fn test_state_hash() {
+        let column_zero = ProgramBuilder::new().ret().build_segment().unwrap();
+        let column_one = tinyvm_asm! {
+            lw r0, 1;
+            ret;
+        };
+        let column_one_scribbling = tinyvm_asm! {
+            lw r1, 0x100;
+            sw r1, r1;
+            lw r0, 1;
+            ret;
+        };
+        let play = |two: &Segment| {
+            let mut game = Game::new(column_zero.clone(), two.clone(), 100);
+            game.conclude();
+            game
+        };
+        let game = play(&column_one);
+        assert_eq!(game.state_hash(), play(&column_one).state_hash());
+
+        // Same board, but different data and step counts.
+        let scribbling = play(&column_one_scribbling);
+        assert_eq!(scribbling.get_board(), game.get_board());
+        assert_eq!(
+            scribbling.get_player_data(Player::One).state_hash(),
+            game.get_player_data(Player::One).state_hash()
+        );
+        assert_ne!(
+            scribbling.get_player_data(Player::Two).state_hash(),
+            game.get_player_data(Player::Two).state_hash()
+        );
+        assert_ne!(scribbling.state_hash(), game.state_hash());
+
+        let mut unfinished = Game::new(column_zero.clone(), column_one.clone(), 100);
+        unfinished.do_move();
+        assert_ne!(unfinished.state_hash(), game.state_hash());
+    }
+
+    #[test]
+    fn test_cpu_features_without_rnd() {
+        let instructions_one = ProgramBuilder::new().ret().build_segment().unwrap();
+        let instructions_two = tinyvm_asm! {
+            rnd r0, r0;
+            ret;
+        };
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+        game.set_cpu_features(CpuFeatures::default().without(CpuFeatures::RND));
+        let GameResult::Won(Player::One, reason) = game.conclude() else {
+            panic!("Player 2 should have lost");
+        };
+        assert_eq!(
+            reason,
+            WinReason::IllegalInstruction {
+                insn: 0x5E00,
+                pc: 0x0000
+            }
+        );
+    }
+
+    #[test]
+    fn test_forbid_random() {
+        let random_bot = tinyvm_asm! {
+            lw r1, 7;
+            rnd r0, r1;
+            ret;
+        };
+        let mut game = Game::new_with_seed(random_bot.clone(), random_bot.clone(), 123, 42);
+        game.set_forbid_random(true);
+        assert_eq!(
+            game.conclude(),
+            GameResult::Won(
+                Player::Two,
+                WinReason::IllegalInstruction {
+                    insn: 0x5E10,
+                    pc: 0x0001
+                }
+            )
         );
+        assert_eq!(game.get_total_moves(), 0);
+
+        let mut game = Game::new_with_seed(random_bot.clone(), random_bot, 123, 42);
+        game.do_move();
+        assert_eq!(game.get_total_moves(), 1);
+        assert_eq!(game.get_state(), GameState::RunningNextIs(Player::Two));
+    }
+
+    #[test]
+    fn test_random_source() {
+        // Plays the column given by the source, which counts 0, 1, 2, ... across both players.
+        let random_bot = tinyvm_asm! {
+            lw r1, 6;
+            rnd r0, r1;
+            ret;
+        };
+        let mut game = Game::new(random_bot.clone(), random_bot, 123);
+        let source = SharedRandomSource::new(Box::new(CountingSource::new(0)));
+        game.set_random_source(Some(source));
+        for _ in 0..4 {
+            game.do_move();
+        }
+        for (x, player) in [
+            (0, Player::One),
+            (1, Player::Two),
+            (2, Player::One),
+            (3, Player::Two),
+        ] {
+            assert_eq!(game.get_board().get_slot(x, 0), SlotState::Token(player));
+        }
+    }
 
-        assert_eq!(game.player_one.total_moves, 1);
-        assert_eq!(game.player_two.total_moves, 0);
+    /// Provides `0` this many times, and then fails forever.
+    struct FailsAfter(usize);
+
+    impl RandomSource for FailsAfter {
+        fn next_upto(&mut self, _upper: u16) -> Option<u16> {
+            self.0 = self.0.checked_sub(1)?;
+            Some(0)
+        }
+    }
+
+    #[test]
+    fn test_failing_random_source() {
+        let random_bot = tinyvm_asm! {
+            lw r1, 6;
+            rnd r0, r1;
+            ret;
+        };
+        let mut game = Game::new(random_bot.clone(), random_bot, 123);
+        game.set_random_source(Some(SharedRandomSource::new(Box::new(FailsAfter(3)))));
+        // Player two gets the fourth draw, which fails. The process does not panic, player two just loses.
+        assert_eq!(
+            game.conclude(),
+            GameResult::Won(Player::One, WinReason::RandomnessUnavailable)
+        );
+        assert_eq!(game.get_total_moves(), 3);
+    }
+
+    #[test]
+    fn test_wall_clock_limit() {
+        let good_bot = tinyvm_asm! {
+            lw r0, 0;
+            ret;
+        };
+        let infinite_loop = tinyvm_asm! {
+            loop_start:
+            incr r1, r1;
+            j loop_start;
+        };
+        let mut game = Game::new(good_bot, infinite_loop, u64::MAX);
+        game.set_wall_clock_limit(Some(Duration::from_millis(10)));
+        assert_eq!(
+            game.conclude(),
+            GameResult::Won(Player::One, WinReason::WallClockTimeout)
+        );
+        assert_eq!(game.get_total_moves(), 1);
+    }
+
+    #[test]
+    fn test_record_and_replay_randomness() {
+        let random_bot = tinyvm_asm! {
+            lw r1, 6;
+            rnd r0, r1;
+            ret;
+        };
+        let mut game = Game::new(random_bot.clone(), random_bot.clone(), 123);
+        game.set_record_randomness(true);
+        let result = game.conclude();
+        assert!(!game.was_deterministic_so_far());
+        let (trace_one, trace_two) = game.get_random_traces().unwrap();
+        // One draw per move.
+        assert!(!trace_one.is_empty());
+        let (trace_one, trace_two) = (trace_one.to_vec(), trace_two.to_vec());
+
+        let mut replayed = Game::new(random_bot.clone(), random_bot, 123);
+        replayed.replay_randomness(trace_one, trace_two);
+        assert_eq!(replayed.conclude(), result);
+        assert_eq!(replayed.get_board(), game.get_board());
+        assert_eq!(replayed.get_total_moves(), game.get_total_moves());
+    }
+
+    #[test]
+    fn test_new_shared_does_not_copy() {
+        let instructions = Arc::new(tinyvm_asm! {
+            lw r0, 3;
+            ret;
+        });
+        let games: Vec<Game> = (0..1000)
+            .map(|_| {
+                let mut game =
+                    Game::new_shared(Arc::clone(&instructions), Arc::clone(&instructions), 123);
+                game.do_move();
+                game
+            })
+            .collect();
+        // Neither the games nor the VMs of their first move own a copy.
+        assert_eq!(Arc::strong_count(&instructions), 1 + 2 * 1000 + 1000);
+        for game in &games {
+            let player_data = game.get_player_data(Player::One);
+            assert!(std::ptr::eq(player_data.get_instructions(), &*instructions));
+            let vm = player_data.get_vm().unwrap();
+            assert!(std::ptr::eq(vm.get_instructions(), &*instructions));
+        }
+    }
+
+    #[test]
+    fn test_win_reason_display() {
+        for (reason, text) in [
+            (WinReason::Connect4, "by connect4"),
+            (WinReason::Timeout, "by timeout of the opponent"),
+            (
+                WinReason::IllegalInstruction {
+                    insn: 0x0123,
+                    pc: 0x1FFE,
+                },
+                "by illegal instruction 0x0123 at pc 0x1FFE of the opponent",
+            ),
+            (
+                WinReason::IllegalColumn(7),
+                "by opponent's attempt to move at non-existent column 7",
+            ),
+            (
+                WinReason::FullColumn(3),
+                "by opponent's attempt to move at full column 3",
+            ),
+            (
+                WinReason::RandomnessUnavailable,
+                "because the host had no randomness for the opponent",
+            ),
+            (
+                WinReason::WallClockTimeout,
+                "by wall-clock timeout of the opponent",
+            ),
+            (
+                WinReason::LoopDetected { period: 2 },
+                "by timeout of the opponent, which was stuck in a loop of 2 instructions",
+            ),
+            (
+                WinReason::ProtectionFault {
+                    addr: 0xFF81,
+                    pc: 0x0001,
+                },
+                "by opponent's store to read-only address 0xFF81 at pc 0x0001",
+            ),
+        ] {
+            assert_eq!(reason.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_game_result_display() {
+        assert_eq!(
+            GameResult::Won(Player::One, WinReason::Connect4).to_string(),
+            "Player 1 won by connect4"
+        );
+        assert_eq!(
+            GameResult::Won(Player::Two, WinReason::FullColumn(3)).to_string(),
+            "Player 2 won by opponent's attempt to move at full column 3"
+        );
+        assert_eq!(GameResult::Draw.to_string(), "draw");
     }
 
     #[test]
     fn test_connect4() {
-        let mut instructions_one = Segment::new_zeroed();
-        instructions_one[0] = 0x102A; // ret
-        let mut instructions_two = Segment::new_zeroed();
-        instructions_two[0] = 0x3001; // lw r0, 0x0001
-        instructions_two[1] = 0x102A; // ret
+        let instructions_one = ProgramBuilder::new().ret().build_segment().unwrap();
+        let instructions_two = ProgramBuilder::new()
+            .lw(0, 1)
+            .ret()
+            .build_segment()
+            .unwrap();
         let mut game = Game::new(instructions_one, instructions_two, 123);
 
         // Player 1 finishes a connect4 in column 0.
@@ -887,49 +2348,315 @@ mod test_game {
             GameResult::Won(Player::One, WinReason::Connect4)
         );
 
-        assert_eq!(game.player_one.total_moves, 4);
-        assert_eq!(game.player_two.total_moves, 3);
+        assert_eq!(game.get_player_data(Player::One).get_total_moves(), 4);
+        assert_eq!(game.get_player_data(Player::Two).get_total_moves(), 3);
+        assert!(game.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_rnd_zero_is_deterministic() {
+        let instructions_one = ProgramBuilder::new()
+            .rnd(0, 0)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let instructions_two = ProgramBuilder::new()
+            .lw(0, 1)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+
+        assert_eq!(
+            game.conclude(),
+            GameResult::Won(Player::One, WinReason::Connect4)
+        );
+        assert!(game.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_rnd_nonzero_is_nondeterministic() {
+        let instructions_one = ProgramBuilder::new()
+            .lw(1, 1)
+            .rnd(2, 1)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let instructions_two = ProgramBuilder::new()
+            .lw(0, 1)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+        assert!(game.was_deterministic_so_far());
+
+        game.do_move();
+        assert!(!game.get_player_data(Player::One).was_deterministic_so_far());
+        assert!(game.get_player_data(Player::Two).was_deterministic_so_far());
+        assert!(!game.was_deterministic_so_far());
+
+        // Stays nondeterministic, even though the remaining moves are the same.
+        game.conclude();
+        assert!(!game.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_random_calls() {
+        let instructions = tinyvm_asm! {
+            lw r1, 6;
+            rnd r2, r1;
+            rnd r2, r1;
+            rnd r0, r1;
+            ret;
+        };
+        let mut player_data = PlayerData::new(instructions);
+        assert_eq!(player_data.get_random_calls(), 0);
+        assert!(matches!(
+            player_data.determine_answer(100),
+            AlgorithmResult::Column(0..=6)
+        ));
+        assert_eq!(player_data.get_vm().unwrap().random_calls(), 3);
+        assert_eq!(player_data.get_random_calls(), 3);
+        player_data.determine_answer(100);
+        assert_eq!(player_data.get_random_calls(), 6);
     }
 
     #[test]
     fn test_board_full() {
-        let mut instructions_one = Segment::new_zeroed();
         // On the nth move, place in column n % 7
-        instructions_one[0] = 0x3189; // lw r1, 0xFF89
-        instructions_one[1] = 0x2111; // lw r1, r1
-        instructions_one[2] = 0x3007; // lw r0, 7
-        instructions_one[3] = 0x6610; // mod r1 r0
-        instructions_one[4] = 0x102A; // ret
-
-        // Mark it read-only to prevent typos.
-        let instructions_one = instructions_one;
+        let instructions_one = ProgramBuilder::new()
+            .lw(1, 0xFF89)
+            .lwd(1, 1)
+            .lw(0, 7)
+            .mod_u(1, 0)
+            .ret()
+            .build_segment()
+            .unwrap();
 
-        let mut instructions_two = Segment::new_zeroed();
         // Force the same pattern as in test_board::test_full_board.
-        instructions_two[0] = 0x3189; // lw r1, 0xFF89
-        instructions_two[1] = 0x2111; // lw r1, r1
-        instructions_two[2] = 0x9101; // b r1 move_nonzero // (offset is +0x3)
-                                      // .label move_zero // On move 0, play in column 3.
-        instructions_two[3] = 0x3003; // lw r0, 3
-        instructions_two[4] = 0x102A; // ret
-                                      // .label move_nonzero
-        instructions_two[5] = 0x3012; // lw r0, 18
-        instructions_two[6] = 0x8610; // ge r1 r0
-        instructions_two[7] = 0x9000; // b r0 move_late // (offset is +0x2)
-                                      // .label move_early // On moves 1-17, play in column (n - 1) % 7.
-        instructions_two[8] = 0x5811; // decr r1
-                                      // j move_late // Surprise optimization: This is a noop, this time!
-                                      // .label move_late // On moves 18-20, play in column n % 7.
-        instructions_two[9] = 0x3007; // lw r0, 7
-        instructions_two[10] = 0x6610; // mod r1 r0
-        instructions_two[11] = 0x102A; // ret
+        let instructions_two = ProgramBuilder::new()
+            .lw(1, 0xFF89)
+            .lwd(1, 1)
+            .b(1, "move_nonzero")
+            // On move 0, play in column 3.
+            .lw(0, 3)
+            .ret()
+            .label("move_nonzero")
+            .lw(0, 18)
+            .compare(0b0110, 1, 0) // ge
+            .b(0, "move_late")
+            // On moves 1-17, play in column (n - 1) % 7.
+            .decr(1, 1)
+            // j move_late // Surprise optimization: This is a noop, this time!
+            .label("move_late")
+            // On moves 18-20, play in column n % 7.
+            .lw(0, 7)
+            .mod_u(1, 0)
+            .ret()
+            .build_segment()
+            .unwrap();
 
         let mut game = Game::new(instructions_one, instructions_two, 123);
 
+        game.set_collect_insn_mix(true);
+
         // The board is full, thus the game is drawn.
         assert_eq!(game.conclude(), GameResult::Draw);
 
-        assert_eq!(game.player_one.total_moves, 21);
-        assert_eq!(game.player_two.total_moves, 21);
+        assert_eq!(game.get_player_data(Player::One).get_total_moves(), 21);
+        assert_eq!(game.get_player_data(Player::Two).get_total_moves(), 21);
+
+        // Return is not counted, as it does not advance the time.
+        let mix_one = game.get_insn_mix(Player::One).unwrap();
+        assert_eq!(mix_one.get(InsnClass::LoadImmLow), 2 * 21);
+        assert_eq!(mix_one.get(InsnClass::LoadData), 21);
+        assert_eq!(mix_one.get(InsnClass::Binary), 21);
+        assert_eq!(mix_one.total(), 4 * 21);
+        assert_eq!(
+            mix_one.total(),
+            game.get_player_data(Player::One).get_total_insns()
+        );
+
+        // Move 0 takes 4 instructions, moves 1-17 take 9, and moves 18-20 take 8 (the second branch is taken).
+        let mix_two = game.get_insn_mix(Player::Two).unwrap();
+        assert_eq!(mix_two.get(InsnClass::Branch), 21 + 20);
+        assert_eq!(mix_two.get(InsnClass::Compare), 20);
+        assert_eq!(mix_two.get(InsnClass::Unary), 17);
+        assert_eq!(mix_two.total(), 4 + 17 * 9 + 3 * 8);
+        assert_eq!(
+            mix_two.total(),
+            game.get_player_data(Player::Two).get_total_insns()
+        );
+    }
+
+    #[test]
+    fn test_sparse_data_equivalence() {
+        // Player one scribbles all over its data segment, player two plays in the same column each move.
+        let instructions_one = tinyvm_asm! {
+            lw r1, 0xFF89;
+            lwd r1, r1;
+            lw r2, 0x1234;
+            mul r1, r2;
+            sw r2, r1;
+            lw r0, 7;
+            mod_u r1, r0;
+            ret;
+        };
+        let instructions_two = tinyvm_asm! { lw r0, 3; ret; };
+
+        let mut dense = Game::new(instructions_one.clone(), instructions_two.clone(), 123);
+        let mut sparse = Game::new(instructions_one, instructions_two, 123);
+        sparse.set_sparse_data(true);
+        assert!(sparse.get_player_data(Player::One).get_data().is_sparse());
+        assert_eq!(dense.conclude(), sparse.conclude());
+        assert_eq!(dense.get_board(), sparse.get_board());
+        for player in [Player::One, Player::Two] {
+            let dense_data = dense.get_player_data(player).get_data();
+            let sparse_data = sparse.get_player_data(player).get_data();
+            assert!(sparse_data.is_sparse());
+            assert_eq!(dense_data, sparse_data);
+            assert_eq!(format!("{:?}", dense_data), format!("{:?}", sparse_data));
+            assert!(sparse_data.heap_bytes() <= dense_data.heap_bytes() / 8);
+        }
+    }
+
+    #[test]
+    fn test_insn_mix_disabled() {
+        let instructions = ProgramBuilder::new()
+            .lw(0, 1)
+            .ret()
+            .build_segment()
+            .unwrap();
+        let mut game = Game::new(instructions.clone(), instructions, 123);
+        game.conclude();
+        assert_eq!(game.get_insn_mix(Player::One), None);
+        assert_eq!(game.get_player_data(Player::One).get_total_insns(), 4);
+
+        // Enabling later only counts from then on.
+        let mut player_data = game.get_player_data(Player::One).clone();
+        player_data.set_collect_insn_mix(true);
+        assert_eq!(player_data.get_insn_mix().unwrap().total(), 0);
+    }
+
+    #[test]
+    fn test_randomness_unavailable() {
+        let instructions_one = tinyvm_asm! {
+            lw r0, 3;
+            ret;
+        };
+        let instructions_two = tinyvm_asm! {
+            lw r1, 6;
+            rnd r0, r1;
+            ret;
+        };
+        crate::vm::set_fail_getrandom(true);
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+        let result = game.conclude();
+        crate::vm::set_fail_getrandom(false);
+        assert_eq!(
+            result,
+            GameResult::Won(Player::One, WinReason::RandomnessUnavailable)
+        );
+        assert_eq!(game.get_total_moves(), 1);
+        assert!(game.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_debug_golden() {
+        let instructions_one = tinyvm_asm! {
+            lw r0, 2;
+            ret;
+        };
+        let instructions_two = tinyvm_asm! {
+            lw r0, 4;
+            ret;
+        };
+        let mut game = Game::new(instructions_one, instructions_two, 123);
+        assert_eq!(
+            format!("{:?}", game),
+            "Game { state: RunningNextIs(One), total_moves: 0, max_steps: 123, \
+            board: \"..........................................\", checkpoints: 0, \
+            player_one: PlayerData { \
+            instructions: Segment { used_len: 2, nonzero: 2, fingerprint: F53588236216722D }, \
+            data: Segment { used_len: 0, nonzero: 0, fingerprint: C74B47C8C74A2325 }, \
+            last_move: 65535, total_moves: 0, deterministic_so_far: true, last_move_deterministic: true, \
+            last_vm: None, layout: V1, total_insns: 0, insn_mix: None, seed: None, features: CpuFeatures(0x6800) }, \
+            player_two: PlayerData { \
+            instructions: Segment { used_len: 2, nonzero: 2, fingerprint: ADAA374994CCA14B }, \
+            data: Segment { used_len: 0, nonzero: 0, fingerprint: C74B47C8C74A2325 }, \
+            last_move: 65535, total_moves: 0, deterministic_so_far: true, last_move_deterministic: true, \
+            last_vm: None, layout: V1, total_insns: 0, insn_mix: None, seed: None, features: CpuFeatures(0x6800) } }"
+        );
+
+        game.do_move();
+        game.do_move();
+        assert_eq!(
+            format!("{:?}", game.get_player_data(Player::Two)),
+            "PlayerData { \
+            instructions: Segment { used_len: 2, nonzero: 2, fingerprint: ADAA374994CCA14B }, \
+            data: Segment { used_len: 65419, nonzero: 7, fingerprint: A94AA80DE9E54D07 }, \
+            last_move: 4, total_moves: 1, deterministic_so_far: true, last_move_deterministic: true, \
+            last_vm: Some(VirtualMachine { \
+            registers: [0004, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000], \
+            program_counter: 0001, time: 1, deterministic_so_far: true, halted: Some(Return(0x0004)), \
+            instructions: Segment { used_len: 2, nonzero: 2, fingerprint: ADAA374994CCA14B }, \
+            data: Segment { used_len: 65419, nonzero: 7, fingerprint: A94AA80DE9E54D07 }, \
+            near_pc: [0000, 3004, 102A, 0000, 0000] }), \
+            layout: V1, total_insns: 1, insn_mix: None, seed: None, features: CpuFeatures(0x6800) }"
+        );
+    }
+
+    #[test]
+    fn test_seeded_replay() {
+        let instructions = tinyvm_asm! {
+            lw r1, 6;
+            rnd r0, r1;
+            ret;
+        };
+        let play = |seed| {
+            let mut game =
+                Game::new_with_seed(instructions.clone(), instructions.clone(), 123, seed);
+            let result = game.conclude();
+            assert!(!game.was_deterministic_so_far());
+            (result, game.get_board().to_compact_string())
+        };
+        let (result, board) = play(0x1337);
+        for _ in 0..5 {
+            assert_eq!(play(0x1337), (result, board.clone()));
+        }
+        // Not a proof, but different seeds should quickly lead to a different game.
+        assert!((0..20).any(|seed| play(seed).1 != board));
+
+        let game = Game::new_with_seed(instructions.clone(), instructions, 123, 0x1337);
+        let seed_one = game.get_player_data(Player::One).get_seed();
+        let seed_two = game.get_player_data(Player::Two).get_seed();
+        assert!(seed_one.is_some());
+        assert_ne!(seed_one, seed_two);
+    }
+
+    #[test]
+    fn test_tracer() {
+        use crate::vm::Tracer;
+        use std::sync::{Arc, Mutex};
+
+        let instructions = tinyvm_asm! {
+            lw r0, 3;
+            ret;
+        };
+        let mut game = Game::new(instructions.clone(), instructions, 123);
+        let pcs = Arc::new(Mutex::new(Vec::new()));
+        let pcs_clone = Arc::clone(&pcs);
+        game.set_tracer(
+            Player::Two,
+            Some(Tracer::new(move |event| {
+                pcs_clone.lock().unwrap().push(event.pc)
+            })),
+        );
+        // Both players move into column 3 until it is full, so player two makes 3 moves.
+        assert_eq!(
+            game.conclude(),
+            GameResult::Won(Player::Two, WinReason::FullColumn(3))
+        );
+        assert_eq!(*pcs.lock().unwrap(), vec![0, 1, 0, 1, 0, 1]);
     }
 }
@@ -0,0 +1,195 @@
+use super::{Board, Game, GameState, PlayerData};
+use std::collections::VecDeque;
+
+/// Everything needed to continue a game right before a particular move.
+///
+/// Each checkpoint holds both players' `PlayerData`, i.e. their data segment and the VM of their most recent move
+/// (another data segment). The instruction segments are shared, not copied. With dense segments of 128 KiB each,
+/// that is roughly 512 KiB per checkpoint. With sparse data segments, that drops to the allocated data pages. The
+/// capacity of the ring bounds the total.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct GameCheckpoint {
+    move_index: u16,
+    player_one: PlayerData,
+    player_two: PlayerData,
+    board: Board,
+    state: GameState,
+    max_steps: u64,
+}
+
+impl GameCheckpoint {
+    /// The number of moves made before this checkpoint, i.e. the checkpoint is right before move `move_index`.
+    pub fn get_move_index(&self) -> u16 {
+        self.move_index
+    }
+
+    pub fn get_board(&self) -> &Board {
+        &self.board
+    }
+
+    pub fn get_state(&self) -> GameState {
+        self.state
+    }
+
+    pub fn get_player_one(&self) -> &PlayerData {
+        &self.player_one
+    }
+
+    pub fn get_player_two(&self) -> &PlayerData {
+        &self.player_two
+    }
+
+    /// Creates a new game that continues from this checkpoint. The new game does not take checkpoints, and has no
+    /// wall-clock limit.
+    pub fn resume(&self) -> Game {
+        Game {
+            player_one: self.player_one.clone(),
+            player_two: self.player_two.clone(),
+            board: self.board.clone(),
+            state: self.state,
+            max_steps: self.max_steps,
+            checkpoints: Checkpoints::default(),
+            wall_clock_limit: None,
+            deadline: None,
+        }
+    }
+}
+
+/// A bounded ring of checkpoints, taken every `every_n_moves` moves. Disabled by default.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub(super) struct Checkpoints {
+    every_n_moves: u16,
+    capacity: usize,
+    ring: VecDeque<GameCheckpoint>,
+}
+
+impl Checkpoints {
+    pub(super) fn new(every_n_moves: u16, capacity: usize) -> Checkpoints {
+        Checkpoints {
+            every_n_moves,
+            capacity,
+            ring: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Takes a checkpoint if one is due before the next move.
+    pub(super) fn maybe_take(&mut self, game: &Game) {
+        let move_index = game.get_total_moves();
+        if self.every_n_moves == 0
+            || self.capacity == 0
+            || !move_index.is_multiple_of(self.every_n_moves)
+        {
+            return;
+        }
+        if self.ring.back().map(GameCheckpoint::get_move_index) == Some(move_index) {
+            // Already taken, e.g. because do_move() was called again after the game ended.
+            return;
+        }
+        if self.ring.len() == self.capacity {
+            self.ring.pop_front();
+        }
+        self.ring.push_back(GameCheckpoint {
+            move_index,
+            player_one: game.player_one.clone(),
+            player_two: game.player_two.clone(),
+            board: game.board.clone(),
+            state: game.state,
+            max_steps: game.max_steps,
+        });
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.ring.len()
+    }
+
+    pub(super) fn get(&self, move_index: u16) -> Option<&GameCheckpoint> {
+        self.ring.iter().find(|c| c.move_index == move_index)
+    }
+}
+
+#[cfg(test)]
+mod test_checkpoint {
+    use super::super::{GameResult, Player};
+    use super::*;
+    use crate::tinyvm_asm;
+
+    /// A deterministic game that fills the board, see `test_game::test_board_full`.
+    fn board_full_game() -> Game {
+        let instructions_one = tinyvm_asm! {
+            lw r1, 0xFF89;
+            lwd r1, r1;
+            lw r0, 7;
+            mod_u r1, r0;
+            ret;
+        };
+        let instructions_two = tinyvm_asm! {
+            lw r1, 0xFF89;
+            lwd r1, r1;
+            b r1, move_nonzero;
+            lw r0, 3;
+            ret;
+            move_nonzero:
+            lw r0, 18;
+            compare 0b0110, r1, r0;
+            b r0, move_late;
+            decr r1, r1;
+            move_late:
+            lw r0, 7;
+            mod_u r1, r0;
+            ret;
+        };
+        Game::new(instructions_one, instructions_two, 123)
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut game = board_full_game();
+        game.conclude();
+        assert_eq!(game.checkpoint_at(0), None);
+    }
+
+    #[test]
+    fn test_ring() {
+        let mut game = board_full_game();
+        game.set_checkpoints(5, 4);
+        assert_eq!(game.conclude(), GameResult::Draw);
+        // Moves 0 through 40 are checkpointed, only the last 4 remain.
+        for move_index in [0, 5, 10, 15, 20, 22] {
+            assert_eq!(game.checkpoint_at(move_index), None);
+        }
+        for move_index in [25, 30, 35, 40] {
+            let checkpoint = game.checkpoint_at(move_index).unwrap();
+            assert_eq!(checkpoint.get_move_index(), move_index);
+        }
+        let checkpoint = game.checkpoint_at(40).unwrap();
+        assert_eq!(
+            checkpoint.get_state(),
+            GameState::RunningNextIs(Player::One)
+        );
+        assert_eq!(checkpoint.get_player_one().get_total_moves(), 20);
+        assert_eq!(checkpoint.get_player_two().get_total_moves(), 20);
+        assert!(!checkpoint.get_board().is_full());
+    }
+
+    #[test]
+    fn test_resume_matches_original() {
+        let mut game = board_full_game();
+        game.set_checkpoints(1, 100);
+        assert_eq!(game.conclude(), GameResult::Draw);
+
+        for move_index in [0, 17, 41] {
+            let checkpoint = game.checkpoint_at(move_index).unwrap();
+            let mut resumed = checkpoint.resume();
+            assert_eq!(resumed.get_total_moves(), move_index);
+            assert_eq!(resumed.conclude(), GameResult::Draw);
+            assert_eq!(resumed.get_board(), game.get_board());
+            for player in [Player::One, Player::Two] {
+                assert_eq!(
+                    resumed.get_player_data(player),
+                    game.get_player_data(player)
+                );
+            }
+            assert_eq!(resumed.checkpoint_at(move_index), None);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+use super::{Board, Player, GAME_VERSION_MAJOR, GAME_VERSION_MINOR};
+use crate::vm::Segment;
+use std::ops::RangeInclusive;
+
+/// Everything the game tells a player before its move.
+#[derive(Debug, Clone, Copy)]
+pub struct MoveInfo<'a> {
+    pub own_identity: Player,
+    pub max_steps: u64,
+    pub board: &'a Board,
+    pub own_total_moves: u16,
+    pub other_total_moves: u16,
+    /// 0xFFFF if the other player has not moved yet.
+    pub other_last_move: u16,
+}
+
+/// Where each piece of information lives in the data segment, and how it is encoded.
+///
+/// Only the game writes the data segment, so adding a new layout does not require touching the game logic.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Layout {
+    /// https://github.com/BenWiederhake/tinyvm/blob/master/data-layout/connect4.md#data-segment-content-and-layout-for-connect4
+    #[default]
+    V1,
+}
+
+impl Layout {
+    /// Returns the major and minor version that the program can read from the data segment.
+    pub fn version(&self) -> (u16, u16) {
+        match self {
+            Layout::V1 => (GAME_VERSION_MAJOR, GAME_VERSION_MINOR),
+        }
+    }
+
+    /// Returns the addresses that the program may read but not write, i.e. the version words. Writing them can only
+    /// be a bug, see `PlayerData::new`.
+    pub fn read_only_data(&self) -> RangeInclusive<u16> {
+        match self {
+            Layout::V1 => 0xFF80..=0xFF81,
+        }
+    }
+
+    /// Returns the address of the word describing the slot at (x, y).
+    pub fn slot_address(&self, board: &Board, x: usize, y: usize) -> u16 {
+        match self {
+            // Observe that x * H + y computes the index of the slot at coordinates (x, y)
+            Layout::V1 => board.index(x, y) as u16,
+        }
+    }
+
+    /// Writes everything that must be (re-)written before each move. Words that are not part of this are left alone,
+    /// in particular the program's scratch space.
+    pub fn write_move(&self, info: &MoveInfo, data: &mut Segment) {
+        match self {
+            Layout::V1 => Layout::write_move_v1(info, data),
+        }
+    }
+
+    fn write_move_v1(info: &MoveInfo, data: &mut Segment) {
+        // https://github.com/BenWiederhake/tinyvm/blob/master/data-layout/connect4.md#data-segment-content-and-layout-for-connect4
+        // - starting at 0x0000, size N words:
+        //     * Contains the entire board.
+        info.board.encode_onto(info.own_identity, data);
+        // - 0xFF80: Major version of the game and data: Must always be 0x0001, to distinguish it from other games. (In case someone wants to write a multi-game algorithm.)
+        data[0xFF80] = GAME_VERSION_MAJOR;
+        // - 0xFF81: Minor version of the game and data: Should be 0x0000 for the version in this document.
+        data[0xFF81] = GAME_VERSION_MINOR;
+        // - 0xFF82: Total time available for this move, in 4 words, most significant word first, similar to the returned value of the Time instruction.
+        data[0xFF82] = (info.max_steps >> 48) as u16;
+        data[0xFF83] = (info.max_steps >> 32) as u16;
+        data[0xFF84] = (info.max_steps >> 16) as u16;
+        data[0xFF85] = info.max_steps as u16;
+        // - 0xFF86: Width of the board.
+        data[0xFF86] = info.board.get_width() as u16;
+        // - 0xFF87: Height of the board.
+        data[0xFF87] = info.board.get_height() as u16;
+        // - 0xFF88: Total number of moves made by the other player.
+        data[0xFF88] = info.other_total_moves;
+        // - 0xFF89: Total number of moves made by this player.
+        data[0xFF89] = info.own_total_moves;
+        // - 0xFF8A: Last move by other player. Again, 0-indexed. If this is the first move (and there is no previous move), this contains the value 0xFFFF.
+        data[0xFF8A] = info.other_last_move;
+        // - 0xFF8B-0xFFFF: These words may be overwritten arbitrarily on each turn by the game. If the game version is 0x0001.0x0000, then these words shall be overwritten with 0x0000.
+        for i in 0xFF8B..=0xFFFF {
+            data[i] = 0x0000;
+        }
+    }
+
+    /// Draws the board as the program currently sees it in `data`, one line per row, top row first: 'x' is the
+    /// program's own token, 'O' the other player's, and '?' any other value. The program may have overwritten the
+    /// board or its dimensions, so this only trusts what it can check.
+    pub fn describe_board(&self, data: &Segment) -> String {
+        match self {
+            Layout::V1 => Layout::describe_board_v1(data),
+        }
+    }
+
+    fn describe_board_v1(data: &Segment) -> String {
+        let (width, height) = (data[0xFF86] as usize, data[0xFF87] as usize);
+        let board = match Board::try_new(width, height) {
+            Ok(board) => board,
+            Err(err) => return format!("(no board: {})\n", err),
+        };
+        let mut text = String::new();
+        for y in (0..height).rev() {
+            text.push('|');
+            for x in 0..width {
+                let symbol = match data[Layout::V1.slot_address(&board, x, y)] {
+                    0 => " _",
+                    1 => " x",
+                    2 => " O",
+                    _ => " ?",
+                };
+                text.push_str(symbol);
+            }
+            text.push_str(" |\n");
+        }
+        text.push('+');
+        text.push_str(&"--".repeat(width));
+        text.push_str("-+\n");
+        text
+    }
+}
+
+#[cfg(test)]
+mod test_layout {
+    use super::*;
+    use crate::connect4::SlotState;
+
+    /// The data segment as it was written before the layout was factored out, spelled out word by word.
+    fn expected_v1(info: &MoveInfo, mut data: Segment) -> Segment {
+        let board = info.board;
+        for x in 0..board.get_width() {
+            for y in 0..board.get_height() {
+                data[(x * board.get_height() + y) as u16] = match board.get_slot(x, y) {
+                    SlotState::Empty => 0,
+                    SlotState::Token(player) if player == info.own_identity => 1,
+                    SlotState::Token(_) => 2,
+                };
+            }
+        }
+        let words = [
+            0x0001,
+            0x0000,
+            (info.max_steps >> 48) as u16,
+            (info.max_steps >> 32) as u16,
+            (info.max_steps >> 16) as u16,
+            info.max_steps as u16,
+            board.get_width() as u16,
+            board.get_height() as u16,
+            info.other_total_moves,
+            info.own_total_moves,
+            info.other_last_move,
+        ];
+        for (i, word) in words.iter().enumerate() {
+            data[0xFF80 + i as u16] = *word;
+        }
+        for i in 0xFF8B..=0xFFFF {
+            data[i] = 0;
+        }
+        data
+    }
+
+    fn dirty_segment() -> Segment {
+        let mut data = Segment::new_zeroed();
+        for i in 0..=0xFFFF {
+            data[i] = i.wrapping_mul(0x9E37);
+        }
+        data
+    }
+
+    #[test]
+    fn test_v1_equivalence() {
+        let mut board = Board::default();
+        for (column, player) in [(3, Player::One), (3, Player::Two), (0, Player::One)] {
+            board.place_into_unsanitized_column(column, player);
+        }
+        let small_board = Board::new_custom(4, 5);
+        for (board, own_identity, max_steps, other_last_move) in [
+            (&board, Player::One, 0x123456789ABCDEF0, 3),
+            (&board, Player::Two, 1, 0),
+            (&small_board, Player::One, u64::MAX, 0xFFFF),
+        ] {
+            let info = MoveInfo {
+                own_identity,
+                max_steps,
+                board,
+                own_total_moves: 0x12,
+                other_total_moves: 0x34,
+                other_last_move,
+            };
+            let mut actual = dirty_segment();
+            Layout::V1.write_move(&info, &mut actual);
+            assert_eq!(actual, expected_v1(&info, dirty_segment()));
+        }
+    }
+
+    #[test]
+    fn test_v1_leaves_scratch_space() {
+        let board = Board::default();
+        let info = MoveInfo {
+            own_identity: Player::One,
+            max_steps: 100,
+            board: &board,
+            own_total_moves: 0,
+            other_total_moves: 0,
+            other_last_move: 0xFFFF,
+        };
+        let mut data = dirty_segment();
+        Layout::V1.write_move(&info, &mut data);
+        let n = (board.get_width() * board.get_height()) as u16;
+        for i in n..0xFF80 {
+            assert_eq!(data[i], i.wrapping_mul(0x9E37));
+        }
+    }
+
+    #[test]
+    fn test_v1_metadata() {
+        assert_eq!(Layout::default(), Layout::V1);
+        assert_eq!(Layout::V1.version(), (0x0001, 0x0000));
+        let board = Board::default();
+        assert_eq!(Layout::V1.slot_address(&board, 0, 0), 0);
+        assert_eq!(Layout::V1.slot_address(&board, 0, 5), 5);
+        assert_eq!(Layout::V1.slot_address(&board, 1, 0), 6);
+        assert_eq!(Layout::V1.slot_address(&board, 6, 5), 41);
+    }
+
+    #[test]
+    fn test_v1_describe_board() {
+        let mut board = Board::new_custom(4, 4);
+        for (column, player) in [(1, Player::One), (1, Player::Two), (3, Player::One)] {
+            board.place_into_unsanitized_column(column, player);
+        }
+        let info = MoveInfo {
+            own_identity: Player::Two,
+            max_steps: 100,
+            board: &board,
+            own_total_moves: 1,
+            other_total_moves: 2,
+            other_last_move: 3,
+        };
+        let mut data = Segment::new_zeroed();
+        Layout::V1.write_move(&info, &mut data);
+        data[Layout::V1.slot_address(&board, 0, 0)] = 0x1234;
+        assert_eq!(
+            Layout::V1.describe_board(&data),
+            "| _ _ _ _ |\n\
+             | _ _ _ _ |\n\
+             | _ x _ _ |\n\
+             | ? O _ O |\n\
+             +---------+\n"
+        );
+        data[0xFF86] = 0;
+        assert_eq!(
+            Layout::V1.describe_board(&data),
+            format!("(no board: {})\n", Board::try_new(0, 4).unwrap_err())
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use super::{Board, BoardError};
+use serde::{Deserialize, Serialize};
+
+/// The serialized form of a `Board`: the dimensions, and one character per slot as in `Board::to_compact_string`.
+#[derive(Serialize, Deserialize)]
+pub struct CompactBoard {
+    width: usize,
+    height: usize,
+    slots: String,
+}
+
+impl From<Board> for CompactBoard {
+    fn from(board: Board) -> CompactBoard {
+        CompactBoard {
+            width: board.width,
+            height: board.height,
+            slots: board.to_compact_string(),
+        }
+    }
+}
+
+impl TryFrom<CompactBoard> for Board {
+    type Error = BoardError;
+
+    fn try_from(compact: CompactBoard) -> Result<Board, BoardError> {
+        Board::from_compact_string(compact.width, compact.height, &compact.slots)
+    }
+}
+
+#[cfg(test)]
+mod test_serde {
+    use super::super::{GameResult, Player, WinReason};
+    use super::*;
+
+    #[test]
+    fn test_board_roundtrip() {
+        let mut board = Board::default();
+        board.place_into_unsanitized_column(3, Player::One);
+        board.place_into_unsanitized_column(3, Player::Two);
+        board.place_into_unsanitized_column(0, Player::One);
+
+        let json = serde_json::to_string(&board).unwrap();
+        assert_eq!(
+            json,
+            r#"{"width":7,"height":6,"slots":"X.................XO......................"}"#
+        );
+        let parsed: Board = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, board);
+    }
+
+    #[test]
+    fn test_board_roundtrip_empty_custom() {
+        let board = Board::new_custom(4, 9);
+        let parsed: Board = serde_json::from_str(&serde_json::to_string(&board).unwrap()).unwrap();
+        assert_eq!(parsed, board);
+    }
+
+    #[test]
+    fn test_board_reject() {
+        for (json, message) in [
+            (
+                r#"{"width":3,"height":6,"slots":".................."}"#,
+                "3x6 are silly dimensions!",
+            ),
+            (
+                r#"{"width":4,"height":4,"slots":"..."}"#,
+                "Expected 16 slots, got 3 instead.",
+            ),
+            (
+                r##"{"width":4,"height":4,"slots":"#..............."}"##,
+                "Slot 0 is '#'",
+            ),
+            (
+                r#"{"width":4,"height":4,"slots":".X.............."}"#,
+                "The token at (0, 1) is floating above an empty slot.",
+            ),
+            (
+                r#"{"width":4,"height":4,"slots":"XX.............."}"#,
+                "Player one has 2 tokens and player two has 0",
+            ),
+            (
+                r#"{"width":4,"height":4,"slots":"O..............."}"#,
+                "Player one has 0 tokens and player two has 1",
+            ),
+            (r#"{"width":4,"height":4}"#, "missing field `slots`"),
+        ] {
+            let err = serde_json::from_str::<Board>(json).unwrap_err();
+            assert!(
+                err.to_string().contains(message),
+                "{:?} should mention {:?}, but got {}",
+                json,
+                message,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_game_result_roundtrip() {
+        let result = GameResult::Won(
+            Player::Two,
+            WinReason::IllegalInstruction {
+                insn: 0xFFFF,
+                pc: 0x1FFE,
+            },
+        );
+        let json = serde_json::to_string(&result).unwrap();
+        assert_eq!(
+            json,
+            r#"{"Won":["Two",{"IllegalInstruction":{"insn":65535,"pc":8190}}]}"#
+        );
+        assert_eq!(serde_json::from_str::<GameResult>(&json).unwrap(), result);
+    }
+}
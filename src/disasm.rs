@@ -0,0 +1,282 @@
+use crate::vm::{
+    decode_branch, decode_jump_imm, BinaryFunction, Instruction, Segment, UnaryFunction,
+};
+
+// The mnemonics follow the comments in the tests and the function names of the instruction set architecture. The
+// operand order is destination first, except for `sw` and `swi` (address, value), binary functions (lhs, rhs, the result
+// overwrites rhs), and compare (lhs, rhs, the result overwrites rhs). Offsets are written as `rA + N`.
+
+/// Returns the mnemonic of a single word, e.g. `lw r2, 0x0034`, `b r2, -0x1`, or `ill 0x0123`.
+#[must_use]
+pub fn disassemble(word: u16) -> String {
+    let instruction = match Instruction::decode(word) {
+        Ok(instruction) => instruction,
+        Err(illegal) => return format!("ill 0x{:04X}", illegal),
+    };
+    match instruction {
+        Instruction::Return => "ret".into(),
+        Instruction::Cpuid => "cpuid".into(),
+        Instruction::DebugDump => "debug-dump".into(),
+        Instruction::Time => "time".into(),
+        Instruction::CompareZero { flags, reg } => format!("{} r{}, 0", compare_name(flags), reg),
+        Instruction::StoreData {
+            address_reg,
+            data_reg,
+        } => format!("sw r{}, r{}", address_reg, data_reg),
+        Instruction::LoadData {
+            address_reg,
+            data_reg,
+        } => format!("lw r{}, r{}", data_reg, address_reg),
+        Instruction::LoadInstruction {
+            address_reg,
+            data_reg,
+        } => format!("lwi r{}, r{}", data_reg, address_reg),
+        Instruction::StoreInstruction {
+            address_reg,
+            data_reg,
+        } => format!("swi r{}, r{}", address_reg, data_reg),
+        Instruction::LoadDataOffset {
+            address_reg,
+            offset,
+            data_reg,
+        } => format!("lw r{}, r{} + {}", data_reg, address_reg, offset),
+        Instruction::StoreDataOffset {
+            address_reg,
+            offset,
+            data_reg,
+        } => format!("sw r{} + {}, r{}", address_reg, offset, data_reg),
+        Instruction::LoadImmLow { reg, value } => {
+            format!("lw r{}, 0x{:04X}", reg, value as u8 as i8 as i16 as u16)
+        }
+        Instruction::LoadImmHigh { reg, value } => format!("lhi r{}, 0x{:04X}", reg, value << 8),
+        Instruction::Unary { func, src, dst } => {
+            format!("{} r{}, r{}", unary_name(func), dst, src)
+        }
+        Instruction::Binary { func, src, dst } => {
+            format!("{} r{}, r{}", binary_name(func), src, dst)
+        }
+        Instruction::Compare { flags, lhs, rhs } => {
+            format!("{} r{}, r{}", compare_name(flags), lhs, rhs)
+        }
+        Instruction::Branch { reg, .. } => {
+            let (_, relative) = decode_branch(word).expect("is a branch");
+            format!("b r{}, {}", reg, signed_hex(relative))
+        }
+        Instruction::JumpImm { .. } => {
+            let relative = decode_jump_imm(word).expect("is a jump by immediate");
+            format!("j {}", signed_hex(relative))
+        }
+        Instruction::JumpReg { reg, offset } => {
+            let offset = offset as u8 as i8 as i32;
+            if offset < 0 {
+                format!("j r{} - 0x{:02X}", reg, -offset)
+            } else {
+                format!("j r{} + 0x{:02X}", reg, offset)
+            }
+        }
+    }
+}
+
+/// Disassembles the segment up to its last nonzero word, one line per instruction, e.g.
+/// `0002: 6012       add r1, r2`.
+///
+/// A "load immediate low" directly followed by a "load immediate high" of the same register is shown as a single
+/// 16-bit load on one line, with both words. Note that this is only a guess: if some jump targets the second word,
+/// the line is misleading.
+#[must_use]
+pub fn disassemble_segment(segment: &Segment) -> Vec<String> {
+    let len = segment.used_len();
+    let mut lines = Vec::new();
+    let mut address = 0;
+    while address < len {
+        let word = segment[address as u16];
+        let next = (address + 1 < len).then(|| segment[(address + 1) as u16]);
+        if let Some(merged) = next.and_then(|next| merge_load(word, next)) {
+            let next = next.expect("checked above");
+            lines.push(format!(
+                "{:04X}: {:04X} {:04X}  {}",
+                address, word, next, merged
+            ));
+            address += 2;
+        } else {
+            lines.push(format!(
+                "{:04X}: {:04X}       {}",
+                address,
+                word,
+                disassemble(word)
+            ));
+            address += 1;
+        }
+    }
+    lines
+}
+
+fn merge_load(low: u16, high: u16) -> Option<String> {
+    match (Instruction::decode(low), Instruction::decode(high)) {
+        (
+            Ok(Instruction::LoadImmLow {
+                reg: low_reg,
+                value: low_byte,
+            }),
+            Ok(Instruction::LoadImmHigh {
+                reg: high_reg,
+                value: high_byte,
+            }),
+        ) if low_reg == high_reg => Some(format!(
+            "lw r{}, 0x{:04X} // lw + lhi",
+            low_reg,
+            (high_byte << 8) | low_byte
+        )),
+        _ => None,
+    }
+}
+
+fn signed_hex(value: i32) -> String {
+    if value < 0 {
+        format!("-0x{:X}", -value)
+    } else {
+        format!("+0x{:X}", value)
+    }
+}
+
+/// Names the LEGS flags. The sign flag appends `.s`, even where it makes no difference.
+fn compare_name(flags: u16) -> String {
+    let name = match flags & 0b1110 {
+        0b0000 => "false",
+        0b0010 => "gt",
+        0b0100 => "eq",
+        0b0110 => "ge",
+        0b1000 => "lt",
+        0b1010 => "ne",
+        0b1100 => "le",
+        _ => "true",
+    };
+    if flags & 0b0001 != 0 {
+        format!("{}.s", name)
+    } else {
+        name.into()
+    }
+}
+
+pub(crate) fn unary_name(func: UnaryFunction) -> &'static str {
+    match func {
+        UnaryFunction::Decr => "decr",
+        UnaryFunction::Incr => "incr",
+        UnaryFunction::Not => "not",
+        UnaryFunction::Popcnt => "popcnt",
+        UnaryFunction::Clz => "clz",
+        UnaryFunction::Ctz => "ctz",
+        UnaryFunction::Rnd => "rnd",
+        UnaryFunction::Mov => "mov",
+    }
+}
+
+pub(crate) fn binary_name(func: BinaryFunction) -> &'static str {
+    match func {
+        BinaryFunction::Add => "add",
+        BinaryFunction::Sub => "sub",
+        BinaryFunction::Mul => "mul",
+        BinaryFunction::Mulh => "mulh",
+        BinaryFunction::DivU => "div.u",
+        BinaryFunction::DivS => "div.s",
+        BinaryFunction::ModU => "mod.u",
+        BinaryFunction::ModS => "mod.s",
+        BinaryFunction::And => "and",
+        BinaryFunction::Or => "or",
+        BinaryFunction::Xor => "xor",
+        BinaryFunction::Sl => "sl",
+        BinaryFunction::Srl => "srl",
+        BinaryFunction::Sra => "sra",
+        BinaryFunction::Exp => "exp",
+        BinaryFunction::Root => "root",
+    }
+}
+
+#[cfg(test)]
+mod test_disasm {
+    use super::*;
+    use crate::selftest::FIBONACCI;
+
+    #[test]
+    fn test_single_words() {
+        // Most of these appear in the comments in tests/instructions.rs, some with other operand order.
+        assert_eq!(disassemble(0x102A), "ret");
+        assert_eq!(disassemble(0x102C), "debug-dump");
+        assert_eq!(disassemble(0x3245), "lw r2, 0x0045");
+        assert_eq!(disassemble(0x31FF), "lw r1, 0xFFFF");
+        assert_eq!(disassemble(0x45AB), "lhi r5, 0xAB00");
+        assert_eq!(disassemble(0x2025), "sw r2, r5");
+        assert_eq!(disassemble(0x2125), "lw r5, r2");
+        assert_eq!(disassemble(0x2225), "lwi r5, r2");
+        assert_eq!(disassemble(0x2325), "swi r2, r5");
+        assert_eq!(disassemble(0x5E12), "rnd r2, r1");
+        assert_eq!(disassemble(0x5F71), "mov r1, r7");
+        assert_eq!(disassemble(0x7312), "lw r2, r1 + 3");
+        assert_eq!(disassemble(0x7F12), "sw r1 + 7, r2");
+        assert_eq!(disassemble(0x8435), "eq r3, r5");
+        assert_eq!(disassemble(0x8A34), "ne r3, r4");
+        assert_eq!(disassemble(0x8D34), "le.s r3, r4");
+        assert_eq!(disassemble(0x11A3), "ne r3, 0");
+        assert_eq!(disassemble(0x9580), "b r5, -0x1");
+        assert_eq!(disassemble(0x9200), "b r2, +0x2");
+        assert_eq!(disassemble(0xA800), "j -0x1");
+        assert_eq!(disassemble(0xA123), "j +0x125");
+        assert_eq!(disassemble(0xB77F), "j r7 + 0x7F");
+        assert_eq!(disassemble(0xB780), "j r7 - 0x80");
+        assert_eq!(disassemble(0xB7FF), "j r7 - 0x01");
+        assert_eq!(disassemble(0x0123), "ill 0x0123");
+        assert_eq!(disassemble(0x5711), "ill 0x5711");
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        let mut segment = Segment::new_zeroed();
+        for (i, &word) in FIBONACCI.instructions.iter().enumerate() {
+            segment[i as u16] = word;
+        }
+        // The comments in selftest.rs, with the canonical operands.
+        assert_eq!(
+            disassemble_segment(&segment),
+            vec![
+                "0000: 3018       lw r0, 0x0018",
+                "0001: 3101       lw r1, 0x0001",
+                "0002: 6012       add r1, r2",
+                "0003: 5800       decr r0, r0",
+                "0004: 2002       sw r0, r2",
+                "0005: 6021       add r2, r1",
+                "0006: 5800       decr r0, r0",
+                "0007: 2001       sw r0, r1",
+                "0008: 9085       b r0, -0x6",
+                "0009: 102A       ret",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_load() {
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x3234; // lw r2, 0x1234
+        segment[1] = 0x4212;
+        segment[2] = 0x3534; // Different registers, not merged.
+        segment[3] = 0x4612;
+        segment[4] = 0x37FF; // lw r7, 0x7FFF
+        segment[5] = 0x477F;
+        assert_eq!(
+            disassemble_segment(&segment),
+            vec![
+                "0000: 3234 4212  lw r2, 0x1234 // lw + lhi",
+                "0002: 3534       lw r5, 0x0034",
+                "0003: 4612       lhi r6, 0x1200",
+                "0004: 37FF 477F  lw r7, 0x7FFF // lw + lhi",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_every_word() {
+        for word in 0..=0xFFFF {
+            let text = disassemble(word);
+            assert_eq!(text.starts_with("ill "), Instruction::decode(word).is_err());
+        }
+    }
+}
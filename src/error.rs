@@ -0,0 +1,195 @@
+use crate::asm::AsmError;
+use crate::connect4::BoardError;
+use crate::format::FormatError;
+use crate::vm::load::SegmentLoadError;
+use crate::vm::{BuildError, MemTraceError, OffsetError, SegmentError};
+use std::error::Error as StdError;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+
+/// Any error that the crate can report. Each module has its own error type, and this just wraps them, so that
+/// callers like the CLI can handle them uniformly.
+///
+/// `Display` and `source` are transparent, i.e. they are those of the wrapped error.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Format(FormatError),
+    Load(SegmentLoadError),
+    Segment(SegmentError),
+    Offset(OffsetError),
+    Board(BoardError),
+    Build(BuildError),
+    MemTrace(MemTraceError),
+    Asm(AsmError),
+}
+
+impl Error {
+    /// The process exit code that the CLI uses for this error. 1 is reserved for usage errors.
+    #[must_use]
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Error::Io(_)
+            | Error::Load(SegmentLoadError::Io { .. })
+            | Error::MemTrace(MemTraceError::Io(_)) => 2,
+            Error::Format(_)
+            | Error::Load(_)
+            | Error::Segment(_)
+            | Error::Offset(_)
+            | Error::Board(_)
+            | Error::Build(_)
+            | Error::MemTrace(_)
+            | Error::Asm(_) => 3,
+        }
+    }
+
+    fn inner(&self) -> &(dyn StdError + 'static) {
+        match self {
+            Error::Io(err) => err,
+            Error::Format(err) => err,
+            Error::Load(err) => err,
+            Error::Segment(err) => err,
+            Error::Offset(err) => err,
+            Error::Board(err) => err,
+            Error::Build(err) => err,
+            Error::MemTrace(err) => err,
+            Error::Asm(err) => err,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        Display::fmt(self.inner(), f)
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        StdError::source(self.inner())
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<FormatError> for Error {
+    fn from(err: FormatError) -> Error {
+        Error::Format(err)
+    }
+}
+
+impl From<SegmentLoadError> for Error {
+    fn from(err: SegmentLoadError) -> Error {
+        Error::Load(err)
+    }
+}
+
+impl From<SegmentError> for Error {
+    fn from(err: SegmentError) -> Error {
+        Error::Segment(err)
+    }
+}
+
+impl From<OffsetError> for Error {
+    fn from(err: OffsetError) -> Error {
+        Error::Offset(err)
+    }
+}
+
+impl From<BuildError> for Error {
+    fn from(err: BuildError) -> Error {
+        Error::Build(err)
+    }
+}
+
+impl From<MemTraceError> for Error {
+    fn from(err: MemTraceError) -> Error {
+        Error::MemTrace(err)
+    }
+}
+
+impl From<AsmError> for Error {
+    fn from(err: AsmError) -> Error {
+        Error::Asm(err)
+    }
+}
+
+impl From<BoardError> for Error {
+    fn from(err: BoardError) -> Error {
+        Error::Board(err)
+    }
+}
+
+#[cfg(test)]
+mod test_error {
+    use super::*;
+    use crate::connect4::Board;
+    use crate::format::{decode_segment, SegmentFormat};
+    use crate::vm::encode_jump_imm;
+    use crate::vm::load::{load_segment, parse_segment_bytes, LoadOptions};
+    use std::path::Path;
+
+    #[test]
+    fn test_display_is_transparent() {
+        let err: Error = Board::try_new(2, 2).unwrap_err().into();
+        assert_eq!(
+            err.to_string(),
+            "2x2 are silly dimensions! Width and height must be between 4 and 1023."
+        );
+        assert!(err.source().is_none());
+        assert_eq!(err.exit_code(), 3);
+
+        let err: Error = encode_jump_imm(0).unwrap_err().into();
+        assert_eq!(
+            err.to_string(),
+            "Relative offset 0 cannot be encoded, it would be an infinite loop or a no-op."
+        );
+
+        let err: Error = decode_segment(&[0; 3], SegmentFormat::BigEndian)
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            err.to_string(),
+            "Wrong segment length, expected 131072, got 3 instead."
+        );
+    }
+
+    #[test]
+    fn test_source_chain() {
+        let options = LoadOptions {
+            format: Some(SegmentFormat::HexText),
+            ..LoadOptions::default()
+        };
+        let err: Error = parse_segment_bytes(Path::new("bot.hex"), b"xyz", options)
+            .unwrap_err()
+            .into();
+        assert_eq!(
+            err.to_string(),
+            "Invalid segment in bot.hex: Line 1: 'xyz' is not a hex word."
+        );
+        let source = err.source().unwrap();
+        assert_eq!(source.to_string(), "Line 1: 'xyz' is not a hex word.");
+        assert!(source.source().is_none());
+        assert_eq!(err.exit_code(), 3);
+    }
+
+    #[test]
+    fn test_io() {
+        let err: Error = load_segment(Path::new("/nonexistent/bot"), LoadOptions::default())
+            .unwrap_err()
+            .into();
+        assert!(err
+            .to_string()
+            .starts_with("Cannot read /nonexistent/bot: "));
+        assert!(err.source().unwrap().downcast_ref::<io::Error>().is_some());
+        assert_eq!(err.exit_code(), 2);
+
+        let err: Error = io::Error::other("disk on fire").into();
+        assert_eq!(err.to_string(), "disk on fire");
+        assert_eq!(err.exit_code(), 2);
+    }
+}
@@ -0,0 +1,437 @@
+use crate::vm::Segment;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+pub const SEGMENT_WORDS: usize = 1 << 16;
+pub const SEGMENT_BYTES: usize = SEGMENT_WORDS * 2;
+
+mod ihex;
+mod rle;
+
+pub use ihex::{decode_ihex, looks_like_ihex, IhexError};
+pub use rle::{decode_rle, encode_rle, looks_like_rle, RleError, RLE_MAGIC, RLE_VERSION};
+
+const HEX_WORDS_PER_LINE: usize = 8;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SegmentFormat {
+    /// Raw 131072 bytes, each word stored most significant byte first. This is the "canonical" format.
+    BigEndian,
+    /// Raw 131072 bytes, each word stored least significant byte first.
+    LittleEndian,
+    /// Text, whitespace-separated hex words starting at address 0. Missing trailing words are zero.
+    /// Everything from '#' to the end of a line is a comment.
+    HexText,
+    /// Binary, runs of equal words after a magic header, see `encode_rle`. Much smaller than the raw formats.
+    Rle,
+}
+
+impl SegmentFormat {
+    pub const ALL: [SegmentFormat; 4] = [
+        SegmentFormat::BigEndian,
+        SegmentFormat::LittleEndian,
+        SegmentFormat::HexText,
+        SegmentFormat::Rle,
+    ];
+
+    pub fn from_name(name: &str) -> Option<SegmentFormat> {
+        match name {
+            "be" => Some(SegmentFormat::BigEndian),
+            "le" => Some(SegmentFormat::LittleEndian),
+            "hex" => Some(SegmentFormat::HexText),
+            "rle" => Some(SegmentFormat::Rle),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SegmentFormat::BigEndian => "be",
+            SegmentFormat::LittleEndian => "le",
+            SegmentFormat::HexText => "hex",
+            SegmentFormat::Rle => "rle",
+        }
+    }
+}
+
+impl Display for SegmentFormat {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum FormatError {
+    WrongLength {
+        expected: usize,
+        actual: usize,
+    },
+    /// Only from `decode_segment_padded`, which accepts any even length up to the full segment.
+    OddLength {
+        actual: usize,
+    },
+    InvalidHexWord {
+        line: usize,
+        token: String,
+    },
+    TooManyWords {
+        line: usize,
+    },
+    DetectFailed {
+        candidates: Vec<SegmentFormat>,
+    },
+    InvalidRle {
+        source: RleError,
+    },
+}
+
+impl Display for FormatError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            FormatError::WrongLength { expected, actual } => write!(
+                f,
+                "Wrong segment length, expected {}, got {} instead.",
+                expected, actual
+            ),
+            FormatError::OddLength { actual } => write!(
+                f,
+                "Wrong segment length, got {} bytes, which is not a whole number of words.",
+                actual
+            ),
+            FormatError::InvalidHexWord { line, token } => {
+                write!(f, "Line {}: '{}' is not a hex word.", line, token)
+            }
+            FormatError::TooManyWords { line } => write!(
+                f,
+                "Line {}: Segment cannot have more than {} words.",
+                line, SEGMENT_WORDS
+            ),
+            FormatError::DetectFailed { candidates } if candidates.is_empty() => {
+                f.write_str("Cannot detect segment format: no known format matches.")
+            }
+            FormatError::DetectFailed { candidates } => {
+                let names = candidates
+                    .iter()
+                    .map(SegmentFormat::name)
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                write!(
+                    f,
+                    "Cannot detect segment format: ambiguous between {}.",
+                    names
+                )
+            }
+            FormatError::InvalidRle { source } => source.fmt(f),
+        }
+    }
+}
+
+impl Error for FormatError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FormatError::InvalidRle { source } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+fn decode_raw(bytes: &[u8], big_endian: bool, padded: bool) -> Result<Segment, FormatError> {
+    if padded && bytes.len() < SEGMENT_BYTES && bytes.len() % 2 == 1 {
+        return Err(FormatError::OddLength {
+            actual: bytes.len(),
+        });
+    }
+    if bytes.len() > SEGMENT_BYTES || (!padded && bytes.len() != SEGMENT_BYTES) {
+        return Err(FormatError::WrongLength {
+            expected: SEGMENT_BYTES,
+            actual: bytes.len(),
+        });
+    }
+
+    let mut segment = Segment::new_zeroed();
+    for (i, pair) in bytes.chunks_exact(2).enumerate() {
+        let pair = [pair[0], pair[1]];
+        segment[i as u16] = if big_endian {
+            u16::from_be_bytes(pair)
+        } else {
+            u16::from_le_bytes(pair)
+        };
+    }
+    Ok(segment)
+}
+
+fn decode_hex(bytes: &[u8]) -> Result<Segment, FormatError> {
+    let mut segment = Segment::new_zeroed();
+    let mut next_index = 0usize;
+    // Non-UTF-8 input is never valid, so report the first line as broken.
+    let text = std::str::from_utf8(bytes).map_err(|_| FormatError::InvalidHexWord {
+        line: 1,
+        token: "<binary data>".into(),
+    })?;
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let content = match line.find('#') {
+            Some(comment_start) => &line[..comment_start],
+            None => line,
+        };
+        for token in content.split_whitespace() {
+            let digits = token
+                .strip_prefix("0x")
+                .or_else(|| token.strip_prefix("0X"))
+                .unwrap_or(token);
+            if digits.is_empty() || digits.len() > 4 {
+                return Err(FormatError::InvalidHexWord {
+                    line: line_number,
+                    token: token.into(),
+                });
+            }
+            let word =
+                u16::from_str_radix(digits, 16).map_err(|_| FormatError::InvalidHexWord {
+                    line: line_number,
+                    token: token.into(),
+                })?;
+            if next_index >= SEGMENT_WORDS {
+                return Err(FormatError::TooManyWords { line: line_number });
+            }
+            segment[next_index as u16] = word;
+            next_index += 1;
+        }
+    }
+
+    Ok(segment)
+}
+
+pub fn decode_segment(bytes: &[u8], format: SegmentFormat) -> Result<Segment, FormatError> {
+    match format {
+        SegmentFormat::BigEndian => decode_raw(bytes, true, false),
+        SegmentFormat::LittleEndian => decode_raw(bytes, false, false),
+        SegmentFormat::HexText => decode_hex(bytes),
+        SegmentFormat::Rle => {
+            decode_rle(bytes).map_err(|source| FormatError::InvalidRle { source })
+        }
+    }
+}
+
+/// Like `decode_segment`, but the raw formats also accept fewer than 131072 bytes, as long as they are a whole
+/// number of words. The missing trailing words are zero, just like in `SegmentFormat::HexText`.
+pub fn decode_segment_padded(bytes: &[u8], format: SegmentFormat) -> Result<Segment, FormatError> {
+    match format {
+        SegmentFormat::BigEndian => decode_raw(bytes, true, true),
+        SegmentFormat::LittleEndian => decode_raw(bytes, false, true),
+        SegmentFormat::HexText | SegmentFormat::Rle => decode_segment(bytes, format),
+    }
+}
+
+fn encode_hex(segment: &Segment) -> Vec<u8> {
+    // Trailing zeros are implied, so don't write them.
+    let used_words = (0..SEGMENT_WORDS)
+        .rev()
+        .find(|&i| segment[i as u16] != 0)
+        .map_or(0, |i| i + 1);
+
+    let mut text = String::new();
+    for i in 0..used_words {
+        if i % HEX_WORDS_PER_LINE != 0 {
+            text.push(' ');
+        }
+        text.push_str(&format!("{:04X}", segment[i as u16]));
+        if i % HEX_WORDS_PER_LINE == HEX_WORDS_PER_LINE - 1 || i + 1 == used_words {
+            text.push('\n');
+        }
+    }
+    text.into_bytes()
+}
+
+#[must_use]
+pub fn encode_segment(segment: &Segment, format: SegmentFormat) -> Vec<u8> {
+    match format {
+        SegmentFormat::BigEndian => (0..SEGMENT_WORDS)
+            .flat_map(|i| segment[i as u16].to_be_bytes())
+            .collect(),
+        SegmentFormat::LittleEndian => (0..SEGMENT_WORDS)
+            .flat_map(|i| segment[i as u16].to_le_bytes())
+            .collect(),
+        SegmentFormat::HexText => encode_hex(segment),
+        SegmentFormat::Rle => encode_rle(segment),
+    }
+}
+
+/// Determines the format of the given bytes, but only if exactly one format can decode them.
+///
+/// Note that the two raw formats can never be told apart, so raw input always needs an explicit format.
+pub fn detect_format(bytes: &[u8]) -> Result<SegmentFormat, FormatError> {
+    let candidates = SegmentFormat::ALL
+        .iter()
+        .copied()
+        .filter(|&format| decode_segment(bytes, format).is_ok())
+        .collect::<Vec<_>>();
+    if candidates.len() == 1 {
+        Ok(candidates[0])
+    } else {
+        Err(FormatError::DetectFailed { candidates })
+    }
+}
+
+pub fn convert_segment(
+    bytes: &[u8],
+    from: Option<SegmentFormat>,
+    to: SegmentFormat,
+) -> Result<Vec<u8>, FormatError> {
+    let from = match from {
+        Some(format) => format,
+        None => detect_format(bytes)?,
+    };
+    let segment = decode_segment(bytes, from)?;
+    Ok(encode_segment(&segment, to))
+}
+
+#[cfg(test)]
+mod test_format {
+    use super::*;
+
+    fn sample_segment() -> Segment {
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x102A;
+        segment[1] = 0x00FF;
+        segment[2] = 0xFF00;
+        segment[9] = 0xABCD;
+        segment[0xFFFF] = 0x1234;
+        segment
+    }
+
+    #[test]
+    fn test_names() {
+        for format in SegmentFormat::ALL {
+            assert_eq!(SegmentFormat::from_name(format.name()), Some(format));
+        }
+        assert_eq!(SegmentFormat::from_name("BE"), None);
+    }
+
+    #[test]
+    fn test_encode_be() {
+        let bytes = encode_segment(&sample_segment(), SegmentFormat::BigEndian);
+        assert_eq!(bytes.len(), SEGMENT_BYTES);
+        assert_eq!(&bytes[0..6], &[0x10, 0x2A, 0x00, 0xFF, 0xFF, 0x00]);
+        assert_eq!(&bytes[SEGMENT_BYTES - 2..], &[0x12, 0x34]);
+    }
+
+    #[test]
+    fn test_encode_le() {
+        let bytes = encode_segment(&sample_segment(), SegmentFormat::LittleEndian);
+        assert_eq!(bytes.len(), SEGMENT_BYTES);
+        assert_eq!(&bytes[0..6], &[0x2A, 0x10, 0xFF, 0x00, 0x00, 0xFF]);
+        assert_eq!(&bytes[SEGMENT_BYTES - 2..], &[0x34, 0x12]);
+    }
+
+    #[test]
+    fn test_encode_hex() {
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x102A;
+        segment[8] = 0x00FF;
+        let text = encode_segment(&segment, SegmentFormat::HexText);
+        assert_eq!(
+            String::from_utf8(text).unwrap(),
+            "102A 0000 0000 0000 0000 0000 0000 0000\n00FF\n"
+        );
+        assert!(encode_segment(&Segment::new_zeroed(), SegmentFormat::HexText).is_empty());
+    }
+
+    #[test]
+    fn test_decode_hex_comments() {
+        let text = b"# Return zero\n3000 # lw r0, 0\n0x102a\n";
+        let segment = decode_segment(text, SegmentFormat::HexText).unwrap();
+        assert_eq!(segment[0], 0x3000);
+        assert_eq!(segment[1], 0x102A);
+        assert_eq!(segment[2], 0x0000);
+    }
+
+    #[test]
+    fn test_decode_hex_invalid() {
+        assert_eq!(
+            decode_segment(b"1234\n12345\n", SegmentFormat::HexText),
+            Err(FormatError::InvalidHexWord {
+                line: 2,
+                token: "12345".into()
+            })
+        );
+        assert_eq!(
+            decode_segment(b"12G4", SegmentFormat::HexText),
+            Err(FormatError::InvalidHexWord {
+                line: 1,
+                token: "12G4".into()
+            })
+        );
+        let too_long = "0 ".repeat(SEGMENT_WORDS + 1);
+        assert_eq!(
+            decode_segment(too_long.as_bytes(), SegmentFormat::HexText),
+            Err(FormatError::TooManyWords { line: 1 })
+        );
+    }
+
+    #[test]
+    fn test_decode_raw_wrong_length() {
+        for format in [SegmentFormat::BigEndian, SegmentFormat::LittleEndian] {
+            assert_eq!(
+                decode_segment(&[0x10, 0x2A], format),
+                Err(FormatError::WrongLength {
+                    expected: SEGMENT_BYTES,
+                    actual: 2
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_all_pairs() {
+        let segment = sample_segment();
+        for from in SegmentFormat::ALL {
+            let input = encode_segment(&segment, from);
+            for to in SegmentFormat::ALL {
+                let output = convert_segment(&input, Some(from), to).unwrap();
+                assert_eq!(output, encode_segment(&segment, to), "{} -> {}", from, to);
+                assert_eq!(decode_segment(&output, to).unwrap(), segment);
+            }
+        }
+    }
+
+    #[test]
+    fn test_detect_hex() {
+        let input = encode_segment(&sample_segment(), SegmentFormat::HexText);
+        assert_eq!(detect_format(&input), Ok(SegmentFormat::HexText));
+        assert_eq!(detect_format(b""), Ok(SegmentFormat::HexText));
+    }
+
+    #[test]
+    fn test_detect_raw_ambiguous() {
+        let input = encode_segment(&sample_segment(), SegmentFormat::BigEndian);
+        assert_eq!(
+            detect_format(&input),
+            Err(FormatError::DetectFailed {
+                candidates: vec![SegmentFormat::BigEndian, SegmentFormat::LittleEndian]
+            })
+        );
+        assert!(convert_segment(&input, None, SegmentFormat::HexText).is_err());
+    }
+
+    #[test]
+    fn test_detect_rle() {
+        let input = encode_segment(&sample_segment(), SegmentFormat::Rle);
+        assert_eq!(input.len(), 8 + 7 * 4);
+        assert_eq!(detect_format(&input), Ok(SegmentFormat::Rle));
+        assert_eq!(
+            decode_segment(&input[..input.len() - 4], SegmentFormat::Rle),
+            Err(FormatError::InvalidRle {
+                source: RleError::TooFewWords { actual: 0xFFFF }
+            })
+        );
+    }
+
+    #[test]
+    fn test_detect_nothing() {
+        assert_eq!(
+            detect_format(&[0xFF, 0x00, 0x12]),
+            Err(FormatError::DetectFailed { candidates: vec![] })
+        );
+    }
+}
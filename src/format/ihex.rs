@@ -0,0 +1,279 @@
+use crate::vm::Segment;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_END_OF_FILE: u8 = 0x01;
+
+/// Byte addresses are 16 bits, so Intel HEX without extended address records covers only the first half of a
+/// segment.
+const ADDRESS_SPACE: usize = 1 << 16;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum IhexError {
+    /// The line does not start with ':'.
+    MissingColon {
+        line: usize,
+    },
+    /// Not an even number of hex digits, or the length does not match the byte count of the record.
+    Malformed {
+        line: usize,
+    },
+    BadChecksum {
+        line: usize,
+        expected: u8,
+        actual: u8,
+    },
+    UnsupportedRecordType {
+        line: usize,
+        record_type: u8,
+    },
+    /// The record would extend beyond byte address 0xFFFF.
+    OutOfRange {
+        line: usize,
+        address: usize,
+    },
+    /// The byte at this address was already written by an earlier record.
+    Overlap {
+        line: usize,
+        address: usize,
+    },
+    DataAfterEnd {
+        line: usize,
+    },
+    MissingEnd,
+}
+
+impl Display for IhexError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            IhexError::MissingColon { line } => {
+                write!(f, "Line {}: Record does not start with ':'.", line)
+            }
+            IhexError::Malformed { line } => write!(f, "Line {}: Malformed record.", line),
+            IhexError::BadChecksum {
+                line,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Line {}: Bad checksum, expected 0x{:02X}, got 0x{:02X} instead.",
+                line, expected, actual
+            ),
+            IhexError::UnsupportedRecordType { line, record_type } => write!(
+                f,
+                "Line {}: Unsupported record type 0x{:02X}.",
+                line, record_type
+            ),
+            IhexError::OutOfRange { line, address } => write!(
+                f,
+                "Line {}: Byte address 0x{:X} is out of range.",
+                line, address
+            ),
+            IhexError::Overlap { line, address } => write!(
+                f,
+                "Line {}: Byte address 0x{:04X} was already written.",
+                line, address
+            ),
+            IhexError::DataAfterEnd { line } => {
+                write!(f, "Line {}: Record after the end-of-file record.", line)
+            }
+            IhexError::MissingEnd => f.write_str("Missing end-of-file record."),
+        }
+    }
+}
+
+impl Error for IhexError {}
+
+/// Whether `bytes` look like Intel HEX, i.e. start with ':' and consist only of hex digits, ':', and line breaks.
+/// Raw segments practically never satisfy this, so loaders can recognize Intel HEX without being told.
+pub fn looks_like_ihex(bytes: &[u8]) -> bool {
+    bytes.first() == Some(&b':')
+        && bytes
+            .iter()
+            .all(|&byte| byte.is_ascii_hexdigit() || matches!(byte, b':' | b'\r' | b'\n'))
+}
+
+fn parse_record(line: usize, text: &str) -> Result<Vec<u8>, IhexError> {
+    let digits = text
+        .strip_prefix(':')
+        .ok_or(IhexError::MissingColon { line })?;
+    if digits.len() % 2 != 0 || !digits.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return Err(IhexError::Malformed { line });
+    }
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap())
+        .collect::<Vec<_>>();
+    // Byte count, two address bytes, record type, data, checksum.
+    if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+        return Err(IhexError::Malformed { line });
+    }
+    let (checksum, content) = bytes.split_last().unwrap();
+    let expected = content
+        .iter()
+        .fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+        .wrapping_neg();
+    if expected != *checksum {
+        return Err(IhexError::BadChecksum {
+            line,
+            expected,
+            actual: *checksum,
+        });
+    }
+    Ok(content.to_vec())
+}
+
+/// Decodes Intel HEX with data (00) and end-of-file (01) records. Byte addresses count from the start of the
+/// segment, and each word is stored most significant byte first, just like in `SegmentFormat::BigEndian`. Bytes that
+/// no record mentions are zero.
+pub fn decode_ihex(text: &str) -> Result<Segment, IhexError> {
+    let mut segment = Segment::new_zeroed();
+    let mut written = vec![false; ADDRESS_SPACE];
+    let mut seen_end = false;
+
+    for (line_index, record) in text.lines().enumerate() {
+        let line = line_index + 1;
+        if record.is_empty() {
+            continue;
+        }
+        if seen_end {
+            return Err(IhexError::DataAfterEnd { line });
+        }
+        let content = parse_record(line, record)?;
+        let base = u16::from_be_bytes([content[1], content[2]]) as usize;
+        let data = &content[4..];
+        match content[3] {
+            RECORD_DATA => {
+                if base + data.len() > ADDRESS_SPACE {
+                    return Err(IhexError::OutOfRange {
+                        line,
+                        address: base + data.len() - 1,
+                    });
+                }
+                for (address, &byte) in (base..).zip(data) {
+                    if written[address] {
+                        return Err(IhexError::Overlap { line, address });
+                    }
+                    written[address] = true;
+                    let word = &mut segment[(address / 2) as u16];
+                    *word = if address % 2 == 0 {
+                        (*word & 0x00FF) | ((byte as u16) << 8)
+                    } else {
+                        (*word & 0xFF00) | byte as u16
+                    };
+                }
+            }
+            RECORD_END_OF_FILE => seen_end = true,
+            record_type => return Err(IhexError::UnsupportedRecordType { line, record_type }),
+        }
+    }
+
+    if !seen_end {
+        return Err(IhexError::MissingEnd);
+    }
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod test_ihex {
+    use super::*;
+
+    #[test]
+    fn test_valid() {
+        let text = ":04000000102A00FFC3\n\
+                    :03001200ABCD1261\n\
+                    :00000001FF\n";
+        assert!(looks_like_ihex(text.as_bytes()));
+        let segment = decode_ihex(text).unwrap();
+        assert_eq!(segment[0x0000], 0x102A);
+        assert_eq!(segment[0x0001], 0x00FF);
+        assert_eq!(segment[0x0009], 0xABCD);
+        // A record with an odd number of bytes only sets the high byte of its last word.
+        assert_eq!(segment[0x000A], 0x1200);
+        assert_eq!(segment.used_len(), 0x000B);
+        assert_eq!(Segment::from_ihex(&text.replace('\n', "\r\n")), Ok(segment));
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(decode_ihex(":00000001FF"), Ok(Segment::new_zeroed()));
+        assert_eq!(decode_ihex(""), Err(IhexError::MissingEnd));
+    }
+
+    #[test]
+    fn test_bad_checksum() {
+        let err = decode_ihex(":04000000102A00FFC2\n:00000001FF\n").unwrap_err();
+        assert_eq!(
+            err,
+            IhexError::BadChecksum {
+                line: 1,
+                expected: 0xC3,
+                actual: 0xC2
+            }
+        );
+        assert_eq!(
+            err.to_string(),
+            "Line 1: Bad checksum, expected 0xC3, got 0xC2 instead."
+        );
+    }
+
+    #[test]
+    fn test_overlapping_records() {
+        let text = ":04000000102A00FFC3\n\
+                    :02000300AAAAA7\n\
+                    :00000001FF\n";
+        assert_eq!(
+            decode_ihex(text),
+            Err(IhexError::Overlap {
+                line: 2,
+                address: 0x0003
+            })
+        );
+    }
+
+    #[test]
+    fn test_out_of_range() {
+        assert_eq!(
+            decode_ihex(":02FFFF00AAAAAC\n:00000001FF\n"),
+            Err(IhexError::OutOfRange {
+                line: 1,
+                address: 0x10000
+            })
+        );
+        // The very last byte is fine.
+        let segment = decode_ihex(":01FFFF00AB56\n:00000001FF\n").unwrap();
+        assert_eq!(segment[0x7FFF], 0x00AB);
+    }
+
+    #[test]
+    fn test_reject() {
+        for (text, err) in [
+            ("0400000010", IhexError::MissingColon { line: 1 }),
+            (":0400000010", IhexError::Malformed { line: 1 }),
+            (":0400000010G", IhexError::Malformed { line: 1 }),
+            (":0100000010", IhexError::Malformed { line: 1 }),
+            (
+                ":00000002FE",
+                IhexError::UnsupportedRecordType {
+                    line: 1,
+                    record_type: 0x02,
+                },
+            ),
+            (
+                ":00000001FF\n:00000001FF",
+                IhexError::DataAfterEnd { line: 2 },
+            ),
+        ] {
+            assert_eq!(decode_ihex(text), Err(err), "{:?}", text);
+        }
+    }
+
+    #[test]
+    fn test_looks_like_ihex() {
+        assert!(!looks_like_ihex(b""));
+        assert!(!looks_like_ihex(b"102A\n"));
+        assert!(!looks_like_ihex(&[0x3A, 0x05, 0x10, 0x2A]));
+        assert!(looks_like_ihex(b":00000001FF\r\n"));
+    }
+}
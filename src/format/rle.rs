@@ -0,0 +1,196 @@
+use crate::format::SEGMENT_WORDS;
+use crate::vm::Segment;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The first bytes of every run-length encoded segment, followed by the version as a big-endian `u16`.
+pub const RLE_MAGIC: [u8; 6] = *b"TVMRLE";
+pub const RLE_VERSION: u16 = 1;
+
+const HEADER_BYTES: usize = RLE_MAGIC.len() + 2;
+const RUN_BYTES: usize = 4;
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum RleError {
+    WrongMagic,
+    UnsupportedVersion {
+        version: u16,
+    },
+    /// The runs do not have a whole number of bytes.
+    Truncated {
+        actual: usize,
+    },
+    /// The run with this index, counting from 0, extends beyond the end of the segment.
+    TooManyWords {
+        run: usize,
+    },
+    /// The runs end before the end of the segment, after this many words.
+    TooFewWords {
+        actual: usize,
+    },
+}
+
+impl Display for RleError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            RleError::WrongMagic => f.write_str("Not a run-length encoded segment."),
+            RleError::UnsupportedVersion { version } => {
+                write!(f, "Unsupported run-length encoding version {}.", version)
+            }
+            RleError::Truncated { actual } => write!(
+                f,
+                "Truncated run-length encoding, got {} bytes, which is not a whole number of runs.",
+                actual
+            ),
+            RleError::TooManyWords { run } => write!(
+                f,
+                "Run {} extends beyond the {} words of a segment.",
+                run, SEGMENT_WORDS
+            ),
+            RleError::TooFewWords { actual } => write!(
+                f,
+                "The runs cover only {} of the {} words of a segment.",
+                actual, SEGMENT_WORDS
+            ),
+        }
+    }
+}
+
+impl Error for RleError {}
+
+/// Whether `bytes` start with `RLE_MAGIC`. Raw segments practically never do, so loaders can recognize the format
+/// without being told.
+pub fn looks_like_rle(bytes: &[u8]) -> bool {
+    bytes.starts_with(&RLE_MAGIC)
+}
+
+/// Encodes `segment` as `RLE_MAGIC`, `RLE_VERSION`, and then runs of equal words, each as the length minus one and
+/// the word, both big-endian. The all-zero segment takes 12 bytes, a segment without any runs about twice as much
+/// as `SegmentFormat::BigEndian`.
+#[must_use]
+pub fn encode_rle(segment: &Segment) -> Vec<u8> {
+    let mut bytes = RLE_MAGIC.to_vec();
+    bytes.extend_from_slice(&RLE_VERSION.to_be_bytes());
+    let mut start = 0;
+    while start < SEGMENT_WORDS {
+        let word = segment[start as u16];
+        let len = (start..SEGMENT_WORDS)
+            .take_while(|&i| segment[i as u16] == word)
+            .count();
+        bytes.extend_from_slice(&((len - 1) as u16).to_be_bytes());
+        bytes.extend_from_slice(&word.to_be_bytes());
+        start += len;
+    }
+    bytes
+}
+
+/// Inverse of `encode_rle`. The runs must cover the segment exactly.
+pub fn decode_rle(bytes: &[u8]) -> Result<Segment, RleError> {
+    if !looks_like_rle(bytes) || bytes.len() < HEADER_BYTES {
+        return Err(RleError::WrongMagic);
+    }
+    let version = u16::from_be_bytes([bytes[6], bytes[7]]);
+    if version != RLE_VERSION {
+        return Err(RleError::UnsupportedVersion { version });
+    }
+    let runs = &bytes[HEADER_BYTES..];
+    if !runs.len().is_multiple_of(RUN_BYTES) {
+        return Err(RleError::Truncated {
+            actual: bytes.len(),
+        });
+    }
+
+    let mut segment = Segment::new_zeroed();
+    let mut start = 0;
+    for (run, pair) in runs.chunks_exact(RUN_BYTES).enumerate() {
+        let len = u16::from_be_bytes([pair[0], pair[1]]) as usize + 1;
+        let word = u16::from_be_bytes([pair[2], pair[3]]);
+        if start + len > SEGMENT_WORDS {
+            return Err(RleError::TooManyWords { run });
+        }
+        for i in start..start + len {
+            segment[i as u16] = word;
+        }
+        start += len;
+    }
+    if start != SEGMENT_WORDS {
+        return Err(RleError::TooFewWords { actual: start });
+    }
+    Ok(segment)
+}
+
+#[cfg(test)]
+mod test_rle {
+    use super::*;
+
+    #[test]
+    fn test_zero_segment() {
+        let bytes = encode_rle(&Segment::new_zeroed());
+        assert_eq!(bytes, b"TVMRLE\x00\x01\xFF\xFF\x00\x00");
+        assert!(looks_like_rle(&bytes));
+        assert_eq!(decode_rle(&bytes), Ok(Segment::new_zeroed()));
+    }
+
+    #[test]
+    fn test_program() {
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x102A;
+        segment[1] = 0x102A;
+        segment[0xFFFF] = 0x1234;
+        let bytes = encode_rle(&segment);
+        assert_eq!(
+            &bytes[HEADER_BYTES..],
+            &[0x00, 0x01, 0x10, 0x2A, 0xFF, 0xFC, 0x00, 0x00, 0x00, 0x00, 0x12, 0x34]
+        );
+        assert_eq!(decode_rle(&bytes), Ok(segment));
+    }
+
+    #[test]
+    fn test_dense_segment() {
+        // A xorshift generator, so that neighbouring words practically never repeat.
+        let mut state = 0x1234_5678_u32;
+        let mut segment = Segment::new_zeroed();
+        for i in 0..=0xFFFF {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            segment[i] = state as u16;
+        }
+        let bytes = encode_rle(&segment);
+        assert!(bytes.len() > 2 * SEGMENT_WORDS, "{}", bytes.len());
+        assert_eq!(decode_rle(&bytes), Ok(segment));
+    }
+
+    #[test]
+    fn test_reject() {
+        let valid = encode_rle(&Segment::new_zeroed());
+        for (bytes, err) in [
+            (&b""[..], RleError::WrongMagic),
+            (&b"TVMRLE\x00"[..], RleError::WrongMagic),
+            (&b"TVMRLF\x00\x01\xFF\xFF\x00\x00"[..], RleError::WrongMagic),
+            (
+                &b"TVMRLE\x00\x02\xFF\xFF\x00\x00"[..],
+                RleError::UnsupportedVersion { version: 2 },
+            ),
+            (
+                &valid[..valid.len() - 1],
+                RleError::Truncated { actual: 11 },
+            ),
+            (&valid[..HEADER_BYTES], RleError::TooFewWords { actual: 0 }),
+            (
+                &b"TVMRLE\x00\x01\xFF\xFE\x00\x00"[..],
+                RleError::TooFewWords { actual: 0xFFFF },
+            ),
+            (
+                &b"TVMRLE\x00\x01\xFF\xFF\x00\x00\x00\x00\x00\x00"[..],
+                RleError::TooManyWords { run: 1 },
+            ),
+        ] {
+            assert_eq!(decode_rle(bytes), Err(err), "{:?}", bytes);
+        }
+        assert_eq!(
+            RleError::TooFewWords { actual: 5 }.to_string(),
+            "The runs cover only 5 of the 65536 words of a segment."
+        );
+    }
+}
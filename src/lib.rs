@@ -1,7 +1,40 @@
+pub mod asm;
+mod calibration;
 mod connect4;
-mod vm;
+pub mod disasm;
+mod error;
+mod format;
+pub mod prelude;
+pub mod selftest;
+pub mod vm;
+mod watch;
 
+pub use calibration::{
+    budget_for_time_limit, measure_steps_per_ms, steps_per_ms, CALIBRATION_STEPS,
+};
+pub use connect4::layout::{Layout, MoveInfo};
 pub use connect4::{
-    AlgorithmResult, Board, Game, GameResult, GameState, Player, SlotState, WinReason,
+    describe_debug_dump, AlgorithmResult, Board, BoardError, Game, GameCheckpoint, GameResult,
+    GameState, Player, PlayerData, SlotState, WinReason,
+};
+pub use error::Error;
+pub use format::{
+    convert_segment, decode_ihex, decode_rle, decode_segment, decode_segment_padded, detect_format,
+    encode_rle, encode_segment, looks_like_ihex, looks_like_rle, FormatError, IhexError, RleError,
+    SegmentFormat, RLE_MAGIC, RLE_VERSION,
+};
+pub use vm::load::{load_segment, parse_segment_bytes, LoadOptions, SegmentLoadError};
+pub use vm::{
+    decode_branch, decode_jump_imm, encode_branch, encode_jump_imm, read_mem_trace, run_program,
+    run_vm, run_vm_with_mem_trace, run_vm_with_syscalls, BinaryFunction, BuildError,
+    ConsolePeripheral, CostModel, CountingSource, CoverageReport, CpuFeatures, DebugDumpHandler,
+    DebugDumps, Extension, FaultInfo, InsnClass, InsnStats, Instruction, MemAccess, MemAccessKind,
+    MemTraceError, MemTraceWriter, MmioHandler, OffsetError, OpcodeStats, OsRandomSource,
+    ProgramBuilder, ProgramOutcome, Protection, RandomSource, RegisterWatchHit, RunOutcome,
+    Segment, SegmentError, SegmentKind, SharedRandomSource, StepResult, StopReason, SyscallHandler,
+    TextOutput, TraceEvent, Tracer, UnaryFunction, VirtualMachine, VirtualMachineBuilder, WatchHit,
+    WriteRecord, BRANCH_MAX, BRANCH_MIN, DEFAULT_MMIO_RANGE, JUMP_IMM_MAX, JUMP_IMM_MIN,
+    SYSCALL_ELAPSED_MS, SYSCALL_END_OF_INPUT, SYSCALL_OK, SYSCALL_READ_WORD, SYSCALL_UNKNOWN,
+    SYSCALL_WRITE_BYTE,
 };
-pub use vm::{Segment, StepResult, VirtualMachine};
+pub use watch::{file_mtime, Watcher};
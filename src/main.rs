@@ -1,84 +1,408 @@
-use std::io::{Error, ErrorKind, Result};
-use std::{env, fs, process};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+use std::{env, process, thread};
 
-use tinyvm::{Game, GameResult, Player, Segment, SlotState, WinReason};
+use tinyvm::{
+    budget_for_time_limit, describe_debug_dump, encode_segment, file_mtime, load_segment,
+    measure_steps_per_ms, run_program, run_vm_with_mem_trace, run_vm_with_syscalls, selftest,
+    ConsolePeripheral, DebugDumpHandler, Error, Game, GameResult, Layout, LoadOptions,
+    MemTraceWriter, Player, ProgramOutcome, Segment, SegmentFormat, SlotState, VirtualMachine,
+    Watcher, WinReason,
+};
 
-fn parse_segment(segment_bytes: &[u8], segment_type: &str) -> Result<Segment> {
-    if segment_bytes.len() != (1 << 17) {
-        return Err(Error::new(
-            ErrorKind::InvalidData,
-            format!(
-                "Wrong {} segment length, expected 131072, got {} instead.",
-                segment_type,
-                segment_bytes.len()
-            ),
-        ));
+type Result<T> = std::result::Result<T, Error>;
+
+fn load(path: &str, options: LoadOptions) -> Result<Segment> {
+    Ok(load_segment(Path::new(path), options)?)
+}
+
+fn print_usage_and_exit(program_name: &str) -> ! {
+    eprintln!(
+        "USAGE: {} [--max-steps N | --time-limit-ms N] [--time-limit-seconds N] [--watch [--watch-interval-ms N]] [--insn-mix] [--forbid-random] [--debug-dump] [--state-hash] [--detect-loops] [--allow-short-segments] /path/to/instruction_segment_player_one /path/to/instruction_segment_player_two",
+        program_name
+    );
+    eprintln!(
+        "       {} run [--max-steps N] [--mem-trace /path/to/trace | --console] [--allow-short-segments] /path/to/instruction_segment [/path/to/data_segment]",
+        program_name
+    );
+    eprintln!("       {} selftest", program_name);
+    eprintln!(
+        "       {} convert [--from be|le|hex|rle] [--allow-short-segments] --to be|le|hex|rle /path/to/input /path/to/output",
+        program_name
+    );
+    process::exit(1);
+}
+
+fn parse_format(program_name: &str, name: Option<&String>) -> SegmentFormat {
+    match name.and_then(|name| SegmentFormat::from_name(name)) {
+        Some(format) => format,
+        None => {
+            eprintln!("Unknown or missing segment format, expected be, le, hex, or rle.");
+            print_usage_and_exit(program_name);
+        }
+    }
+}
+
+fn run_convert(program_name: &str, args: &[String]) -> Result<()> {
+    let mut from = None;
+    let mut allow_short = false;
+    let mut to = None;
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--from" => from = Some(parse_format(program_name, args.next())),
+            "--to" => to = Some(parse_format(program_name, args.next())),
+            "--allow-short-segments" => allow_short = true,
+            _ => paths.push(arg),
+        }
     }
+    let (to, input_path, output_path) = match (to, paths.as_slice()) {
+        (Some(to), [input_path, output_path]) => (to, input_path, output_path),
+        _ => print_usage_and_exit(program_name),
+    };
+
+    let options = LoadOptions {
+        format: from,
+        allow_short,
+    };
+    let segment = load(input_path, options)?;
+    fs::write(output_path, encode_segment(&segment, to))?;
+    Ok(())
+}
 
-    let mut segment = Segment::new_zeroed();
+const DEFAULT_MAX_STEPS: u64 = 10_000_000;
 
-    for i in 0..(1 << 16) {
-        let byte_index = i * 2;
-        let high_byte = (segment_bytes[byte_index] as u16) << 8;
-        let low_byte = segment_bytes[byte_index + 1] as u16;
-        segment[i as u16] = high_byte | low_byte;
+fn parse_number(program_name: &str, value: Option<&String>) -> u64 {
+    match value.and_then(|value| value.parse().ok()) {
+        Some(number) => number,
+        None => {
+            eprintln!("Missing or invalid number.");
+            print_usage_and_exit(program_name);
+        }
     }
+}
 
-    Ok(segment)
+fn run_bare(program_name: &str, args: &[String]) -> Result<()> {
+    let mut max_steps = DEFAULT_MAX_STEPS;
+    let mut mem_trace_path = None;
+    let mut console = false;
+    let mut options = LoadOptions::default();
+    let mut paths = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--max-steps" => max_steps = parse_number(program_name, args.next()),
+            "--mem-trace" => match args.next() {
+                Some(path) => mem_trace_path = Some(path),
+                None => print_usage_and_exit(program_name),
+            },
+            "--console" => console = true,
+            "--allow-short-segments" => options.allow_short = true,
+            _ => paths.push(arg),
+        }
+    }
+    let (instructions_path, data_path) = match paths.as_slice() {
+        [instructions_path] => (instructions_path, None),
+        [instructions_path, data_path] => (instructions_path, Some(data_path)),
+        _ => print_usage_and_exit(program_name),
+    };
+
+    let instructions = load(instructions_path, options)?;
+    let data = match data_path {
+        Some(data_path) => load(data_path, options)?,
+        None => Segment::new_zeroed(),
+    };
+    if console && mem_trace_path.is_some() {
+        print_usage_and_exit(program_name);
+    }
+    let outcome = match mem_trace_path {
+        None if console => {
+            let mut console = ConsolePeripheral::new();
+            let mut vm = VirtualMachine::new(instructions, data);
+            let outcome = run_vm_with_syscalls(&mut vm, max_steps, &mut console);
+            print!("{}", console.take_text());
+            outcome
+        }
+        Some(path) => {
+            let mut trace = MemTraceWriter::new(File::create(path)?)?;
+            let mut vm = VirtualMachine::new(instructions, data);
+            let outcome = run_vm_with_mem_trace(&mut vm, max_steps, &mut trace);
+            trace.finish()?;
+            outcome
+        }
+        None => run_program(instructions, data, max_steps),
+    };
+    match outcome {
+        ProgramOutcome::Returned { value, steps } => {
+            println!("Returned 0x{:04X} after {} steps.", value, steps)
+        }
+        ProgramOutcome::Faulted { insn, pc, steps } => println!(
+            "Illegal instruction 0x{:04X} at 0x{:04X} after {} steps.",
+            insn, pc, steps
+        ),
+        ProgramOutcome::OutOfBudget { steps } => {
+            println!("Did not return within {} steps.", steps)
+        }
+        ProgramOutcome::RandomnessUnavailable { pc, steps } => println!(
+            "No randomness available for rnd at 0x{:04X} after {} steps.",
+            pc, steps
+        ),
+        ProgramOutcome::Breakpoint { pc, steps } => {
+            println!("Stopped at breakpoint 0x{:04X} after {} steps.", pc, steps)
+        }
+        ProgramOutcome::LoopDetected { period, steps } => println!(
+            "Stuck in a loop of {} instructions after {} steps.",
+            period, steps
+        ),
+        ProgramOutcome::ProtectionFault { addr, pc, steps } => println!(
+            "Store to read-only address 0x{:04X} at 0x{:04X} after {} steps.",
+            addr, pc, steps
+        ),
+    }
+    Ok(())
 }
 
-fn parse_args() -> Result<(Segment, Segment)> {
-    let args = env::args().collect::<Vec<_>>();
-    if args.len() != 3 {
-        eprintln!(
-            "USAGE: {} /path/to/instruction_segment_player_one /path/to/instruction_segment_player_two",
-            args[0]
-        );
+const DEFAULT_WATCH_INTERVAL_MS: u64 = 500;
+
+struct Connect4Args {
+    path_one: String,
+    path_two: String,
+    max_steps: u64,
+    watch_interval_ms: Option<u64>,
+    wall_clock_limit: Option<Duration>,
+    insn_mix: bool,
+    forbid_random: bool,
+    debug_dump: bool,
+    state_hash: bool,
+    detect_loops: bool,
+    allow_short_segments: bool,
+}
+
+fn run_selftest_and_exit() -> ! {
+    let mut all_passed = true;
+    for (name, result) in selftest::run_selftest() {
+        match result {
+            Ok(()) => println!("PASS {}", name),
+            Err(reason) => {
+                println!("FAIL {}: {}", name, reason);
+                all_passed = false;
+            }
+        }
+    }
+    if all_passed {
+        println!("All self-tests passed.");
+        process::exit(0);
+    } else {
+        println!("Some self-tests FAILED.");
         process::exit(1);
     }
+}
+
+fn parse_args(args: &[String]) -> Connect4Args {
+    let program_name = &args[0];
+    let mut max_steps = None;
+    let mut time_limit_ms = None;
+    let mut wall_clock_limit = None;
+    let mut watch = false;
+    let mut watch_interval_ms = DEFAULT_WATCH_INTERVAL_MS;
+    let mut insn_mix = false;
+    let mut forbid_random = false;
+    let mut debug_dump = false;
+    let mut state_hash = false;
+    let mut detect_loops = false;
+    let mut allow_short_segments = false;
+    let mut paths = Vec::new();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match arg.as_str() {
+            "--max-steps" => max_steps = Some(parse_number(program_name, rest.next())),
+            "--time-limit-ms" => time_limit_ms = Some(parse_number(program_name, rest.next())),
+            "--time-limit-seconds" => {
+                wall_clock_limit =
+                    Some(Duration::from_secs(parse_number(program_name, rest.next())))
+            }
+            "--watch" => watch = true,
+            "--watch-interval-ms" => watch_interval_ms = parse_number(program_name, rest.next()),
+            "--insn-mix" => insn_mix = true,
+            "--forbid-random" => forbid_random = true,
+            "--debug-dump" => debug_dump = true,
+            "--state-hash" => state_hash = true,
+            "--detect-loops" => detect_loops = true,
+            "--allow-short-segments" => allow_short_segments = true,
+            _ => paths.push(arg),
+        }
+    }
+    let (path_one, path_two) = match (paths.as_slice(), max_steps, time_limit_ms) {
+        ([path_one, path_two], _, None) | ([path_one, path_two], None, _) => (path_one, path_two),
+        _ => print_usage_and_exit(program_name),
+    };
+
+    let max_steps = match time_limit_ms {
+        Some(time_limit_ms) => {
+            let start = Instant::now();
+            let steps_per_ms = measure_steps_per_ms(|| start.elapsed());
+            let max_steps = budget_for_time_limit(time_limit_ms, steps_per_ms);
+            println!(
+                "Calibrated {} steps/ms, so a time limit of {} ms per move is a budget of {} steps (use --max-steps {} to reproduce).",
+                steps_per_ms, time_limit_ms, max_steps, max_steps
+            );
+            max_steps
+        }
+        None => max_steps.unwrap_or(DEFAULT_MAX_STEPS),
+    };
+
+    Connect4Args {
+        path_one: (*path_one).clone(),
+        path_two: (*path_two).clone(),
+        max_steps,
+        watch_interval_ms: watch.then_some(watch_interval_ms),
+        wall_clock_limit,
+        insn_mix,
+        forbid_random,
+        debug_dump,
+        state_hash,
+        detect_loops,
+        allow_short_segments,
+    }
+}
 
-    let instructions_one_bytes = fs::read(args[1].clone())?;
-    let instructions_two_bytes = fs::read(args[2].clone())?;
+fn watch_and_rerun(paths: &[&str], interval_ms: u64, mut run: impl FnMut() -> Result<()>) -> ! {
+    let mut watcher = Watcher::new(paths.iter().map(PathBuf::from).collect(), |path: &Path| {
+        file_mtime(path)
+    });
+    if let Err(err) = run() {
+        eprintln!("Error: {}", err);
+    }
+    loop {
+        thread::sleep(Duration::from_millis(interval_ms));
+        if watcher.poll() {
+            let unix_time = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            println!();
+            println!(
+                "===== Input changed, rerunning (unix time {}) =====",
+                unix_time
+            );
+            if let Err(err) = run() {
+                eprintln!("Error: {}", err);
+            }
+        }
+    }
+}
 
-    Ok((
-        parse_segment(&instructions_one_bytes, "player one instruction")?,
-        parse_segment(&instructions_two_bytes, "player two instruction")?,
-    ))
+fn main() {
+    if let Err(err) = run_main() {
+        eprintln!("Error: {}", err);
+        process::exit(err.exit_code());
+    }
 }
 
-fn main() -> Result<()> {
-    let (instructions_one, instructions_two) = parse_args()?;
-    println!("Player one: {:?}", &instructions_one);
-    println!("Player two: {:?}", &instructions_two);
-    let mut game = Game::new(instructions_one, instructions_two, 10_000_000);
+fn run_main() -> Result<()> {
+    let args = env::args().collect::<Vec<_>>();
+    match args.get(1).map(String::as_str) {
+        Some("convert") => return run_convert(&args[0], &args[2..]),
+        Some("run") => return run_bare(&args[0], &args[2..]),
+        Some("selftest") => run_selftest_and_exit(),
+        _ => {}
+    }
+
+    let connect4_args = parse_args(&args);
+    match connect4_args.watch_interval_ms {
+        Some(interval_ms) => watch_and_rerun(
+            &[&connect4_args.path_one, &connect4_args.path_two],
+            interval_ms,
+            || run_connect4(&connect4_args),
+        ),
+        None => run_connect4(&connect4_args),
+    }
+}
+
+#[cfg(feature = "sha256")]
+fn print_sha256(instructions: &Segment) {
+    let digest = instructions
+        .sha256()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    println!("SHA-256: {}", digest);
+}
+
+#[cfg(not(feature = "sha256"))]
+fn print_sha256(_instructions: &Segment) {}
+
+fn run_connect4(args: &Connect4Args) -> Result<()> {
+    let options = LoadOptions {
+        allow_short: args.allow_short_segments,
+        ..LoadOptions::default()
+    };
+    let instructions_one = load(&args.path_one, options)?;
+    let instructions_two = load(&args.path_two, options)?;
+    println!(
+        "Player one, fingerprint {:016X}:\n{}",
+        instructions_one.fingerprint(),
+        &instructions_one
+    );
+    print_sha256(&instructions_one);
+    println!(
+        "Player two, fingerprint {:016X}:\n{}",
+        instructions_two.fingerprint(),
+        &instructions_two
+    );
+    print_sha256(&instructions_two);
+    let mut game = Game::new(instructions_one, instructions_two, args.max_steps);
+    game.set_collect_insn_mix(args.insn_mix);
+    game.set_forbid_random(args.forbid_random);
+    game.set_wall_clock_limit(args.wall_clock_limit);
+    game.set_detect_loops(args.detect_loops);
+    if args.debug_dump {
+        for player in [Player::One, Player::Two] {
+            let handler = DebugDumpHandler::new(move |vm| {
+                print!("{}", describe_debug_dump(player, Layout::default(), vm));
+            });
+            game.set_debug_dump_handler(player, Some(handler));
+        }
+    }
 
     let result = game.conclude();
 
     let result_text = match result {
         GameResult::Draw => "The game was drawn".into(),
-        GameResult::Won(player, reason) => {
-            let player_name = match player {
-                Player::One => "1",
-                Player::Two => "2",
-            };
-            let reason_text = match reason {
-                WinReason::Connect4 => "by connect4".into(),
-                WinReason::Timeout => "by timeout of the opponent".into(),
-                WinReason::IllegalInstruction(insn) => {
-                    format!("by illegal instruction (0x{:04X}) of the opponent", insn)
-                }
-                WinReason::IllegalColumn(col) => format!(
-                    "by opponent's attempt to move at non-existent column {}",
-                    col
-                ),
-                WinReason::FullColumn(col) => {
-                    format!("by opponent's attempt to move at full column {}", col)
-                }
-            };
-            format!("Player {} won {}", player_name, reason_text)
-        }
+        GameResult::Won(..) => result.to_string(),
     };
     println!("{} after {} moves.", result_text, game.get_total_moves());
+    if let GameResult::Won(
+        winner,
+        WinReason::IllegalInstruction { .. } | WinReason::ProtectionFault { .. },
+    ) = result
+    {
+        let (loser, loser_name) = match winner.other() {
+            Player::One => (Player::One, "1"),
+            Player::Two => (Player::Two, "2"),
+        };
+        if let Some(vm) = game.get_player_data(loser).get_vm() {
+            let pcs = vm
+                .recent_pcs()
+                .iter()
+                .map(|pc| format!("{:04X}", pc))
+                .collect::<Vec<_>>();
+            println!(
+                "Last program counters of player {}, oldest first: {}",
+                loser_name,
+                pcs.join(" ")
+            );
+        }
+    }
+    let random_calls =
+        [Player::One, Player::Two].map(|player| game.get_player_data(player).get_random_calls());
+    if random_calls != [0, 0] {
+        println!(
+            "Player 1 executed rnd {} times, player 2 {} times.",
+            random_calls[0], random_calls[1]
+        );
+    }
     println!("End result (1=x, 2=O):");
     let board = game.get_board();
     for y in (0..board.get_height()).rev() {
@@ -99,5 +423,23 @@ fn main() -> Result<()> {
     }
     println!("-+");
 
+    for (player, name) in [(Player::One, "one"), (Player::Two, "two")] {
+        if let Some(insn_mix) = game.get_insn_mix(player) {
+            println!(
+                "Instruction mix of player {} ({} instructions):",
+                name,
+                insn_mix.total()
+            );
+            print!("{}", insn_mix);
+        }
+    }
+    if args.state_hash {
+        for (player, name) in [(Player::One, "one"), (Player::Two, "two")] {
+            let hash = game.get_player_data(player).state_hash();
+            println!("State hash of player {}: {:016X}", name, hash);
+        }
+        println!("State hash of the game: {:016X}", game.state_hash());
+    }
+
     Ok(())
 }
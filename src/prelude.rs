@@ -0,0 +1,4 @@
+//! The types that nearly every embedder needs: `use tinyvm::prelude::*;`
+
+pub use crate::connect4::{AlgorithmResult, Board, Game, GameResult, GameState, Player, WinReason};
+pub use crate::vm::{Segment, StepResult, VirtualMachine};
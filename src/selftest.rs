@@ -0,0 +1,237 @@
+use crate::vm::{Segment, StepResult, VirtualMachine};
+
+/// A property of the final VM state that a self-test program must satisfy.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Check {
+    Data(u16, u16),
+    NumSteps(u64),
+    ProgramCounter(u16),
+    Register(u16, u16),
+    Returned(u16),
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub name: &'static str,
+    pub instructions: &'static [u16],
+    pub max_steps: u64,
+    pub checks: &'static [Check],
+}
+
+#[rustfmt::skip] // Would break the labels. See https://github.com/rust-lang/rustfmt/issues/5630
+pub const FIBONACCI: Program = Program {
+    name: "fibonacci",
+    instructions: &[
+        0x3018, // lw r0, 24
+        0x3101, // lw r1, 1
+                // .label start:
+        0x6012, // add r1 r2
+        0x5800, // decr r0
+        0x2002, // sw r0, r2
+        0x6021, // add r2 r1
+        0x5800, // decr r0
+        0x2001, // sw r0, r1
+        0x9085, // b r0 start // (offset is -0x6)
+        0x102A, // ret
+    ],
+    max_steps: 0xFFFF,
+    checks: &[
+        Check::NumSteps(2 + (24 / 2) * 7),
+        Check::ProgramCounter(9),
+        Check::Data(23, 1),
+        Check::Data(22, 2),
+        Check::Data(12, 233),
+        Check::Data(1, 46368),
+        Check::Data(0, 9489), // 75025 & 0xFFFF
+        Check::Register(1, 9489),
+        Check::Register(2, 46368),
+        Check::Returned(0),
+    ],
+};
+
+pub const COMPARE: Program = Program {
+    name: "compare",
+    instructions: &[
+        0x3305, // lw r3, 0x0005
+        0x3407, // lw r4, 0x0007
+        0x8A34, // ne r4, r3
+        0x3505, // lw r5, 0x0005
+        0x8435, // eq r5, r3
+        0x36FF, // lw r6, 0xFFFF
+        0x8963, // lt.s r3, r6
+        0x102A, // ret
+    ],
+    max_steps: 8,
+    checks: &[
+        Check::NumSteps(7),
+        Check::Register(4, 1),
+        Check::Register(5, 1),
+        Check::Register(3, 1),
+        Check::Returned(0),
+    ],
+};
+
+pub const UNARY: Program = Program {
+    name: "unary",
+    instructions: &[
+        0x3534, 0x4512, // lw r5, 0x1234
+        0x5A56, // not r6, r5
+        0x5B57, // popcnt r7, r5
+        0x5C58, // clz r8, r5
+        0x5D59, // ctz r9, r5
+        0x5850, // decr r0, r5
+        0x102A, // ret
+    ],
+    max_steps: 8,
+    checks: &[
+        Check::NumSteps(7),
+        Check::Register(6, 0xEDCB),
+        Check::Register(7, 5),
+        Check::Register(8, 3),
+        Check::Register(9, 2),
+        Check::Returned(0x1233),
+    ],
+};
+
+pub const BINARY: Program = Program {
+    name: "binary",
+    instructions: &[
+        0x3505, // lw r5, 5
+        0x3607, // lw r6, 7
+        0x6256, // mul r5 r6
+        0x3107, // lw r1, 7
+        0x6461, // div.u r6 r1
+        0x3710, // lw r7, 0x10
+        0x6B57, // sl r5 r7
+        0x102A, // ret
+    ],
+    max_steps: 8,
+    checks: &[
+        Check::NumSteps(7),
+        Check::Register(6, 35),
+        Check::Register(1, 5),
+        Check::Register(7, 0),
+        Check::Returned(0),
+    ],
+};
+
+pub const RETURN_VALUE: Program = Program {
+    name: "return",
+    instructions: &[
+        0x3042, // lw r0, 0x0042
+        0x102A, // ret
+    ],
+    max_steps: 2,
+    checks: &[
+        Check::NumSteps(1),
+        Check::ProgramCounter(1),
+        Check::Returned(0x0042),
+    ],
+};
+
+pub const RND_BOUNDS: Program = Program {
+    name: "rnd-bounds",
+    instructions: &[
+        0x3105, // lw r1, 5
+        0x5E12, // rnd r2, r1
+        0x8C21, // le r2 r1
+        0x8620, // ge r2 r0
+        0x5E43, // rnd r3, r4
+        0x102A, // ret
+    ],
+    max_steps: 6,
+    checks: &[
+        Check::NumSteps(5),
+        Check::Register(1, 1),
+        Check::Register(3, 0),
+        Check::Returned(1),
+    ],
+};
+
+pub const PROGRAMS: &[Program] = &[FIBONACCI, COMPARE, UNARY, BINARY, RETURN_VALUE, RND_BOUNDS];
+
+/// Runs a single program, and returns a description of the first failed check, if any.
+pub fn run_program(program: &Program) -> Result<(), String> {
+    let mut instructions = Segment::new_zeroed();
+    for (i, &word) in program.instructions.iter().enumerate() {
+        instructions[i as u16] = word;
+    }
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+
+    let mut last_step_result = StepResult::Continue;
+    for _ in 0..program.max_steps {
+        last_step_result = vm.step();
+        match last_step_result {
+            StepResult::Continue | StepResult::DebugDump => {}
+            StepResult::IllegalInstruction(_)
+            | StepResult::Return(_)
+            | StepResult::RandomnessUnavailable
+            | StepResult::Breakpoint(_)
+            | StepResult::ProtectionFault { .. } => {
+                break;
+            }
+        }
+    }
+
+    for check in program.checks {
+        let (expected, actual) = match *check {
+            Check::Data(address, expected) => (expected as u64, vm.get_data()[address] as u64),
+            Check::NumSteps(expected) => (expected, vm.get_time()),
+            Check::ProgramCounter(expected) => (expected as u64, vm.get_program_counter() as u64),
+            Check::Register(index, expected) => {
+                (expected as u64, vm.get_registers()[index as usize] as u64)
+            }
+            Check::Returned(expected) => {
+                if last_step_result != StepResult::Return(expected) {
+                    return Err(format!(
+                        "expected {}, got {}",
+                        StepResult::Return(expected),
+                        last_step_result
+                    ));
+                }
+                continue;
+            }
+        };
+        if expected != actual {
+            return Err(format!(
+                "{:?}: got 0x{:X} instead (last step {})",
+                check, actual, last_step_result
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs all built-in programs, and returns each name with its outcome.
+pub fn run_selftest() -> Vec<(&'static str, Result<(), String>)> {
+    PROGRAMS
+        .iter()
+        .map(|program| (program.name, run_program(program)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test_selftest {
+    use super::*;
+
+    #[test]
+    fn test_all_pass() {
+        for (name, result) in run_selftest() {
+            assert_eq!(result, Ok(()), "program {}", name);
+        }
+    }
+
+    #[test]
+    fn test_detects_failure() {
+        let broken = Program {
+            name: "broken",
+            instructions: &[0x3041, 0x102A],
+            max_steps: 2,
+            checks: &[Check::Returned(0x42)],
+        };
+        assert_eq!(
+            run_program(&broken),
+            Err("expected return 0x0042, got return 0x0041".into())
+        );
+    }
+}
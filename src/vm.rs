@@ -1,28 +1,467 @@
-use getrandom::getrandom;
-use std::fmt::{Debug, Formatter, Result};
-use std::ops::{Index, IndexMut};
+mod asm;
+mod builder;
+mod cost_model;
+mod dispatch;
+mod history;
+mod insn_stats;
+mod instruction;
+pub mod load;
+mod loop_detector;
+mod machine_builder;
+mod mem_trace;
+mod mmio;
+mod offsets;
+mod opcode_stats;
+mod peripherals;
+mod profile;
+mod random;
+mod recent_pcs;
+mod register_watch;
+mod run;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod splitmix;
+mod trace;
 
-#[derive(Clone, PartialEq, Eq)]
+use crate::format::{
+    decode_ihex, decode_rle, decode_segment, decode_segment_padded, encode_rle, encode_segment,
+    FormatError, IhexError, RleError, SegmentFormat,
+};
+use std::collections::VecDeque;
+use std::fmt::{Debug, Display, Formatter, Result};
+use std::hash::{Hash, Hasher};
+use std::ops::{Index, IndexMut, RangeInclusive};
+use std::sync::Arc;
+
+pub use builder::{BuildError, ProgramBuilder};
+pub use cost_model::CostModel;
+use dispatch::DISPATCH;
+use history::History;
+pub use insn_stats::{InsnClass, InsnStats};
+pub use instruction::{BinaryFunction, Instruction, UnaryFunction};
+use loop_detector::LoopDetector;
+pub use machine_builder::VirtualMachineBuilder;
+pub use mem_trace::{
+    read_mem_trace, run_vm_with_mem_trace, MemAccess, MemAccessKind, MemTraceError, MemTraceWriter,
+    MEM_TRACE_HEADER_BYTES, MEM_TRACE_MAGIC, MEM_TRACE_RECORD_BYTES, MEM_TRACE_VERSION,
+};
+use mmio::Mmio;
+pub use mmio::{MmioHandler, TextOutput, DEFAULT_MMIO_RANGE};
+pub use offsets::{
+    decode_branch, decode_jump_imm, encode_branch, encode_jump_imm, OffsetError, BRANCH_MAX,
+    BRANCH_MIN, JUMP_IMM_MAX, JUMP_IMM_MIN,
+};
+pub use opcode_stats::OpcodeStats;
+pub use peripherals::{
+    run_vm_with_syscalls, ConsolePeripheral, SyscallHandler, SYSCALL_ELAPSED_MS,
+    SYSCALL_END_OF_INPUT, SYSCALL_OK, SYSCALL_READ_WORD, SYSCALL_UNKNOWN, SYSCALL_WRITE_BYTE,
+};
+pub use profile::CoverageReport;
+use profile::Profile;
+#[cfg(test)]
+pub(crate) use random::set_fail_getrandom;
+pub use random::{CountingSource, OsRandomSource, RandomSource, ReplaySource, SharedRandomSource};
+use recent_pcs::RecentPcs;
+pub use recent_pcs::RECENT_PCS_LEN;
+use register_watch::RegisterWatch;
+pub use register_watch::RegisterWatchHit;
+pub(crate) use run::run_stepping;
+pub use run::{run_program, run_vm, DebugDumps, ProgramOutcome, RunOutcome, StopReason};
+pub(crate) use splitmix::SplitMix64;
+pub use trace::{DebugDumpHandler, TraceEvent, Tracer};
+
+/// Words per page of a sparse segment, i.e. 1 KiB.
+const PAGE_WORDS: usize = 512;
+const PAGES: usize = (1 << 16) / PAGE_WORDS;
+
+#[derive(Clone)]
+enum Backing {
+    Dense(Box<[u16; 1 << 16]>),
+    /// Pages that were never written are `None`, and read as zero.
+    Sparse(Box<[Option<Box<[u16; PAGE_WORDS]>>; PAGES]>),
+}
+
+/// 65536 words of memory. Both backings behave identically, they only differ in memory footprint: A dense segment
+/// always takes 128 KiB, and a sparse segment allocates each 1 KiB page on the first write to it.
+#[derive(Clone)]
 pub struct Segment {
-    backing: Box<[u16; 1 << 16]>,
+    backing: Backing,
 }
 
+static ZERO: u16 = 0;
+static ZERO_PAGE: [u16; PAGE_WORDS] = [0; PAGE_WORDS];
+
 impl Segment {
     #[must_use]
     pub fn new_zeroed() -> Segment {
         Segment {
-            backing: Box::new([0; 1 << 16]),
+            backing: Backing::Dense(Box::new([0; 1 << 16])),
+        }
+    }
+
+    #[must_use]
+    pub fn new_sparse() -> Segment {
+        const NO_PAGE: Option<Box<[u16; PAGE_WORDS]>> = None;
+        Segment {
+            backing: Backing::Sparse(Box::new([NO_PAGE; PAGES])),
+        }
+    }
+
+    pub fn is_sparse(&self) -> bool {
+        matches!(self.backing, Backing::Sparse(_))
+    }
+
+    /// Returns a sparse copy, which only allocates pages that contain a nonzero word.
+    #[must_use]
+    pub fn to_sparse(&self) -> Segment {
+        let mut sparse = Segment::new_sparse();
+        for (address, word) in self.iter_nonzero() {
+            sparse[address] = word;
+        }
+        sparse
+    }
+
+    #[must_use]
+    pub fn to_dense(&self) -> Segment {
+        let mut dense = Segment::new_zeroed();
+        for (index, word) in self.words().enumerate() {
+            dense[index as u16] = word;
+        }
+        dense
+    }
+
+    /// All 65536 words, starting at address 0.
+    pub fn words(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..PAGES).flat_map(move |page| self.page(page).iter().copied())
+    }
+
+    /// Copies `len` words from `src`, starting at `src_start`, to this segment, starting at `dst`. Both ranges
+    /// wrap around from 0xFFFF to 0x0000.
+    pub fn copy_from(&mut self, dst: u16, src: &Segment, src_start: u16, len: u16) {
+        let mut dst = dst as usize;
+        let mut src_index = src_start as usize;
+        let mut remaining = len as usize;
+        while remaining > 0 {
+            // Never cross a page boundary, in particular not the wrap-around at 0xFFFF.
+            let src_offset = src_index % PAGE_WORDS;
+            let dst_offset = dst % PAGE_WORDS;
+            let chunk = remaining
+                .min(PAGE_WORDS - src_offset)
+                .min(PAGE_WORDS - dst_offset);
+            let words = &src.page(src_index / PAGE_WORDS)[src_offset..src_offset + chunk];
+            if let Some(page) = self.page_mut(dst / PAGE_WORDS, words) {
+                page[dst_offset..dst_offset + chunk].copy_from_slice(words);
+            }
+            dst = (dst + chunk) % (1 << 16);
+            src_index = (src_index + chunk) % (1 << 16);
+            remaining -= chunk;
         }
     }
+
+    /// All 65536 words at once, or `None` if the segment is sparse. See `to_dense`.
+    pub fn as_slice(&self) -> Option<&[u16; 1 << 16]> {
+        match &self.backing {
+            Backing::Dense(words) => Some(&**words),
+            Backing::Sparse(_) => None,
+        }
+    }
+
+    /// All 65536 words at once. A sparse segment is converted to a dense one first.
+    pub fn as_mut_slice(&mut self) -> &mut [u16; 1 << 16] {
+        if self.is_sparse() {
+            *self = self.to_dense();
+        }
+        match &mut self.backing {
+            Backing::Dense(words) => words,
+            Backing::Sparse(_) => unreachable!("just converted to dense"),
+        }
+    }
+
+    /// Writes `words` to this segment, starting at `base`. Wraps around from 0xFFFF to 0x0000, so if there are more
+    /// than 65536 words, the later ones overwrite the earlier ones.
+    pub fn copy_from_slice_at(&mut self, base: u16, words: &[u16]) {
+        let mut address = base as usize;
+        let mut words = words;
+        while !words.is_empty() {
+            // Never cross a page boundary, in particular not the wrap-around at 0xFFFF.
+            let offset = address % PAGE_WORDS;
+            let (chunk, rest) = words.split_at(words.len().min(PAGE_WORDS - offset));
+            if let Some(page) = self.page_mut(address / PAGE_WORDS, chunk) {
+                page[offset..offset + chunk.len()].copy_from_slice(chunk);
+            }
+            address = (address + chunk.len()) % (1 << 16);
+            words = rest;
+        }
+    }
+
+    /// Fills `out` with the words starting at `base`. Wraps around from 0xFFFF to 0x0000.
+    pub fn read_into(&self, base: u16, out: &mut [u16]) {
+        let mut address = base as usize;
+        let mut out = out;
+        while !out.is_empty() {
+            let offset = address % PAGE_WORDS;
+            let (chunk, rest) = out.split_at_mut(out.len().min(PAGE_WORDS - offset));
+            chunk.copy_from_slice(&self.page(address / PAGE_WORDS)[offset..offset + chunk.len()]);
+            address = (address + chunk.len()) % (1 << 16);
+            out = rest;
+        }
+    }
+
+    /// All `(address, word)` pairs, starting at address 0.
+    pub fn iter(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.words()
+            .enumerate()
+            .map(|(address, word)| (address as u16, word))
+    }
+
+    /// All `(address, word)` pairs with a nonzero word, in ascending order of address. Unallocated pages of a sparse
+    /// segment are skipped without looking at them.
+    pub fn iter_nonzero(&self) -> impl Iterator<Item = (u16, u16)> + '_ {
+        self.nonzero_from(0)
+    }
+
+    /// Returns the number of nonzero words, see `iter_nonzero`.
+    pub fn count_nonzero(&self) -> u32 {
+        self.iter_nonzero().count() as u32
+    }
+
+    /// Returns the first nonzero word strictly after `address` with its address, or `None` if there is none up to
+    /// 0xFFFF. This does not wrap around.
+    pub fn first_nonzero_after(&self, address: u16) -> Option<(u16, u16)> {
+        self.nonzero_from(address as usize + 1).next()
+    }
+
+    /// Like `iter_nonzero`, but starts at `start`, which may be 0x10000.
+    fn nonzero_from(&self, start: usize) -> impl Iterator<Item = (u16, u16)> + '_ {
+        (start / PAGE_WORDS..PAGES)
+            .filter(move |&page| match &self.backing {
+                Backing::Dense(_) => true,
+                Backing::Sparse(pages) => pages[page].is_some(),
+            })
+            .flat_map(move |page| {
+                let first = start.saturating_sub(page * PAGE_WORDS);
+                self.page(page)
+                    .iter()
+                    .enumerate()
+                    .skip(first)
+                    .filter(|&(_, &word)| word != 0)
+                    .map(move |(offset, &word)| ((page * PAGE_WORDS + offset) as u16, word))
+            })
+    }
+
+    fn page(&self, page: usize) -> &[u16] {
+        match &self.backing {
+            Backing::Dense(words) => &words[page * PAGE_WORDS..(page + 1) * PAGE_WORDS],
+            Backing::Sparse(pages) => match &pages[page] {
+                Some(words) => &words[..],
+                None => &ZERO_PAGE,
+            },
+        }
+    }
+
+    /// Returns the page for writing `words` into it, or `None` if that would be a no-op, i.e. if the page is
+    /// unallocated and `words` are all zero.
+    fn page_mut(&mut self, page: usize, words: &[u16]) -> Option<&mut [u16]> {
+        match &mut self.backing {
+            Backing::Dense(all) => Some(&mut all[page * PAGE_WORDS..(page + 1) * PAGE_WORDS]),
+            Backing::Sparse(pages) => {
+                if pages[page].is_none() && words.iter().all(|&word| word == 0) {
+                    return None;
+                }
+                Some(&mut pages[page].get_or_insert_with(|| Box::new([0; PAGE_WORDS]))[..])
+            }
+        }
+    }
+
+    /// Returns the number of bytes allocated on the heap for this segment.
+    pub fn heap_bytes(&self) -> usize {
+        match &self.backing {
+            Backing::Dense(words) => std::mem::size_of_val(&**words),
+            Backing::Sparse(pages) => {
+                let allocated = pages.iter().filter(|page| page.is_some()).count();
+                std::mem::size_of_val(&**pages) + allocated * PAGE_WORDS * 2
+            }
+        }
+    }
+
+    /// Returns the number of words up to and including the last nonzero word, i.e. 0 for an all-zero segment.
+    pub fn used_len(&self) -> u32 {
+        for page in (0..PAGES).rev() {
+            if let Some(last) = self.page(page).iter().rposition(|&word| word != 0) {
+                return (page * PAGE_WORDS + last + 1) as u32;
+            }
+        }
+        0
+    }
+
+    /// Returns the 64-bit FNV-1a hash of all words, in ascending order of address, each as two little-endian bytes.
+    /// This identifies the content, e.g. of a program, and is stable across runs, platforms, and versions of this
+    /// crate, see `test_fingerprint_golden`. It is not cryptographic, so it does not protect against collisions on
+    /// purpose.
+    pub fn fingerprint(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        self.write_to(&mut hash);
+        hash.finish()
+    }
+
+    fn write_to(&self, hash: &mut Fnv1a) {
+        for word in self.words() {
+            hash.write_u16(word);
+        }
+    }
+
+    /// Returns a value whose Debug output is a one-line summary instead of the full dump.
+    pub fn summary(&self) -> SegmentSummary<'_> {
+        SegmentSummary(self)
+    }
+
+    /// Returns `(address, self_value, other_value)` for every word that differs, in ascending order of address.
+    pub fn diff(&self, other: &Segment) -> Vec<(u16, u16, u16)> {
+        let mut differences = Vec::new();
+        for page in 0..PAGES {
+            let (mine, theirs) = (self.page(page), other.page(page));
+            // Fast path, in particular for unallocated pages of sparse segments.
+            if mine == theirs {
+                continue;
+            }
+            for (offset, (&mine, &theirs)) in mine.iter().zip(theirs).enumerate() {
+                if mine != theirs {
+                    differences.push(((page * PAGE_WORDS + offset) as u16, mine, theirs));
+                }
+            }
+        }
+        differences
+    }
+
+    /// Formats `diff` for humans, one line per contiguous range of differing words, like
+    /// `0010-0012: 0000 0000 0000 -> 1111 2222 3333`. Returns the empty string if the segments are identical.
+    pub fn diff_report(&self, other: &Segment) -> String {
+        let differences = self.diff(other);
+        let mut report = String::new();
+        for range in differences.chunk_by(|a, b| a.0.checked_add(1) == Some(b.0)) {
+            let (first, last) = (range[0].0, range[range.len() - 1].0);
+            if first == last {
+                report.push_str(&format!("{:04X}:", first));
+            } else {
+                report.push_str(&format!("{:04X}-{:04X}:", first, last));
+            }
+            for &(_, mine, _) in range {
+                report.push_str(&format!(" {:04X}", mine));
+            }
+            report.push_str(" ->");
+            for &(_, _, theirs) in range {
+                report.push_str(&format!(" {:04X}", theirs));
+            }
+            report.push('\n');
+        }
+        report
+    }
+
+    /// Returns a dense segment that starts with `words`, and is zero after them. Unlike `copy_from_slice_at`, more
+    /// than 65536 words are an error instead of wrapping around.
+    pub fn try_from_prefix(words: &[u16]) -> std::result::Result<Segment, SegmentError> {
+        if words.len() > 1 << 16 {
+            return Err(SegmentError::TooLong {
+                actual: words.len(),
+            });
+        }
+        let mut segment = Segment::new_zeroed();
+        segment.as_mut_slice()[..words.len()].copy_from_slice(words);
+        Ok(segment)
+    }
+
+    /// Like `try_from_prefix`, but panics if there are more than 65536 words. Handy in tests.
+    #[must_use]
+    pub fn from_prefix(words: &[u16]) -> Segment {
+        match Segment::try_from_prefix(words) {
+            Ok(segment) => segment,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Parses the canonical file format: exactly 131072 bytes, each word stored most significant byte first. See
+    /// `decode_segment` for the other formats, and `load_segment` for reading files.
+    pub fn from_bytes(bytes: &[u8]) -> std::result::Result<Segment, FormatError> {
+        decode_segment(bytes, SegmentFormat::BigEndian)
+    }
+
+    /// Like `from_bytes`, but also accepts shorter input with an even length, and fills the rest with zeros. Handy for
+    /// small programs, which no longer need to be padded to 128 KiB on disk.
+    pub fn from_bytes_padded(bytes: &[u8]) -> std::result::Result<Segment, FormatError> {
+        decode_segment_padded(bytes, SegmentFormat::BigEndian)
+    }
+
+    /// Parses Intel HEX, see `decode_ihex`.
+    pub fn from_ihex(text: &str) -> std::result::Result<Segment, IhexError> {
+        decode_ihex(text)
+    }
+
+    /// Inverse of `from_bytes`, always 131072 bytes.
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        encode_segment(self, SegmentFormat::BigEndian)
+    }
+
+    /// Parses the compressed format, see `encode_rle`.
+    pub fn from_rle_bytes(bytes: &[u8]) -> std::result::Result<Segment, RleError> {
+        decode_rle(bytes)
+    }
+
+    /// Inverse of `from_rle_bytes`. Usually much smaller than `to_bytes`, as most segments are mostly zeros.
+    #[must_use]
+    pub fn to_rle_bytes(&self) -> Vec<u8> {
+        encode_rle(self)
+    }
+
+    /// SHA-256 of `to_bytes`, so this is also what `sha256sum` prints for a segment file in the canonical format.
+    /// Unlike `fingerprint`, this identifies a program beyond doubt, e.g. to record which bot produced a result.
+    #[cfg(feature = "sha256")]
+    #[must_use]
+    pub fn sha256(&self) -> [u8; 32] {
+        use sha2::{Digest, Sha256};
+        Sha256::digest(self.to_bytes()).into()
+    }
+}
+
+/// Debug-formats a segment as `Segment { used_len: .., nonzero: .., fingerprint: .. }`, see `Segment::summary`.
+pub struct SegmentSummary<'a>(&'a Segment);
+
+impl Debug for SegmentSummary<'_> {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.write_fmt(format_args!(
+            "Segment {{ used_len: {}, nonzero: {}, fingerprint: {:016X} }}",
+            self.0.used_len(),
+            self.0.count_nonzero(),
+            self.0.fingerprint()
+        ))
+    }
+}
+
+impl PartialEq for Segment {
+    fn eq(&self, other: &Segment) -> bool {
+        match (&self.backing, &other.backing) {
+            (Backing::Dense(words), Backing::Dense(other_words)) => words == other_words,
+            _ => self.words().eq(other.words()),
+        }
+    }
+}
+
+impl Eq for Segment {}
+
+/// Agrees with `PartialEq`, so a dense and a sparse segment with the same words hash the same.
+impl Hash for Segment {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.fingerprint());
+    }
 }
 
 impl Debug for Segment {
     fn fmt(&self, f: &mut Formatter) -> Result {
         f.write_str("Segment { backing: [")?;
-        f.write_fmt(format_args!("{:04X}", self.backing[0]))?;
+        f.write_fmt(format_args!("{:04X}", self[0]))?;
         // Invariant: Formatter is "always" dirently after a value or value-ish part.
 
-        let mut last_word = self.backing[0];
+        let mut last_word = self[0];
         let mut repetitions = 0;
 
         fn append_value(f: &mut Formatter, word: u16) -> Result {
@@ -39,14 +478,19 @@ impl Debug for Segment {
             }
         }
 
-        for word in self.backing.iter().skip(1) {
-            if *word == last_word {
-                repetitions += 1;
-            } else {
-                close_repetitions(f, last_word, repetitions)?;
-                repetitions = 0;
-                append_value(f, *word)?;
-                last_word = *word;
+        for page in 0..PAGES {
+            let words = self.page(page);
+            // Skip the very first word, it was already written above.
+            let words = if page == 0 { &words[1..] } else { words };
+            for &word in words {
+                if word == last_word {
+                    repetitions += 1;
+                } else {
+                    close_repetitions(f, last_word, repetitions)?;
+                    repetitions = 0;
+                    append_value(f, word)?;
+                    last_word = word;
+                }
             }
         }
         close_repetitions(f, last_word, repetitions)?;
@@ -55,26 +499,133 @@ impl Debug for Segment {
     }
 }
 
+/// Words per line of the hexdump.
+const HEXDUMP_WORDS: usize = 8;
+
+/// A hexdump in the style of `hexdump -C`: 8 words per line, prefixed by the address of the first word. Lines that
+/// are identical to the line before are collapsed into a single `*` line. The last line is always shown, so the
+/// dump visibly ends at `FFF8`.
+impl Display for Segment {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let lines = PAGES * PAGE_WORDS / HEXDUMP_WORDS;
+        let mut previous: Option<&[u16]> = None;
+        let mut collapsed = false;
+        for line in 0..lines {
+            let start = line * HEXDUMP_WORDS;
+            let page = self.page(start / PAGE_WORDS);
+            let offset = start % PAGE_WORDS;
+            let words = &page[offset..offset + HEXDUMP_WORDS];
+            if previous == Some(words) && line + 1 < lines {
+                if !collapsed {
+                    f.write_str("*\n")?;
+                    collapsed = true;
+                }
+                continue;
+            }
+            previous = Some(words);
+            collapsed = false;
+            f.write_fmt(format_args!("{:04X}:", start))?;
+            for word in words {
+                f.write_fmt(format_args!(" {:04X}", word))?;
+            }
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
 impl Index<u16> for Segment {
     type Output = u16;
 
     fn index(&self, index: u16) -> &Self::Output {
-        &self.backing[index as usize]
+        let index = index as usize;
+        match &self.backing {
+            Backing::Dense(words) => &words[index],
+            Backing::Sparse(pages) => match &pages[index / PAGE_WORDS] {
+                Some(page) => &page[index % PAGE_WORDS],
+                None => &ZERO,
+            },
+        }
     }
 }
 
 impl IndexMut<u16> for Segment {
     fn index_mut(&mut self, index: u16) -> &mut Self::Output {
-        &mut self.backing[index as usize]
+        let index = index as usize;
+        match &mut self.backing {
+            Backing::Dense(words) => &mut words[index],
+            Backing::Sparse(pages) => {
+                let page =
+                    pages[index / PAGE_WORDS].get_or_insert_with(|| Box::new([0; PAGE_WORDS]));
+                &mut page[index % PAGE_WORDS]
+            }
+        }
+    }
+}
+
+/// See `Segment::try_from_prefix`.
+impl TryFrom<&[u16]> for Segment {
+    type Error = SegmentError;
+
+    fn try_from(words: &[u16]) -> std::result::Result<Segment, SegmentError> {
+        Segment::try_from_prefix(words)
+    }
+}
+
+/// Why a `Segment` cannot be built from a slice, see `Segment::try_from_prefix`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SegmentError {
+    /// More than 65536 words.
+    TooLong { actual: usize },
+}
+
+impl Display for SegmentError {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            SegmentError::TooLong { actual } => write!(
+                f,
+                "Too many words for a segment, expected at most 65536, got {} instead.",
+                actual
+            ),
+        }
     }
 }
 
+impl std::error::Error for SegmentError {}
+
+/// What the program may do with a data address, see `VirtualMachine::protect_data`. The host may always write.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum Protection {
+    #[default]
+    ReadWrite,
+    /// Stores by the program halt the machine with `StepResult::ProtectionFault`.
+    ReadOnly,
+}
+
+/// Selects one of the two segments of a VM, see `VirtualMachine::copy_data_from`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SegmentKind {
+    Data,
+    Instructions,
+}
+
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 #[derive(PartialEq, Eq, Clone, Copy)]
 pub enum StepResult {
     Continue,
     DebugDump,
     IllegalInstruction(u16),
     Return(u16),
+    /// The `rnd` instruction could not obtain entropy from the host. This is not the program's fault.
+    RandomnessUnavailable,
+    /// The instruction at this address has a breakpoint, and was not executed yet. See `add_breakpoint`.
+    Breakpoint(u16),
+    /// The instruction at `pc` tried to store to the read-only data address `addr`, see `protect_data`. The store
+    /// did not happen.
+    ProtectionFault {
+        addr: u16,
+        pc: u16,
+    },
 }
 
 impl Debug for StepResult {
@@ -86,60 +637,550 @@ impl Debug for StepResult {
                 f.write_fmt(format_args!("IllegalInstruction(0x{:04x})", *insn))
             }
             StepResult::Return(value) => f.write_fmt(format_args!("Return(0x{:04x})", *value)),
+            StepResult::RandomnessUnavailable => f.write_str("RandomnessUnavailable"),
+            StepResult::Breakpoint(pc) => f.write_fmt(format_args!("Breakpoint(0x{:04x})", *pc)),
+            StepResult::ProtectionFault { addr, pc } => f.write_fmt(format_args!(
+                "ProtectionFault {{ addr: 0x{:04x}, pc: 0x{:04x} }}",
+                *addr, *pc
+            )),
         }
     }
 }
 
-fn random_upto_including(upper_bound: u16) -> u16 {
-    let modulus = (upper_bound as u64) + 1;
-    // Make a random u64, and do the modulo trick.
-    // This *does* create a disparity in probabilities, but it's at most (2**16) / (2**64) = 3.55e-13,
-    // so pretty darn unlikely to be noticed by anyone.
-    let mut bytes = [0u8; 8];
-    // If getrandom fails, tinyvm probably doesn't matter anymore. Crash and burn.
-    getrandom(&mut bytes).expect("Cannot satisfy rnd instruction");
-    let mut value: u64 = 0;
-    value |= bytes[0] as u64;
-    value <<= 8;
-    value |= bytes[1] as u64;
-    value <<= 8;
-    value |= bytes[2] as u64;
-    value <<= 8;
-    value |= bytes[3] as u64;
-    value <<= 8;
-    value |= bytes[4] as u64;
-    value <<= 8;
-    value |= bytes[5] as u64;
-    value <<= 8;
-    value |= bytes[6] as u64;
-    value <<= 8;
-    value |= bytes[7] as u64;
-    value <<= 8;
-    value %= modulus;
-    value as u16
-}
-
-#[derive(Debug)]
+/// For humans, e.g. `return 0x0042` or `illegal instruction 0x0123`.
+impl Display for StepResult {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self {
+            StepResult::Continue => write!(f, "continue"),
+            StepResult::DebugDump => write!(f, "debug-dump"),
+            StepResult::IllegalInstruction(insn) => write!(f, "illegal instruction 0x{:04X}", insn),
+            StepResult::Return(value) => write!(f, "return 0x{:04X}", value),
+            StepResult::RandomnessUnavailable => write!(f, "randomness unavailable"),
+            StepResult::Breakpoint(pc) => write!(f, "breakpoint at 0x{:04X}", pc),
+            StepResult::ProtectionFault { addr, pc } => write!(
+                f,
+                "store to read-only address 0x{:04X} at 0x{:04X}",
+                addr, pc
+            ),
+        }
+    }
+}
+
+/// Draws from `random_source` if present, else from `rng` if present, and otherwise from the operating system.
+/// Returns `None` if the source cannot provide randomness.
+fn random_upto_including(
+    random_source: &Option<SharedRandomSource>,
+    rng: &mut Option<SplitMix64>,
+    upper_bound: u16,
+) -> Option<u16> {
+    if upper_bound == 0 {
+        // No entropy needed.
+        return Some(0);
+    }
+    match (random_source, rng) {
+        (Some(source), _) => source.next_upto(upper_bound),
+        (None, Some(rng)) => rng.next_upto(upper_bound),
+        (None, None) => OsRandomSource.next_upto(upper_bound),
+    }
+}
+
+/// CPUID leaf 0, register 0: The binary instructions for exponentiation and roots (0x6Exx, 0x6Fxx) are supported.
+pub const CPUID_0_EXP_ROOT: u16 = 0x4000;
+
+/// CPUID leaf 0, register 0: The "compare to zero" instructions (0x11xx) are supported.
+pub const CPUID_0_COMPARE_ZERO: u16 = 0x2000;
+
+/// CPUID leaf 0, register 0: The "store word instruction" instructions (0x23xx) are supported, see
+/// `CpuFeatures::STORE_INSTRUCTION`.
+pub const CPUID_0_STORE_INSTRUCTION: u16 = 0x1000;
+
+/// CPUID leaf 0, register 0: The unary function `rnd` (0x5Exx) is supported.
+pub const CPUID_0_RND: u16 = 0x0800;
+
+/// CPUID leaf 0, register 0: The "load/store word data with offset" instructions (0x7xxx) are supported, see
+/// `CpuFeatures::MEMORY_OFFSET`.
+pub const CPUID_0_MEMORY_OFFSET: u16 = 0x0400;
+
+/// CPUID leaf 0, register 0: Debug-dump (0x102C) is a host call, which may overwrite registers 0 to 3. Only a host
+/// that serves them enables this, see `run_vm_with_syscalls`.
+pub const CPUID_0_SYSCALL: u16 = 0x0200;
+
+/// The optional capabilities of a VM, see `VirtualMachine::with_features`. CPUID leaf 0 reports the enabled ones
+/// in register 0, using the `CPUID_0_*` bits. While disabled, their instructions are illegal, exactly like any other
+/// reserved instruction.
+///
+/// By default, everything except the extensions and `SYSCALL` is enabled, see `VirtualMachine::enable_extension`.
+/// Restricting the features is useful for fair play, e.g.
+/// `CpuFeatures::default().without(CpuFeatures::RND)` for programs that must be deterministic.
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(transparent)
+)]
+#[derive(PartialEq, Eq, Clone, Copy, Hash)]
+pub struct CpuFeatures(u16);
+
+impl Debug for CpuFeatures {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "CpuFeatures(0x{:04X})", self.0)
+    }
+}
+
+impl CpuFeatures {
+    pub const EXP_ROOT: CpuFeatures = CpuFeatures(CPUID_0_EXP_ROOT);
+    pub const COMPARE_ZERO: CpuFeatures = CpuFeatures(CPUID_0_COMPARE_ZERO);
+    /// Extension: `0x23AD` stores the value of register D at the address in register A of the instruction segment,
+    /// i.e. self-modifying code.
+    pub const STORE_INSTRUCTION: CpuFeatures = CpuFeatures(CPUID_0_STORE_INSTRUCTION);
+    pub const RND: CpuFeatures = CpuFeatures(CPUID_0_RND);
+    /// Extension: `0x7SOO AAAA DDDD` loads (S=0) register D from, or stores (S=1) register D to, the data segment at
+    /// the address in register A plus the offset O (0 to 7). This saves computing addresses for fields of a
+    /// structure.
+    pub const MEMORY_OFFSET: CpuFeatures = CpuFeatures(CPUID_0_MEMORY_OFFSET);
+    pub const SYSCALL: CpuFeatures = CpuFeatures(CPUID_0_SYSCALL);
+    /// Every feature this implementation knows, as reported by CPUID leaf 1.
+    pub const ALL: CpuFeatures = CpuFeatures(
+        CPUID_0_EXP_ROOT
+            | CPUID_0_COMPARE_ZERO
+            | CPUID_0_STORE_INSTRUCTION
+            | CPUID_0_RND
+            | CPUID_0_MEMORY_OFFSET
+            | CPUID_0_SYSCALL,
+    );
+
+    #[must_use]
+    pub const fn empty() -> CpuFeatures {
+        CpuFeatures(0)
+    }
+
+    /// Ignores any bits that are not in `ALL`.
+    #[must_use]
+    pub const fn from_bits_truncate(bits: u16) -> CpuFeatures {
+        CpuFeatures(bits & CpuFeatures::ALL.0)
+    }
+
+    #[must_use]
+    pub const fn bits(self) -> u16 {
+        self.0
+    }
+
+    #[must_use]
+    pub const fn contains(self, other: CpuFeatures) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    #[must_use]
+    pub const fn with(self, other: CpuFeatures) -> CpuFeatures {
+        CpuFeatures(self.0 | other.0)
+    }
+
+    #[must_use]
+    pub const fn without(self, other: CpuFeatures) -> CpuFeatures {
+        CpuFeatures(self.0 & !other.0)
+    }
+}
+
+impl Default for CpuFeatures {
+    fn default() -> CpuFeatures {
+        CpuFeatures::EXP_ROOT
+            .with(CpuFeatures::COMPARE_ZERO)
+            .with(CpuFeatures::RND)
+    }
+}
+
+/// The opt-in additions to the instruction set, by name, see `VirtualMachine::enable_extension`. Each one is just a
+/// bit of `CpuFeatures`, which documents what it does.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Extension {
+    /// `CpuFeatures::STORE_INSTRUCTION`
+    StoreInstruction,
+    /// `CpuFeatures::MEMORY_OFFSET`
+    MemoryOffset,
+}
+
+impl Extension {
+    #[must_use]
+    pub fn features(&self) -> CpuFeatures {
+        match self {
+            Extension::StoreInstruction => CpuFeatures::STORE_INSTRUCTION,
+            Extension::MemoryOffset => CpuFeatures::MEMORY_OFFSET,
+        }
+    }
+}
+
+/// Rounds to the nearest integer (ties to even), and clamps to the signed 16-bit range. We define NaN as 0.
+fn round_and_clamp(value: f64) -> u16 {
+    // Float-to-int casts saturate, and turn NaN into 0.
+    value.round_ties_even() as i16 as u16
+}
+
+/// The `degree`-th root of `radicand`, e.g. the cube root of -8 is -2. The 0th root is always 1, and even roots
+/// of negative numbers are NaN.
+fn signed_root(radicand: i16, degree: i16) -> f64 {
+    if degree == 0 {
+        return 1.0;
+    }
+    let exponent = 1.0 / degree as f64;
+    if radicand >= 0 {
+        (radicand as f64).powf(exponent)
+    } else if degree % 2 != 0 {
+        -(-(radicand as f64)).powf(exponent)
+    } else {
+        f64::NAN
+    }
+}
+
+/// Evaluates the LEGS flags of a compare instruction, see
+/// https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x8xxx-compare
+fn compare_with_flags(flags: u16, lhs: u16, rhs: u16) -> bool {
+    let flag_l = (flags & 0b1000) != 0;
+    let flag_e = (flags & 0b0100) != 0;
+    let flag_g = (flags & 0b0010) != 0;
+    let flag_s = (flags & 0b0001) != 0;
+
+    let (lhs, rhs) = if flag_s {
+        // Sign-extend
+        (lhs as i16 as i32, rhs as i16 as i32)
+    } else {
+        // Zero-extend
+        (lhs as u32 as i32, rhs as u32 as i32)
+    };
+
+    (flag_l && lhs < rhs) || (flag_e && lhs == rhs) || (flag_g && lhs > rhs)
+}
+
+/// The 64-bit FNV-1a hash, fed with little-endian bytes, see `Segment::fingerprint`.
+pub(crate) struct Fnv1a(u64);
+
+impl Fnv1a {
+    pub(crate) fn new() -> Fnv1a {
+        Fnv1a(0xCBF2_9CE4_8422_2325)
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 ^= byte as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01B3);
+        }
+    }
+
+    pub(crate) fn write_u16(&mut self, value: u16) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(crate) fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub(crate) fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Clone, PartialEq, Eq)]
 pub struct VirtualMachine {
     registers: [u16; 16],
     program_counter: u16,
     time: u64,
-    instructions: Segment,
+    /// Copied on the first write, see `new_shared`.
+    instructions: Arc<Segment>,
     data: Segment,
+    deterministic_so_far: bool,
+    /// Executed `rnd` instructions, see `random_calls`.
+    random_calls: u64,
+    halted: Option<StepResult>,
+    features: CpuFeatures,
+    /// Only for VMs created by `new_with_seed`, otherwise `rnd` uses the operating system's entropy.
+    rng: Option<SplitMix64>,
+    /// Takes precedence over `rng`.
+    random_source: Option<SharedRandomSource>,
+    /// Every value drawn by `rnd`, `None` unless recording is enabled.
+    random_trace: Option<Vec<u16>>,
+    breakpoints: AddressSet,
+    /// Set after reporting a breakpoint, so that the next step executes the instruction instead of reporting the
+    /// breakpoint again.
+    resuming_from_breakpoint: bool,
+    watched_data: AddressSet,
+    watch_hits: Vec<WatchHit>,
+    register_watch: RegisterWatch,
+    /// Data addresses with `Protection::ReadOnly`.
+    read_only_data: AddressSet,
+    tracer: Option<Tracer>,
+    /// Called on every Debug-dump instruction, `None` unless set.
+    debug_dump_handler: Option<DebugDumpHandler>,
+    /// Only stores by the program, `None` unless enabled.
+    write_log: Option<WriteLog>,
+    /// The data segment as of `remember_initial_data`, `None` unless called.
+    initial_data: Option<Box<Segment>>,
+    /// Executions per address, `None` unless profiling is enabled.
+    profile: Option<Profile>,
+    /// Addresses executed at least once, `None` unless coverage is enabled.
+    coverage: Option<AddressSet>,
+    /// Executions per opcode family, `None` unless enabled.
+    opcode_stats: Option<Box<OpcodeStats>>,
+    /// The addresses of the last few instructions, `None` unless enabled.
+    recent_pcs: Option<Box<RecentPcs>>,
+    /// Recognizes endless loops during `run`, `None` unless enabled.
+    loop_detector: Option<Box<LoopDetector>>,
+    /// Undo records for `step_back`, `None` unless enabled.
+    history: Option<History>,
+    /// Consulted by loads and stores before the data segment, see `set_mmio_handler`.
+    mmio: Option<Mmio>,
+    /// How far each instruction advances the time, `None` means always 1.
+    cost_model: Option<Box<CostModel>>,
+    /// `time` right after the last Debug-dump instruction, see `steps_since_last_debug_dump`.
+    last_debug_dump_time: u64,
+}
+
+/// A write to a watched data address, see `VirtualMachine::watch_data`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WatchHit {
+    pub address: u16,
+    pub old: u16,
+    pub new: u16,
+    /// The address of the store instruction, or `None` if the host wrote the word through `set_data_word`.
+    pub pc: Option<u16>,
+}
+
+/// Where and why the machine halted with an illegal instruction, see `VirtualMachine::last_fault`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct FaultInfo {
+    pub pc: u16,
+    pub insn: u16,
+}
+
+const ADDRESS_SET_WORDS: usize = (1 << 16) / 64;
+
+/// One bit per address. Cheap to query, and not even allocated until the first address is inserted.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct AddressSet {
+    bits: Option<Box<[u64; ADDRESS_SET_WORDS]>>,
+}
+
+impl AddressSet {
+    fn insert(&mut self, address: u16) {
+        let bits = self
+            .bits
+            .get_or_insert_with(|| Box::new([0; ADDRESS_SET_WORDS]));
+        bits[address as usize / 64] |= 1 << (address % 64);
+    }
+
+    fn remove(&mut self, address: u16) {
+        if let Some(bits) = &mut self.bits {
+            bits[address as usize / 64] &= !(1 << (address % 64));
+        }
+    }
+
+    fn contains(&self, address: u16) -> bool {
+        match &self.bits {
+            Some(bits) => bits[address as usize / 64] & (1 << (address % 64)) != 0,
+            None => false,
+        }
+    }
+
+    /// All addresses in the set, in ascending order.
+    fn iter(&self) -> impl Iterator<Item = u16> + '_ {
+        self.bits.iter().flat_map(|bits| {
+            bits.iter().enumerate().flat_map(|(index, &word)| {
+                (0..64)
+                    .filter(move |bit| word & (1 << bit) != 0)
+                    .map(move |bit| (index * 64 + bit) as u16)
+            })
+        })
+    }
+}
+
+/// A store by the program, see `VirtualMachine::enable_write_log`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WriteRecord {
+    /// `get_time` before the store was executed.
+    pub time: u64,
+    pub pc: u16,
+    pub address: u16,
+    pub value: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct WriteLog {
+    capacity: usize,
+    records: VecDeque<WriteRecord>,
+    dirty: AddressSet,
+}
+
+/// Summarizes the segments instead of dumping them, see `Segment::summary`, and shows the instruction words from
+/// two before to two after the program counter as `near_pc`. Use `dump_full` for everything.
+impl Debug for VirtualMachine {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.debug_struct("VirtualMachine")
+            .field("registers", &format_args!("{:04X?}", self.registers))
+            .field(
+                "program_counter",
+                &format_args!("{:04X}", self.program_counter),
+            )
+            .field("time", &self.time)
+            .field("deterministic_so_far", &self.deterministic_so_far)
+            .field("halted", &self.halted)
+            .field("instructions", &self.instructions.summary())
+            .field("data", &self.data.summary())
+            .field("near_pc", &format_args!("{:04X?}", self.words_near_pc()))
+            .finish()
+    }
 }
 
 impl VirtualMachine {
+    fn words_near_pc(&self) -> [u16; 5] {
+        let mut words = [0; 5];
+        self.instructions
+            .read_into(self.program_counter.wrapping_sub(2), &mut words);
+        words
+    }
+
+    /// Like the `Debug` output, but followed by hexdumps of both segments, see `Display for Segment`. Only
+    /// repeated lines are collapsed, so this is long.
+    #[must_use]
+    pub fn dump_full(&self) -> String {
+        format!(
+            "{:?}\ninstructions:\n{}data:\n{}",
+            self, self.instructions, self.data
+        )
+    }
+
     #[must_use]
     pub fn new(instructions: Segment, data: Segment) -> VirtualMachine {
+        VirtualMachine::new_shared(Arc::new(instructions), data)
+    }
+
+    /// Like `new`, but shares the instruction segment instead of owning it, so that many VMs can run the same
+    /// program without copying 128 KiB each. The first write to the instructions, e.g. by
+    /// `get_instructions_mut`, copies them, so that other VMs never see the change.
+    #[must_use]
+    pub fn new_shared(instructions: Arc<Segment>, data: Segment) -> VirtualMachine {
         VirtualMachine {
             registers: [0; 16],
             program_counter: 0,
             time: 0,
             instructions,
             data,
+            deterministic_so_far: true,
+            random_calls: 0,
+            halted: None,
+            features: CpuFeatures::default(),
+            rng: None,
+            random_source: None,
+            random_trace: None,
+            breakpoints: AddressSet::default(),
+            resuming_from_breakpoint: false,
+            watched_data: AddressSet::default(),
+            read_only_data: AddressSet::default(),
+            watch_hits: Vec::new(),
+            register_watch: RegisterWatch::default(),
+            tracer: None,
+            debug_dump_handler: None,
+            write_log: None,
+            initial_data: None,
+            profile: None,
+            coverage: None,
+            opcode_stats: None,
+            recent_pcs: None,
+            loop_detector: None,
+            history: None,
+            mmio: None,
+            cost_model: None,
+            last_debug_dump_time: 0,
+        }
+    }
+
+    /// Like `new`, but only with the given features instead of the default ones. Instructions of the other features
+    /// are illegal.
+    #[must_use]
+    pub fn with_features(
+        instructions: Segment,
+        data: Segment,
+        features: CpuFeatures,
+    ) -> VirtualMachine {
+        VirtualMachine {
+            features,
+            ..VirtualMachine::new(instructions, data)
+        }
+    }
+
+    /// Like `new`, but `rnd` draws from a pseudo-random generator seeded with `seed` instead of the operating
+    /// system's entropy. Two such VMs with the same seed and segments behave identically.
+    ///
+    /// Note that `was_deterministic_so_far` still reports whether `rnd` was used.
+    #[must_use]
+    pub fn new_with_seed(instructions: Segment, data: Segment, seed: u64) -> VirtualMachine {
+        VirtualMachine {
+            rng: Some(SplitMix64::new(seed)),
+            ..VirtualMachine::new(instructions, data)
         }
     }
 
+    /// Makes `rnd` draw from a pseudo-random generator seeded with `seed` from now on, see `new_with_seed`. `None`
+    /// restores the operating system's entropy.
+    pub fn set_seed(&mut self, seed: Option<u64>) {
+        self.rng = seed.map(SplitMix64::new);
+    }
+
+    /// Like `new`, but `rnd` draws from `source` instead of the operating system's entropy, e.g. a
+    /// `CountingSource` for exact tests, or whatever entropy the embedding environment allows.
+    #[must_use]
+    pub fn with_random_source(
+        instructions: Segment,
+        data: Segment,
+        source: Box<dyn RandomSource>,
+    ) -> VirtualMachine {
+        VirtualMachine {
+            random_source: Some(SharedRandomSource::new(source)),
+            ..VirtualMachine::new(instructions, data)
+        }
+    }
+
+    /// Makes `rnd` draw from `source` from now on, which takes precedence over the seed of `new_with_seed`. `None`
+    /// restores the seeded generator, if any, or else the operating system's entropy.
+    pub fn set_random_source(&mut self, source: Option<SharedRandomSource>) {
+        self.random_source = source;
+    }
+
+    /// Attaches a device to the data segment: Loads and stores by the program to addresses in `range` ask `handler`
+    /// first, and only touch the data segment if it does not handle them. See `DEFAULT_MMIO_RANGE` and `TextOutput`.
+    ///
+    /// Handled stores bypass watchpoints and the write log, and `step_back` cannot undo what the device did. Clones of
+    /// the VM share the handler. This replaces any previous handler.
+    pub fn set_mmio_handler(&mut self, range: RangeInclusive<u16>, handler: Box<dyn MmioHandler>) {
+        self.mmio = Some(Mmio::new(range, handler));
+    }
+
+    /// Detaches the handler of `set_mmio_handler`, if any, so that loads and stores only use the data segment.
+    pub fn clear_mmio_handler(&mut self) {
+        self.mmio = None;
+    }
+
+    /// Records from now on every value that `rnd` draws, see `take_random_trace`. Calling this again keeps what was
+    /// recorded so far.
+    pub fn record_randomness(&mut self) {
+        self.random_trace.get_or_insert_with(Vec::new);
+    }
+
+    /// Returns the values drawn by `rnd` since the last call, oldest first. `rnd` with an upper bound of zero draws
+    /// nothing, so it is not recorded. Feed the trace to `replay_randomness` to repeat the run exactly.
+    pub fn take_random_trace(&mut self) -> Vec<u16> {
+        match &mut self.random_trace {
+            Some(trace) => std::mem::take(trace),
+            None => Vec::new(),
+        }
+    }
+
+    /// Makes `rnd` return exactly `values` in order, e.g. a trace from `take_random_trace`, see `ReplaySource`. Once
+    /// the values are exhausted, or if a value exceeds the upper bound of `rnd` (i.e. the run diverged from the
+    /// recorded one), `rnd` halts the VM with `StepResult::RandomnessUnavailable` instead of falling back to real
+    /// entropy, so that a replay never silently turns into a different run.
+    pub fn replay_randomness(&mut self, values: Vec<u16>) {
+        self.set_random_source(Some(SharedRandomSource::new(Box::new(ReplaySource::new(
+            values,
+        )))));
+    }
+
     #[must_use]
     pub fn get_registers(&self) -> &[u16; 16] {
         &self.registers
@@ -154,16 +1195,117 @@ impl VirtualMachine {
         self.program_counter
     }
 
+    /// Continues execution at `program_counter`. This does not resume a halted machine, see `reset`.
+    pub fn set_program_counter(&mut self, program_counter: u16) {
+        self.program_counter = program_counter;
+        self.resuming_from_breakpoint = false;
+    }
+
     #[must_use]
     pub fn get_time(&self) -> u64 {
         self.time
     }
 
+    /// Overrides the number of executed instructions, e.g. to reconstruct a captured state, or to start a program
+    /// with a nonzero clock. The Time instruction reports the new value from now on. See `VirtualMachineBuilder`.
+    ///
+    /// The time wraps around to 0 after `u64::MAX`, like a hardware counter.
+    pub fn set_time(&mut self, time: u64) {
+        self.time = time;
+    }
+
+    /// Returns how far the time advanced since the last Debug-dump instruction, i.e. the last item of
+    /// `debug_dumps`, or since the machine was created or reset. If the host moved the time backwards in between, this
+    /// is zero.
+    #[must_use]
+    pub fn steps_since_last_debug_dump(&self) -> u64 {
+        self.time.saturating_sub(self.last_debug_dump_time)
+    }
+
+    /// Makes every executed instruction advance the time by its cost in `cost_model` instead of by 1. This affects
+    /// the Time instruction, and all budgets, e.g. of `run`. `None` restores the usual cost of 1.
+    pub fn set_cost_model(&mut self, cost_model: Option<CostModel>) {
+        self.cost_model = cost_model.map(Box::new);
+    }
+
+    #[must_use]
+    pub fn get_cost_model(&self) -> Option<&CostModel> {
+        self.cost_model.as_deref()
+    }
+
+    /// How far executing the instruction at the program counter would advance the time.
+    pub(crate) fn next_cost(&self) -> u64 {
+        match &self.cost_model {
+            None => 1,
+            Some(cost_model) => cost_model.cost_of(self.instructions[self.program_counter]) as u64,
+        }
+    }
+
+    /// Returns the FNV-1a hash of the registers, the program counter, the time, and the data segment, in this order.
+    /// Two machines that ran in lockstep have the same hash. The instructions, whether the machine halted, and all
+    /// host-side settings are ignored.
+    #[must_use]
+    pub fn state_hash(&self) -> u64 {
+        let mut hash = Fnv1a::new();
+        for register in self.registers {
+            hash.write_u16(register);
+        }
+        hash.write_u16(self.program_counter);
+        hash.write_u64(self.time);
+        self.data.write_to(&mut hash);
+        hash.finish()
+    }
+
+    /// Returns false if the program has drawn actual randomness, i.e. executed `rnd` with a nonzero upper bound.
+    ///
+    /// `rnd` with an upper bound of zero always yields zero, so it does not count.
+    #[must_use]
+    pub fn was_deterministic_so_far(&self) -> bool {
+        self.deterministic_so_far
+    }
+
+    /// Returns how many `rnd` instructions the program has executed, i.e. how often it asked for randomness. Unlike
+    /// `was_deterministic_so_far`, this includes `rnd` with an upper bound of zero, but not one that failed with
+    /// `StepResult::RandomnessUnavailable`.
+    #[must_use]
+    pub fn random_calls(&self) -> u64 {
+        self.random_calls
+    }
+
+    /// Returns the result that halted the machine, i.e. the first `IllegalInstruction`, `Return`,
+    /// `RandomnessUnavailable`, or `ProtectionFault`, if any.
+    #[must_use]
+    pub fn get_halted(&self) -> Option<StepResult> {
+        self.halted
+    }
+
+    /// Returns the illegal instruction that halted the machine, and its address. Note that `get_program_counter`
+    /// reports the same address, but only this tells it apart from the other reasons to halt.
+    #[must_use]
+    pub fn last_fault(&self) -> Option<FaultInfo> {
+        match self.halted {
+            Some(StepResult::IllegalInstruction(insn)) => Some(FaultInfo {
+                pc: self.program_counter,
+                insn,
+            }),
+            _ => None,
+        }
+    }
+
     #[must_use]
     pub fn get_instructions(&self) -> &Segment {
         &self.instructions
     }
 
+    /// Lets the host patch the program. Changes take effect with the next `step`, even at the current program
+    /// counter.
+    ///
+    /// If the instructions are shared, see `new_shared`, this copies them first.
+    #[must_use]
+    pub fn get_instructions_mut(&mut self) -> &mut Segment {
+        Arc::make_mut(&mut self.instructions)
+    }
+
     #[must_use]
     pub fn get_data(&self) -> &Segment {
         &self.data
@@ -175,227 +1317,741 @@ impl VirtualMachine {
     }
 
     pub fn set_data_word(&mut self, index: u16, value: u16) {
-        self.data[index] = value;
+        self.write_data_watched(index, value, None);
     }
 
-    pub fn step(&mut self) -> StepResult {
-        let instruction = self.instructions[self.program_counter];
-        let mut increment_pc_as_usual = true;
-        let step_result = match instruction & 0xF000 {
-            // 0x0000 illegal
-            0x1000 => self.step_special(instruction, &mut increment_pc_as_usual),
-            0x2000 => self.step_memory(instruction),
-            0x3000 => self.step_load_imm_low(instruction),
-            0x4000 => self.step_load_imm_high(instruction),
-            0x5000 => self.step_unary(instruction),
-            0x6000 => self.step_binary(instruction),
-            // 0x7000 illegal
-            0x8000 => self.step_compare(instruction),
-            0x9000 => self.step_branch(instruction, &mut increment_pc_as_usual),
-            0xA000 => {
-                increment_pc_as_usual = false;
-                self.step_jump_imm(instruction)
-            }
-            0xB000 => {
-                increment_pc_as_usual = false;
-                self.step_jump_reg(instruction)
-            }
-            // 0xC000, 0xD000, 0xE000, 0xF000 illegal
-            _ => {
-                increment_pc_as_usual = false;
-                StepResult::IllegalInstruction(instruction)
-            }
+    /// Overwrites a single instruction, see `get_instructions_mut`.
+    pub fn set_instruction_word(&mut self, index: u16, value: u16) {
+        self.get_instructions_mut()[index] = value;
+    }
+
+    /// Copies `len` words from the given segment of `src`, starting at `src_start`, into this VM's data segment,
+    /// starting at `dst`. Both ranges wrap around, see `Segment::copy_from`.
+    pub fn copy_data_from(
+        &mut self,
+        dst: u16,
+        src: &VirtualMachine,
+        src_kind: SegmentKind,
+        src_start: u16,
+        len: u16,
+    ) {
+        let src_segment = match src_kind {
+            SegmentKind::Data => &src.data,
+            SegmentKind::Instructions => &src.instructions,
+        };
+        self.data.copy_from(dst, src_segment, src_start, len);
+    }
+
+    /// Writes `words` to the data segment, starting at `dst`, see `Segment::copy_from_slice_at`. Wraps around from
+    /// 0xFFFF to 0x0000. Like `copy_data_from`, this does not produce watch hits.
+    pub fn copy_into_data(&mut self, dst: u16, words: &[u16]) {
+        self.data.copy_from_slice_at(dst, words);
+    }
+
+    /// Returns `len` words of the data segment, starting at `src`. Wraps around from 0xFFFF to 0x0000.
+    #[must_use]
+    pub fn read_data(&self, src: u16, len: u16) -> Vec<u16> {
+        let mut words = vec![0; len as usize];
+        self.data.read_into(src, &mut words);
+        words
+    }
+
+    /// Returns `len` words of the instruction segment, starting at `src`. Wraps around from 0xFFFF to 0x0000.
+    #[must_use]
+    pub fn read_instructions(&self, src: u16, len: u16) -> Vec<u16> {
+        let mut words = vec![0; len as usize];
+        self.instructions.read_into(src, &mut words);
+        words
+    }
+
+    /// Takes a copy of the data segment as it is now, so that `modified_data` can compare against it later. Calling
+    /// this again replaces the copy.
+    pub fn remember_initial_data(&mut self) {
+        self.initial_data = Some(Box::new(self.data.clone()));
+    }
+
+    /// Returns `(address, initial, current)` for each data word that differs from the copy taken by
+    /// `remember_initial_data`, in ascending order of the address, or nothing if there is no copy. A word that was
+    /// overwritten and later restored is not included, as this only compares the contents; see `enable_write_log`
+    /// for every address that was stored to.
+    #[must_use]
+    pub fn modified_data(&self) -> Vec<(u16, u16, u16)> {
+        self.initial_data
+            .as_ref()
+            .map_or_else(Vec::new, |initial| initial.diff(&self.data))
+    }
+
+    /// Puts the machine back into its initial state, as if freshly created with the same instructions and an
+    /// all-zero data segment: registers, program counter and data are zeroed, and the machine is no longer halted.
+    /// A sparse data segment stays sparse.
+    ///
+    /// With `keep_time`, `get_time`, `was_deterministic_so_far`, and `random_calls` keep describing everything the
+    /// machine has executed since its creation; otherwise they start over. Breakpoints, watched addresses, protected
+    /// addresses, the tracer, the debug-dump handler, the copy of `remember_initial_data`, the profile, the coverage,
+    /// the opcode stats, the recent program counters, the loop detection, the cost model, and the seeded generator of
+    /// `new_with_seed` are kept, as they belong to the host and not to the program. Zeroing the data does not produce
+    /// watch hits. The history of `step_back` is discarded.
+    pub fn reset(&mut self, keep_time: bool) {
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        self.registers = [0; 16];
+        self.program_counter = 0;
+        self.data = if self.data.is_sparse() {
+            Segment::new_sparse()
+        } else {
+            Segment::new_zeroed()
         };
-        if increment_pc_as_usual {
-            self.program_counter = self.program_counter.wrapping_add(1);
+        self.halted = None;
+        self.resuming_from_breakpoint = false;
+        if !keep_time {
+            self.time = 0;
+            self.deterministic_so_far = true;
+            self.random_calls = 0;
         }
-        match step_result {
-            StepResult::Continue | StepResult::DebugDump => {
-                self.time += 1;
+        self.last_debug_dump_time = self.time;
+    }
+
+    /// Makes the instructions of `extension` legal from now on, and advertises it through CPUID. This is a shorthand
+    /// for adding `extension.features()` with `set_features`.
+    pub fn enable_extension(&mut self, extension: Extension) {
+        self.features = self.features.with(extension.features());
+    }
+
+    #[must_use]
+    pub fn is_extension_enabled(&self, extension: Extension) -> bool {
+        self.features.contains(extension.features())
+    }
+
+    #[must_use]
+    pub fn get_features(&self) -> CpuFeatures {
+        self.features
+    }
+
+    /// Replaces the enabled features, including extensions, see `with_features`.
+    pub fn set_features(&mut self, features: CpuFeatures) {
+        self.features = features;
+    }
+
+    /// Makes `rnd` an illegal instruction, so that programs cannot be nondeterministic at all, instead of merely
+    /// being flagged by `was_deterministic_so_far`. CPUID no longer reports `CPUID_0_RND`, so programs can detect
+    /// this. This is the same as disabling `CpuFeatures::RND`.
+    pub fn set_forbid_random(&mut self, forbid: bool) {
+        self.features = if forbid {
+            self.features.without(CpuFeatures::RND)
+        } else {
+            self.features.with(CpuFeatures::RND)
+        };
+    }
+
+    #[must_use]
+    pub fn is_random_forbidden(&self) -> bool {
+        !self.features.contains(CpuFeatures::RND)
+    }
+
+    /// Makes `step` return `StepResult::Breakpoint` before executing the instruction at `pc`.
+    pub fn add_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.insert(pc);
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u16) {
+        self.breakpoints.remove(pc);
+    }
+
+    #[must_use]
+    pub fn has_breakpoint(&self, pc: u16) -> bool {
+        self.breakpoints.contains(pc)
+    }
+
+    /// Records every write to the data word at `address`, by the program or by `set_data_word`, see
+    /// `take_watch_hits`. Other host-side changes, like `copy_data_from`, are not recorded.
+    pub fn watch_data(&mut self, address: u16) {
+        self.watched_data.insert(address);
+    }
+
+    pub fn unwatch_data(&mut self, address: u16) {
+        self.watched_data.remove(address);
+    }
+
+    /// Sets the protection of every data address in `range`, e.g. to catch a program that overwrites what it should
+    /// only read. Only stores by the program are checked, so the host can still write through `set_data_word` and
+    /// the like.
+    pub fn protect_data(&mut self, range: RangeInclusive<u16>, protection: Protection) {
+        for address in range {
+            match protection {
+                Protection::ReadWrite => self.read_only_data.remove(address),
+                Protection::ReadOnly => self.read_only_data.insert(address),
             }
-            _ => {}
         }
+    }
 
-        step_result
+    #[must_use]
+    pub fn data_protection(&self, address: u16) -> Protection {
+        if self.read_only_data.contains(address) {
+            Protection::ReadOnly
+        } else {
+            Protection::ReadWrite
+        }
+    }
+
+    /// Returns all writes to watched addresses since the last call, in order. Hits accumulate until then, even
+    /// across runs.
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        std::mem::take(&mut self.watch_hits)
+    }
+
+    /// Records every instruction that changes the value of register `index`, whichever instruction it is, see
+    /// `take_register_watch_hits`. Writing the same value again is not a change, and neither are writes by the host,
+    /// e.g. through `set_register`. Panics if `index` is not a register.
+    pub fn watch_register(&mut self, index: u16) {
+        self.register_watch.watch(index);
+    }
+
+    pub fn unwatch_register(&mut self, index: u16) {
+        self.register_watch.unwatch(index);
+    }
+
+    /// Returns all changes of watched registers since the last call, in order. If an instruction changes several
+    /// watched registers, they are ordered by index.
+    pub fn take_register_watch_hits(&mut self) -> Vec<RegisterWatchHit> {
+        self.register_watch.take_hits()
+    }
+
+    /// Records every store by the program from now on: the most recent `capacity` stores in order, see
+    /// `take_write_log`, and every address ever stored to, see `dirty_addresses`. Writes by the host, e.g. through
+    /// `set_data_word`, `copy_data_from`, or `reset`, are not recorded.
+    ///
+    /// Calling this again changes the capacity, and keeps what was recorded so far.
+    pub fn enable_write_log(&mut self, capacity: usize) {
+        let log = self.write_log.get_or_insert_with(|| WriteLog {
+            capacity,
+            records: VecDeque::new(),
+            dirty: AddressSet::default(),
+        });
+        log.capacity = capacity;
+        while log.records.len() > capacity {
+            log.records.pop_front();
+        }
+    }
+
+    /// Returns the recorded stores since the last call, oldest first. If there were more than the capacity, only
+    /// the most recent ones are left.
+    pub fn take_write_log(&mut self) -> Vec<WriteRecord> {
+        match &mut self.write_log {
+            Some(log) => log.records.drain(..).collect(),
+            None => Vec::new(),
+        }
     }
 
-    fn step_special(&mut self, instruction: u16, increment_pc_as_usual: &mut bool) -> StepResult {
-        if instruction & 0x0F00 != 0x0000 {
-            return StepResult::IllegalInstruction(instruction);
+    /// Returns every address the program has stored to since `enable_write_log`, in ascending order. This is not
+    /// limited by the capacity, and includes stores that did not change the value.
+    #[must_use]
+    pub fn dirty_addresses(&self) -> Vec<u16> {
+        match &self.write_log {
+            Some(log) => log.dirty.iter().collect(),
+            None => Vec::new(),
         }
+    }
 
-        match instruction & 0x00FF {
-            0x2A => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102a-return
-                // Return
-                *increment_pc_as_usual = false;
-                StepResult::Return(self.registers[0])
+    fn write_data_watched(&mut self, address: u16, value: u16, pc: Option<u16>) {
+        if let (Some(log), Some(pc)) = (&mut self.write_log, pc) {
+            log.dirty.insert(address);
+            if log.capacity > 0 {
+                if log.records.len() == log.capacity {
+                    log.records.pop_front();
+                }
+                log.records.push_back(WriteRecord {
+                    time: self.time,
+                    pc,
+                    address,
+                    value,
+                });
             }
-            0x2B => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102b-cpuid
-                // CPUID
-                if self.registers[0] == 0x0000 {
-                    self.registers[0] = 0x8000; // TODO: binary instructions for exponentiation and roots
-                    self.registers[1] = 0x0000;
-                    self.registers[2] = 0x0000;
-                    self.registers[3] = 0x0000;
-                } else {
-                    self.registers[0] = 0x0000;
-                    self.registers[1] = 0x0000;
-                    self.registers[2] = 0x0000;
-                    self.registers[3] = 0x0000;
+        }
+        if self.watched_data.contains(address) {
+            self.watch_hits.push(WatchHit {
+                address,
+                old: self.data[address],
+                new: value,
+                pc,
+            });
+        }
+        self.data[address] = value;
+    }
+
+    /// Records from now on how to undo each executed instruction, so that `step_back` can undo up to the most recent
+    /// `capacity` of them. Each instruction costs about 80 bytes of memory, and nothing while this is disabled.
+    ///
+    /// Stepping forward again after `step_back` repeats exactly the same instructions only if the program cannot
+    /// observe anything outside of the VM: `rnd` must draw from the seeded generator of `new_with_seed`, not from a
+    /// `RandomSource` or the operating system, and no MMIO handler may be attached. Otherwise the replay may differ.
+    ///
+    /// Calling this again changes the capacity, and keeps what was recorded so far.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history
+            .get_or_insert_with(|| History::new(capacity))
+            .set_capacity(capacity);
+    }
+
+    /// Returns how many instructions `step_back` can currently undo.
+    #[must_use]
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, History::len)
+    }
+
+    /// Undoes the most recently executed instruction, including one that halted the machine, see `enable_history`.
+    /// Returns false if there is nothing left to undo.
+    ///
+    /// This restores the registers, program counter, time, `random_calls`, `steps_since_last_debug_dump`, memory, and
+    /// the seeded generator of `new_with_seed`, so that stepping forward again repeats the same instructions, see
+    /// `enable_history` for the limits. Neither a `RandomSource` nor an MMIO device is rewound. What the host observed
+    /// is not undone: the profile, the coverage, the opcode stats, the recent program counters, the write log, the
+    /// random trace, and watch hits keep their entries. Changes by the host in between, e.g. through `set_data_word`,
+    /// are not undone either, unless the undone instruction stored to the same address.
+    pub fn step_back(&mut self) -> bool {
+        let Some(record) = self.history.as_mut().and_then(History::pop) else {
+            return false;
+        };
+        self.undo(record);
+        true
+    }
+
+    /// Counts from now on how often the instruction at each address is executed, see `profile`. Instructions that
+    /// halt the machine are not counted, just like they do not advance the time. Calling this again keeps the
+    /// counts.
+    pub fn enable_profiling(&mut self) {
+        self.profile.get_or_insert_with(Default::default);
+    }
+
+    /// Returns the execution count of each address, or `None` if profiling was never enabled.
+    #[must_use]
+    pub fn profile(&self) -> Option<&[u64; 1 << 16]> {
+        self.profile.as_ref().map(Profile::counts)
+    }
+
+    /// Returns up to `n` addresses with the highest nonzero execution counts, hottest first. Ties are ordered by
+    /// address.
+    #[must_use]
+    pub fn hottest(&self, n: usize) -> Vec<(u16, u64)> {
+        self.profile
+            .as_ref()
+            .map_or_else(Vec::new, |profile| profile.hottest(n))
+    }
+
+    /// Records from now on which addresses are executed at least once, see `coverage`. Unlike the profile, this also
+    /// includes an instruction that halts the machine. Calling this again keeps what was recorded so far.
+    pub fn enable_coverage(&mut self) {
+        self.coverage.get_or_insert_with(AddressSet::default);
+    }
+
+    /// Returns the addresses executed so far, or `None` if coverage was never enabled.
+    #[must_use]
+    pub fn coverage(&self) -> Option<CoverageReport> {
+        let coverage = self.coverage.as_ref()?;
+        Some(CoverageReport::from_addresses(coverage.iter().collect()))
+    }
+
+    /// Counts from now on how many instructions of each opcode family are executed, see `opcode_stats`. Like the
+    /// profile, this only counts instructions that advance the time. Calling this again keeps the counts so far.
+    pub fn enable_opcode_stats(&mut self) {
+        self.opcode_stats.get_or_insert_with(Default::default);
+    }
+
+    /// Returns the counts so far, or `None` if opcode stats were never enabled.
+    #[must_use]
+    pub fn opcode_stats(&self) -> Option<&OpcodeStats> {
+        self.opcode_stats.as_deref()
+    }
+
+    /// Remembers from now on the addresses of the last `RECENT_PCS_LEN` (32) instructions, see `recent_pcs`. This
+    /// includes an illegal instruction, so the list shows how the program got there. Calling this again keeps the
+    /// addresses so far.
+    pub fn enable_recent_pcs(&mut self) {
+        self.recent_pcs.get_or_insert_with(Default::default);
+    }
+
+    /// Returns the addresses of the last instructions, oldest first, or nothing if not enabled.
+    #[must_use]
+    pub fn recent_pcs(&self) -> &[u16] {
+        self.recent_pcs
+            .as_deref()
+            .map_or(&[], |recent_pcs| recent_pcs.as_slice())
+    }
+
+    /// Makes `run` and its variants stop with `StopReason::LoopDetected` once the machine is in exactly the same
+    /// state as some instructions earlier within the same call, because then it would repeat them forever. The state
+    /// is the program counter, the registers, and both segments, but not the time, so a program that looks at the
+    /// time or draws randomness is not considered stuck. This is off while an MMIO handler is attached, as the
+    /// device may change what the program sees.
+    ///
+    /// A loop is noticed within about twice the instructions that led into it plus three times its period. Checking
+    /// costs about as much as executing a few instructions, for every executed instruction.
+    pub fn enable_loop_detection(&mut self) {
+        self.loop_detector.get_or_insert_with(Default::default);
+    }
+
+    /// Called by `run_stepping` at the start of every run, so that changes by the host in between do not matter.
+    pub(crate) fn restart_loop_detection(&mut self) {
+        if let Some(loop_detector) = &mut self.loop_detector {
+            loop_detector.restart();
+        }
+    }
+
+    /// Called by `run_stepping` after every executed instruction, see `enable_loop_detection`.
+    pub(crate) fn check_for_loop(&mut self) -> Option<u64> {
+        if self.mmio.is_some() {
+            return None;
+        }
+        let loop_detector = self.loop_detector.as_mut()?;
+        let mut hash = Fnv1a::new();
+        for register in self.registers {
+            hash.write_u16(register);
+        }
+        hash.write_u16(self.program_counter);
+        loop_detector.check(hash.finish())
+    }
+
+    /// Calls `tracer` on every step from now on, or stops tracing if `None`.
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Calls `handler` whenever the program executes Debug-dump, or does nothing on Debug-dump if `None`, which is
+    /// the default. The handler sees the VM right before the instruction completes, so the program counter still
+    /// points at it.
+    pub fn set_debug_dump_handler(&mut self, handler: Option<DebugDumpHandler>) {
+        self.debug_dump_handler = handler;
+    }
+
+    /// Executes a single instruction.
+    ///
+    /// Once the machine has halted (by an illegal instruction, by returning, because `rnd` could not obtain
+    /// entropy, or by a protection fault), any further call does nothing and returns the same result again. In particular, registers,
+    /// program counter, and time remain unchanged.
+    ///
+    /// If there is a breakpoint at the program counter, this first returns `StepResult::Breakpoint` without
+    /// executing anything, and the next call executes the instruction.
+    pub fn step(&mut self) -> StepResult {
+        let Some(tracer) = self.tracer.take() else {
+            return self.step_untraced();
+        };
+        let pc = self.program_counter;
+        let instruction = self.instructions[pc];
+        let registers = self.registers;
+        let result = self.step_untraced();
+        tracer.call(TraceEvent {
+            pc,
+            instruction,
+            registers,
+            result,
+        });
+        self.tracer = Some(tracer);
+        result
+    }
+
+    fn step_untraced(&mut self) -> StepResult {
+        if let Some(step_result) = self.halted {
+            return step_result;
+        }
+        let resuming_from_breakpoint = self.resuming_from_breakpoint;
+        if self.resuming_from_breakpoint {
+            self.resuming_from_breakpoint = false;
+        } else if self.has_breakpoint(self.program_counter) {
+            self.resuming_from_breakpoint = true;
+            return StepResult::Breakpoint(self.program_counter);
+        }
+        let pc = self.program_counter;
+        let instruction = self.instructions[pc];
+        if let Some(coverage) = &mut self.coverage {
+            coverage.insert(pc);
+        }
+        if let Some(recent_pcs) = &mut self.recent_pcs {
+            recent_pcs.push(pc);
+        }
+        let undo = self
+            .history
+            .is_some()
+            .then(|| self.undo_record(instruction, resuming_from_breakpoint));
+        // Comparing afterwards catches every handler, no matter how it writes the registers.
+        let registers_before = self
+            .register_watch
+            .is_active()
+            .then_some((self.registers, self.time));
+        let mut increment_pc_as_usual = true;
+        let op = &DISPATCH[(instruction >> 8) as usize];
+        let step_result = if self.features.contains(op.features) {
+            (op.handler)(self, instruction, &mut increment_pc_as_usual)
+        } else {
+            StepResult::IllegalInstruction(instruction)
+        };
+        if let Some((registers, time)) = registers_before {
+            self.register_watch
+                .record(&registers, &self.registers, time, pc);
+        }
+        if step_result == StepResult::DebugDump {
+            if let Some(handler) = self.debug_dump_handler.take() {
+                handler.call(self);
+                self.debug_dump_handler = Some(handler);
+            }
+        }
+        match step_result {
+            StepResult::Continue | StepResult::DebugDump => {
+                if let Some(profile) = &mut self.profile {
+                    profile.record(pc);
+                }
+                if let Some(opcode_stats) = &mut self.opcode_stats {
+                    // Only a taken branch moves the program counter by itself.
+                    opcode_stats.record(instruction, !increment_pc_as_usual);
+                }
+                if increment_pc_as_usual {
+                    self.program_counter = self.program_counter.wrapping_add(1);
+                }
+                self.time = self.time.wrapping_add(match &self.cost_model {
+                    None => 1,
+                    Some(cost_model) => cost_model.cost_of(instruction) as u64,
+                });
+                if step_result == StepResult::DebugDump {
+                    self.last_debug_dump_time = self.time;
                 }
-                StepResult::Continue
             }
-            0x2C => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102c-debug-dump
-                // Debug-dump
-                StepResult::DebugDump
+            StepResult::IllegalInstruction(_)
+            | StepResult::Return(_)
+            | StepResult::RandomnessUnavailable
+            | StepResult::ProtectionFault { .. } => {
+                // The program counter keeps pointing at the offending instruction, no matter which part of the
+                // instruction space it came from.
+                self.halted = Some(step_result);
             }
-            0x2D => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102d-time
-                // Time
-                self.registers[0] = (self.time >> 48) as u16;
-                self.registers[1] = (self.time >> 32) as u16;
-                self.registers[2] = (self.time >> 16) as u16;
-                self.registers[3] = self.time as u16;
-                StepResult::Continue
+            StepResult::Breakpoint(_) => {
+                // Only ever returned above, before executing anything.
             }
-            _ => StepResult::IllegalInstruction(instruction),
         }
+        if let (Some(history), Some(undo)) = (&mut self.history, undo) {
+            history.push(undo);
+        }
+
+        step_result
+    }
+
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102b-cpuid
+    // Leaf 0 reports the enabled features, leaf 1 all features this VM knows in the same layout, i.e. which of the
+    // missing ones were disabled on purpose. Leaves 2 to 7 are reserved, and like all other leaves report zeros.
+    fn step_cpuid(&mut self) -> StepResult {
+        let leaf = self.registers[0];
+        self.registers[0] = match leaf {
+            0 => 0x8000 | self.features.bits(),
+            1 => 0x8000 | CpuFeatures::ALL.bits(),
+            _ => 0x0000,
+        };
+        self.registers[1] = 0x0000;
+        self.registers[2] = 0x0000;
+        self.registers[3] = 0x0000;
+        StepResult::Continue
+    }
+
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102d-time
+    fn step_time(&mut self) -> StepResult {
+        if let Some(loop_detector) = &mut self.loop_detector {
+            loop_detector.forget();
+        }
+        self.registers[0] = (self.time >> 48) as u16;
+        self.registers[1] = (self.time >> 32) as u16;
+        self.registers[2] = (self.time >> 16) as u16;
+        self.registers[3] = self.time as u16;
+        StepResult::Continue
+    }
+
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x20xx-store-word-data
+    fn step_store_data(&mut self, address_reg: u16, data_reg: u16) -> StepResult {
+        let address = self.registers[address_reg as usize];
+        self.store_data(address, self.registers[data_reg as usize])
+    }
+
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x21xx-load-word-data
+    fn step_load_data(&mut self, address_reg: u16, data_reg: u16) -> StepResult {
+        let address = self.registers[address_reg as usize];
+        self.registers[data_reg as usize] = self.load_data(address);
+        StepResult::Continue
+    }
+
+    // Only with `CpuFeatures::MEMORY_OFFSET`. Behaves exactly like 0x20xx and 0x21xx, just at a different address.
+    fn step_memory_offset(
+        &mut self,
+        store: bool,
+        offset: u16,
+        address_reg: u16,
+        data_reg: u16,
+    ) -> StepResult {
+        let address = self.registers[address_reg as usize].wrapping_add(offset);
+        if store {
+            self.store_data(address, self.registers[data_reg as usize])
+        } else {
+            self.registers[data_reg as usize] = self.load_data(address);
+            StepResult::Continue
+        }
+    }
+
+    /// A store by the program, which may go to the MMIO handler instead of the data segment, or fault if the address
+    /// is read-only.
+    fn store_data(&mut self, address: u16, value: u16) -> StepResult {
+        if self.read_only_data.contains(address) {
+            return StepResult::ProtectionFault {
+                addr: address,
+                pc: self.program_counter,
+            };
+        }
+        if self
+            .mmio
+            .as_ref()
+            .is_some_and(|mmio| mmio.store(address, value))
+        {
+            return StepResult::Continue;
+        }
+        if let Some(loop_detector) = &mut self.loop_detector {
+            loop_detector.record_store(false, address, self.data[address], value);
+        }
+        self.write_data_watched(address, value, Some(self.program_counter));
+        StepResult::Continue
     }
 
-    fn step_memory(&mut self, instruction: u16) -> StepResult {
-        let memory_command = (instruction & 0x0F00) >> 8;
-        let register_address = (instruction & 0x00F0) >> 4;
-        let register_data = instruction & 0x000F;
-        let address = self.registers[register_address as usize];
-        let value_in_register = &mut self.registers[register_data as usize];
+    /// A load by the program, which may come from the MMIO handler instead of the data segment.
+    fn load_data(&self, address: u16) -> u16 {
+        let mapped = self.mmio.as_ref().and_then(|mmio| mmio.load(address));
+        mapped.unwrap_or(self.data[address])
+    }
 
-        match memory_command {
-            0 => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x20xx-store-word-data
-                // Store word data
-                self.data[address] = *value_in_register;
-                StepResult::Continue
-            }
-            1 => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x21xx-load-word-data
-                // Load word data
-                *value_in_register = self.data[address];
-                StepResult::Continue
-            }
-            2 => {
-                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x22xx-load-word-instruction
-                // Load word instruction
-                *value_in_register = self.instructions[address];
-                StepResult::Continue
-            }
-            _ => StepResult::IllegalInstruction(instruction),
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x22xx-load-word-instruction
+    fn step_load_instruction(&mut self, address_reg: u16, data_reg: u16) -> StepResult {
+        let address = self.registers[address_reg as usize];
+        self.registers[data_reg as usize] = self.instructions[address];
+        StepResult::Continue
+    }
+
+    // Only with `CpuFeatures::STORE_INSTRUCTION`. The next step decodes the instruction afresh, so this even works for
+    // the instruction at the program counter.
+    fn step_store_instruction(&mut self, address_reg: u16, data_reg: u16) -> StepResult {
+        let address = self.registers[address_reg as usize];
+        let value = self.registers[data_reg as usize];
+        if let Some(loop_detector) = &mut self.loop_detector {
+            loop_detector.record_store(true, address, self.instructions[address], value);
         }
+        self.get_instructions_mut()[address] = value;
+        StepResult::Continue
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x3xxx-load-immediate-low-sign-extended
-    fn step_load_imm_low(&mut self, instruction: u16) -> StepResult {
-        let register = (instruction & 0x0F00) >> 8;
-        let data = (instruction & 0x00FF) as i8 as i16 as u16; // sign-extend to 16 bits
+    fn step_load_imm_low(&mut self, register: u16, value: u16) -> StepResult {
+        let data = value as u8 as i8 as i16 as u16; // sign-extend to 16 bits
         self.registers[register as usize] = data;
         StepResult::Continue
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x4xxx-load-immediate-high-only-high-byte
-    fn step_load_imm_high(&mut self, instruction: u16) -> StepResult {
-        let register_index = (instruction & 0x0F00) >> 8;
+    fn step_load_imm_high(&mut self, register_index: u16, value: u16) -> StepResult {
         let register = &mut self.registers[register_index as usize];
-        let data = (instruction & 0x00FF) << 8;
+        let data = value << 8;
         *register &= 0x00FF;
         *register |= data;
         StepResult::Continue
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x5xxx-unary-functions
-    fn step_unary(&mut self, instruction: u16) -> StepResult {
-        let function = (instruction & 0x0F00) >> 8;
-        let source = self.registers[((instruction & 0x00F0) >> 4) as usize];
-        let destination = &mut self.registers[(instruction & 0x000F) as usize];
+    fn step_unary(&mut self, function: UnaryFunction, source: u16, destination: u16) -> StepResult {
+        let source = self.registers[source as usize];
+        let destination = &mut self.registers[destination as usize];
 
         match function {
-            0b1000 => {
+            UnaryFunction::Decr => {
                 // * If FFFF=1000, the computed function is "decr" (add 1), e.g. decr(41) = 40
                 *destination = source.wrapping_sub(1);
             }
-            0b1001 => {
+            UnaryFunction::Incr => {
                 // * If FFFF=1001, the computed function is "incr" (subtract 1), e.g. incr(41) = 42
                 *destination = source.wrapping_add(1);
             }
-            0b1010 => {
+            UnaryFunction::Not => {
                 // * If FFFF=1010, the computed function is "not" (bite-wise logical negation), e.g. not(0x1234) = 0xEDCB
                 *destination = !source;
             }
-            0b1011 => {
+            UnaryFunction::Popcnt => {
                 // * If FFFF=1011, the computed function is "popcnt" (population count), e.g. popcnt(0xFFFF) = 16, popcnt(0x0000) = 0
                 //     * Note that there are no silly exceptions as there would be in x86.
                 *destination = source.count_ones() as u16;
             }
-            0b1100 => {
+            UnaryFunction::Clz => {
                 // * If FFFF=1100, the computed function is "clz" (count leading zeros), e.g. clz(0x8000) = 0, clz(0x0002) = 14
                 *destination = source.leading_zeros() as u16;
             }
-            0b1101 => {
+            UnaryFunction::Ctz => {
                 // * If FFFF=1101, the computed function is "ctz" (count trailing zeros), e.g. ctz(0x8000) = 15, ctz(0x0002) = 1
                 *destination = source.trailing_zeros() as u16;
             }
-            0b1110 => {
+            UnaryFunction::Rnd => {
                 // * If FFFF=1110, the computed function is "rnd" (random number up to AND INCLUDING), e.g. rnd(5) = 3, rnd(5) = 5, rnd(5) = 0
                 //     * Note that rnd must never result in a value larger than the argument, so rnd(5) must never generate 6 or even 0xFFFF.
-                *destination = random_upto_including(source);
+                let Some(value) = random_upto_including(&self.random_source, &mut self.rng, source)
+                else {
+                    return StepResult::RandomnessUnavailable;
+                };
+                *destination = value;
+                self.random_calls += 1;
+                if source != 0 {
+                    self.deterministic_so_far = false;
+                    if let Some(loop_detector) = &mut self.loop_detector {
+                        loop_detector.forget();
+                    }
+                    if let Some(trace) = &mut self.random_trace {
+                        trace.push(value);
+                    }
+                }
             }
-            0b1111 => {
+            UnaryFunction::Mov => {
                 // * If FFFF=1111, the computed function is "mov" (move, identity function), e.g. mov(0x5678) = 0x5678
                 *destination = source;
             }
-            _ => {
-                return StepResult::IllegalInstruction(instruction);
-            }
         }
 
         StepResult::Continue
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x6xxx-basic-binary-functions
-    fn step_binary(&mut self, instruction: u16) -> StepResult {
-        let function = (instruction & 0x0F00) >> 8;
-        let source = self.registers[((instruction & 0x00F0) >> 4) as usize];
-        let destination = &mut self.registers[(instruction & 0x000F) as usize];
+    fn step_binary(
+        &mut self,
+        function: BinaryFunction,
+        source: u16,
+        destination: u16,
+    ) -> StepResult {
+        let source = self.registers[source as usize];
+        let destination = &mut self.registers[destination as usize];
 
         match function {
-            0b0000 => {
+            BinaryFunction::Add => {
                 // * If FFFF=0000, the computed function is "add" (overflowing addition), e.g. fn(0x1234, 0xABCD) = 0xBE01
                 //     * Note that there is no need to distinguish signedness, as the results would always bit-identical.
                 *destination = source.wrapping_add(*destination);
             }
-            0b0001 => {
+            BinaryFunction::Sub => {
                 // * If FFFF=0001, the computed function is "sub" (overflowing subtraction), e.g. fn(0xBE01, 0xABCD) = 0x1234, fn(0x0007, 0x0009) = 0xFFFE
                 //     * Note that there is no need to distinguish signedness, as the results would always bit-identical.
                 *destination = source.wrapping_sub(*destination);
             }
-            0b0010 => {
+            BinaryFunction::Mul => {
                 // * If FFFF=0010, the computed function is "mul" (truncated multiplication, low word), e.g. fn(0x0005, 0x0007) = 0x0023, fn(0x1234, 0xABCD) = 0x4FA4
                 //     * Note that there is no need to distinguish signedness, as the results would always bit-identical.
                 *destination = source.wrapping_mul(*destination);
             }
-            0b0011 => {
+            BinaryFunction::Mulh => {
                 // * If FFFF=0011, the computed function is "mulh" (truncated multiplication, high word), e.g. fn(0x0005, 0x0007) = 0x0000, fn(0x1234, 0xABCD) = 0x0C37
                 //     * Note that there is no signed equivalent.
                 let result = (source as u32) * (*destination as u32);
                 *destination = (result >> 16) as u16;
             }
-            0b0100 => {
+            BinaryFunction::DivU => {
                 // * If FFFF=0100, the computed function is "div.u" (unsigned division, rounded towards 0), e.g. fn(0x0023, 0x0007) = 0x0005, fn(0xABCD, 0x1234) = 0x0009
                 //     * The result of dividing by zero is 0xFFFF, the highest unsigned value.
                 *destination = source.checked_div(*destination).unwrap_or(0xFFFF);
             }
-            0b0101 => {
+            BinaryFunction::DivS => {
                 // * If FFFF=0101, the computed function is "div.s" (signed division, rounded towards 0), e.g. fn(0x0023, 0x0007) = 0x0005, fn(0xABCD, 0x1234) = 0xFFFC
                 //     * The result of dividing by zero is 0x7FFF, the highest signed value.
                 //     * We define fn(0x8000, 0xFFFF) = 0x8000.
@@ -406,13 +2062,13 @@ impl VirtualMachine {
                     *destination = (source as i16).wrapping_div(*destination as i16) as u16;
                 }
             }
-            0b0110 => {
+            BinaryFunction::ModU => {
                 // * If FFFF=0110, the computed function is "mod.u" (unsigned modulo), e.g. fn(0x0023, 0x0007) = 0x0000, fn(0xABCD, 0x1234) = 0x07F9
                 //     * The result of modulo by zero is 0x0000.
                 //     * Note that if x = div.u(a, b) and y = mod.u(a, b), then add(mul(x, b), y) will usually result in a.
                 *destination = source.checked_rem(*destination).unwrap_or(0x0000);
             }
-            0b0111 => {
+            BinaryFunction::ModS => {
                 // * If FFFF=0111, the computed function is "mod.s" (signed modulo), e.g. fn(0x0023, 0x0007) = 0x0000, fn(0xABCD, 0x1234) = 0x06D1
                 //     * The result of modulo by zero is 0x0000.
                 //     * Note that if x = div.s(a, b) and y = mod.s(a, b), then add(mul(x, b), y) will usually result in a.
@@ -420,19 +2076,19 @@ impl VirtualMachine {
                     .checked_rem(*destination as i16)
                     .unwrap_or(0x0000) as u16;
             }
-            0b1000 => {
+            BinaryFunction::And => {
                 // * If FFFF=1000, the computed function is "and" (bitwise and), e.g. fn(0x5500, 0x5050) = 0x5000
                 *destination &= source;
             }
-            0b1001 => {
+            BinaryFunction::Or => {
                 // * If FFFF=1001, the computed function is "or" (bitwise inclusive or), e.g. fn(0x5500, 0x5050) = 0x5550
                 *destination |= source;
             }
-            0b1010 => {
+            BinaryFunction::Xor => {
                 // * If FFFF=1010, the computed function is "xor" (bitwise exclusive or), e.g. fn(0x5500, 0x5050) = 0x0550
                 *destination ^= source;
             }
-            0b1011 => {
+            BinaryFunction::Sl => {
                 // * If FFFF=1011, the computed function is "sl" (bitshift left, filling the least-significant bits with zero), e.g. fn(0x1234, 0x0001) = 0x2468, fn(0xFFFF, 0x0010) = 0x0000
                 //     * Note that there are no silly exceptions as there would be in x86.
 
@@ -443,7 +2099,7 @@ impl VirtualMachine {
                     *destination = source.wrapping_shl(*destination as u32);
                 }
             }
-            0b1100 => {
+            BinaryFunction::Srl => {
                 // * If FFFF=1100, the computed function is "srl" (logical bitshift right, filling the most significant bits with zero), e.g. fn(0x2468, 0x0001) = 0x1234, fn(0xFFFF, 0x0010) = 0x0000
 
                 // '>>' would shift by (*destination & 0xF), which is not what we want. Therefore, do it manually:
@@ -453,7 +2109,7 @@ impl VirtualMachine {
                     *destination = source.wrapping_shr(*destination as u32);
                 }
             }
-            0b1101 => {
+            BinaryFunction::Sra => {
                 // * If FFFF=1101, the computed function is "sra" (arithmetic bitshift right, filling the most significant bits with the sign-bit), e.g. fn(0x2468, 0x0001) = 0x1234, fn(0xFFFF, 0x0010) = 0xFFFF
 
                 // '>>' would shift by (*destination & 0xF), which is not what we want. Therefore, do it manually:
@@ -463,48 +2119,51 @@ impl VirtualMachine {
                     *destination = (source as i16).wrapping_shr(*destination as u32) as u16;
                 }
             }
-            _ => {
-                return StepResult::IllegalInstruction(instruction);
+            BinaryFunction::Exp => {
+                // * If FFFF=1110, the computed function may be "exp" (signed exponentiation according to IEEE754 double-precision arithmetic, then rounded to the nearest integer, clamped between 0x8000 (-32768) and 0x7FFF (+32767)), e.g. fn(0x0003, 0x0005) = 0x00F3, fn(0xFFFF, 0x0002) = 0x0001
+                //     * If the result is positive or negative Infinity, it is clamped accordingly.
+                let result = (source as i16 as f64).powi(*destination as i16 as i32);
+                *destination = round_and_clamp(result);
+            }
+            BinaryFunction::Root => {
+                // * If FFFF=1111, the computed function may be "root" (signed root according to IEEE754 double-precision arithmetic, then rounded to the nearest integer), e.g. fn(0x0009, 0x0002) = 0x0003, fn(0x0900, 0x0002) = 0x0030, fn(0x00F3, 0x0005) = 0x0003, fn(0x0002, 0x0002) = 0x0001, fn(0x1234, 0x0000) = 0x0001
+                //     * If the result is NaN, the written value may be arbitrary.
+                *destination = round_and_clamp(signed_root(source as i16, *destination as i16));
             }
         }
 
         StepResult::Continue
     }
 
-    fn step_compare(&mut self, instruction: u16) -> StepResult {
-        let flag_l = (instruction & 0x0800) != 0;
-        let flag_e = (instruction & 0x0400) != 0;
-        let flag_g = (instruction & 0x0200) != 0;
-        let flag_s = (instruction & 0x0100) != 0;
-        let register_lhs = ((instruction & 0x00F0) >> 4) as usize;
-        let register_rhs = (instruction & 0x000F) as usize;
-
-        let (lhs, rhs) = if flag_s {
-            // Sign-extend
-            (
-                self.registers[register_lhs] as i16 as i32,
-                self.registers[register_rhs] as i16 as i32,
-            )
-        } else {
-            // Zero-extend
-            (
-                self.registers[register_lhs] as u32 as i32,
-                self.registers[register_rhs] as u32 as i32,
-            )
-        };
+    fn step_compare(&mut self, flags: u16, register_lhs: u16, register_rhs: u16) -> StepResult {
+        let register_lhs = register_lhs as usize;
+        let register_rhs = register_rhs as usize;
+        self.registers[register_rhs] = compare_with_flags(
+            flags,
+            self.registers[register_lhs],
+            self.registers[register_rhs],
+        ) as u16;
+        StepResult::Continue
+    }
 
-        self.registers[register_rhs] =
-            ((flag_l && lhs < rhs) || (flag_e && lhs == rhs) || (flag_g && lhs > rhs)) as u16;
+    // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x11xx-compare-to-zero
+    fn step_compare_zero(&mut self, flags: u16, register: u16) -> StepResult {
+        let register = register as usize;
+        self.registers[register] = compare_with_flags(flags, self.registers[register], 0) as u16;
         StepResult::Continue
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x9xxx-branch
-    fn step_branch(&mut self, instruction: u16, increment_pc_as_usual: &mut bool) -> StepResult {
-        let register = (instruction & 0x0F00) >> 8;
+    fn step_branch(
+        &mut self,
+        register: u16,
+        offset_bits: u16,
+        increment_pc_as_usual: &mut bool,
+    ) -> StepResult {
         if self.registers[register as usize] != 0 {
             *increment_pc_as_usual = false;
-            let offset = (instruction & 0x007F) as i8 as i16 as u16; // sign-extend to 16 bits
-            let sign_bit = instruction & 0x0080;
+            let offset = offset_bits & 0x007F;
+            let sign_bit = offset_bits & 0x0080;
             if sign_bit == 0 {
                 // - If S=0, the program counter is not incremented by 1 as usual, but rather incremented by 2 + 0b0VVVVVVV.
                 self.program_counter = self.program_counter.wrapping_add(2 + offset);
@@ -517,9 +2176,9 @@ impl VirtualMachine {
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0xaxxx-jump-by-immediate
-    fn step_jump_imm(&mut self, instruction: u16) -> StepResult {
-        let offset = instruction & 0x07FF;
-        let sign_bit = instruction & 0x0800;
+    fn step_jump_imm(&mut self, offset_bits: u16) -> StepResult {
+        let offset = offset_bits & 0x07FF;
+        let sign_bit = offset_bits & 0x0800;
         if sign_bit == 0 {
             // - If S=0, the program counter is not incremented by 1 as usual, but rather incremented by 2 + 0b0000 0VVV VVVV VVVV.
             self.program_counter = self.program_counter.wrapping_add(2 + offset);
@@ -531,10 +2190,1383 @@ impl VirtualMachine {
     }
 
     // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0xbxxx-jump-to-register
-    fn step_jump_reg(&mut self, instruction: u16) -> StepResult {
-        let register = (instruction & 0x0F00) >> 8;
-        let offset = (instruction & 0x00FF) as i8 as i16 as u16; // sign-extend to 16 bits
+    fn step_jump_reg(&mut self, register: u16, offset: u16) -> StepResult {
+        let offset = offset as u8 as i8 as i16 as u16; // sign-extend to 16 bits
         self.program_counter = self.registers[register as usize].wrapping_add(offset);
         StepResult::Continue
     }
 }
+
+#[cfg(test)]
+mod test_segment {
+    use super::*;
+    use crate::selftest::PROGRAMS;
+
+    #[test]
+    fn test_hexdump_zeroed() {
+        let expected = "0000: 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                        *\n\
+                        FFF8: 0000 0000 0000 0000 0000 0000 0000 0000\n";
+        assert_eq!(Segment::new_zeroed().to_string(), expected);
+    }
+
+    #[test]
+    fn test_hexdump_crafted() {
+        let mut segment = Segment::new_zeroed();
+        segment[0x0000] = 0x102A;
+        segment[0x0009] = 0xABCD;
+        // Two identical lines in a row, the second one is collapsed.
+        for i in 0x0020..0x0030 {
+            segment[i] = 0x1111;
+        }
+        segment[0x0207] = 0xBEEF;
+        segment[0xFFFF] = 0xFFFF;
+        let expected = "0000: 102A 0000 0000 0000 0000 0000 0000 0000\n\
+                        0008: 0000 ABCD 0000 0000 0000 0000 0000 0000\n\
+                        0010: 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                        *\n\
+                        0020: 1111 1111 1111 1111 1111 1111 1111 1111\n\
+                        *\n\
+                        0030: 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                        *\n\
+                        0200: 0000 0000 0000 0000 0000 0000 0000 BEEF\n\
+                        0208: 0000 0000 0000 0000 0000 0000 0000 0000\n\
+                        *\n\
+                        FFF8: 0000 0000 0000 0000 0000 0000 0000 FFFF\n";
+        assert_eq!(segment.to_string(), expected);
+        // The representation does not matter.
+        assert_eq!(segment.to_sparse().to_string(), expected);
+    }
+
+    #[test]
+    fn test_fingerprint_golden() {
+        let fingerprints = PROGRAMS
+            .iter()
+            .map(|program| {
+                let mut segment = Segment::new_sparse();
+                segment.copy_from_slice_at(0, program.instructions);
+                (program.name, format!("{:016X}", segment.fingerprint()))
+            })
+            .collect::<Vec<_>>();
+        // If this fails, the fingerprint changed, which breaks everyone who stored one.
+        assert_eq!(
+            fingerprints,
+            vec![
+                ("fibonacci", "F71568FD8A6FA9C8".to_string()),
+                ("compare", "F52B9402BA2C0F64".to_string()),
+                ("unary", "756543BAD647498D".to_string()),
+                ("binary", "05AA00209F5B923A".to_string()),
+                ("return", "83FB94FBE416726D".to_string()),
+                ("rnd-bounds", "D30C4344F49E75FF".to_string()),
+            ]
+        );
+    }
+
+    #[cfg(feature = "sha256")]
+    #[test]
+    fn test_sha256() {
+        fn hex(digest: [u8; 32]) -> String {
+            digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+        }
+        assert_eq!(
+            hex(Segment::new_sparse().sha256()),
+            "fa43239bcee7b97ca62f007cc68487560a39e19f74f3dde7486db3f98df8e471"
+        );
+        let fibonacci = Segment::from_prefix(crate::selftest::FIBONACCI.instructions);
+        assert_eq!(
+            hex(fibonacci.sha256()),
+            "bf1e62790b1ae96e6ae2642aebe8e6d2d94b135c3f216613aca5b0e794e3289b"
+        );
+    }
+
+    #[test]
+    fn test_hash() {
+        fn hash_of(segment: &Segment) -> u64 {
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            segment.hash(&mut hasher);
+            hasher.finish()
+        }
+        let mut dense = Segment::new_zeroed();
+        dense[0x1234] = 0x5678;
+        let sparse = dense.to_sparse();
+        assert_eq!(dense, sparse);
+        assert_eq!(hash_of(&dense), hash_of(&sparse));
+        let mut other = dense.clone();
+        other[0xFFFF] = 1;
+        assert_ne!(hash_of(&dense), hash_of(&other));
+        let set = [dense, sparse, other]
+            .into_iter()
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn test_try_from_prefix() {
+        let segment = Segment::try_from_prefix(&[0x1234, 0x5678]).unwrap();
+        assert!(!segment.is_sparse());
+        assert_eq!(segment.used_len(), 2);
+        assert_eq!(segment[0x0001], 0x5678);
+        assert_eq!(Segment::from_prefix(&[]), Segment::new_zeroed());
+
+        let mut words = vec![0; 1 << 16];
+        words[0xFFFF] = 0xABCD;
+        let segment = Segment::try_from(&words[..]).unwrap();
+        assert_eq!(segment[0xFFFF], 0xABCD);
+        assert_eq!(segment, Segment::from_prefix(&words));
+
+        words.push(0);
+        let err = Segment::try_from(&words[..]).unwrap_err();
+        assert_eq!(err, SegmentError::TooLong { actual: 65537 });
+        assert_eq!(
+            err.to_string(),
+            "Too many words for a segment, expected at most 65536, got 65537 instead."
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "got 65537 instead")]
+    fn test_from_prefix_too_long() {
+        let _ = Segment::from_prefix(&[0; (1 << 16) + 1]);
+    }
+
+    #[test]
+    fn test_iter_nonzero() {
+        for mut segment in [Segment::new_zeroed(), Segment::new_sparse()] {
+            assert_eq!(segment.iter_nonzero().next(), None);
+            assert_eq!(segment.count_nonzero(), 0);
+            assert_eq!(segment.first_nonzero_after(0x0000), None);
+
+            segment[0x0000] = 0x1234;
+            segment[0x0300] = 0;
+            segment[0xFFFF] = 0xABCD;
+            let pairs = segment.iter_nonzero().collect::<Vec<_>>();
+            assert_eq!(pairs, vec![(0x0000, 0x1234), (0xFFFF, 0xABCD)]);
+            assert_eq!(segment.count_nonzero(), 2);
+            assert_eq!(segment.first_nonzero_after(0x0000), Some((0xFFFF, 0xABCD)));
+            assert_eq!(segment.first_nonzero_after(0xFFFE), Some((0xFFFF, 0xABCD)));
+            assert_eq!(segment.first_nonzero_after(0xFFFF), None);
+        }
+    }
+
+    #[test]
+    fn test_first_nonzero_after_within_page() {
+        let mut segment = Segment::new_sparse();
+        segment[0x0201] = 1;
+        segment[0x0203] = 3;
+        assert_eq!(segment.first_nonzero_after(0x0000), Some((0x0201, 1)));
+        assert_eq!(segment.first_nonzero_after(0x0201), Some((0x0203, 3)));
+        assert_eq!(segment.first_nonzero_after(0x0202), Some((0x0203, 3)));
+        assert_eq!(segment.first_nonzero_after(0x0203), None);
+    }
+
+    #[test]
+    fn test_slices() {
+        let mut segment = Segment::new_sparse();
+        segment[0x0042] = 0x1337;
+        assert_eq!(segment.as_slice(), None);
+        segment.as_mut_slice()[0x0043] = 0xBEEF;
+        assert!(!segment.is_sparse());
+        assert_eq!(
+            segment.as_slice().unwrap()[0x0042..0x0044],
+            [0x1337, 0xBEEF]
+        );
+        let pairs = segment
+            .iter()
+            .filter(|&(_, word)| word != 0)
+            .collect::<Vec<_>>();
+        assert_eq!(pairs, vec![(0x0042, 0x1337), (0x0043, 0xBEEF)]);
+    }
+
+    #[test]
+    fn test_copy_from_slice_at_wraps() {
+        for mut segment in [Segment::new_zeroed(), Segment::new_sparse()] {
+            segment.copy_from_slice_at(0xFFFE, &[1, 2, 3, 4]);
+            assert_eq!(segment[0xFFFD], 0);
+            assert_eq!(segment[0xFFFE], 1);
+            assert_eq!(segment[0xFFFF], 2);
+            assert_eq!(segment[0x0000], 3);
+            assert_eq!(segment[0x0001], 4);
+            assert_eq!(segment[0x0002], 0);
+
+            let mut out = [0xFFFF; 5];
+            segment.read_into(0xFFFD, &mut out);
+            assert_eq!(out, [0, 1, 2, 3, 4]);
+            let mut out = [0xFFFF; 0];
+            segment.read_into(0xFFFF, &mut out);
+        }
+    }
+
+    #[test]
+    fn test_copy_from_slice_at_overlong() {
+        let mut words = vec![0x1111; 1 << 16];
+        words.extend_from_slice(&[0x2222, 0x3333]);
+        let mut segment = Segment::new_sparse();
+        segment.copy_from_slice_at(0xFFFF, &words);
+        // The last two words wrapped around all the way and overwrote the first two.
+        assert_eq!(segment[0xFFFE], 0x1111);
+        assert_eq!(segment[0xFFFF], 0x2222);
+        assert_eq!(segment[0x0000], 0x3333);
+        assert_eq!(segment[0x0001], 0x1111);
+
+        let mut out = vec![0; (1 << 16) + 2];
+        segment.read_into(0xFFFF, &mut out);
+        assert_eq!(out[0], 0x2222);
+        assert_eq!(out[1], 0x3333);
+        assert_eq!(out[1 << 16], 0x2222);
+        assert_eq!(out[(1 << 16) + 1], 0x3333);
+    }
+
+    #[test]
+    fn test_diff_identical() {
+        let mut segment = Segment::new_zeroed();
+        segment[0x1234] = 0x5678;
+        assert_eq!(segment.diff(&segment.clone()), vec![]);
+        assert_eq!(segment.diff(&segment.to_sparse()), vec![]);
+        assert_eq!(segment.diff_report(&segment.clone()), "");
+    }
+
+    #[test]
+    fn test_diff_single() {
+        let before = Segment::new_sparse();
+        let mut after = Segment::new_zeroed();
+        after[0xFFFF] = 0x0042;
+        assert_eq!(before.diff(&after), vec![(0xFFFF, 0x0000, 0x0042)]);
+        assert_eq!(after.diff(&before), vec![(0xFFFF, 0x0042, 0x0000)]);
+        assert_eq!(before.diff_report(&after), "FFFF: 0000 -> 0042\n");
+    }
+
+    #[test]
+    fn test_diff_contiguous_run() {
+        let before = Segment::new_zeroed();
+        let mut after = Segment::new_zeroed();
+        // Crosses a page boundary.
+        for address in 0x0180..0x0280 {
+            after[address] = address;
+        }
+        after[0x0281] = 0xAAAA;
+        after[0x0000] = 0x1111;
+        assert_eq!(before.diff(&after).len(), 0x102);
+        let report = before.diff_report(&after);
+        let lines = report.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], "0000: 0000 -> 1111");
+        assert!(lines[1].starts_with("0180-027F: 0000 0000 "));
+        assert!(lines[1].contains(" 0000 -> 0180 0181 "));
+        assert!(lines[1].ends_with(" 027E 027F"));
+        assert_eq!(lines[2], "0281: 0000 -> AAAA");
+    }
+
+    #[test]
+    fn test_bytes_round_trip() {
+        let mut segment = Segment::new_zeroed();
+        for (i, word) in [0x0000, 0x00FF, 0xFF00, 0xFFFF, 0x0001, 0x8000, 0x1234]
+            .into_iter()
+            .enumerate()
+        {
+            segment[i as u16] = word;
+        }
+        segment[0xFFFF] = 0xFF00;
+        let bytes = segment.to_bytes();
+        assert_eq!(bytes.len(), 131072);
+        assert_eq!(
+            bytes[0..8],
+            [0x00, 0x00, 0x00, 0xFF, 0xFF, 0x00, 0xFF, 0xFF]
+        );
+        assert_eq!(bytes[131070..], [0xFF, 0x00]);
+        assert_eq!(Segment::from_bytes(&bytes), Ok(segment));
+    }
+
+    #[test]
+    fn test_from_bytes_padded() {
+        assert_eq!(Segment::from_bytes_padded(&[]), Ok(Segment::new_zeroed()));
+        let segment = Segment::from_bytes_padded(&[0x10, 0x2A]).unwrap();
+        assert_eq!(segment[0], 0x102A);
+        assert_eq!(segment.used_len(), 1);
+        let mut full = Segment::new_zeroed();
+        full[0xFFFF] = 0x00FF;
+        assert_eq!(Segment::from_bytes_padded(&full.to_bytes()), Ok(full));
+        assert_eq!(
+            Segment::from_bytes_padded(&[0x10, 0x2A, 0x10]),
+            Err(FormatError::OddLength { actual: 3 })
+        );
+        assert_eq!(
+            Segment::from_bytes_padded(&[0x10, 0x2A, 0x10])
+                .unwrap_err()
+                .to_string(),
+            "Wrong segment length, got 3 bytes, which is not a whole number of words."
+        );
+        assert_eq!(
+            Segment::from_bytes_padded(&vec![0; 131074]),
+            Err(FormatError::WrongLength {
+                expected: 131072,
+                actual: 131074
+            })
+        );
+    }
+
+    #[test]
+    fn test_from_bytes_wrong_length() {
+        for len in [0, 1, 131071, 131073, 131074] {
+            assert_eq!(
+                Segment::from_bytes(&vec![0; len]),
+                Err(FormatError::WrongLength {
+                    expected: 131072,
+                    actual: len
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn test_sparse_reads_zero() {
+        let mut segment = Segment::new_sparse();
+        assert!(segment.is_sparse());
+        assert_eq!(segment[0], 0);
+        assert_eq!(segment[0xFFFF], 0);
+        assert_eq!(segment, Segment::new_zeroed());
+        let empty_bytes = segment.heap_bytes();
+
+        segment[0x1234] = 0xABCD;
+        assert_eq!(segment[0x1234], 0xABCD);
+        assert_eq!(segment[0x1233], 0);
+        assert_eq!(segment[0x1235], 0);
+        assert_eq!(segment.heap_bytes(), empty_bytes + 1024);
+        segment[0x1235] = 0x0001;
+        assert_eq!(segment.heap_bytes(), empty_bytes + 1024);
+        segment[0xFFFF] = 0x0002;
+        assert_eq!(segment.heap_bytes(), empty_bytes + 2048);
+        assert!(segment.heap_bytes() < Segment::new_zeroed().heap_bytes() / 16);
+    }
+
+    #[test]
+    fn test_conversions() {
+        let mut dense = Segment::new_zeroed();
+        for i in (0..=0xFFFF).step_by(0x1111) {
+            dense[i] = i ^ 0x5A5A;
+        }
+        let sparse = dense.to_sparse();
+        assert!(sparse.is_sparse());
+        assert_eq!(sparse, dense);
+        assert_eq!(dense, sparse);
+        assert!(sparse.words().eq(dense.words()));
+        assert_eq!(format!("{:?}", sparse), format!("{:?}", dense));
+        let roundtrip = sparse.to_dense();
+        assert!(!roundtrip.is_sparse());
+        assert_eq!(roundtrip, dense);
+
+        let mut other = sparse.clone();
+        other[0x0042] = 1;
+        assert_ne!(other, dense);
+        assert_ne!(dense, other);
+    }
+
+    #[test]
+    fn test_selftest_programs_sparse() {
+        for program in PROGRAMS {
+            let mut instructions = Segment::new_zeroed();
+            for (i, &word) in program.instructions.iter().enumerate() {
+                instructions[i as u16] = word;
+            }
+            let mut dense = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+            let mut sparse = VirtualMachine::new(instructions.to_sparse(), Segment::new_sparse());
+            let dense_outcome = run_vm(&mut dense, program.max_steps);
+            let sparse_outcome = run_vm(&mut sparse, program.max_steps);
+            assert!(sparse.get_data().is_sparse());
+            if !dense.was_deterministic_so_far() {
+                // Cannot be compared, different random numbers.
+                continue;
+            }
+            assert_eq!(dense_outcome, sparse_outcome, "{}", program.name);
+            assert_eq!(dense, sparse, "{}", program.name);
+        }
+    }
+    fn numbered_segment() -> Segment {
+        let mut segment = Segment::new_zeroed();
+        for (index, value) in (0..=0xFFFF).zip((1..=0xFFFF).cycle()) {
+            segment[index] = value;
+        }
+        segment
+    }
+
+    fn copy_word_by_word(
+        dst_segment: &mut Segment,
+        dst: u16,
+        src: &Segment,
+        src_start: u16,
+        len: u16,
+    ) {
+        for i in 0..len {
+            dst_segment[dst.wrapping_add(i)] = src[src_start.wrapping_add(i)];
+        }
+    }
+
+    #[test]
+    fn test_copy_from_matches_word_by_word() {
+        let src = numbered_segment();
+        let cases = [
+            (0x0000, 0x0000, 0),
+            (0x1234, 0x0000, 0),
+            (0x0000, 0x0000, 0xFFFF),
+            (0x0001, 0x0000, 0xFFFF),
+            (0x0000, 0x8765, 0xFFFF),
+            (0xFFF0, 0x0010, 0x20),
+            (0x0010, 0xFFF0, 0x20),
+            (0xFFFF, 0xFFFF, 2),
+            (0x01FF, 0x0201, 0x0400),
+        ];
+        for (dst, src_start, len) in cases {
+            for base in [Segment::new_zeroed(), Segment::new_sparse()] {
+                let mut expected = base.clone();
+                copy_word_by_word(&mut expected, dst, &src, src_start, len);
+                let mut actual = base;
+                actual.copy_from(dst, &src, src_start, len);
+                assert_eq!(
+                    actual, expected,
+                    "dst={dst:04X} src={src_start:04X} len={len:04X}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_copy_from_zeros_into_sparse() {
+        let mut segment = Segment::new_sparse();
+        let empty_bytes = segment.heap_bytes();
+        segment.copy_from(0x1000, &Segment::new_zeroed(), 0, 0x2000);
+        assert_eq!(segment.heap_bytes(), empty_bytes);
+
+        let mut src = Segment::new_zeroed();
+        src[0x0300] = 0x1337;
+        segment.copy_from(0x1000, &src, 0, 0x2000);
+        assert_eq!(segment.heap_bytes(), empty_bytes + PAGE_WORDS * 2);
+        assert_eq!(segment[0x1300], 0x1337);
+    }
+
+    #[test]
+    fn test_copy_data_from_vm() {
+        let src = VirtualMachine::new(numbered_segment(), Segment::new_zeroed());
+        let mut dst = VirtualMachine::new(Segment::new_zeroed(), Segment::new_zeroed());
+        dst.copy_data_from(0xFFFE, &src, SegmentKind::Instructions, 0x0041, 3);
+        assert_eq!(dst.get_data()[0xFFFE], 0x42);
+        assert_eq!(dst.get_data()[0xFFFF], 0x43);
+        assert_eq!(dst.get_data()[0x0000], 0x44);
+        assert_eq!(dst.get_data()[0x0001], 0);
+
+        dst.copy_data_from(0xFFFE, &src, SegmentKind::Data, 0x0041, 2);
+        assert_eq!(dst.get_data()[0xFFFE], 0);
+        assert_eq!(dst.get_data()[0xFFFF], 0);
+        assert_eq!(dst.get_data()[0x0000], 0x44);
+    }
+
+    #[test]
+    fn test_bulk_copy_wraps() {
+        for data in [Segment::new_zeroed(), Segment::new_sparse()] {
+            let mut vm = VirtualMachine::new(numbered_segment(), data);
+            vm.watch_data(0xFFFF);
+            vm.copy_into_data(0xFFFE, &[1, 2, 3, 4]);
+            assert_eq!(vm.read_data(0xFFFD, 6), vec![0, 1, 2, 3, 4, 0]);
+            assert_eq!(vm.read_data(0x0000, 0), Vec::<u16>::new());
+            assert_eq!(vm.take_watch_hits(), vec![]);
+            assert_eq!(
+                vm.read_instructions(0xFFFE, 3),
+                vec![0xFFFF, 0x0001, 0x0001]
+            );
+            assert_eq!(vm.read_instructions(0x0041, 2), vec![0x42, 0x43]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_vm_debug {
+    use super::*;
+
+    #[test]
+    fn test_step_result_display() {
+        for (step_result, text) in [
+            (StepResult::Continue, "continue"),
+            (StepResult::DebugDump, "debug-dump"),
+            (
+                StepResult::IllegalInstruction(0x0123),
+                "illegal instruction 0x0123",
+            ),
+            (StepResult::Return(0x0042), "return 0x0042"),
+            (StepResult::RandomnessUnavailable, "randomness unavailable"),
+            (StepResult::Breakpoint(0xBEEF), "breakpoint at 0xBEEF"),
+        ] {
+            assert_eq!(step_result.to_string(), text);
+        }
+    }
+
+    #[test]
+    fn test_segment_summary() {
+        assert_eq!(
+            format!("{:?}", Segment::new_zeroed().summary()),
+            "Segment { used_len: 0, nonzero: 0, fingerprint: C74B47C8C74A2325 }"
+        );
+        let mut dense = Segment::new_zeroed();
+        dense[0xFFFF] = 1;
+        let sparse = dense.to_sparse();
+        assert_eq!(dense.used_len(), 0x10000);
+        assert_eq!(sparse.used_len(), 0x10000);
+        assert_eq!(dense.fingerprint(), sparse.fingerprint());
+        assert_ne!(dense.fingerprint(), Segment::new_zeroed().fingerprint());
+    }
+
+    #[test]
+    fn test_golden() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3042; // lw r0, 0x0042
+        instructions[1] = 0x102A; // ret
+        let mut vm = VirtualMachine::new(instructions, Segment::new_sparse());
+        vm.set_data_word(0x1234, 0xABCD);
+        vm.step();
+        vm.step();
+        assert_eq!(
+            format!("{:?}", vm),
+            "VirtualMachine { \
+            registers: [0042, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000, 0000], \
+            program_counter: 0001, time: 1, deterministic_so_far: true, halted: Some(Return(0x0042)), \
+            instructions: Segment { used_len: 2, nonzero: 2, fingerprint: 83FB94FBE416726D }, \
+            data: Segment { used_len: 4661, nonzero: 1, fingerprint: 2CB44C51D080EB61 }, \
+            near_pc: [0000, 3042, 102A, 0000, 0000] }"
+        );
+    }
+
+    #[test]
+    fn test_dump_full() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3042; // lw r0, 0x0042
+        instructions[1] = 0x102A; // ret
+        let mut vm = VirtualMachine::new(instructions, Segment::new_sparse());
+        vm.set_data_word(0x1234, 0xABCD);
+        let dump = vm.dump_full();
+        assert!(dump.starts_with(&format!("{:?}\ninstructions:\n", vm)));
+        assert!(dump.contains("\n0000: 3042 102A 0000 0000 0000 0000 0000 0000\n"));
+        assert!(dump.contains("\ndata:\n"));
+        assert!(dump.ends_with(
+            "1230: 0000 0000 0000 0000 ABCD 0000 0000 0000\n\
+             1238: 0000 0000 0000 0000 0000 0000 0000 0000\n\
+             *\n\
+             FFF8: 0000 0000 0000 0000 0000 0000 0000 0000\n"
+        ));
+    }
+}
+
+#[cfg(test)]
+mod test_debug_dump {
+    use super::*;
+    use crate::tinyvm_asm;
+    use std::sync::Mutex;
+
+    #[test]
+    fn test_handler() {
+        let instructions = tinyvm_asm! {
+            lw r1, 5;
+            debug_dump;
+            lw r1, 6;
+            debug_dump;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let seen_clone = Arc::clone(&seen);
+        vm.set_debug_dump_handler(Some(DebugDumpHandler::new(move |vm| {
+            seen_clone
+                .lock()
+                .unwrap()
+                .push((vm.get_program_counter(), vm.get_registers()[1]))
+        })));
+        vm.run(100);
+        assert_eq!(vm.get_halted(), Some(StepResult::Return(0)));
+        assert_eq!(*seen.lock().unwrap(), vec![(1, 5), (3, 6)]);
+
+        vm.set_debug_dump_handler(None);
+        vm.reset(false);
+        vm.run(100);
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_steps_since_last_debug_dump() {
+        let instructions = tinyvm_asm! {
+            lw r1, 5;
+            lw r2, 6;
+            debug_dump;
+            lw r1, 6;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.step();
+        assert_eq!(vm.steps_since_last_debug_dump(), 1);
+        assert_eq!(vm.debug_dumps(100).next(), Some(0));
+        assert_eq!(vm.steps_since_last_debug_dump(), 0);
+        vm.run(100);
+        assert_eq!(vm.steps_since_last_debug_dump(), 1);
+        assert_eq!(vm.get_time(), 4);
+
+        vm.reset(true);
+        assert_eq!(vm.steps_since_last_debug_dump(), 0);
+        vm.set_time(2);
+        assert_eq!(vm.steps_since_last_debug_dump(), 0);
+        vm.reset(false);
+        vm.step();
+        assert_eq!(vm.steps_since_last_debug_dump(), 1);
+    }
+
+    #[test]
+    fn test_steps_since_last_debug_dump_step_back() {
+        let instructions = tinyvm_asm! {
+            lw r1, 5;
+            lw r2, 6;
+            debug_dump;
+            lw r1, 6;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_history(10);
+        vm.run(100);
+        assert_eq!(vm.steps_since_last_debug_dump(), 1);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.steps_since_last_debug_dump(), 0);
+        assert!(vm.step_back());
+        assert_eq!(vm.steps_since_last_debug_dump(), 2);
+        assert_eq!(vm.step(), StepResult::DebugDump);
+        assert_eq!(vm.steps_since_last_debug_dump(), 0);
+    }
+}
+
+#[cfg(test)]
+mod test_breakpoint {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn countdown() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r1, 3;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_address_zero() {
+        let mut vm = countdown();
+        vm.add_breakpoint(0);
+        assert!(vm.has_breakpoint(0));
+        assert!(!vm.has_breakpoint(1));
+        assert_eq!(vm.step(), StepResult::Breakpoint(0));
+        assert_eq!(vm.get_time(), 0);
+        assert_eq!(vm.get_program_counter(), 0);
+        assert_eq!(vm.get_registers()[1], 0);
+        assert_eq!(vm.get_halted(), None);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_time(), 1);
+        assert_eq!(vm.get_registers()[1], 3);
+    }
+
+    #[test]
+    fn test_jump_target_hit_repeatedly() {
+        let mut vm = countdown();
+        vm.add_breakpoint(1);
+        assert_eq!(vm.step(), StepResult::Continue);
+        for expected_r1 in [3, 2, 1] {
+            assert_eq!(vm.step(), StepResult::Breakpoint(1));
+            assert_eq!(vm.get_registers()[1], expected_r1);
+            assert_eq!(vm.step(), StepResult::Continue);
+            assert_eq!(vm.step(), StepResult::Continue);
+        }
+        assert_eq!(vm.step(), StepResult::Return(0));
+        assert_eq!(vm.get_time(), 7);
+    }
+
+    #[test]
+    fn test_run_stops_and_resumes() {
+        let mut vm = countdown();
+        vm.add_breakpoint(1);
+        assert_eq!(
+            vm.run(100),
+            RunOutcome {
+                steps: 1,
+                reason: StopReason::Breakpoint(1)
+            }
+        );
+        assert_eq!(
+            vm.run(100),
+            RunOutcome {
+                steps: 2,
+                reason: StopReason::Breakpoint(1)
+            }
+        );
+        vm.remove_breakpoint(1);
+        assert!(!vm.has_breakpoint(1));
+        assert_eq!(
+            vm.run(100),
+            RunOutcome {
+                steps: 4,
+                reason: StopReason::Returned(0)
+            }
+        );
+    }
+
+    #[test]
+    fn test_run_vm() {
+        let mut vm = countdown();
+        vm.add_breakpoint(3);
+        assert_eq!(
+            run_vm(&mut vm, 100),
+            ProgramOutcome::Breakpoint { pc: 3, steps: 7 }
+        );
+        assert_eq!(
+            run_vm(&mut vm, 100),
+            ProgramOutcome::Returned { value: 0, steps: 7 }
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_watchpoint {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn fill_loop() -> VirtualMachine {
+        // Writes 3, 2, 1 to the addresses 3, 2, 1.
+        let instructions = tinyvm_asm! {
+            lw r1, 3;
+            loop_start:
+            sw r1, r1;
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_inside_loop() {
+        let mut vm = fill_loop();
+        vm.set_data_word(2, 0x1234);
+        vm.watch_data(2);
+        vm.set_data_word(2, 0x5678);
+        vm.run(100);
+        assert_eq!(
+            vm.take_watch_hits(),
+            vec![
+                WatchHit {
+                    address: 2,
+                    old: 0x1234,
+                    new: 0x5678,
+                    pc: None
+                },
+                WatchHit {
+                    address: 2,
+                    old: 0x5678,
+                    new: 2,
+                    pc: Some(1)
+                },
+            ]
+        );
+        assert_eq!(vm.take_watch_hits(), vec![]);
+    }
+
+    #[test]
+    fn test_never_fires() {
+        let mut vm = fill_loop();
+        vm.watch_data(4);
+        vm.watch_data(0);
+        vm.watch_data(1);
+        vm.unwatch_data(1);
+        vm.run(100);
+        assert_eq!(vm.get_data()[1], 1);
+        assert_eq!(vm.take_watch_hits(), vec![]);
+    }
+}
+
+#[cfg(test)]
+mod test_protection {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    #[test]
+    fn test_store_faults() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x10;
+            lw r2, 0x1234;
+            sw r1, r2;
+            incr r1, r1;
+            sw r1, r2;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.protect_data(0x11..=0x12, Protection::ReadOnly);
+        assert_eq!(vm.data_protection(0x10), Protection::ReadWrite);
+        assert_eq!(vm.data_protection(0x11), Protection::ReadOnly);
+        // The host may still write.
+        vm.set_data_word(0x11, 0x5555);
+        let fault = StepResult::ProtectionFault {
+            addr: 0x11,
+            pc: 0x0005,
+        };
+        assert_eq!(
+            vm.run(100).reason,
+            StopReason::ProtectionFault {
+                addr: 0x11,
+                pc: 0x0005
+            }
+        );
+        assert_eq!(vm.get_halted(), Some(fault));
+        assert_eq!(vm.get_program_counter(), 0x0005);
+        assert_eq!(vm.get_data()[0x10], 0x1234);
+        assert_eq!(vm.get_data()[0x11], 0x5555);
+        assert_eq!(
+            format!("{:?}", fault),
+            "ProtectionFault { addr: 0x0011, pc: 0x0005 }"
+        );
+        assert_eq!(
+            fault.to_string(),
+            "store to read-only address 0x0011 at 0x0005"
+        );
+
+        vm.reset(false);
+        vm.protect_data(0x11..=0x11, Protection::ReadWrite);
+        assert_eq!(vm.run(100).reason, StopReason::Returned(0));
+        assert_eq!(vm.get_data()[0x11], 0x1234);
+    }
+
+    #[test]
+    fn test_store_with_offset_and_mmio() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0xFF00;
+            sw_offset r1, 1, r1;
+            ret;
+        };
+        let output = TextOutput::new();
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_extension(Extension::MemoryOffset);
+        vm.set_mmio_handler(DEFAULT_MMIO_RANGE, Box::new(output.clone()));
+        vm.protect_data(0xFF01..=0xFF01, Protection::ReadOnly);
+        assert_eq!(
+            vm.run(100).reason,
+            StopReason::ProtectionFault {
+                addr: 0xFF01,
+                pc: 0x0002
+            }
+        );
+        // The device did not see the store either.
+        assert_eq!(output.take_text(), "");
+    }
+}
+
+#[cfg(test)]
+mod test_state_hash {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn run_fibonacci(data: Segment) -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r0, 24;
+            lw r1, 1;
+            loop_start:
+            add r1, r2;
+            decr r0, r0;
+            sw r0, r2;
+            add r2, r1;
+            decr r0, r0;
+            sw r0, r1;
+            b r0, loop_start;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, data);
+        vm.run(1000);
+        vm
+    }
+
+    #[test]
+    fn test_identical_runs() {
+        let vm = run_fibonacci(Segment::new_zeroed());
+        assert_eq!(
+            vm.state_hash(),
+            run_fibonacci(Segment::new_sparse()).state_hash()
+        );
+        // The instructions do not matter.
+        let mut other = vm.clone();
+        other.set_instruction_word(0xFFFF, 0x1234);
+        assert_eq!(other.state_hash(), vm.state_hash());
+    }
+
+    #[test]
+    fn test_differences() {
+        let vm = run_fibonacci(Segment::new_zeroed());
+        let mut data = Segment::new_zeroed();
+        data[0x8000] = 1;
+        assert_ne!(run_fibonacci(data).state_hash(), vm.state_hash());
+
+        let mut other = vm.clone();
+        other.set_register(5, 1);
+        assert_ne!(other.state_hash(), vm.state_hash());
+        let mut other = vm.clone();
+        other.set_time(vm.get_time() + 1);
+        assert_ne!(other.state_hash(), vm.state_hash());
+    }
+}
+
+#[cfg(test)]
+mod test_reset {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn dirty_vm() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x1234;
+            lw r2, 5;
+            sw r2, r1;
+            rnd r3, r1;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_sparse());
+        assert!(matches!(vm.run(100).reason, StopReason::Returned(_)));
+        assert_eq!(vm.get_data()[5], 0x1234);
+        assert!(!vm.was_deterministic_so_far());
+        vm
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut vm = dirty_vm();
+        let instructions = vm.get_instructions().clone();
+        vm.reset(false);
+        assert_eq!(vm.get_registers(), &[0; 16]);
+        assert_eq!(vm.get_program_counter(), 0);
+        assert_eq!(vm.get_time(), 0);
+        assert!(vm.was_deterministic_so_far());
+        assert_eq!(vm.random_calls(), 0);
+        assert_eq!(vm.get_halted(), None);
+        assert!(vm.get_data().is_sparse());
+        assert_eq!(vm.get_data().used_len(), 0);
+        assert_eq!(vm.get_instructions(), &instructions);
+    }
+
+    #[test]
+    fn test_reset_keep_time() {
+        let mut vm = dirty_vm();
+        let time = vm.get_time();
+        vm.reset(true);
+        assert_eq!(vm.get_registers(), &[0; 16]);
+        assert_eq!(vm.get_data()[5], 0);
+        assert_eq!(vm.get_time(), time);
+        assert!(!vm.was_deterministic_so_far());
+        assert_eq!(vm.random_calls(), 1);
+        // Runs the same program again.
+        assert!(matches!(vm.run(100).reason, StopReason::Returned(_)));
+        assert_eq!(vm.get_data()[5], 0x1234);
+        assert_eq!(vm.random_calls(), 2);
+        assert_eq!(vm.get_time(), 2 * time);
+    }
+
+    #[test]
+    fn test_reset_at_breakpoint() {
+        let mut vm = dirty_vm();
+        vm.reset(false);
+        vm.add_breakpoint(0);
+        assert_eq!(vm.step(), StepResult::Breakpoint(0));
+        vm.reset(false);
+        // The breakpoint is kept, and reported again.
+        assert_eq!(vm.step(), StepResult::Breakpoint(0));
+        assert_eq!(vm.step(), StepResult::Continue);
+    }
+}
+
+#[cfg(test)]
+mod test_patching {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn incrementer() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            incr r1, r1;
+            incr r1, r1;
+            incr r1, r1;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_shared_copy_on_write() {
+        let instructions = Arc::new(incrementer().get_instructions().clone());
+        let mut patched =
+            VirtualMachine::new_shared(Arc::clone(&instructions), Segment::new_zeroed());
+        let mut unpatched =
+            VirtualMachine::new_shared(Arc::clone(&instructions), Segment::new_zeroed());
+        assert!(std::ptr::eq(patched.get_instructions(), &*instructions));
+        patched.set_instruction_word(1, 0x102A); // ret
+        assert!(!std::ptr::eq(patched.get_instructions(), &*instructions));
+        patched.run(10);
+        unpatched.run(10);
+        assert_eq!(patched.get_registers()[1], 1);
+        assert_eq!(unpatched.get_registers()[1], 3);
+        assert_eq!(instructions[1], 0x5911);
+        assert!(std::ptr::eq(unpatched.get_instructions(), &*instructions));
+    }
+
+    #[test]
+    fn test_patch_next_instruction() {
+        let mut vm = incrementer();
+        assert_eq!(vm.step(), StepResult::Continue);
+        vm.set_instruction_word(2, 0x5811); // decr r1, r1
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_registers()[1], 1);
+        assert_eq!(vm.get_instructions()[2], 0x5811);
+    }
+
+    #[test]
+    fn test_patch_current_instruction() {
+        let mut vm = incrementer();
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_program_counter(), 1);
+        vm.get_instructions_mut()[1] = 0x102A; // ret
+        assert_eq!(vm.step(), StepResult::Return(0));
+        assert_eq!(vm.get_registers()[1], 1);
+        assert_eq!(vm.get_time(), 1);
+    }
+
+    #[test]
+    fn test_patch_at_breakpoint() {
+        let mut vm = incrementer();
+        vm.add_breakpoint(1);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::Breakpoint(1));
+        vm.set_instruction_word(1, 0xFFFF);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0xFFFF));
+    }
+}
+
+#[cfg(test)]
+mod test_write_log {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn overlapping_writes() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r1, 5;
+            lw r2, 0x1234;
+            sw r1, r2;
+            sw r1, r1;
+            incr r1, r1;
+            sw r1, r2;
+            lw r3, 0xFFFF;
+            sw r3, r1;
+            sw r1, r2;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut vm = overlapping_writes();
+        vm.run(100);
+        assert_eq!(vm.take_write_log(), vec![]);
+        assert!(vm.dirty_addresses().is_empty());
+    }
+
+    #[test]
+    fn test_overlapping_writes() {
+        let mut vm = overlapping_writes();
+        vm.enable_write_log(10);
+        vm.set_data_word(7, 0x4242);
+        assert!(matches!(vm.run(100).reason, StopReason::Returned(_)));
+        let record = |time, pc, address, value| WriteRecord {
+            time,
+            pc,
+            address,
+            value,
+        };
+        assert_eq!(
+            vm.take_write_log(),
+            vec![
+                record(3, 3, 5, 0x1234),
+                record(4, 4, 5, 5),
+                record(6, 6, 6, 0x1234),
+                record(8, 8, 0xFFFF, 6),
+                record(9, 9, 6, 0x1234),
+            ]
+        );
+        assert_eq!(vm.take_write_log(), vec![]);
+        // The host write to 7 is not included.
+        assert_eq!(vm.dirty_addresses(), vec![5, 6, 0xFFFF]);
+        assert_eq!(vm.get_data()[5], 5);
+    }
+
+    #[test]
+    fn test_ring_keeps_most_recent() {
+        let mut vm = overlapping_writes();
+        vm.enable_write_log(2);
+        vm.run(100);
+        let log = vm.take_write_log();
+        assert_eq!(log.len(), 2);
+        assert_eq!((log[0].address, log[1].address), (0xFFFF, 6));
+        // The dirty addresses are complete nevertheless.
+        assert_eq!(vm.dirty_addresses(), vec![5, 6, 0xFFFF]);
+    }
+
+    #[test]
+    fn test_modified_data() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x10;
+            lwd r1, r2;
+            lw r3, 0x1234;
+            sw r1, r3;
+            sw r1, r2;
+            lw r1, 0x0FFF;
+            sw r1, r3;
+            lw r1, 0x20;
+            sw r1, r0;
+            ret;
+        };
+        for data in [Segment::new_zeroed(), Segment::new_sparse()] {
+            let mut vm = VirtualMachine::new(instructions.clone(), data);
+            vm.set_data_word(0x10, 0x5555);
+            vm.set_data_word(0x20, 0x6666);
+            assert_eq!(vm.modified_data(), vec![]);
+            vm.remember_initial_data();
+            assert_eq!(vm.run(100).reason, StopReason::Returned(0));
+            // 0x10 was overwritten, but then restored.
+            assert_eq!(
+                vm.modified_data(),
+                vec![(0x0020, 0x6666, 0x0000), (0x0FFF, 0x0000, 0x1234)]
+            );
+            vm.remember_initial_data();
+            assert_eq!(vm.modified_data(), vec![]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_extension {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    #[test]
+    fn test_store_instruction_disabled() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x102B; // cpuid
+        instructions[1] = 0x2312; // swi r1, r2
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert!(!vm.is_extension_enabled(Extension::StoreInstruction));
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_registers()[0] & CPUID_0_STORE_INSTRUCTION, 0);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0x2312));
+        assert_eq!(vm.get_instructions()[0], 0x102B);
+    }
+
+    #[test]
+    fn test_store_instruction_cpuid() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x102B; // cpuid
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_extension(Extension::StoreInstruction);
+        assert!(vm.is_extension_enabled(Extension::StoreInstruction));
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_registers()[0], 0xF800);
+    }
+
+    #[test]
+    fn test_store_next_instruction() {
+        let mut vm = VirtualMachine::new(
+            tinyvm_asm! {
+                lw r1, 4;
+                lw r2, 0x5911; // incr r1, r1
+                word 0x2312; // swi r1, r2
+                ret; // Overwritten.
+                ret;
+            },
+            Segment::new_zeroed(),
+        );
+        vm.enable_extension(Extension::StoreInstruction);
+        let outcome = vm.run(10);
+        assert_eq!(outcome.reason, StopReason::Returned(0));
+        assert_eq!(vm.get_registers()[1], 5);
+        assert_eq!(vm.get_program_counter(), 5);
+    }
+
+    #[test]
+    fn test_store_current_instruction() {
+        // The loop overwrites the store itself with "decr r3, r3" on its first iteration, so that the second
+        // iteration executes the new instruction.
+        let mut vm = VirtualMachine::new(
+            tinyvm_asm! {
+                lw r1, 5;
+                lw r2, 0x5833; // decr r3, r3
+                lw r3, 2;
+                lw r4, 2;
+                loop_start:
+                word 0x2312; // swi r1, r2
+                decr r4, r4;
+                b r4, loop_start;
+                ret;
+            },
+            Segment::new_zeroed(),
+        );
+        vm.enable_extension(Extension::StoreInstruction);
+        assert_eq!(vm.run(100).reason, StopReason::Returned(0));
+        assert_eq!(vm.get_instructions()[5], 0x5833);
+        // Executed once as the store, and once as the decrement.
+        assert_eq!(vm.get_registers()[3], 1);
+    }
+
+    #[test]
+    fn test_memory_offset_disabled() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x102B; // cpuid
+        instructions[1] = 0x7312; // lw r2, r1 + 3
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert!(!vm.is_extension_enabled(Extension::MemoryOffset));
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_registers()[0] & CPUID_0_MEMORY_OFFSET, 0);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0x7312));
+    }
+
+    #[test]
+    fn test_memory_offset_fields() {
+        let mut data = Segment::new_zeroed();
+        data[0x1233] = 0x5678;
+        let mut vm = VirtualMachine::new(
+            tinyvm_asm! {
+                cpuid;
+                lw r1, 0x1230;
+                lwd_offset r1, 3, r2;
+                incr r2, r2;
+                sw_offset r1, 7, r2;
+                lw r3, 0xFFFF;
+                sw_offset r3, 2, r2;
+                ret;
+            },
+            data,
+        );
+        vm.enable_extension(Extension::MemoryOffset);
+        vm.enable_history(10);
+        assert_eq!(vm.run(100).reason, StopReason::Returned(0xEC00));
+        assert_eq!(vm.get_registers()[2], 0x5679);
+        assert_eq!(vm.get_data()[0x1237], 0x5679);
+        // The address wraps around.
+        assert_eq!(vm.get_data()[0x0001], 0x5679);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert_eq!(vm.get_data()[0x0001], 0);
+    }
+}
+
+#[cfg(test)]
+mod test_cpu_features {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    fn cpuid(leaf: u16, features: CpuFeatures) -> [u16; 4] {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x102B; // cpuid
+        let mut vm = VirtualMachine::with_features(instructions, Segment::new_zeroed(), features);
+        vm.set_register(0, leaf);
+        vm.set_register(1, 0x1111);
+        assert_eq!(vm.step(), StepResult::Continue);
+        vm.get_registers()[0..4].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_leaf_0() {
+        assert_eq!(cpuid(0, CpuFeatures::default()), [0xE800, 0, 0, 0]);
+        assert_eq!(cpuid(0, CpuFeatures::empty()), [0x8000, 0, 0, 0]);
+        assert_eq!(cpuid(0, CpuFeatures::ALL), [0xFE00, 0, 0, 0]);
+        let no_rnd = CpuFeatures::default().without(CpuFeatures::RND);
+        assert_eq!(cpuid(0, no_rnd), [0xE000, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_leaf_1() {
+        for features in [CpuFeatures::empty(), CpuFeatures::default()] {
+            assert_eq!(cpuid(1, features), [0xFE00, 0, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn test_reserved_leaves() {
+        for leaf in (2..=7).chain([0x0100, 0xFFFF]) {
+            assert_eq!(cpuid(leaf, CpuFeatures::ALL), [0, 0, 0, 0], "leaf {}", leaf);
+        }
+    }
+
+    #[test]
+    fn test_disabled_features_are_illegal() {
+        for (word, feature) in [
+            (0x5E12, CpuFeatures::RND),          // rnd r2, r1
+            (0x6E12, CpuFeatures::EXP_ROOT),     // exp r1, r2
+            (0x6F12, CpuFeatures::EXP_ROOT),     // root r1, r2
+            (0x1143, CpuFeatures::COMPARE_ZERO), // eq r3, 0
+        ] {
+            let mut instructions = Segment::new_zeroed();
+            instructions[0] = word;
+            let without = CpuFeatures::default().without(feature);
+            let mut vm =
+                VirtualMachine::with_features(instructions.clone(), Segment::new_zeroed(), without);
+            assert_eq!(vm.step(), StepResult::IllegalInstruction(word));
+            let mut vm = VirtualMachine::new_with_seed(instructions, Segment::new_zeroed(), 0);
+            assert_eq!(vm.step(), StepResult::Continue, "{:04X}", word);
+        }
+    }
+
+    #[test]
+    fn test_features_survive_extensions() {
+        let mut vm = VirtualMachine::with_features(
+            tinyvm_asm! {
+                rnd r0, r0;
+            },
+            Segment::new_zeroed(),
+            CpuFeatures::empty(),
+        );
+        vm.enable_extension(Extension::StoreInstruction);
+        assert_eq!(vm.get_features(), CpuFeatures::STORE_INSTRUCTION);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0x5E00));
+    }
+
+    #[test]
+    fn test_forbid_random() {
+        let mut vm = VirtualMachine::new_with_seed(
+            tinyvm_asm! {
+                cpuid;
+                lw r1, 10;
+                rnd r2, r1;
+            },
+            Segment::new_zeroed(),
+            0,
+        );
+        vm.set_forbid_random(true);
+        assert!(vm.is_random_forbidden());
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_registers()[0], 0xE000);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0x5E12));
+        assert!(vm.was_deterministic_so_far());
+
+        vm.reset(false);
+        vm.set_forbid_random(false);
+        assert!(!vm.is_random_forbidden());
+        assert_eq!(vm.run(3).reason, StopReason::OutOfBudget);
+        assert_eq!(vm.get_registers()[0], 0xE800);
+        assert!(!vm.was_deterministic_so_far());
+    }
+
+    #[test]
+    fn test_from_bits_truncate() {
+        assert_eq!(CpuFeatures::from_bits_truncate(0xFFFF), CpuFeatures::ALL);
+        assert_eq!(
+            CpuFeatures::from_bits_truncate(0x8000),
+            CpuFeatures::empty()
+        );
+        assert!(CpuFeatures::ALL.contains(CpuFeatures::default()));
+        assert!(!CpuFeatures::default().contains(CpuFeatures::STORE_INSTRUCTION));
+    }
+}
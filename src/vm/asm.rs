@@ -0,0 +1,188 @@
+/// Assembles a program with `ProgramBuilder` and returns it as an instruction segment.
+///
+/// Each statement is a builder method name followed by its operands, and ends with a semicolon. Registers are
+/// written `r0` to `r15`, labels are `name:`, and branch or jump targets are just the label name. Immediates may
+/// be decimal or hex, and negative only where the builder takes a signed value (`jr`).
+///
+/// ```
+/// let instructions = tinyvm::tinyvm_asm! {
+///     lw r0, 3;
+///     loop_start:
+///     decr r0, r0;
+///     b r0, loop_start;
+///     lw r1, 0xABCD;
+///     ret;
+/// };
+/// assert_eq!(instructions[0], 0x3003);
+/// assert_eq!(instructions[2], 0x9080);
+/// ```
+///
+/// Unknown mnemonics and registers, and immediates that do not fit, are compile errors:
+///
+/// ```compile_fail
+/// tinyvm::tinyvm_asm! { frobnicate r0; };
+/// ```
+///
+/// ```compile_fail
+/// tinyvm::tinyvm_asm! { lw r0, 0x10000; };
+/// ```
+///
+/// Mistakes that only `ProgramBuilder::build` can detect, like unknown labels, panic with its error message.
+///
+/// Note that every statement and operand is one level of macro recursion, so very long programs may need a
+/// higher `recursion_limit`.
+#[macro_export]
+macro_rules! tinyvm_asm {
+    (@stmts $b:ident) => {};
+    (@stmts $b:ident $label:ident : $($rest:tt)*) => {
+        $b.label(stringify!($label));
+        $crate::tinyvm_asm!(@stmts $b $($rest)*);
+    };
+    (@stmts $b:ident $mnemonic:ident $($rest:tt)*) => {
+        $crate::tinyvm_asm!(@ops $b $mnemonic [] $($rest)*);
+    };
+
+    (@ops $b:ident $mnemonic:ident [$($op:expr),*] ; $($rest:tt)*) => {
+        $b.$mnemonic($($op),*);
+        $crate::tinyvm_asm!(@stmts $b $($rest)*);
+    };
+    (@ops $b:ident $mnemonic:ident [$($op:expr),*] - $value:literal , $($rest:tt)*) => {
+        $crate::tinyvm_asm!(@ops $b $mnemonic [$($op,)* -$value] $($rest)*);
+    };
+    (@ops $b:ident $mnemonic:ident [$($op:expr),*] - $value:literal ; $($rest:tt)*) => {
+        $crate::tinyvm_asm!(@ops $b $mnemonic [$($op,)* -$value] ; $($rest)*);
+    };
+    (@ops $b:ident $mnemonic:ident [$($op:expr),*] $operand:tt , $($rest:tt)*) => {
+        $crate::tinyvm_asm!(@ops $b $mnemonic [$($op,)* $crate::tinyvm_asm!(@operand $operand)] $($rest)*);
+    };
+    (@ops $b:ident $mnemonic:ident [$($op:expr),*] $operand:tt ; $($rest:tt)*) => {
+        $crate::tinyvm_asm!(@ops $b $mnemonic [$($op,)* $crate::tinyvm_asm!(@operand $operand)] ; $($rest)*);
+    };
+
+    (@operand r0) => { 0u16 };
+    (@operand r1) => { 1u16 };
+    (@operand r2) => { 2u16 };
+    (@operand r3) => { 3u16 };
+    (@operand r4) => { 4u16 };
+    (@operand r5) => { 5u16 };
+    (@operand r6) => { 6u16 };
+    (@operand r7) => { 7u16 };
+    (@operand r8) => { 8u16 };
+    (@operand r9) => { 9u16 };
+    (@operand r10) => { 10u16 };
+    (@operand r11) => { 11u16 };
+    (@operand r12) => { 12u16 };
+    (@operand r13) => { 13u16 };
+    (@operand r14) => { 14u16 };
+    (@operand r15) => { 15u16 };
+    (@operand $label:ident) => { stringify!($label) };
+    (@operand $value:literal) => { $value };
+    (@ $($unparsed:tt)*) => {
+        compile_error!(concat!("tinyvm_asm!: Cannot parse: ", stringify!($($unparsed)*)))
+    };
+
+    ($($program:tt)*) => {{
+        #[allow(unused_mut)] // Only for the empty program.
+        let mut builder = $crate::vm::ProgramBuilder::new();
+        $crate::tinyvm_asm!(@stmts builder $($program)*);
+        builder
+            .build_segment()
+            .unwrap_or_else(|err| panic!("Invalid tinyvm_asm! program: {}", err))
+    }};
+}
+
+#[cfg(test)]
+mod test_asm {
+    use crate::selftest::FIBONACCI;
+    use crate::vm::ProgramBuilder;
+
+    #[test]
+    fn test_matches_builder() {
+        let instructions = tinyvm_asm! {
+            lw r0, 0x1337;
+            lw r15, 65535;
+            lwd r1, r1;
+            lwi r2, r3;
+            sw r7, r7;
+            compare 0b0110, r1, r0;
+            compare_zero 0b1001, r3;
+            mov r0, r3;
+            add r1, r2;
+            jr r7, -1;
+            jr r7, 0x34;
+            ill 0xFFFF;
+            debug_dump;
+            ret;
+        };
+        let expected = ProgramBuilder::new()
+            .lw(0, 0x1337)
+            .lw(15, 0xFFFF)
+            .lwd(1, 1)
+            .lwi(2, 3)
+            .sw(7, 7)
+            .compare(0b0110, 1, 0)
+            .compare_zero(0b1001, 3)
+            .mov(0, 3)
+            .add(1, 2)
+            .jr(7, -1)
+            .jr(7, 0x34)
+            .ill(0xFFFF)
+            .debug_dump()
+            .ret()
+            .build_segment()
+            .unwrap();
+        assert_eq!(instructions, expected);
+    }
+
+    #[test]
+    fn test_labels() {
+        let instructions = tinyvm_asm! {
+            top:
+            j end;
+            b r3, top;
+            end:
+            j top;
+        };
+        assert_eq!(instructions[0], 0xA000);
+        assert_eq!(instructions[1], 0x9380);
+        assert_eq!(instructions[2], 0xA801);
+        assert_eq!(instructions[3], 0x0000);
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        let instructions = tinyvm_asm! {
+            lw r0, 24;
+            lw r1, 1;
+            start:
+            add r1, r2;
+            decr r0, r0;
+            sw r0, r2;
+            add r2, r1;
+            decr r0, r0;
+            sw r0, r1;
+            b r0, start;
+            ret;
+        };
+        for (i, &word) in FIBONACCI.instructions.iter().enumerate() {
+            assert_eq!(instructions[i as u16], word);
+        }
+    }
+
+    #[test]
+    fn test_empty() {
+        assert_eq!(tinyvm_asm! {}, crate::vm::Segment::new_zeroed());
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid tinyvm_asm! program: Label 'nowhere' is never defined.")]
+    fn test_unknown_label() {
+        tinyvm_asm! { j nowhere; };
+    }
+
+    #[test]
+    #[should_panic(expected = "Invalid tinyvm_asm! program: Cannot reach label 'here'")]
+    fn test_unreachable_label() {
+        tinyvm_asm! { here: b r0, here; };
+    }
+}
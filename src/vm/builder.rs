@@ -0,0 +1,603 @@
+use crate::vm::{encode_branch, encode_jump_imm, OffsetError, Segment};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum BuildError {
+    InvalidRegister {
+        register: u16,
+    },
+    /// Compare flags must fit into the four bits `LEGS`.
+    InvalidFlags {
+        flags: u16,
+    },
+    /// Offsets of `lwd_offset` and `sw_offset` must fit into three bits.
+    InvalidMemoryOffset {
+        offset: u16,
+    },
+    DuplicateLabel {
+        label: String,
+    },
+    UnknownLabel {
+        label: String,
+    },
+    Offset {
+        label: String,
+        source: OffsetError,
+    },
+    TooLong {
+        len: usize,
+    },
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            BuildError::InvalidRegister { register } => {
+                write!(f, "Register {} does not exist.", register)
+            }
+            BuildError::InvalidFlags { flags } => write!(
+                f,
+                "Compare flags 0x{:X} do not fit into the four bits LEGS.",
+                flags
+            ),
+            BuildError::InvalidMemoryOffset { offset } => {
+                write!(f, "Memory offset {} does not fit into three bits.", offset)
+            }
+            BuildError::DuplicateLabel { label } => {
+                write!(f, "Label '{}' is defined more than once.", label)
+            }
+            BuildError::UnknownLabel { label } => {
+                write!(f, "Label '{}' is never defined.", label)
+            }
+            BuildError::Offset { label, source } => {
+                write!(f, "Cannot reach label '{}': {}", label, source)
+            }
+            BuildError::TooLong { len } => write!(
+                f,
+                "Program has {} words, but a segment only holds 65536.",
+                len
+            ),
+        }
+    }
+}
+
+impl Error for BuildError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            BuildError::Offset { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Fixup {
+    Branch { register: u16 },
+    JumpImm,
+}
+
+/// Assembles a program one instruction at a time, so that tests can spell out what they mean instead of
+/// hand-encoding words next to a comment that may or may not match.
+///
+/// Operands are in the order of the instruction set architecture, e.g. `add(lhs, rhs)` writes to `rhs`, except
+/// for unary functions, which take `(dest, src)` like the usual mnemonics. Mistakes like unknown labels or
+/// nonexistent registers are reported by `build`.
+#[derive(Debug, Default, Clone)]
+pub struct ProgramBuilder {
+    words: Vec<u16>,
+    labels: HashMap<String, usize>,
+    fixups: Vec<(usize, String, Fixup)>,
+    error: Option<BuildError>,
+}
+
+impl ProgramBuilder {
+    #[must_use]
+    pub fn new() -> ProgramBuilder {
+        ProgramBuilder::default()
+    }
+
+    fn fail(&mut self, error: BuildError) {
+        self.error.get_or_insert(error);
+    }
+
+    fn reg(&mut self, register: u16) -> u16 {
+        if register >= 16 {
+            self.fail(BuildError::InvalidRegister { register });
+        }
+        register & 0xF
+    }
+
+    fn flags(&mut self, flags: u16) -> u16 {
+        if flags >= 16 {
+            self.fail(BuildError::InvalidFlags { flags });
+        }
+        flags & 0xF
+    }
+
+    fn memory_offset(&mut self, offset: u16) -> u16 {
+        if offset >= 8 {
+            self.fail(BuildError::InvalidMemoryOffset { offset });
+        }
+        offset & 0x7
+    }
+
+    fn two_regs(&mut self, prefix: u16, high: u16, low: u16) -> &mut Self {
+        let high = self.reg(high);
+        let low = self.reg(low);
+        self.word(prefix | (high << 4) | low)
+    }
+
+    /// Emits an arbitrary word, e.g. data to be read by `lwi`.
+    pub fn word(&mut self, word: u16) -> &mut Self {
+        self.words.push(word);
+        self
+    }
+
+    /// Emits an illegal instruction. This is just `word`, but says what the word is meant for.
+    pub fn ill(&mut self, word: u16) -> &mut Self {
+        self.word(word)
+    }
+
+    /// Marks the address of the next instruction.
+    pub fn label(&mut self, label: &str) -> &mut Self {
+        if self.labels.insert(label.into(), self.words.len()).is_some() {
+            self.fail(BuildError::DuplicateLabel {
+                label: label.into(),
+            });
+        }
+        self
+    }
+
+    pub fn ret(&mut self) -> &mut Self {
+        self.word(0x102A)
+    }
+
+    pub fn cpuid(&mut self) -> &mut Self {
+        self.word(0x102B)
+    }
+
+    pub fn debug_dump(&mut self) -> &mut Self {
+        self.word(0x102C)
+    }
+
+    pub fn time(&mut self) -> &mut Self {
+        self.word(0x102D)
+    }
+
+    /// Compares `register` against zero, see `compare` for the flags.
+    pub fn compare_zero(&mut self, flags: u16, register: u16) -> &mut Self {
+        let flags = self.flags(flags);
+        let register = self.reg(register);
+        self.word(0x1100 | (flags << 4) | register)
+    }
+
+    pub fn sw(&mut self, address: u16, value: u16) -> &mut Self {
+        self.two_regs(0x2000, address, value)
+    }
+
+    /// Load word data.
+    pub fn lwd(&mut self, address: u16, dest: u16) -> &mut Self {
+        self.two_regs(0x2100, address, dest)
+    }
+
+    /// Store word data at the address plus `offset`, only legal with `CpuFeatures::MEMORY_OFFSET`.
+    pub fn sw_offset(&mut self, address: u16, offset: u16, value: u16) -> &mut Self {
+        let offset = self.memory_offset(offset);
+        self.two_regs(0x7800 | (offset << 8), address, value)
+    }
+
+    /// Load word data from the address plus `offset`, only legal with `CpuFeatures::MEMORY_OFFSET`.
+    pub fn lwd_offset(&mut self, address: u16, offset: u16, dest: u16) -> &mut Self {
+        let offset = self.memory_offset(offset);
+        self.two_regs(0x7000 | (offset << 8), address, dest)
+    }
+
+    /// Load word instruction.
+    pub fn lwi(&mut self, address: u16, dest: u16) -> &mut Self {
+        self.two_regs(0x2200, address, dest)
+    }
+
+    /// Loads an arbitrary value, using "load immediate high" only if the sign-extended low byte is not enough.
+    pub fn lw(&mut self, register: u16, value: u16) -> &mut Self {
+        self.lw_low(register, value as u8);
+        if value != value as u8 as i8 as u16 {
+            self.lhi(register, (value >> 8) as u8);
+        }
+        self
+    }
+
+    /// Load immediate low, sign-extended.
+    pub fn lw_low(&mut self, register: u16, byte: u8) -> &mut Self {
+        let register = self.reg(register);
+        self.word(0x3000 | (register << 8) | byte as u16)
+    }
+
+    /// Load immediate high.
+    pub fn lhi(&mut self, register: u16, byte: u8) -> &mut Self {
+        let register = self.reg(register);
+        self.word(0x4000 | (register << 8) | byte as u16)
+    }
+
+    pub fn decr(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5800, src, dest)
+    }
+
+    pub fn incr(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5900, src, dest)
+    }
+
+    pub fn not(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5A00, src, dest)
+    }
+
+    pub fn popcnt(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5B00, src, dest)
+    }
+
+    pub fn clz(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5C00, src, dest)
+    }
+
+    pub fn ctz(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5D00, src, dest)
+    }
+
+    pub fn rnd(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5E00, src, dest)
+    }
+
+    pub fn mov(&mut self, dest: u16, src: u16) -> &mut Self {
+        self.two_regs(0x5F00, src, dest)
+    }
+
+    pub fn add(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6000, lhs, rhs)
+    }
+
+    pub fn sub(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6100, lhs, rhs)
+    }
+
+    pub fn mul(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6200, lhs, rhs)
+    }
+
+    pub fn mulh(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6300, lhs, rhs)
+    }
+
+    pub fn div_u(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6400, lhs, rhs)
+    }
+
+    pub fn div_s(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6500, lhs, rhs)
+    }
+
+    pub fn mod_u(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6600, lhs, rhs)
+    }
+
+    pub fn mod_s(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6700, lhs, rhs)
+    }
+
+    pub fn and(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6800, lhs, rhs)
+    }
+
+    pub fn or(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6900, lhs, rhs)
+    }
+
+    pub fn xor(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6A00, lhs, rhs)
+    }
+
+    pub fn sl(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6B00, lhs, rhs)
+    }
+
+    pub fn srl(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6C00, lhs, rhs)
+    }
+
+    pub fn sra(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6D00, lhs, rhs)
+    }
+
+    pub fn exp(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6E00, lhs, rhs)
+    }
+
+    pub fn root(&mut self, lhs: u16, rhs: u16) -> &mut Self {
+        self.two_regs(0x6F00, lhs, rhs)
+    }
+
+    /// Compares `lhs` with `rhs` and writes the result to `rhs`. `flags` is `0bLEGS`, e.g. `0b0110` is "greater or
+    /// equal, unsigned".
+    pub fn compare(&mut self, flags: u16, lhs: u16, rhs: u16) -> &mut Self {
+        let flags = self.flags(flags);
+        self.two_regs(0x8000 | (flags << 8), lhs, rhs)
+    }
+
+    /// Branches to `label` if `register` is nonzero.
+    pub fn b(&mut self, register: u16, label: &str) -> &mut Self {
+        let register = self.reg(register);
+        self.fixup(label, Fixup::Branch { register })
+    }
+
+    /// Jumps by immediate to `label`.
+    pub fn j(&mut self, label: &str) -> &mut Self {
+        self.fixup(label, Fixup::JumpImm)
+    }
+
+    /// Jumps to the content of `register` plus `offset`.
+    pub fn jr(&mut self, register: u16, offset: i8) -> &mut Self {
+        let register = self.reg(register);
+        self.word(0xB000 | (register << 8) | offset as u8 as u16)
+    }
+
+    fn fixup(&mut self, label: &str, fixup: Fixup) -> &mut Self {
+        self.fixups.push((self.words.len(), label.into(), fixup));
+        // Placeholder, overwritten by build.
+        self.word(0x0000)
+    }
+
+    /// Resolves all labels and returns the program, starting at address 0.
+    pub fn build(&self) -> Result<Vec<u16>, BuildError> {
+        if let Some(error) = &self.error {
+            return Err(error.clone());
+        }
+        if self.words.len() > 1 << 16 {
+            return Err(BuildError::TooLong {
+                len: self.words.len(),
+            });
+        }
+        let mut words = self.words.clone();
+        for (address, label, fixup) in &self.fixups {
+            let target = self
+                .labels
+                .get(label)
+                .ok_or_else(|| BuildError::UnknownLabel {
+                    label: label.clone(),
+                })?;
+            let relative = *target as i32 - *address as i32;
+            let encoded = match fixup {
+                Fixup::Branch { register } => encode_branch(*register, relative),
+                Fixup::JumpImm => encode_jump_imm(relative),
+            };
+            words[*address] = encoded.map_err(|source| BuildError::Offset {
+                label: label.clone(),
+                source,
+            })?;
+        }
+        Ok(words)
+    }
+
+    /// Like `build`, but as an instruction segment, padded with zeros.
+    pub fn build_segment(&self) -> Result<Segment, BuildError> {
+        let mut segment = Segment::new_zeroed();
+        for (i, word) in self.build()?.into_iter().enumerate() {
+            segment[i as u16] = word;
+        }
+        Ok(segment)
+    }
+}
+
+#[cfg(test)]
+mod test_builder {
+    use super::*;
+    use crate::selftest::FIBONACCI;
+
+    #[test]
+    fn test_lw() {
+        let mut b = ProgramBuilder::new();
+        b.lw(0, 0x1337)
+            .lw(7, 0xABCD)
+            .lw(3, 0xFFFF)
+            .lw(1, 0xFF89)
+            .lw(2, 0x007F);
+        b.lw(5, 0x0080).lw(4, 0);
+        assert_eq!(
+            b.build().unwrap(),
+            vec![
+                0x3037, 0x4013, // lw r0, 0x1337
+                0x37CD, 0x47AB, // lw r7, 0xABCD
+                0x33FF, // lw r3, 0xFFFF
+                0x3189, // lw r1, 0xFF89
+                0x327F, // lw r2, 0x007F
+                0x3580, 0x4500, // lw r5, 0x0080
+                0x3400, // lw r4, 0
+            ]
+        );
+    }
+
+    #[test]
+    fn test_all_instructions() {
+        let mut b = ProgramBuilder::new();
+        b.ret().cpuid().debug_dump().time();
+        b.compare_zero(0b1001, 3).compare_zero(0b0100, 7);
+        b.sw(2, 5).lwd(2, 5).lwi(2, 5);
+        b.lw_low(5, 0x8E).lhi(10, 0x56);
+        b.decr(0, 0)
+            .incr(3, 3)
+            .not(6, 5)
+            .popcnt(7, 5)
+            .clz(8, 5)
+            .ctz(9, 5)
+            .rnd(0, 2)
+            .mov(0, 3);
+        b.add(1, 2)
+            .sub(1, 2)
+            .mul(5, 6)
+            .mulh(1, 2)
+            .div_u(1, 2)
+            .div_s(1, 2)
+            .mod_u(1, 0);
+        b.mod_s(1, 2)
+            .and(1, 2)
+            .or(1, 2)
+            .xor(1, 2)
+            .sl(1, 2)
+            .srl(1, 2)
+            .sra(1, 2);
+        b.exp(1, 2).root(1, 2);
+        b.compare(0b1010, 3, 4).compare(0b0110, 1, 0);
+        b.jr(7, 0x34).jr(7, -1).jr(0, 0);
+        b.lwd_offset(1, 3, 2).sw_offset(1, 7, 2);
+        b.ill(0xFFFF).word(0x1234);
+        assert_eq!(
+            b.build().unwrap(),
+            vec![
+                0x102A, 0x102B, 0x102C, 0x102D, //
+                0x1193, 0x1147, //
+                0x2025, 0x2125, 0x2225, //
+                0x358E, 0x4A56, //
+                0x5800, 0x5933, 0x5A56, 0x5B57, 0x5C58, 0x5D59, 0x5E20, 0x5F30, //
+                0x6012, 0x6112, 0x6256, 0x6312, 0x6412, 0x6512, 0x6610, //
+                0x6712, 0x6812, 0x6912, 0x6A12, 0x6B12, 0x6C12, 0x6D12, //
+                0x6E12, 0x6F12, //
+                0x8A34, 0x8610, //
+                0xB734, 0xB7FF, 0xB000, //
+                0x7312, 0x7F12, //
+                0xFFFF, 0x1234,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        let mut b = ProgramBuilder::new();
+        b.lw(0, 24).lw(1, 1);
+        b.label("start");
+        b.add(1, 2)
+            .decr(0, 0)
+            .sw(0, 2)
+            .add(2, 1)
+            .decr(0, 0)
+            .sw(0, 1);
+        b.b(0, "start");
+        b.ret();
+        assert_eq!(b.build().unwrap(), FIBONACCI.instructions);
+    }
+
+    #[test]
+    fn test_connect4_bot() {
+        // The second player in connect4's test_board_full, as it was originally hand-encoded.
+        let mut b = ProgramBuilder::new();
+        b.lw(1, 0xFF89).lwd(1, 1).b(1, "move_nonzero");
+        b.lw(0, 3).ret();
+        b.label("move_nonzero");
+        b.lw(0, 18).compare(0b0110, 1, 0).b(0, "move_late");
+        b.decr(1, 1);
+        b.label("move_late");
+        b.lw(0, 7).mod_u(1, 0).ret();
+        assert_eq!(
+            b.build().unwrap(),
+            vec![
+                0x3189, // lw r1, 0xFF89
+                0x2111, // lw r1, r1
+                0x9101, // b r1 move_nonzero // (offset is +0x3)
+                0x3003, // lw r0, 3
+                0x102A, // ret
+                0x3012, // lw r0, 18
+                0x8610, // ge r1 r0
+                0x9000, // b r0 move_late // (offset is +0x2)
+                0x5811, // decr r1
+                0x3007, // lw r0, 7
+                0x6610, // mod r1 r0
+                0x102A, // ret
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels() {
+        let mut b = ProgramBuilder::new();
+        b.label("top").j("end").b(3, "top").label("end").j("top");
+        b.b(1, "far");
+        for _ in 0..0x80 {
+            b.word(0);
+        }
+        b.label("far");
+        assert_eq!(
+            b.build().unwrap()[..4],
+            [
+                0xA000, // j end // (offset is +0x2)
+                0x9380, // b r3 top // (offset is -0x1)
+                0xA801, // j top // (offset is -0x2)
+                0x917F, // b r1 far // (offset is +0x81)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_segment() {
+        let segment = ProgramBuilder::new()
+            .lw(0, 0x1337)
+            .ret()
+            .build_segment()
+            .unwrap();
+        assert_eq!(segment[0], 0x3037);
+        assert_eq!(segment[1], 0x4013);
+        assert_eq!(segment[2], 0x102A);
+        assert_eq!(segment[3], 0x0000);
+        assert_eq!(segment[0xFFFF], 0x0000);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            ProgramBuilder::new().mov(16, 0).build(),
+            Err(BuildError::InvalidRegister { register: 16 })
+        );
+        assert_eq!(
+            ProgramBuilder::new().compare(0x10, 0, 1).build(),
+            Err(BuildError::InvalidFlags { flags: 0x10 })
+        );
+        assert_eq!(
+            ProgramBuilder::new().sw_offset(0, 8, 1).build(),
+            Err(BuildError::InvalidMemoryOffset { offset: 8 })
+        );
+        assert_eq!(
+            ProgramBuilder::new().label("a").label("a").build(),
+            Err(BuildError::DuplicateLabel { label: "a".into() })
+        );
+        assert_eq!(
+            ProgramBuilder::new().j("nowhere").build(),
+            Err(BuildError::UnknownLabel {
+                label: "nowhere".into()
+            })
+        );
+        let err = ProgramBuilder::new()
+            .label("self")
+            .b(0, "self")
+            .build()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Cannot reach label 'self': Relative offset 0 cannot be encoded, it would be an infinite loop or a no-op."
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "Relative offset 0 cannot be encoded, it would be an infinite loop or a no-op."
+        );
+
+        let mut b = ProgramBuilder::new();
+        b.b(0, "far");
+        for _ in 0..0x81 {
+            b.word(0);
+        }
+        b.label("far");
+        assert!(matches!(
+            b.build(),
+            Err(BuildError::Offset {
+                source: OffsetError::OutOfRange { relative: 0x82, .. },
+                ..
+            })
+        ));
+    }
+}
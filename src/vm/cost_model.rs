@@ -0,0 +1,145 @@
+use super::{BinaryFunction, InsnClass, UnaryFunction};
+
+/// How much `time` each instruction costs, see `VirtualMachine::set_cost_model`. The cost only depends on the high
+/// byte of the instruction, i.e. on the opcode family, and for unary and binary instructions on the function. By
+/// default, every instruction costs 1, just like without a model.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CostModel {
+    /// Indexed by the high byte of the instruction.
+    costs: [u8; 256],
+}
+
+impl Default for CostModel {
+    fn default() -> CostModel {
+        CostModel { costs: [1; 256] }
+    }
+}
+
+impl CostModel {
+    #[must_use]
+    pub fn new() -> CostModel {
+        CostModel::default()
+    }
+
+    /// Sets the cost of every instruction in `class`, including the unary and binary functions. Panics if `cost`
+    /// is zero, because then a loop could run forever within any budget.
+    pub fn set_class(&mut self, class: InsnClass, cost: u8) {
+        assert!(cost > 0, "Instructions must cost at least 1.");
+        for high_byte in 0..=0xFF {
+            if InsnClass::of(high_byte << 8) == Some(class) {
+                self.costs[high_byte as usize] = cost;
+            }
+        }
+    }
+
+    /// Panics if `cost` is zero, see `set_class`.
+    pub fn set_unary(&mut self, function: UnaryFunction, cost: u8) {
+        assert!(cost > 0, "Instructions must cost at least 1.");
+        self.costs[0x50 | function as usize] = cost;
+    }
+
+    /// Panics if `cost` is zero, see `set_class`.
+    pub fn set_binary(&mut self, function: BinaryFunction, cost: u8) {
+        assert!(cost > 0, "Instructions must cost at least 1.");
+        self.costs[0x60 | function as usize] = cost;
+    }
+
+    /// Returns the cost of `instruction`. Illegal instructions have a cost too, but are never charged.
+    #[must_use]
+    pub fn cost_of(&self, instruction: u16) -> u8 {
+        self.costs[(instruction >> 8) as usize]
+    }
+}
+
+#[cfg(test)]
+mod test_cost_model {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StopReason, VirtualMachine};
+
+    fn weighted() -> CostModel {
+        let mut model = CostModel::new();
+        model.set_class(InsnClass::LoadData, 3);
+        model.set_class(InsnClass::StoreData, 3);
+        model.set_binary(BinaryFunction::Mul, 4);
+        model.set_binary(BinaryFunction::DivU, 20);
+        model
+    }
+
+    #[test]
+    fn test_cost_of() {
+        let model = weighted();
+        assert_eq!(model.cost_of(0x2012), 3);
+        assert_eq!(model.cost_of(0x2112), 3);
+        assert_eq!(model.cost_of(0x2212), 1);
+        assert_eq!(model.cost_of(0x6212), 4);
+        assert_eq!(model.cost_of(0x6412), 20);
+        assert_eq!(model.cost_of(0x6012), 1);
+        assert_eq!(CostModel::new().cost_of(0x6412), 1);
+
+        let mut model = CostModel::new();
+        model.set_class(InsnClass::Binary, 2);
+        model.set_unary(UnaryFunction::Rnd, 7);
+        assert_eq!(model.cost_of(0x6F12), 2);
+        assert_eq!(model.cost_of(0x5E12), 7);
+        assert_eq!(model.cost_of(0x5F12), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "at least 1")]
+    fn test_zero_cost() {
+        CostModel::new().set_class(InsnClass::JumpImm, 0);
+    }
+
+    fn program() -> Segment {
+        tinyvm_asm! {
+            lw r1, 0x10;
+            lw r2, 6;
+            sw r1, r2;
+            lwd r1, r3;
+            mul r2, r3;
+            div_u r2, r3;
+            time;
+            mov r0, r3;
+            ret;
+        }
+    }
+
+    #[test]
+    fn test_default_model_changes_nothing() {
+        let mut plain = VirtualMachine::new(program(), Segment::new_zeroed());
+        let mut modeled = VirtualMachine::new(program(), Segment::new_zeroed());
+        modeled.set_cost_model(Some(CostModel::new()));
+        let plain_outcome = plain.run(100);
+        assert_eq!(modeled.run(100), plain_outcome);
+        assert_eq!(plain_outcome.steps, 8);
+        assert_eq!(plain_outcome.reason, StopReason::Returned(6));
+        assert_eq!(modeled.get_registers(), plain.get_registers());
+    }
+
+    #[test]
+    fn test_weighted_model() {
+        let mut vm = VirtualMachine::new(program(), Segment::new_zeroed());
+        vm.set_cost_model(Some(weighted()));
+        let outcome = vm.run(100);
+        // Two loads of immediates, the store, the load, mul, and div.u before Time.
+        assert_eq!(outcome.reason, StopReason::Returned(1 + 1 + 3 + 3 + 4 + 20));
+        assert_eq!(outcome.steps, 32 + 1 + 1);
+        assert_eq!(vm.get_time(), 34);
+    }
+
+    #[test]
+    fn test_weighted_budget() {
+        let mut vm = VirtualMachine::new(program(), Segment::new_zeroed());
+        vm.set_cost_model(Some(weighted()));
+        // The division does not fit into what is left of the budget, so it is not executed.
+        let outcome = vm.run(20);
+        assert_eq!(outcome.reason, StopReason::OutOfBudget);
+        assert_eq!(outcome.steps, 12);
+        assert_eq!(vm.get_program_counter(), 5);
+        let outcome = vm.run(20);
+        assert_eq!(outcome.reason, StopReason::OutOfBudget);
+        assert_eq!(outcome.steps, 20);
+        assert_eq!(vm.run(20).reason, StopReason::Returned(32));
+    }
+}
@@ -0,0 +1,214 @@
+use super::{BinaryFunction, CpuFeatures, StepResult, UnaryFunction, VirtualMachine};
+
+/// Executes the given instruction word. The last argument is cleared if the handler already moved the program
+/// counter, or if it must not move at all.
+type Handler = fn(&mut VirtualMachine, u16, &mut bool) -> StepResult;
+
+/// How to execute all instructions that share the same high byte, see `DISPATCH`.
+#[derive(Clone, Copy)]
+pub(super) struct Op {
+    pub(super) handler: Handler,
+    /// The features that must be enabled, otherwise the instruction is illegal.
+    pub(super) features: CpuFeatures,
+}
+
+const fn op(handler: Handler) -> Op {
+    Op {
+        handler,
+        features: CpuFeatures::empty(),
+    }
+}
+
+const fn op_with(handler: Handler, features: CpuFeatures) -> Op {
+    Op { handler, features }
+}
+
+/// The high byte of an instruction word determines everything but the operands, so `step` looks up how to execute
+/// it in this table, instead of decoding into an `Instruction` first. Must agree with `Instruction::decode` and
+/// `Instruction::required_features`, see `test_agrees_with_decode`.
+pub(super) static DISPATCH: [Op; 256] = build_dispatch();
+
+const fn build_dispatch() -> [Op; 256] {
+    const UNARY: [Handler; 8] = [
+        VirtualMachine::op_unary::<0x8>,
+        VirtualMachine::op_unary::<0x9>,
+        VirtualMachine::op_unary::<0xA>,
+        VirtualMachine::op_unary::<0xB>,
+        VirtualMachine::op_unary::<0xC>,
+        VirtualMachine::op_unary::<0xD>,
+        VirtualMachine::op_unary::<0xE>,
+        VirtualMachine::op_unary::<0xF>,
+    ];
+    const BINARY: [Handler; 16] = [
+        VirtualMachine::op_binary::<0x0>,
+        VirtualMachine::op_binary::<0x1>,
+        VirtualMachine::op_binary::<0x2>,
+        VirtualMachine::op_binary::<0x3>,
+        VirtualMachine::op_binary::<0x4>,
+        VirtualMachine::op_binary::<0x5>,
+        VirtualMachine::op_binary::<0x6>,
+        VirtualMachine::op_binary::<0x7>,
+        VirtualMachine::op_binary::<0x8>,
+        VirtualMachine::op_binary::<0x9>,
+        VirtualMachine::op_binary::<0xA>,
+        VirtualMachine::op_binary::<0xB>,
+        VirtualMachine::op_binary::<0xC>,
+        VirtualMachine::op_binary::<0xD>,
+        VirtualMachine::op_binary::<0xE>,
+        VirtualMachine::op_binary::<0xF>,
+    ];
+
+    let mut table = [op(VirtualMachine::op_illegal); 256];
+    table[0x10] = op(VirtualMachine::op_system);
+    table[0x11] = op_with(VirtualMachine::op_compare_zero, CpuFeatures::COMPARE_ZERO);
+    table[0x20] = op(VirtualMachine::op_store_data);
+    table[0x21] = op(VirtualMachine::op_load_data);
+    table[0x22] = op(VirtualMachine::op_load_instruction);
+    table[0x23] = op_with(
+        VirtualMachine::op_store_instruction,
+        CpuFeatures::STORE_INSTRUCTION,
+    );
+    let mut nibble = 0;
+    while nibble < 16 {
+        table[0x30 | nibble] = op(VirtualMachine::op_load_imm_low);
+        table[0x40 | nibble] = op(VirtualMachine::op_load_imm_high);
+        table[0x60 | nibble] = op(BINARY[nibble]);
+        table[0x70 | nibble] =
+            op_with(VirtualMachine::op_memory_offset, CpuFeatures::MEMORY_OFFSET);
+        table[0x80 | nibble] = op(VirtualMachine::op_compare);
+        table[0x90 | nibble] = op(VirtualMachine::op_branch);
+        table[0xA0 | nibble] = op(VirtualMachine::op_jump_imm);
+        table[0xB0 | nibble] = op(VirtualMachine::op_jump_reg);
+        if nibble >= 8 {
+            table[0x50 | nibble] = op(UNARY[nibble - 8]);
+        }
+        nibble += 1;
+    }
+    table[0x5E].features = CpuFeatures::RND;
+    table[0x6E].features = CpuFeatures::EXP_ROOT;
+    table[0x6F].features = CpuFeatures::EXP_ROOT;
+    table
+}
+
+// The operands are always in the same nibbles, so the handlers extract them directly. The step_* functions only
+// look at the bits they need, so the immediates are passed as the whole word.
+impl VirtualMachine {
+    fn op_illegal(&mut self, word: u16, _: &mut bool) -> StepResult {
+        StepResult::IllegalInstruction(word)
+    }
+
+    fn op_system(&mut self, word: u16, increment_pc_as_usual: &mut bool) -> StepResult {
+        match word {
+            0x102A => {
+                // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102a-return
+                *increment_pc_as_usual = false;
+                StepResult::Return(self.registers[0])
+            }
+            0x102B => self.step_cpuid(),
+            // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102c-debug-dump
+            0x102C => StepResult::DebugDump,
+            0x102D => self.step_time(),
+            _ => StepResult::IllegalInstruction(word),
+        }
+    }
+
+    fn op_compare_zero(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_compare_zero((word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_store_data(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_store_data((word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_load_data(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_load_data((word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_load_instruction(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_load_instruction((word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_store_instruction(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_store_instruction((word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_load_imm_low(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_load_imm_low((word >> 8) & 0xF, word)
+    }
+
+    fn op_load_imm_high(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_load_imm_high((word >> 8) & 0xF, word & 0xFF)
+    }
+
+    fn op_unary<const FUNCTION: u16>(&mut self, word: u16, _: &mut bool) -> StepResult {
+        let function = const {
+            match UnaryFunction::from_bits(FUNCTION) {
+                Some(function) => function,
+                None => panic!("reserved unary function"),
+            }
+        };
+        self.step_unary(function, (word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_binary<const FUNCTION: u16>(&mut self, word: u16, _: &mut bool) -> StepResult {
+        let function = const { BinaryFunction::from_bits(FUNCTION) };
+        self.step_binary(function, (word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_memory_offset(&mut self, word: u16, _: &mut bool) -> StepResult {
+        let store = word & 0x0800 != 0;
+        self.step_memory_offset(store, (word >> 8) & 0x7, (word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_compare(&mut self, word: u16, _: &mut bool) -> StepResult {
+        self.step_compare((word >> 8) & 0xF, (word >> 4) & 0xF, word & 0xF)
+    }
+
+    fn op_branch(&mut self, word: u16, increment_pc_as_usual: &mut bool) -> StepResult {
+        self.step_branch((word >> 8) & 0xF, word, increment_pc_as_usual)
+    }
+
+    fn op_jump_imm(&mut self, word: u16, increment_pc_as_usual: &mut bool) -> StepResult {
+        *increment_pc_as_usual = false;
+        self.step_jump_imm(word)
+    }
+
+    fn op_jump_reg(&mut self, word: u16, increment_pc_as_usual: &mut bool) -> StepResult {
+        *increment_pc_as_usual = false;
+        self.step_jump_reg((word >> 8) & 0xF, word)
+    }
+}
+
+#[cfg(test)]
+mod test_dispatch {
+    use super::*;
+    use crate::vm::{Instruction, Segment};
+
+    /// Whether `word` is legal, judged by actually executing it.
+    fn executes(word: u16, features: CpuFeatures) -> bool {
+        let mut vm = VirtualMachine::new_with_seed(Segment::new_sparse(), Segment::new_sparse(), 0);
+        vm.set_features(features);
+        vm.set_instruction_word(0, word);
+        vm.step() != StepResult::IllegalInstruction(word)
+    }
+
+    #[test]
+    fn test_agrees_with_decode() {
+        for word in 0..=0xFFFF {
+            let required = Instruction::decode(word).map(|decoded| decoded.required_features());
+            assert_eq!(
+                executes(word, CpuFeatures::ALL),
+                required.is_ok(),
+                "{:04X}",
+                word
+            );
+            let legal_without = required.is_ok_and(|required| required == CpuFeatures::empty());
+            assert_eq!(
+                executes(word, CpuFeatures::empty()),
+                legal_without,
+                "{:04X}",
+                word
+            );
+        }
+    }
+}
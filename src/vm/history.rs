@@ -0,0 +1,272 @@
+use super::{CpuFeatures, Instruction, SplitMix64, VirtualMachine};
+use std::collections::VecDeque;
+
+/// Everything a single executed instruction may change, from before it was executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct UndoRecord {
+    program_counter: u16,
+    time: u64,
+    registers: [u16; 16],
+    deterministic_so_far: bool,
+    random_calls: u64,
+    last_debug_dump_time: u64,
+    resuming_from_breakpoint: bool,
+    rng: Option<SplitMix64>,
+    /// Address and old value, only for stores.
+    data: Option<(u16, u16)>,
+    /// Address and old value, only for `swi`.
+    instruction: Option<(u16, u16)>,
+}
+
+/// The undo records of at most `capacity` instructions, oldest first, see `VirtualMachine::enable_history`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct History {
+    capacity: usize,
+    records: VecDeque<UndoRecord>,
+}
+
+impl History {
+    pub(crate) fn new(capacity: usize) -> History {
+        History {
+            capacity,
+            records: VecDeque::new(),
+        }
+    }
+
+    /// Discards the oldest records that no longer fit.
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.records.len() > capacity {
+            self.records.pop_front();
+        }
+    }
+
+    /// Discards the oldest record if there is no room left.
+    pub(crate) fn push(&mut self, record: UndoRecord) {
+        if self.capacity > 0 {
+            if self.records.len() == self.capacity {
+                self.records.pop_front();
+            }
+            self.records.push_back(record);
+        }
+    }
+
+    /// Removes the most recent record.
+    pub(crate) fn pop(&mut self) -> Option<UndoRecord> {
+        self.records.pop_back()
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+impl VirtualMachine {
+    /// Saves what executing `instruction` in the current state may change.
+    pub(super) fn undo_record(
+        &self,
+        instruction: u16,
+        resuming_from_breakpoint: bool,
+    ) -> UndoRecord {
+        let mut record = UndoRecord {
+            program_counter: self.program_counter,
+            time: self.time,
+            registers: self.registers,
+            deterministic_so_far: self.deterministic_so_far,
+            random_calls: self.random_calls,
+            last_debug_dump_time: self.last_debug_dump_time,
+            resuming_from_breakpoint,
+            rng: self.rng.clone(),
+            data: None,
+            instruction: None,
+        };
+        match Instruction::decode(instruction) {
+            Ok(Instruction::StoreData { address_reg, .. }) => {
+                let address = self.registers[address_reg as usize];
+                record.data = Some((address, self.data[address]));
+            }
+            Ok(Instruction::StoreDataOffset {
+                address_reg,
+                offset,
+                ..
+            }) => {
+                let address = self.registers[address_reg as usize].wrapping_add(offset);
+                record.data = Some((address, self.data[address]));
+            }
+            Ok(Instruction::StoreInstruction { address_reg, .. })
+                if self.features.contains(CpuFeatures::STORE_INSTRUCTION) =>
+            {
+                let address = self.registers[address_reg as usize];
+                record.instruction = Some((address, self.instructions[address]));
+            }
+            _ => {}
+        }
+        record
+    }
+
+    /// Restores everything `record` saved, see `step_back`.
+    pub(super) fn undo(&mut self, record: UndoRecord) {
+        self.program_counter = record.program_counter;
+        self.time = record.time;
+        self.registers = record.registers;
+        self.deterministic_so_far = record.deterministic_so_far;
+        self.random_calls = record.random_calls;
+        self.last_debug_dump_time = record.last_debug_dump_time;
+        self.resuming_from_breakpoint = record.resuming_from_breakpoint;
+        self.rng = record.rng;
+        if let Some((address, old)) = record.data {
+            self.data[address] = old;
+        }
+        if let Some((address, old)) = record.instruction {
+            // The store may have written the value that was already there. Writing it again would copy a shared
+            // segment for nothing.
+            if self.get_instructions()[address] != old {
+                self.get_instructions_mut()[address] = old;
+            }
+        }
+        // Only instructions of a running machine are recorded.
+        self.halted = None;
+    }
+}
+
+#[cfg(test)]
+mod test_history {
+    use crate::selftest::FIBONACCI;
+    use crate::vm::{CountingSource, Extension, Segment, StepResult, StopReason, VirtualMachine};
+    use std::sync::Arc;
+
+    fn fibonacci_vm() -> VirtualMachine {
+        let mut instructions = Segment::new_zeroed();
+        for (i, &word) in FIBONACCI.instructions.iter().enumerate() {
+            instructions[i as u16] = word;
+        }
+        // Compute more numbers, so that it runs for more than 200 steps.
+        instructions[0] = 0x3040; // lw r0, 64
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_step_back_and_rerun() {
+        let mut straight = fibonacci_vm();
+        straight.run(100);
+
+        let mut vm = fibonacci_vm();
+        vm.enable_history(50);
+        vm.run(100);
+        assert_eq!(vm.history_len(), 50);
+        for _ in 0..30 {
+            assert!(vm.step_back());
+        }
+        assert_eq!(vm.get_time(), 70);
+        assert_ne!(vm.get_data(), straight.get_data());
+        vm.run(30);
+        assert_eq!(vm.get_time(), straight.get_time());
+        assert_eq!(vm.get_program_counter(), straight.get_program_counter());
+        assert_eq!(vm.get_registers(), straight.get_registers());
+        assert_eq!(vm.get_data(), straight.get_data());
+    }
+
+    #[test]
+    fn test_step_back_matches_earlier_state() {
+        let mut vm = fibonacci_vm();
+        vm.enable_history(10);
+        vm.run(20);
+        let earlier = vm.clone();
+        vm.run(5);
+        for _ in 0..5 {
+            assert!(vm.step_back());
+        }
+        assert_eq!(vm.get_time(), earlier.get_time());
+        assert_eq!(vm.get_program_counter(), earlier.get_program_counter());
+        assert_eq!(vm.get_registers(), earlier.get_registers());
+        assert_eq!(vm.get_data(), earlier.get_data());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut vm = fibonacci_vm();
+        assert!(!vm.step_back());
+        vm.enable_history(3);
+        vm.run(10);
+        assert_eq!(vm.history_len(), 3);
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(!vm.step_back());
+        assert_eq!(vm.get_time(), 7);
+    }
+
+    #[test]
+    fn test_step_back_unhalts() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3105; // lw r1, 5
+        instructions[1] = 0x0123; // illegal
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_history(10);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0x0123));
+        assert!(vm.step_back());
+        assert_eq!(vm.get_halted(), None);
+        assert_eq!(vm.get_program_counter(), 1);
+        assert!(vm.step_back());
+        assert_eq!(vm.get_registers()[1], 0);
+        assert_eq!(vm.get_program_counter(), 0);
+    }
+
+    #[test]
+    fn test_step_back_store_instruction_and_rnd() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3108; // lw r1, 8
+        instructions[1] = 0x5E12; // rnd r2, r1
+        instructions[2] = 0x2312; // swi r1, r2
+        let mut vm = VirtualMachine::new_with_seed(instructions, Segment::new_zeroed(), 42);
+        vm.enable_extension(Extension::StoreInstruction);
+        vm.enable_history(10);
+        vm.run(3);
+        let stored = vm.get_instructions()[8];
+        assert!(vm.step_back());
+        assert_eq!(vm.get_instructions()[8], 0x0000);
+        assert!(vm.step_back());
+        assert!(vm.was_deterministic_so_far());
+        vm.run(2);
+        assert_eq!(vm.get_instructions()[8], stored);
+    }
+
+    #[test]
+    fn test_step_back_does_not_rewind_random_source() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3108; // lw r1, 8
+        instructions[1] = 0x5E12; // rnd r2, r1
+        let mut vm = VirtualMachine::with_random_source(
+            instructions,
+            Segment::new_zeroed(),
+            Box::new(CountingSource::new(3)),
+        );
+        vm.enable_history(10);
+        vm.run(2);
+        assert_eq!(vm.get_registers()[2], 3);
+        assert!(vm.step_back());
+        vm.step();
+        // The source moved on, as documented by `enable_history`.
+        assert_eq!(vm.get_registers()[2], 4);
+    }
+
+    #[test]
+    fn test_step_back_keeps_instructions_shared() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x2212; // lwi r2, r1
+        instructions[1] = 0x2312; // swi r1, r2
+        let instructions = Arc::new(instructions);
+        let mut vm = VirtualMachine::new_shared(Arc::clone(&instructions), Segment::new_zeroed());
+        vm.enable_history(10);
+        // Without the extension, the store is illegal and writes nothing, so undoing it must not copy either.
+        assert_eq!(vm.run(2).reason, StopReason::IllegalInstruction(0x2312));
+        assert!(vm.step_back());
+        assert!(vm.step_back());
+        assert!(std::ptr::eq(vm.get_instructions(), &*instructions));
+    }
+}
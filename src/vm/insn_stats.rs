@@ -0,0 +1,212 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// The instruction classes of the instruction set architecture, by prefix.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum InsnClass {
+    /// Return, CPUID, Debug-dump, Time
+    Special,
+    CompareZero,
+    StoreData,
+    LoadData,
+    LoadInstruction,
+    /// Only with `CpuFeatures::STORE_INSTRUCTION`.
+    StoreInstruction,
+    LoadImmLow,
+    LoadImmHigh,
+    Unary,
+    Binary,
+    /// Only with `CpuFeatures::MEMORY_OFFSET`.
+    LoadDataOffset,
+    /// Only with `CpuFeatures::MEMORY_OFFSET`.
+    StoreDataOffset,
+    Compare,
+    Branch,
+    JumpImm,
+    JumpReg,
+}
+
+impl InsnClass {
+    pub const ALL: [InsnClass; 16] = [
+        InsnClass::Special,
+        InsnClass::CompareZero,
+        InsnClass::StoreData,
+        InsnClass::LoadData,
+        InsnClass::LoadInstruction,
+        InsnClass::StoreInstruction,
+        InsnClass::LoadImmLow,
+        InsnClass::LoadImmHigh,
+        InsnClass::Unary,
+        InsnClass::Binary,
+        InsnClass::LoadDataOffset,
+        InsnClass::StoreDataOffset,
+        InsnClass::Compare,
+        InsnClass::Branch,
+        InsnClass::JumpImm,
+        InsnClass::JumpReg,
+    ];
+
+    /// Returns the class by prefix only, or `None` if the prefix is illegal or reserved. Note that some
+    /// instructions with a known prefix may still be illegal, e.g. reserved unary functions.
+    #[must_use]
+    pub fn of(instruction: u16) -> Option<InsnClass> {
+        match instruction >> 12 {
+            0x1 => match instruction >> 8 {
+                0x10 => Some(InsnClass::Special),
+                0x11 => Some(InsnClass::CompareZero),
+                _ => None,
+            },
+            0x2 => match instruction >> 8 {
+                0x20 => Some(InsnClass::StoreData),
+                0x21 => Some(InsnClass::LoadData),
+                0x22 => Some(InsnClass::LoadInstruction),
+                0x23 => Some(InsnClass::StoreInstruction),
+                _ => None,
+            },
+            0x3 => Some(InsnClass::LoadImmLow),
+            0x4 => Some(InsnClass::LoadImmHigh),
+            0x5 => Some(InsnClass::Unary),
+            0x6 => Some(InsnClass::Binary),
+            0x7 if instruction & 0x0800 == 0 => Some(InsnClass::LoadDataOffset),
+            0x7 => Some(InsnClass::StoreDataOffset),
+            0x8 => Some(InsnClass::Compare),
+            0x9 => Some(InsnClass::Branch),
+            0xA => Some(InsnClass::JumpImm),
+            0xB => Some(InsnClass::JumpReg),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            InsnClass::Special => "special",
+            InsnClass::CompareZero => "compare-zero",
+            InsnClass::StoreData => "store-data",
+            InsnClass::LoadData => "load-data",
+            InsnClass::LoadInstruction => "load-instruction",
+            InsnClass::StoreInstruction => "store-instruction",
+            InsnClass::LoadImmLow => "load-imm-low",
+            InsnClass::LoadImmHigh => "load-imm-high",
+            InsnClass::Unary => "unary",
+            InsnClass::Binary => "binary",
+            InsnClass::LoadDataOffset => "load-data-offset",
+            InsnClass::StoreDataOffset => "store-data-offset",
+            InsnClass::Compare => "compare",
+            InsnClass::Branch => "branch",
+            InsnClass::JumpImm => "jump-imm",
+            InsnClass::JumpReg => "jump-reg",
+        }
+    }
+
+    fn index(&self) -> usize {
+        *self as usize
+    }
+}
+
+/// How many executed instructions fell into each class.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct InsnStats {
+    counts: [u64; InsnClass::ALL.len()],
+}
+
+impl InsnStats {
+    #[must_use]
+    pub fn new() -> InsnStats {
+        InsnStats::default()
+    }
+
+    /// Counts an instruction that was actually executed, i.e. that advanced the time. Such an instruction always
+    /// has a class.
+    pub fn record(&mut self, instruction: u16) {
+        if let Some(class) = InsnClass::of(instruction) {
+            self.counts[class.index()] += 1;
+        }
+    }
+
+    pub fn get(&self, class: InsnClass) -> u64 {
+        self.counts[class.index()]
+    }
+
+    pub fn total(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn add(&mut self, other: &InsnStats) {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+            *count += other_count;
+        }
+    }
+}
+
+impl Display for InsnStats {
+    /// One "class: count" per line, skipping classes that never occurred.
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        for class in InsnClass::ALL {
+            let count = self.get(class);
+            if count > 0 {
+                writeln!(f, "{}: {}", class.name(), count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_insn_stats {
+    use super::*;
+
+    #[test]
+    fn test_all_is_in_order() {
+        for (i, class) in InsnClass::ALL.iter().enumerate() {
+            assert_eq!(class.index(), i);
+        }
+    }
+
+    #[test]
+    fn test_of() {
+        assert_eq!(InsnClass::of(0x0000), None);
+        assert_eq!(InsnClass::of(0x102A), Some(InsnClass::Special));
+        assert_eq!(InsnClass::of(0x1193), Some(InsnClass::CompareZero));
+        assert_eq!(InsnClass::of(0x1234), None);
+        assert_eq!(InsnClass::of(0x2012), Some(InsnClass::StoreData));
+        assert_eq!(InsnClass::of(0x2111), Some(InsnClass::LoadData));
+        assert_eq!(InsnClass::of(0x2225), Some(InsnClass::LoadInstruction));
+        assert_eq!(InsnClass::of(0x2300), Some(InsnClass::StoreInstruction));
+        assert_eq!(InsnClass::of(0x2400), None);
+        assert_eq!(InsnClass::of(0x3189), Some(InsnClass::LoadImmLow));
+        assert_eq!(InsnClass::of(0x4013), Some(InsnClass::LoadImmHigh));
+        assert_eq!(InsnClass::of(0x5F30), Some(InsnClass::Unary));
+        assert_eq!(InsnClass::of(0x6610), Some(InsnClass::Binary));
+        assert_eq!(InsnClass::of(0x7000), Some(InsnClass::LoadDataOffset));
+        assert_eq!(InsnClass::of(0x7F12), Some(InsnClass::StoreDataOffset));
+        assert_eq!(InsnClass::of(0x8610), Some(InsnClass::Compare));
+        assert_eq!(InsnClass::of(0x9101), Some(InsnClass::Branch));
+        assert_eq!(InsnClass::of(0xA800), Some(InsnClass::JumpImm));
+        assert_eq!(InsnClass::of(0xB000), Some(InsnClass::JumpReg));
+        assert_eq!(InsnClass::of(0xC000), None);
+        assert_eq!(InsnClass::of(0xFFFF), None);
+    }
+
+    #[test]
+    fn test_record_and_add() {
+        let mut stats = InsnStats::new();
+        for insn in [0x3189, 0x3007, 0x2111, 0x6610, 0x0000] {
+            stats.record(insn);
+        }
+        assert_eq!(stats.get(InsnClass::LoadImmLow), 2);
+        assert_eq!(stats.get(InsnClass::LoadData), 1);
+        assert_eq!(stats.get(InsnClass::Binary), 1);
+        assert_eq!(stats.get(InsnClass::Branch), 0);
+        assert_eq!(stats.total(), 4);
+        assert_eq!(
+            stats.to_string(),
+            "load-data: 1\nload-imm-low: 2\nbinary: 1\n"
+        );
+
+        let mut sum = stats.clone();
+        sum.add(&stats);
+        assert_eq!(sum.get(InsnClass::LoadImmLow), 4);
+        assert_eq!(sum.total(), 8);
+    }
+}
@@ -0,0 +1,403 @@
+// A typed view of the instruction set architecture, see
+// https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md
+//
+// All register fields are register indices (0 to 15), and all offsets are the raw bits of the instruction. Use
+// `decode_branch` and `decode_jump_imm` to get the PC-relative delta. `encode` ignores any bits that do not fit in
+// their field.
+
+use crate::vm::CpuFeatures;
+
+/// The unary functions of `0x5xxx`. The functions `0b0000` to `0b0111` are illegal.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum UnaryFunction {
+    Decr = 0b1000,
+    Incr = 0b1001,
+    Not = 0b1010,
+    Popcnt = 0b1011,
+    Clz = 0b1100,
+    Ctz = 0b1101,
+    Rnd = 0b1110,
+    Mov = 0b1111,
+}
+
+impl UnaryFunction {
+    pub(crate) const fn from_bits(bits: u16) -> Option<UnaryFunction> {
+        Some(match bits {
+            0b1000 => UnaryFunction::Decr,
+            0b1001 => UnaryFunction::Incr,
+            0b1010 => UnaryFunction::Not,
+            0b1011 => UnaryFunction::Popcnt,
+            0b1100 => UnaryFunction::Clz,
+            0b1101 => UnaryFunction::Ctz,
+            0b1110 => UnaryFunction::Rnd,
+            0b1111 => UnaryFunction::Mov,
+            _ => return None,
+        })
+    }
+}
+
+/// The binary functions of `0x6xxx`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum BinaryFunction {
+    Add = 0b0000,
+    Sub = 0b0001,
+    Mul = 0b0010,
+    Mulh = 0b0011,
+    DivU = 0b0100,
+    DivS = 0b0101,
+    ModU = 0b0110,
+    ModS = 0b0111,
+    And = 0b1000,
+    Or = 0b1001,
+    Xor = 0b1010,
+    Sl = 0b1011,
+    Srl = 0b1100,
+    Sra = 0b1101,
+    Exp = 0b1110,
+    Root = 0b1111,
+}
+
+impl BinaryFunction {
+    pub(crate) const fn from_bits(bits: u16) -> BinaryFunction {
+        match bits & 0xF {
+            0b0000 => BinaryFunction::Add,
+            0b0001 => BinaryFunction::Sub,
+            0b0010 => BinaryFunction::Mul,
+            0b0011 => BinaryFunction::Mulh,
+            0b0100 => BinaryFunction::DivU,
+            0b0101 => BinaryFunction::DivS,
+            0b0110 => BinaryFunction::ModU,
+            0b0111 => BinaryFunction::ModS,
+            0b1000 => BinaryFunction::And,
+            0b1001 => BinaryFunction::Or,
+            0b1010 => BinaryFunction::Xor,
+            0b1011 => BinaryFunction::Sl,
+            0b1100 => BinaryFunction::Srl,
+            0b1101 => BinaryFunction::Sra,
+            0b1110 => BinaryFunction::Exp,
+            _ => BinaryFunction::Root,
+        }
+    }
+}
+
+/// A single legal instruction, e.g. for disassembling. `VirtualMachine::step` does not decode into this, but executes
+/// the word directly, see `dispatch::DISPATCH`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Hash)]
+pub enum Instruction {
+    /// `0x102A`
+    Return,
+    /// `0x102B`
+    Cpuid,
+    /// `0x102C`
+    DebugDump,
+    /// `0x102D`
+    Time,
+    /// `0x11FR`, the LEGS flags and the register that is compared to zero and overwritten.
+    CompareZero { flags: u16, reg: u16 },
+    /// `0x20AD`
+    StoreData { address_reg: u16, data_reg: u16 },
+    /// `0x21AD`
+    LoadData { address_reg: u16, data_reg: u16 },
+    /// `0x22AD`
+    LoadInstruction { address_reg: u16, data_reg: u16 },
+    /// `0x23AD`, only legal with `CpuFeatures::STORE_INSTRUCTION`.
+    StoreInstruction { address_reg: u16, data_reg: u16 },
+    /// `0x7OAD` with `O` in 0 to 7, only legal with `CpuFeatures::MEMORY_OFFSET`.
+    LoadDataOffset {
+        address_reg: u16,
+        offset: u16,
+        data_reg: u16,
+    },
+    /// `0x7OAD` with `O` in 8 to F, i.e. the offset is `O - 8`, only legal with `CpuFeatures::MEMORY_OFFSET`.
+    StoreDataOffset {
+        address_reg: u16,
+        offset: u16,
+        data_reg: u16,
+    },
+    /// `0x3RVV`, the value is sign-extended when executed.
+    LoadImmLow { reg: u16, value: u16 },
+    /// `0x4RVV`
+    LoadImmHigh { reg: u16, value: u16 },
+    /// `0x5FSD`
+    Unary {
+        func: UnaryFunction,
+        src: u16,
+        dst: u16,
+    },
+    /// `0x6FSD`
+    Binary {
+        func: BinaryFunction,
+        src: u16,
+        dst: u16,
+    },
+    /// `0x8FLR`, the LEGS flags. The result overwrites `rhs`.
+    Compare { flags: u16, lhs: u16, rhs: u16 },
+    /// `0x9RSV`, the offset is the raw `SVVVVVVV` bits.
+    Branch { reg: u16, offset: u16 },
+    /// `0xASVV`, the offset is the raw `SVVV VVVV VVVV` bits.
+    JumpImm { offset: u16 },
+    /// `0xBRVV`, the offset is sign-extended when executed.
+    JumpReg { reg: u16, offset: u16 },
+}
+
+impl Instruction {
+    /// Returns the instruction encoded by `word`, or the word itself if it is illegal or reserved. Executing exactly
+    /// these words yields `StepResult::IllegalInstruction`, and so do the instructions of disabled features, see
+    /// `required_features`.
+    pub fn decode(word: u16) -> Result<Instruction, u16> {
+        let nibble_2 = (word & 0x0F00) >> 8;
+        let nibble_1 = (word & 0x00F0) >> 4;
+        let nibble_0 = word & 0x000F;
+        let low_byte = word & 0x00FF;
+        let instruction = match word >> 12 {
+            0x1 => match (nibble_2, low_byte) {
+                (0x0, 0x2A) => Instruction::Return,
+                (0x0, 0x2B) => Instruction::Cpuid,
+                (0x0, 0x2C) => Instruction::DebugDump,
+                (0x0, 0x2D) => Instruction::Time,
+                (0x1, _) => Instruction::CompareZero {
+                    flags: nibble_1,
+                    reg: nibble_0,
+                },
+                _ => return Err(word),
+            },
+            0x2 => {
+                let (address_reg, data_reg) = (nibble_1, nibble_0);
+                match nibble_2 {
+                    0x0 => Instruction::StoreData {
+                        address_reg,
+                        data_reg,
+                    },
+                    0x1 => Instruction::LoadData {
+                        address_reg,
+                        data_reg,
+                    },
+                    0x2 => Instruction::LoadInstruction {
+                        address_reg,
+                        data_reg,
+                    },
+                    0x3 => Instruction::StoreInstruction {
+                        address_reg,
+                        data_reg,
+                    },
+                    _ => return Err(word),
+                }
+            }
+            0x3 => Instruction::LoadImmLow {
+                reg: nibble_2,
+                value: low_byte,
+            },
+            0x4 => Instruction::LoadImmHigh {
+                reg: nibble_2,
+                value: low_byte,
+            },
+            0x5 => Instruction::Unary {
+                func: UnaryFunction::from_bits(nibble_2).ok_or(word)?,
+                src: nibble_1,
+                dst: nibble_0,
+            },
+            0x6 => Instruction::Binary {
+                func: BinaryFunction::from_bits(nibble_2),
+                src: nibble_1,
+                dst: nibble_0,
+            },
+            0x7 => {
+                let (address_reg, offset, data_reg) = (nibble_1, nibble_2 & 0x7, nibble_0);
+                if nibble_2 & 0x8 == 0 {
+                    Instruction::LoadDataOffset {
+                        address_reg,
+                        offset,
+                        data_reg,
+                    }
+                } else {
+                    Instruction::StoreDataOffset {
+                        address_reg,
+                        offset,
+                        data_reg,
+                    }
+                }
+            }
+            0x8 => Instruction::Compare {
+                flags: nibble_2,
+                lhs: nibble_1,
+                rhs: nibble_0,
+            },
+            0x9 => Instruction::Branch {
+                reg: nibble_2,
+                offset: low_byte,
+            },
+            0xA => Instruction::JumpImm {
+                offset: word & 0x0FFF,
+            },
+            0xB => Instruction::JumpReg {
+                reg: nibble_2,
+                offset: low_byte,
+            },
+            // 0x0, 0xC, 0xD, 0xE, 0xF
+            _ => return Err(word),
+        };
+        Ok(instruction)
+    }
+
+    /// Returns the features that must be enabled to execute this instruction, usually none.
+    #[must_use]
+    pub fn required_features(&self) -> CpuFeatures {
+        match self {
+            Instruction::CompareZero { .. } => CpuFeatures::COMPARE_ZERO,
+            Instruction::StoreInstruction { .. } => CpuFeatures::STORE_INSTRUCTION,
+            Instruction::LoadDataOffset { .. } | Instruction::StoreDataOffset { .. } => {
+                CpuFeatures::MEMORY_OFFSET
+            }
+            Instruction::Unary {
+                func: UnaryFunction::Rnd,
+                ..
+            } => CpuFeatures::RND,
+            Instruction::Binary {
+                func: BinaryFunction::Exp | BinaryFunction::Root,
+                ..
+            } => CpuFeatures::EXP_ROOT,
+            _ => CpuFeatures::empty(),
+        }
+    }
+
+    /// Inverse of `decode`.
+    #[must_use]
+    pub fn encode(&self) -> u16 {
+        fn nibbles(prefix: u16, n2: u16, n1: u16, n0: u16) -> u16 {
+            (prefix << 12) | ((n2 & 0xF) << 8) | ((n1 & 0xF) << 4) | (n0 & 0xF)
+        }
+        fn with_byte(prefix: u16, n2: u16, byte: u16) -> u16 {
+            (prefix << 12) | ((n2 & 0xF) << 8) | (byte & 0xFF)
+        }
+        match *self {
+            Instruction::Return => 0x102A,
+            Instruction::Cpuid => 0x102B,
+            Instruction::DebugDump => 0x102C,
+            Instruction::Time => 0x102D,
+            Instruction::CompareZero { flags, reg } => nibbles(0x1, 0x1, flags, reg),
+            Instruction::StoreData {
+                address_reg,
+                data_reg,
+            } => nibbles(0x2, 0x0, address_reg, data_reg),
+            Instruction::LoadData {
+                address_reg,
+                data_reg,
+            } => nibbles(0x2, 0x1, address_reg, data_reg),
+            Instruction::LoadInstruction {
+                address_reg,
+                data_reg,
+            } => nibbles(0x2, 0x2, address_reg, data_reg),
+            Instruction::StoreInstruction {
+                address_reg,
+                data_reg,
+            } => nibbles(0x2, 0x3, address_reg, data_reg),
+            Instruction::LoadDataOffset {
+                address_reg,
+                offset,
+                data_reg,
+            } => nibbles(0x7, offset & 0x7, address_reg, data_reg),
+            Instruction::StoreDataOffset {
+                address_reg,
+                offset,
+                data_reg,
+            } => nibbles(0x7, 0x8 | offset, address_reg, data_reg),
+            Instruction::LoadImmLow { reg, value } => with_byte(0x3, reg, value),
+            Instruction::LoadImmHigh { reg, value } => with_byte(0x4, reg, value),
+            Instruction::Unary { func, src, dst } => nibbles(0x5, func as u16, src, dst),
+            Instruction::Binary { func, src, dst } => nibbles(0x6, func as u16, src, dst),
+            Instruction::Compare { flags, lhs, rhs } => nibbles(0x8, flags, lhs, rhs),
+            Instruction::Branch { reg, offset } => with_byte(0x9, reg, offset),
+            Instruction::JumpImm { offset } => 0xA000 | (offset & 0x0FFF),
+            Instruction::JumpReg { reg, offset } => with_byte(0xB, reg, offset),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_instruction {
+    use super::*;
+    use crate::vm::{Segment, StepResult, VirtualMachine};
+
+    #[test]
+    fn test_examples() {
+        assert_eq!(Instruction::decode(0x102A), Ok(Instruction::Return));
+        assert_eq!(
+            Instruction::decode(0x3042),
+            Ok(Instruction::LoadImmLow {
+                reg: 0,
+                value: 0x42
+            })
+        );
+        assert_eq!(
+            Instruction::decode(0x5911),
+            Ok(Instruction::Unary {
+                func: UnaryFunction::Incr,
+                src: 1,
+                dst: 1
+            })
+        );
+        assert_eq!(
+            Instruction::decode(0x6E12),
+            Ok(Instruction::Binary {
+                func: BinaryFunction::Exp,
+                src: 1,
+                dst: 2
+            })
+        );
+        assert_eq!(
+            Instruction::decode(0x9380),
+            Ok(Instruction::Branch {
+                reg: 3,
+                offset: 0x80
+            })
+        );
+        assert_eq!(Instruction::decode(0x5711), Err(0x5711));
+        assert_eq!(Instruction::decode(0x102E), Err(0x102E));
+        assert_eq!(Instruction::decode(0x2412), Err(0x2412));
+        assert_eq!(
+            Instruction::decode(0x7F12),
+            Ok(Instruction::StoreDataOffset {
+                address_reg: 1,
+                offset: 7,
+                data_reg: 2
+            })
+        );
+        assert_eq!(Instruction::decode(0xC000), Err(0xC000));
+    }
+
+    #[test]
+    fn test_encode_truncates() {
+        let instruction = Instruction::LoadImmLow {
+            reg: 0x12,
+            value: 0x345,
+        };
+        assert_eq!(instruction.encode(), 0x3245);
+    }
+
+    #[test]
+    fn test_round_trip_all_words() {
+        for word in 0..=0xFFFF {
+            match Instruction::decode(word) {
+                Ok(instruction) => assert_eq!(instruction.encode(), word, "{:?}", instruction),
+                Err(illegal) => assert_eq!(illegal, word),
+            }
+        }
+    }
+
+    #[test]
+    fn test_illegal_matches_vm() {
+        for word in 0..=0xFFFF {
+            let mut instructions = Segment::new_sparse();
+            instructions[0] = word;
+            let mut vm = VirtualMachine::new_with_seed(instructions, Segment::new_sparse(), 0);
+            let is_illegal = vm.step() == StepResult::IllegalInstruction(word);
+            let expected = match Instruction::decode(word) {
+                Ok(instruction) => {
+                    !CpuFeatures::default().contains(instruction.required_features())
+                }
+                Err(_) => true,
+            };
+            assert_eq!(expected, is_illegal, "{:04X}", word);
+        }
+    }
+}
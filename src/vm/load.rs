@@ -0,0 +1,368 @@
+use crate::format::{
+    decode_ihex, decode_rle, decode_segment, decode_segment_padded, detect_format, looks_like_ihex,
+    looks_like_rle, FormatError, IhexError, RleError, SegmentFormat,
+};
+use crate::vm::Segment;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct LoadOptions {
+    /// The expected on-disk format, or `None` to detect it from the content. Intel HEX and `SegmentFormat::Rle` are
+    /// always recognized by their content, see `looks_like_ihex` and `looks_like_rle`.
+    pub format: Option<SegmentFormat>,
+    /// Accepts raw segments shorter than 131072 bytes and fills them up with zeros, see `decode_segment_padded`.
+    pub allow_short: bool,
+}
+
+impl Default for LoadOptions {
+    fn default() -> LoadOptions {
+        LoadOptions {
+            format: Some(SegmentFormat::BigEndian),
+            allow_short: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SegmentLoadError {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    WrongLength {
+        path: PathBuf,
+        expected: usize,
+        actual: usize,
+    },
+    OddLength {
+        path: PathBuf,
+        actual: usize,
+    },
+    FormatDetectFailed {
+        path: PathBuf,
+    },
+    InvalidContent {
+        path: PathBuf,
+        source: FormatError,
+    },
+    InvalidIntelHex {
+        path: PathBuf,
+        source: IhexError,
+    },
+    InvalidRle {
+        path: PathBuf,
+        source: RleError,
+    },
+}
+
+impl SegmentLoadError {
+    pub fn path(&self) -> &Path {
+        match self {
+            SegmentLoadError::Io { path, .. }
+            | SegmentLoadError::WrongLength { path, .. }
+            | SegmentLoadError::OddLength { path, .. }
+            | SegmentLoadError::FormatDetectFailed { path }
+            | SegmentLoadError::InvalidContent { path, .. }
+            | SegmentLoadError::InvalidIntelHex { path, .. }
+            | SegmentLoadError::InvalidRle { path, .. } => path,
+        }
+    }
+}
+
+impl Display for SegmentLoadError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SegmentLoadError::Io { path, source } => {
+                write!(f, "Cannot read {}: {}", path.display(), source)
+            }
+            SegmentLoadError::WrongLength {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Wrong segment length in {}, expected {}, got {} instead.",
+                path.display(),
+                expected,
+                actual
+            ),
+            SegmentLoadError::OddLength { path, actual } => write!(
+                f,
+                "Wrong segment length in {}, got {} bytes, which is not a whole number of words.",
+                path.display(),
+                actual
+            ),
+            SegmentLoadError::FormatDetectFailed { path } => write!(
+                f,
+                "Cannot detect the segment format of {}, please specify it explicitly.",
+                path.display()
+            ),
+            SegmentLoadError::InvalidContent { path, source } => {
+                write!(f, "Invalid segment in {}: {}", path.display(), source)
+            }
+            SegmentLoadError::InvalidIntelHex { path, source } => {
+                write!(f, "Invalid Intel HEX in {}: {}", path.display(), source)
+            }
+            SegmentLoadError::InvalidRle { path, source } => {
+                write!(
+                    f,
+                    "Invalid compressed segment in {}: {}",
+                    path.display(),
+                    source
+                )
+            }
+        }
+    }
+}
+
+impl Error for SegmentLoadError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SegmentLoadError::Io { source, .. } => Some(source),
+            SegmentLoadError::InvalidContent { source, .. } => Some(source),
+            SegmentLoadError::InvalidIntelHex { source, .. } => Some(source),
+            SegmentLoadError::InvalidRle { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Decodes bytes that were read from `path`. This is the IO-free part of `load_segment`.
+pub fn parse_segment_bytes(
+    path: &Path,
+    bytes: &[u8],
+    options: LoadOptions,
+) -> Result<Segment, SegmentLoadError> {
+    if looks_like_rle(bytes) {
+        return decode_rle(bytes).map_err(|source| SegmentLoadError::InvalidRle {
+            path: path.into(),
+            source,
+        });
+    }
+    if looks_like_ihex(bytes) {
+        // Cannot fail, looks_like_ihex only accepts ASCII.
+        let text = std::str::from_utf8(bytes).unwrap();
+        return decode_ihex(text).map_err(|source| SegmentLoadError::InvalidIntelHex {
+            path: path.into(),
+            source,
+        });
+    }
+    let format = match options.format {
+        Some(format) => format,
+        None => detect_format(bytes)
+            .map_err(|_| SegmentLoadError::FormatDetectFailed { path: path.into() })?,
+    };
+    let decoded = if options.allow_short {
+        decode_segment_padded(bytes, format)
+    } else {
+        decode_segment(bytes, format)
+    };
+    decoded.map_err(|err| match err {
+        FormatError::WrongLength { actual, .. } | FormatError::OddLength { actual }
+            if actual % 2 == 1 =>
+        {
+            SegmentLoadError::OddLength {
+                path: path.into(),
+                actual,
+            }
+        }
+        FormatError::WrongLength { expected, actual } => SegmentLoadError::WrongLength {
+            path: path.into(),
+            expected,
+            actual,
+        },
+        source => SegmentLoadError::InvalidContent {
+            path: path.into(),
+            source,
+        },
+    })
+}
+
+pub fn load_segment(path: &Path, options: LoadOptions) -> Result<Segment, SegmentLoadError> {
+    let bytes = std::fs::read(path).map_err(|source| SegmentLoadError::Io {
+        path: path.into(),
+        source,
+    })?;
+    parse_segment_bytes(path, &bytes, options)
+}
+
+#[cfg(test)]
+mod test_load {
+    use super::*;
+    use crate::format::{encode_segment, SEGMENT_BYTES};
+
+    fn sample_be_bytes() -> Vec<u8> {
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x102A;
+        encode_segment(&segment, SegmentFormat::BigEndian)
+    }
+
+    #[test]
+    fn test_default_is_be() {
+        let segment =
+            parse_segment_bytes(Path::new("x"), &sample_be_bytes(), LoadOptions::default())
+                .unwrap();
+        assert_eq!(segment[0], 0x102A);
+    }
+
+    #[test]
+    fn test_detect_hex() {
+        let options = LoadOptions {
+            format: None,
+            ..LoadOptions::default()
+        };
+        let segment = parse_segment_bytes(Path::new("x"), b"102A\n", options).unwrap();
+        assert_eq!(segment[0], 0x102A);
+    }
+
+    #[test]
+    fn test_detect_raw_fails() {
+        let options = LoadOptions {
+            format: None,
+            ..LoadOptions::default()
+        };
+        let err =
+            parse_segment_bytes(Path::new("bot.bin"), &sample_be_bytes(), options).unwrap_err();
+        assert!(matches!(err, SegmentLoadError::FormatDetectFailed { .. }));
+        assert_eq!(
+            err.to_string(),
+            "Cannot detect the segment format of bot.bin, please specify it explicitly."
+        );
+        assert!(err.source().is_none());
+    }
+
+    #[test]
+    fn test_wrong_length() {
+        let err =
+            parse_segment_bytes(Path::new("bot.bin"), &[0; 4], LoadOptions::default()).unwrap_err();
+        assert!(matches!(
+            err,
+            SegmentLoadError::WrongLength {
+                expected: SEGMENT_BYTES,
+                actual: 4,
+                ..
+            }
+        ));
+        assert_eq!(
+            err.to_string(),
+            "Wrong segment length in bot.bin, expected 131072, got 4 instead."
+        );
+    }
+
+    #[test]
+    fn test_odd_length() {
+        let err =
+            parse_segment_bytes(Path::new("bot.bin"), &[0; 3], LoadOptions::default()).unwrap_err();
+        assert!(matches!(err, SegmentLoadError::OddLength { actual: 3, .. }));
+        assert_eq!(
+            err.to_string(),
+            "Wrong segment length in bot.bin, got 3 bytes, which is not a whole number of words."
+        );
+    }
+
+    #[test]
+    fn test_allow_short() {
+        let options = LoadOptions {
+            allow_short: true,
+            ..LoadOptions::default()
+        };
+        let segment = parse_segment_bytes(Path::new("bot.bin"), &[0x10, 0x2A], options).unwrap();
+        assert_eq!(segment[0], 0x102A);
+        assert_eq!(segment.used_len(), 1);
+        let segment = parse_segment_bytes(Path::new("bot.bin"), &[], options).unwrap();
+        assert_eq!(segment.used_len(), 0);
+
+        let err = parse_segment_bytes(Path::new("bot.bin"), &[0; 3], options).unwrap_err();
+        assert!(matches!(err, SegmentLoadError::OddLength { actual: 3, .. }));
+        let err = parse_segment_bytes(Path::new("bot.bin"), &[0; SEGMENT_BYTES + 2], options)
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Wrong segment length in bot.bin, expected 131072, got 131074 instead."
+        );
+    }
+
+    #[test]
+    fn test_invalid_content() {
+        let options = LoadOptions {
+            format: Some(SegmentFormat::HexText),
+            ..LoadOptions::default()
+        };
+        let err = parse_segment_bytes(Path::new("bot.hex"), b"xyz", options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid segment in bot.hex: Line 1: 'xyz' is not a hex word."
+        );
+        assert_eq!(
+            err.source().unwrap().to_string(),
+            "Line 1: 'xyz' is not a hex word."
+        );
+    }
+
+    #[test]
+    fn test_detect_ihex() {
+        // Regardless of the format option.
+        let text = b":02000000102AC4\n:00000001FF\n";
+        let segment =
+            parse_segment_bytes(Path::new("bot.ihex"), text, LoadOptions::default()).unwrap();
+        assert_eq!(segment[0], 0x102A);
+
+        let err = parse_segment_bytes(
+            Path::new("bot.ihex"),
+            b":02000000102AC5\n",
+            LoadOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid Intel HEX in bot.ihex: Line 1: Bad checksum, expected 0xC4, got 0xC5 instead."
+        );
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_detect_rle() {
+        // Regardless of the format option.
+        let mut segment = Segment::new_zeroed();
+        segment[0] = 0x102A;
+        let bytes = segment.to_rle_bytes();
+        for format in [None, Some(SegmentFormat::BigEndian)] {
+            let options = LoadOptions {
+                format,
+                ..LoadOptions::default()
+            };
+            let loaded = parse_segment_bytes(Path::new("bot.rle"), &bytes, options).unwrap();
+            assert_eq!(loaded, segment);
+        }
+
+        let err = parse_segment_bytes(
+            Path::new("bot.rle"),
+            &bytes[..bytes.len() - 2],
+            LoadOptions::default(),
+        )
+        .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid compressed segment in bot.rle: Truncated run-length encoding, got 14 bytes, which is not a whole number of runs."
+        );
+        assert!(err.source().is_some());
+    }
+
+    #[test]
+    fn test_io() {
+        let err = load_segment(
+            Path::new("/nonexistent/tinyvm/segment"),
+            LoadOptions::default(),
+        )
+        .unwrap_err();
+        assert!(matches!(err, SegmentLoadError::Io { .. }));
+        assert_eq!(err.path(), Path::new("/nonexistent/tinyvm/segment"));
+        assert!(err
+            .to_string()
+            .starts_with("Cannot read /nonexistent/tinyvm/segment: "));
+        assert!(err.source().is_some());
+    }
+}
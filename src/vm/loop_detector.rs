@@ -0,0 +1,197 @@
+/// Recognizes that the machine is in exactly the same state as some instructions earlier, which means that it will
+/// repeat these instructions forever, see `VirtualMachine::enable_loop_detection`. This is Brent's algorithm, so it
+/// compares only one hash per instruction, and notices a loop at most about twice its preperiod plus period after
+/// the loop started.
+///
+/// The state is the program counter, the registers, and both segments. The segments would be too expensive to hash
+/// on every instruction, so stores update `memory_delta` instead.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct LoopDetector {
+    /// The sum of `mix` over all words stored since `restart`, minus the same for the words they overwrote. Equal
+    /// sums mean equal segments, up to hash collisions.
+    memory_delta: u64,
+    /// The hash of the state `since_saved` instructions ago, `None` right after `restart` or `forget`.
+    saved: Option<u64>,
+    since_saved: u64,
+    /// Once `since_saved` reaches this, the current state is saved instead. Doubles every time.
+    horizon: u64,
+}
+
+/// Spreads a stored word with its address over all 64 bits, like the finalizer of SplitMix64.
+fn mix(instruction_segment: bool, address: u16, value: u16) -> u64 {
+    let mut z = (instruction_segment as u64) << 32 | (address as u64) << 16 | value as u64;
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+impl LoopDetector {
+    /// Starts over, taking the current segments as the reference.
+    pub(crate) fn restart(&mut self) {
+        *self = LoopDetector::default();
+    }
+
+    /// Discards the saved state, e.g. because the program just observed something outside of its state, like the
+    /// time. A later identical state would not mean that the program repeats itself.
+    pub(crate) fn forget(&mut self) {
+        self.saved = None;
+    }
+
+    pub(crate) fn record_store(
+        &mut self,
+        instruction_segment: bool,
+        address: u16,
+        old: u16,
+        new: u16,
+    ) {
+        self.memory_delta = self
+            .memory_delta
+            .wrapping_sub(mix(instruction_segment, address, old))
+            .wrapping_add(mix(instruction_segment, address, new));
+    }
+
+    /// Call this after each executed instruction, with a hash of the program counter and the registers. Returns
+    /// the period if the machine was in the same state exactly that many instructions ago.
+    pub(crate) fn check(&mut self, registers_hash: u64) -> Option<u64> {
+        let hash = registers_hash ^ self.memory_delta;
+        let Some(saved) = self.saved else {
+            self.saved = Some(hash);
+            self.since_saved = 0;
+            self.horizon = 1;
+            return None;
+        };
+        self.since_saved += 1;
+        if hash == saved {
+            let period = self.since_saved;
+            self.forget();
+            return Some(period);
+        }
+        if self.since_saved == self.horizon {
+            self.saved = Some(hash);
+            self.since_saved = 0;
+            self.horizon *= 2;
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test_loop_detector {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Extension, Segment, StopReason, VirtualMachine};
+
+    #[test]
+    fn test_check() {
+        let mut detector = LoopDetector::default();
+        // 1, 2, then 3, 4, 5 forever.
+        let hashes = [1, 2, 3, 4, 5]
+            .into_iter()
+            .chain([3, 4, 5].into_iter().cycle());
+        let found = hashes.take(20).find_map(|hash| detector.check(hash));
+        assert_eq!(found, Some(3));
+    }
+
+    #[test]
+    fn test_memory_delta() {
+        let mut detector = LoopDetector::default();
+        detector.record_store(false, 5, 0, 7);
+        assert_ne!(detector.memory_delta, 0);
+        detector.record_store(true, 5, 0, 7);
+        detector.record_store(false, 5, 7, 0);
+        detector.record_store(true, 5, 7, 0);
+        assert_eq!(detector.memory_delta, 0);
+    }
+
+    #[test]
+    fn test_jump_to_itself() {
+        let instructions = tinyvm_asm! {
+            lw r1, 1;
+            jr r1, 0;
+        };
+        let mut vm = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+        assert_eq!(vm.run(1000).reason, StopReason::OutOfBudget);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_loop_detection();
+        let outcome = vm.run(1000);
+        assert_eq!(outcome.reason, StopReason::LoopDetected { period: 1 });
+        assert!(outcome.steps < 10, "{:?}", outcome);
+        assert_eq!(vm.get_program_counter(), 1);
+    }
+
+    #[test]
+    fn test_loop_through_memory() {
+        // Flips a data word back and forth, so the registers alone repeat every iteration, but the state only
+        // every second one.
+        let instructions = tinyvm_asm! {
+            lw r1, 0x10;
+            loop_start:
+            lwd r1, r2;
+            not r2, r2;
+            sw r1, r2;
+            lw r2, 0;
+            j loop_start;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_loop_detection();
+        let outcome = vm.run(1000);
+        assert_eq!(outcome.reason, StopReason::LoopDetected { period: 10 });
+        assert!(outcome.steps < 100, "{:?}", outcome);
+    }
+
+    #[test]
+    fn test_long_loop_terminates() {
+        // Counts down from 30000, so no state ever repeats.
+        let instructions = tinyvm_asm! {
+            lw r1, 30000;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_loop_detection();
+        assert_eq!(vm.run(100_000).reason, StopReason::Returned(0));
+    }
+
+    #[test]
+    fn test_time_is_not_a_loop() {
+        // Spins until the time reaches 0x0400. Apart from the time, the state repeats every iteration.
+        let instructions = tinyvm_asm! {
+            loop_start:
+            time;
+            lw r5, 0xFC00;
+            and r3, r5;
+            lw r3, 0;
+            b r5, done;
+            j loop_start;
+            done:
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_loop_detection();
+        assert_eq!(vm.run(100_000).reason, StopReason::Returned(0));
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x10;
+            loop_start:
+            lwi r1, r2;
+            incr r2, r2;
+            word 0x2312; // swi r1, r2
+            j loop_start;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_extension(Extension::StoreInstruction);
+        vm.enable_loop_detection();
+        // The instruction word at 0x10 counts up, and wraps around after 65536 iterations.
+        let outcome = vm.run(2_000_000);
+        assert_eq!(
+            outcome.reason,
+            StopReason::LoopDetected { period: 4 * 65536 }
+        );
+    }
+}
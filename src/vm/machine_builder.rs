@@ -0,0 +1,176 @@
+use super::{Segment, VirtualMachine};
+
+/// Creates a VM in an arbitrary state, e.g. in the middle of a function, or to reconstruct a captured state. For a
+/// fresh VM, `VirtualMachine::new` is simpler.
+///
+/// Everything not given is like in `VirtualMachine::new`: all-zero segments, registers, program counter, and time,
+/// and `rnd` uses the operating system's entropy.
+#[derive(Debug, Default, Clone)]
+pub struct VirtualMachineBuilder {
+    instructions: Option<Segment>,
+    data: Option<Segment>,
+    registers: [u16; 16],
+    program_counter: u16,
+    time: u64,
+    seed: Option<u64>,
+}
+
+impl VirtualMachineBuilder {
+    #[must_use]
+    pub fn new() -> VirtualMachineBuilder {
+        VirtualMachineBuilder::default()
+    }
+
+    #[must_use]
+    pub fn instructions(mut self, instructions: Segment) -> VirtualMachineBuilder {
+        self.instructions = Some(instructions);
+        self
+    }
+
+    #[must_use]
+    pub fn data(mut self, data: Segment) -> VirtualMachineBuilder {
+        self.data = Some(data);
+        self
+    }
+
+    #[must_use]
+    pub fn registers(mut self, registers: [u16; 16]) -> VirtualMachineBuilder {
+        self.registers = registers;
+        self
+    }
+
+    #[must_use]
+    pub fn program_counter(mut self, program_counter: u16) -> VirtualMachineBuilder {
+        self.program_counter = program_counter;
+        self
+    }
+
+    #[must_use]
+    pub fn time(mut self, time: u64) -> VirtualMachineBuilder {
+        self.time = time;
+        self
+    }
+
+    /// See `VirtualMachine::new_with_seed`.
+    #[must_use]
+    pub fn seed(mut self, seed: u64) -> VirtualMachineBuilder {
+        self.seed = Some(seed);
+        self
+    }
+
+    #[must_use]
+    pub fn build(self) -> VirtualMachine {
+        let mut vm = VirtualMachine::new(
+            self.instructions.unwrap_or_else(Segment::new_zeroed),
+            self.data.unwrap_or_else(Segment::new_zeroed),
+        );
+        for (index, value) in self.registers.into_iter().enumerate() {
+            vm.set_register(index as u16, value);
+        }
+        vm.set_program_counter(self.program_counter);
+        vm.set_time(self.time);
+        vm.set_seed(self.seed);
+        vm
+    }
+}
+
+#[cfg(test)]
+mod test_machine_builder {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{StepResult, StopReason};
+
+    #[test]
+    fn test_defaults() {
+        let vm = VirtualMachineBuilder::new().build();
+        assert_eq!(
+            vm,
+            VirtualMachine::new(Segment::new_zeroed(), Segment::new_zeroed())
+        );
+    }
+
+    #[test]
+    fn test_step_at_0x1234() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0x1234] = 0x6012; // add r1, r2
+        let mut registers = [0; 16];
+        registers[1] = 0x0100;
+        registers[2] = 0x0023;
+        let mut vm = VirtualMachineBuilder::new()
+            .instructions(instructions)
+            .registers(registers)
+            .program_counter(0x1234)
+            .time(1000)
+            .build();
+        assert_eq!(vm.get_program_counter(), 0x1234);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_program_counter(), 0x1235);
+        assert_eq!(vm.get_registers()[2], 0x0123);
+        assert_eq!(vm.get_time(), 1001);
+    }
+
+    #[test]
+    fn test_time_wraps_around() {
+        let instructions = tinyvm_asm! {
+            lw r1, 4;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        let mut vm = VirtualMachineBuilder::new()
+            .instructions(instructions)
+            .time(u64::MAX - 1)
+            .build();
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_time(), u64::MAX);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_time(), 0);
+        let outcome = vm.run(100);
+        assert_eq!(outcome.reason, StopReason::Returned(0));
+        assert_eq!(outcome.steps, 7);
+        assert_eq!(vm.get_time(), 7);
+    }
+
+    #[test]
+    fn test_run_across_wraparound() {
+        let instructions = tinyvm_asm! {
+            lw r1, 4;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        let mut vm = VirtualMachineBuilder::new()
+            .instructions(instructions)
+            .time(u64::MAX - 1)
+            .build();
+        let outcome = vm.run(5);
+        assert_eq!(outcome.reason, StopReason::OutOfBudget);
+        assert_eq!(outcome.steps, 5);
+        assert_eq!(vm.get_time(), 3);
+    }
+
+    #[test]
+    fn test_reconstruct() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x31FF; // lw r1, 0xFFFF
+        instructions[1] = 0x5E12; // rnd r2, r1
+        instructions[2] = 0x5E13; // rnd r3, r1
+        instructions[3] = 0x102A; // ret
+        let mut original =
+            VirtualMachine::new_with_seed(instructions.clone(), Segment::new_zeroed(), 7);
+        original.step();
+        let mut reconstructed = VirtualMachineBuilder::new()
+            .instructions(instructions)
+            .registers(*original.get_registers())
+            .program_counter(original.get_program_counter())
+            .time(original.get_time())
+            .seed(7)
+            .build();
+        assert_eq!(reconstructed, original);
+        original.run(10);
+        reconstructed.run(10);
+        assert_eq!(reconstructed.get_registers(), original.get_registers());
+    }
+}
@@ -0,0 +1,408 @@
+use crate::vm::run::run_vm_stepping;
+use crate::vm::{ProgramOutcome, StepResult, VirtualMachine};
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{self, BufWriter, Read, Write};
+
+// A memory trace file is a 16-byte header followed by any number of 16-byte records, all little-endian:
+//
+// Header:
+// - 8 bytes: The magic `MEM_TRACE_MAGIC`, i.e. "TVMMTRAC" in ASCII.
+// - u16: Format version, currently `MEM_TRACE_VERSION`.
+// - u16: Size of each record in bytes, currently `MEM_TRACE_RECORD_BYTES`.
+// - 4 bytes: Reserved, zero.
+//
+// Record, one per executed data memory access:
+// - u64: Step number, i.e. the value of `VirtualMachine::get_time` before executing the instruction.
+// - u16: Program counter of the instruction.
+// - u8: Kind, 0 for a read (load word data), 1 for a write (store word data).
+// - u8: Reserved, zero.
+// - u16: Data address.
+// - u16: Value that was read or written.
+//
+// Instruction memory reads (load word instruction) are not data accesses, and are not traced.
+
+pub const MEM_TRACE_MAGIC: [u8; 8] = *b"TVMMTRAC";
+pub const MEM_TRACE_VERSION: u16 = 1;
+pub const MEM_TRACE_HEADER_BYTES: usize = 16;
+pub const MEM_TRACE_RECORD_BYTES: usize = 16;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum MemAccessKind {
+    Read,
+    Write,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct MemAccess {
+    pub step: u64,
+    pub pc: u16,
+    pub kind: MemAccessKind,
+    pub address: u16,
+    pub value: u16,
+}
+
+impl MemAccess {
+    fn to_bytes(self) -> [u8; MEM_TRACE_RECORD_BYTES] {
+        let mut bytes = [0; MEM_TRACE_RECORD_BYTES];
+        bytes[0..8].copy_from_slice(&self.step.to_le_bytes());
+        bytes[8..10].copy_from_slice(&self.pc.to_le_bytes());
+        bytes[10] = match self.kind {
+            MemAccessKind::Read => 0,
+            MemAccessKind::Write => 1,
+        };
+        bytes[12..14].copy_from_slice(&self.address.to_le_bytes());
+        bytes[14..16].copy_from_slice(&self.value.to_le_bytes());
+        bytes
+    }
+
+    fn from_bytes(
+        bytes: &[u8; MEM_TRACE_RECORD_BYTES],
+        index: usize,
+    ) -> Result<MemAccess, MemTraceError> {
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let kind = match bytes[10] {
+            0 => MemAccessKind::Read,
+            1 => MemAccessKind::Write,
+            kind => return Err(MemTraceError::InvalidKind { index, kind }),
+        };
+        Ok(MemAccess {
+            step: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            pc: u16_at(8),
+            kind,
+            address: u16_at(12),
+            value: u16_at(14),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum MemTraceError {
+    Io(io::Error),
+    WrongMagic,
+    UnsupportedVersion { version: u16, record_bytes: u16 },
+    TruncatedRecord { index: usize },
+    InvalidKind { index: usize, kind: u8 },
+}
+
+impl Display for MemTraceError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            MemTraceError::Io(err) => write!(f, "Cannot read memory trace: {}", err),
+            MemTraceError::WrongMagic => write!(f, "Not a memory trace, the magic bytes are wrong."),
+            MemTraceError::UnsupportedVersion {
+                version,
+                record_bytes,
+            } => write!(
+                f,
+                "Unsupported memory trace version {} with {}-byte records, expected version {} with {}-byte records.",
+                version, record_bytes, MEM_TRACE_VERSION, MEM_TRACE_RECORD_BYTES
+            ),
+            MemTraceError::TruncatedRecord { index } => {
+                write!(f, "Memory trace record {} is truncated.", index)
+            }
+            MemTraceError::InvalidKind { index, kind } => write!(
+                f,
+                "Memory trace record {} has invalid access kind {}.",
+                index, kind
+            ),
+        }
+    }
+}
+
+impl Error for MemTraceError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MemTraceError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MemTraceError {
+    fn from(err: io::Error) -> MemTraceError {
+        MemTraceError::Io(err)
+    }
+}
+
+/// Steps a VM and appends a record for every data memory access to a buffered writer.
+///
+/// Write errors do not interrupt the VM. The first one is kept, and returned by `finish`.
+#[derive(Debug)]
+pub struct MemTraceWriter<W: Write> {
+    writer: BufWriter<W>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> MemTraceWriter<W> {
+    /// Writes the header.
+    pub fn new(writer: W) -> io::Result<MemTraceWriter<W>> {
+        let mut writer = BufWriter::new(writer);
+        let mut header = [0; MEM_TRACE_HEADER_BYTES];
+        header[0..8].copy_from_slice(&MEM_TRACE_MAGIC);
+        header[8..10].copy_from_slice(&MEM_TRACE_VERSION.to_le_bytes());
+        header[10..12].copy_from_slice(&(MEM_TRACE_RECORD_BYTES as u16).to_le_bytes());
+        writer.write_all(&header)?;
+        Ok(MemTraceWriter {
+            writer,
+            error: None,
+        })
+    }
+
+    pub fn record(&mut self, access: MemAccess) {
+        if self.error.is_none() {
+            if let Err(err) = self.writer.write_all(&access.to_bytes()) {
+                self.error = Some(err);
+            }
+        }
+    }
+
+    /// Like `VirtualMachine::step`, but records the data memory access of the instruction, if any.
+    pub fn step(&mut self, vm: &mut VirtualMachine) -> StepResult {
+        let step = vm.get_time();
+        let pc = vm.get_program_counter();
+        let instruction = vm.get_instructions()[pc];
+        let address_register = ((instruction & 0x00F0) >> 4) as usize;
+        let mut address = vm.get_registers()[address_register];
+        let kind = match instruction & 0xFF00 {
+            0x2000 => Some(MemAccessKind::Write),
+            0x2100 => Some(MemAccessKind::Read),
+            0x7000..=0x7F00 => {
+                address = address.wrapping_add((instruction >> 8) & 0x7);
+                if instruction & 0x0800 == 0 {
+                    Some(MemAccessKind::Read)
+                } else {
+                    Some(MemAccessKind::Write)
+                }
+            }
+            _ => None,
+        };
+
+        let result = vm.step();
+        if let (Some(kind), StepResult::Continue) = (kind, result) {
+            if vm.get_time() != step {
+                self.record(MemAccess {
+                    step,
+                    pc,
+                    kind,
+                    address,
+                    value: vm.get_data()[address],
+                });
+            }
+        }
+        result
+    }
+
+    /// Flushes the buffer and returns the underlying writer, or the first write error.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.into_inner().map_err(|err| err.into_error())
+    }
+}
+
+/// Like `run_vm`, but records all data memory accesses.
+pub fn run_vm_with_mem_trace<W: Write>(
+    vm: &mut VirtualMachine,
+    max_steps: u64,
+    trace: &mut MemTraceWriter<W>,
+) -> ProgramOutcome {
+    run_vm_stepping(vm, max_steps, |vm| trace.step(vm))
+}
+
+/// Parses a whole memory trace, as written by `MemTraceWriter`.
+pub fn read_mem_trace(mut reader: impl Read) -> Result<Vec<MemAccess>, MemTraceError> {
+    let mut header = [0; MEM_TRACE_HEADER_BYTES];
+    reader
+        .read_exact(&mut header)
+        .map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => MemTraceError::WrongMagic,
+            _ => MemTraceError::Io(err),
+        })?;
+    if header[0..8] != MEM_TRACE_MAGIC {
+        return Err(MemTraceError::WrongMagic);
+    }
+    let version = u16::from_le_bytes([header[8], header[9]]);
+    let record_bytes = u16::from_le_bytes([header[10], header[11]]);
+    if version != MEM_TRACE_VERSION || record_bytes as usize != MEM_TRACE_RECORD_BYTES {
+        return Err(MemTraceError::UnsupportedVersion {
+            version,
+            record_bytes,
+        });
+    }
+
+    let mut body = Vec::new();
+    reader.read_to_end(&mut body)?;
+    let records = body.chunks(MEM_TRACE_RECORD_BYTES);
+    records
+        .enumerate()
+        .map(|(index, chunk)| match chunk.try_into() {
+            Ok(bytes) => MemAccess::from_bytes(bytes, index),
+            Err(_) => Err(MemTraceError::TruncatedRecord { index }),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_mem_trace {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::Segment;
+
+    fn traced_run(
+        instructions: Segment,
+        data: Segment,
+        max_steps: u64,
+    ) -> (ProgramOutcome, Vec<u8>) {
+        let mut vm = VirtualMachine::new(instructions, data);
+        let mut trace = MemTraceWriter::new(Vec::new()).unwrap();
+        let outcome = run_vm_with_mem_trace(&mut vm, max_steps, &mut trace);
+        (outcome, trace.finish().unwrap())
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0x1234;
+            lw r2, 0x5678;
+            sw r1, r2;
+            lwd r1, r3;
+            lw r4, 7;
+            lwd r4, r0;
+            lwi r4, r5;
+            ret;
+        };
+        let mut data = Segment::new_zeroed();
+        data[7] = 0xABCD;
+        let (outcome, bytes) = traced_run(instructions, data, 100);
+        assert_eq!(
+            outcome,
+            ProgramOutcome::Returned {
+                value: 0xABCD,
+                steps: 9
+            }
+        );
+        assert_eq!(
+            bytes.len(),
+            MEM_TRACE_HEADER_BYTES + 3 * MEM_TRACE_RECORD_BYTES
+        );
+        assert_eq!(&bytes[0..16], b"TVMMTRAC\x01\x00\x10\x00\x00\x00\x00\x00");
+        assert_eq!(
+            read_mem_trace(&bytes[..]).unwrap(),
+            vec![
+                MemAccess {
+                    step: 4,
+                    pc: 4,
+                    kind: MemAccessKind::Write,
+                    address: 0x1234,
+                    value: 0x5678,
+                },
+                MemAccess {
+                    step: 5,
+                    pc: 5,
+                    kind: MemAccessKind::Read,
+                    address: 0x1234,
+                    value: 0x5678,
+                },
+                MemAccess {
+                    step: 7,
+                    pc: 7,
+                    kind: MemAccessKind::Read,
+                    address: 7,
+                    value: 0xABCD,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_fibonacci() {
+        let instructions = tinyvm_asm! {
+            lw r0, 24;
+            lw r1, 1;
+            start:
+            add r1, r2;
+            decr r0, r0;
+            sw r0, r2;
+            add r2, r1;
+            decr r0, r0;
+            sw r0, r1;
+            b r0, start;
+            ret;
+        };
+        let (_, bytes) = traced_run(instructions, Segment::new_zeroed(), 1000);
+        let accesses = read_mem_trace(&bytes[..]).unwrap();
+        assert_eq!(accesses.len(), 24);
+        assert!(accesses.iter().all(|a| a.kind == MemAccessKind::Write));
+        let addresses = accesses.iter().map(|a| a.address).collect::<Vec<_>>();
+        assert_eq!(addresses, (0..24).rev().collect::<Vec<_>>());
+        assert_eq!(accesses[0].value, 1);
+        assert_eq!(accesses[23].value, 9489);
+    }
+
+    #[test]
+    fn test_halted_is_not_traced() {
+        let instructions = tinyvm_asm! { sw r0, r0; ret; };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let mut trace = MemTraceWriter::new(Vec::new()).unwrap();
+        assert_eq!(trace.step(&mut vm), StepResult::Continue);
+        assert_eq!(trace.step(&mut vm), StepResult::Return(0));
+        assert_eq!(trace.step(&mut vm), StepResult::Return(0));
+        let bytes = trace.finish().unwrap();
+        assert_eq!(read_mem_trace(&bytes[..]).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_reject() {
+        let (_, good) = traced_run(tinyvm_asm! { sw r0, r0; ret; }, Segment::new_zeroed(), 10);
+
+        let err = read_mem_trace(&b"TVM"[..]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Not a memory trace, the magic bytes are wrong."
+        );
+
+        let mut bad = good.clone();
+        bad[8] = 2;
+        assert_eq!(
+            read_mem_trace(&bad[..]).unwrap_err().to_string(),
+            "Unsupported memory trace version 2 with 16-byte records, expected version 1 with 16-byte records."
+        );
+
+        let mut bad = good.clone();
+        bad[MEM_TRACE_HEADER_BYTES + 10] = 7;
+        assert_eq!(
+            read_mem_trace(&bad[..]).unwrap_err().to_string(),
+            "Memory trace record 0 has invalid access kind 7."
+        );
+
+        assert_eq!(
+            read_mem_trace(&good[..good.len() - 1])
+                .unwrap_err()
+                .to_string(),
+            "Memory trace record 0 is truncated."
+        );
+    }
+
+    #[derive(Debug)]
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+            Err(io::Error::other("disk on fire"))
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_error() {
+        let mut vm = VirtualMachine::new(tinyvm_asm! { ret; }, Segment::new_zeroed());
+        let mut trace = MemTraceWriter::new(FailingWriter).unwrap();
+        run_vm_with_mem_trace(&mut vm, 10, &mut trace);
+        assert_eq!(trace.finish().unwrap_err().to_string(), "disk on fire");
+    }
+}
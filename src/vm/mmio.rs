@@ -0,0 +1,199 @@
+use std::fmt::{Debug, Formatter, Result};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, Mutex};
+
+/// A good place for devices: the top 256 words of the data segment, far away from where programs usually start.
+pub const DEFAULT_MMIO_RANGE: RangeInclusive<u16> = 0xFF00..=0xFFFF;
+
+/// A device attached to the data segment, see `VirtualMachine::set_mmio_handler`. The VM only asks it about
+/// addresses in the configured range.
+pub trait MmioHandler: Send {
+    /// Called for a load from `address`. `Some` is the loaded value, `None` loads from the data segment as usual.
+    fn on_load(&mut self, address: u16) -> Option<u16>;
+
+    /// Called for a store to `address`. Returns whether the device consumed the store. If not, `value` is written to
+    /// the data segment as usual.
+    fn on_store(&mut self, address: u16, value: u16) -> bool;
+}
+
+/// A character output port: Every store to its range appends the low byte of the value, and loads are not handled.
+/// Clones share the same buffer, so the host can keep one to read the text while the VM owns the other.
+#[derive(Debug, Default, Clone)]
+pub struct TextOutput {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl TextOutput {
+    #[must_use]
+    pub fn new() -> TextOutput {
+        TextOutput::default()
+    }
+
+    /// Returns everything written so far, and clears the buffer. Invalid UTF-8 becomes U+FFFD.
+    pub fn take_text(&self) -> String {
+        let bytes = std::mem::take(&mut *self.bytes.lock().unwrap_or_else(|err| err.into_inner()));
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl MmioHandler for TextOutput {
+    fn on_load(&mut self, _address: u16) -> Option<u16> {
+        None
+    }
+
+    fn on_store(&mut self, _address: u16, value: u16) -> bool {
+        let mut bytes = self.bytes.lock().unwrap_or_else(|err| err.into_inner());
+        bytes.push(value as u8);
+        true
+    }
+}
+
+/// The installed handler and its range. Clones of the VM share the handler, just like a `SharedRandomSource`.
+#[derive(Clone)]
+pub(crate) struct Mmio {
+    range: RangeInclusive<u16>,
+    handler: Arc<Mutex<Box<dyn MmioHandler>>>,
+}
+
+impl Mmio {
+    pub(crate) fn new(range: RangeInclusive<u16>, handler: Box<dyn MmioHandler>) -> Mmio {
+        Mmio {
+            range,
+            handler: Arc::new(Mutex::new(handler)),
+        }
+    }
+
+    pub(crate) fn load(&self, address: u16) -> Option<u16> {
+        if !self.range.contains(&address) {
+            return None;
+        }
+        let mut handler = self.handler.lock().unwrap_or_else(|err| err.into_inner());
+        handler.on_load(address)
+    }
+
+    pub(crate) fn store(&self, address: u16, value: u16) -> bool {
+        if !self.range.contains(&address) {
+            return false;
+        }
+        let mut handler = self.handler.lock().unwrap_or_else(|err| err.into_inner());
+        handler.on_store(address, value)
+    }
+}
+
+impl Debug for Mmio {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(
+            f,
+            "Mmio({:04X}..={:04X})",
+            self.range.start(),
+            self.range.end()
+        )
+    }
+}
+
+impl PartialEq for Mmio {
+    fn eq(&self, other: &Mmio) -> bool {
+        self.range == other.range && Arc::ptr_eq(&self.handler, &other.handler)
+    }
+}
+
+impl Eq for Mmio {}
+
+#[cfg(test)]
+mod test_mmio {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StepResult, VirtualMachine};
+
+    #[test]
+    fn test_print_string() {
+        let mut data = Segment::new_zeroed();
+        for (i, byte) in "Hi, tinyvm!".bytes().enumerate() {
+            data[0x0100 + i as u16] = byte as u16;
+        }
+        let instructions = tinyvm_asm! {
+            lw r1, 0x100;
+            lw r2, 0xFF00;
+            loop_start:
+            lwd r1, r3;
+            b r3, print;
+            ret;
+            print:
+            sw r2, r3;
+            incr r1, r1;
+            j loop_start;
+        };
+        let output = TextOutput::new();
+        let mut vm = VirtualMachine::new(instructions, data);
+        vm.set_mmio_handler(DEFAULT_MMIO_RANGE, Box::new(output.clone()));
+        vm.run(1000);
+        assert_eq!(vm.get_halted(), Some(StepResult::Return(0)));
+        assert_eq!(output.take_text(), "Hi, tinyvm!");
+        assert_eq!(output.take_text(), "");
+        // The stores never reached the data segment.
+        assert_eq!(vm.get_data()[0xFF00], 0);
+    }
+
+    /// Counts loads, and ignores stores.
+    struct Counter(u16);
+
+    impl MmioHandler for Counter {
+        fn on_load(&mut self, address: u16) -> Option<u16> {
+            if address != 0xFF10 {
+                return None;
+            }
+            self.0 += 1;
+            Some(self.0)
+        }
+
+        fn on_store(&mut self, _address: u16, _value: u16) -> bool {
+            false
+        }
+    }
+
+    #[test]
+    fn test_counter_and_fallthrough() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0xFF10;
+            lwd r1, r2;
+            lwd r1, r3;
+            lw r4, 0xFF11;
+            sw r4, r4;
+            lwd r4, r5;
+            lw r6, 0x0010;
+            lwd r6, r7;
+            ret;
+        };
+        let mut data = Segment::new_zeroed();
+        data[0xFF10] = 0x1234;
+        data[0x0010] = 0x5678;
+        let mut vm = VirtualMachine::new(instructions, data);
+        vm.set_mmio_handler(0xFF10..=0xFF1F, Box::new(Counter(0)));
+        vm.run(100);
+        let registers = vm.get_registers();
+        assert_eq!(registers[2..4], [1, 2]);
+        // Not handled, so the store and the load went to the data segment.
+        assert_eq!(registers[5], 0xFF11);
+        assert_eq!(vm.get_data()[0xFF11], 0xFF11);
+        assert_eq!(registers[7], 0x5678);
+        // Clones share the handler, and compare equal only while they do.
+        let mut clone = vm.clone();
+        assert_eq!(clone, vm);
+        clone.clear_mmio_handler();
+        assert_ne!(clone, vm);
+    }
+
+    #[test]
+    fn test_without_handler() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0xFF00;
+            sw r1, r1;
+            lwd r1, r2;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.run(100);
+        assert_eq!(vm.get_registers()[2], 0xFF00);
+        assert_eq!(vm.get_data()[0xFF00], 0xFF00);
+    }
+}
@@ -0,0 +1,217 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+// All "relative" values in this module are the intuitive PC-relative delta in instructions, i.e. the
+// difference between the new program counter and the address of the branch or jump itself. A relative
+// value of -1 means "the instruction before", and 2 means "skip one instruction".
+
+/// Range of a branch (`0x9xxx`), see the `S` and `V` bits in the instruction set architecture.
+pub const BRANCH_MIN: i32 = -1 - 0x7F;
+pub const BRANCH_MAX: i32 = 2 + 0x7F;
+/// Range of a jump by immediate (`0xAxxx`).
+pub const JUMP_IMM_MIN: i32 = -1 - 0x7FF;
+pub const JUMP_IMM_MAX: i32 = 2 + 0x7FF;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OffsetError {
+    /// The delta 0 (infinite loop) and 1 (no-op) have no encoding, by design.
+    Unencodable {
+        relative: i32,
+    },
+    OutOfRange {
+        relative: i32,
+        min: i32,
+        max: i32,
+    },
+    InvalidRegister {
+        register: u16,
+    },
+}
+
+impl Display for OffsetError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            OffsetError::Unencodable { relative } => write!(
+                f,
+                "Relative offset {} cannot be encoded, it would be an infinite loop or a no-op.",
+                relative
+            ),
+            OffsetError::OutOfRange { relative, min, max } => write!(
+                f,
+                "Relative offset {} is out of range, must be between {} and {}.",
+                relative, min, max
+            ),
+            OffsetError::InvalidRegister { register } => {
+                write!(f, "Register {} does not exist.", register)
+            }
+        }
+    }
+}
+
+impl Error for OffsetError {}
+
+/// Encodes the `SVVV…` bits shared by branch and jump by immediate. `value_bits` is 7 or 11.
+fn encode_offset(relative: i32, value_bits: u32, min: i32, max: i32) -> Result<u16, OffsetError> {
+    if relative == 0 || relative == 1 {
+        return Err(OffsetError::Unencodable { relative });
+    }
+    if relative < min || relative > max {
+        return Err(OffsetError::OutOfRange { relative, min, max });
+    }
+    if relative >= 2 {
+        // - If S=0, the program counter is […] incremented by 2 + 0b0VVVVVVV.
+        Ok((relative - 2) as u16)
+    } else {
+        // - If S=1, the program counter is […] decremented by 1 + 0b0VVVVVVV.
+        Ok((1 << value_bits) | (-1 - relative) as u16)
+    }
+}
+
+fn decode_offset(bits: u16, value_bits: u32) -> i32 {
+    let value = (bits & ((1 << value_bits) - 1)) as i32;
+    if bits & (1 << value_bits) == 0 {
+        2 + value
+    } else {
+        -1 - value
+    }
+}
+
+/// Returns the branch instruction (`0x9xxx`) that moves the program counter by `relative` if register `register` is nonzero.
+pub fn encode_branch(register: u16, relative: i32) -> Result<u16, OffsetError> {
+    if register > 0xF {
+        return Err(OffsetError::InvalidRegister { register });
+    }
+    let offset = encode_offset(relative, 7, BRANCH_MIN, BRANCH_MAX)?;
+    Ok(0x9000 | (register << 8) | offset)
+}
+
+/// Returns the jump by immediate instruction (`0xAxxx`) that moves the program counter by `relative`.
+pub fn encode_jump_imm(relative: i32) -> Result<u16, OffsetError> {
+    let offset = encode_offset(relative, 11, JUMP_IMM_MIN, JUMP_IMM_MAX)?;
+    Ok(0xA000 | offset)
+}
+
+/// Inverse of `encode_branch`. Returns `None` if the word is not a branch instruction.
+#[must_use]
+pub fn decode_branch(instruction: u16) -> Option<(u16, i32)> {
+    if instruction & 0xF000 != 0x9000 {
+        return None;
+    }
+    let register = (instruction & 0x0F00) >> 8;
+    Some((register, decode_offset(instruction & 0x00FF, 7)))
+}
+
+/// Inverse of `encode_jump_imm`. Returns `None` if the word is not a jump by immediate instruction.
+#[must_use]
+pub fn decode_jump_imm(instruction: u16) -> Option<i32> {
+    if instruction & 0xF000 != 0xA000 {
+        return None;
+    }
+    Some(decode_offset(instruction & 0x0FFF, 11))
+}
+
+#[cfg(test)]
+mod test_offsets {
+    use super::*;
+
+    #[test]
+    fn test_branch_examples() {
+        // From the instruction set architecture, and the comments in existing tests.
+        assert_eq!(encode_branch(3, -1), Ok(0x9380));
+        assert_eq!(encode_branch(0, -6), Ok(0x9085));
+        assert_eq!(encode_branch(1, -4), Ok(0x9183));
+        assert_eq!(encode_branch(2, 2), Ok(0x9200));
+        assert_eq!(encode_branch(0xF, BRANCH_MAX), Ok(0x9F7F));
+        assert_eq!(encode_branch(0xF, BRANCH_MIN), Ok(0x9FFF));
+    }
+
+    #[test]
+    fn test_jump_imm_examples() {
+        assert_eq!(encode_jump_imm(2 + 0x123), Ok(0xA123));
+        assert_eq!(encode_jump_imm(-1), Ok(0xA800));
+        assert_eq!(encode_jump_imm(-0x31), Ok(0xA830));
+        assert_eq!(encode_jump_imm(0x801), Ok(0xA7FF));
+        assert_eq!(encode_jump_imm(-0x800), Ok(0xAFFF));
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            encode_branch(0, 0),
+            Err(OffsetError::Unencodable { relative: 0 })
+        );
+        assert_eq!(
+            encode_jump_imm(1),
+            Err(OffsetError::Unencodable { relative: 1 })
+        );
+        assert_eq!(
+            encode_branch(0, BRANCH_MAX + 1),
+            Err(OffsetError::OutOfRange {
+                relative: 130,
+                min: -128,
+                max: 129
+            })
+        );
+        assert_eq!(
+            encode_jump_imm(JUMP_IMM_MIN - 1),
+            Err(OffsetError::OutOfRange {
+                relative: -2049,
+                min: -2048,
+                max: 2049
+            })
+        );
+        assert_eq!(
+            encode_branch(16, 2),
+            Err(OffsetError::InvalidRegister { register: 16 })
+        );
+        assert_eq!(
+            encode_branch(0, 0).unwrap_err().to_string(),
+            "Relative offset 0 cannot be encoded, it would be an infinite loop or a no-op."
+        );
+        assert_eq!(
+            encode_jump_imm(5000).unwrap_err().to_string(),
+            "Relative offset 5000 is out of range, must be between -2048 and 2049."
+        );
+    }
+
+    #[test]
+    fn test_decode_rejects_other_instructions() {
+        assert_eq!(decode_branch(0xA123), None);
+        assert_eq!(decode_branch(0x102A), None);
+        assert_eq!(decode_jump_imm(0x9380), None);
+        assert_eq!(decode_jump_imm(0xB734), None);
+    }
+
+    #[test]
+    fn test_branch_roundtrip_exhaustive() {
+        for register in 0..16 {
+            for relative in BRANCH_MIN..=BRANCH_MAX {
+                if relative == 0 || relative == 1 {
+                    continue;
+                }
+                let instruction = encode_branch(register, relative).unwrap();
+                assert_eq!(decode_branch(instruction), Some((register, relative)));
+            }
+        }
+        // And the other way around: every branch word decodes to something that encodes back to the same word.
+        for instruction in 0x9000..=0x9FFF {
+            let (register, relative) = decode_branch(instruction).unwrap();
+            assert_eq!(encode_branch(register, relative), Ok(instruction));
+        }
+    }
+
+    #[test]
+    fn test_jump_imm_roundtrip_exhaustive() {
+        for relative in JUMP_IMM_MIN..=JUMP_IMM_MAX {
+            if relative == 0 || relative == 1 {
+                continue;
+            }
+            let instruction = encode_jump_imm(relative).unwrap();
+            assert_eq!(decode_jump_imm(instruction), Some(relative));
+        }
+        for instruction in 0xA000..=0xAFFF {
+            let relative = decode_jump_imm(instruction).unwrap();
+            assert_eq!(encode_jump_imm(relative), Ok(instruction));
+        }
+    }
+}
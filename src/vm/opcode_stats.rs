@@ -0,0 +1,184 @@
+use super::{BinaryFunction, InsnClass, UnaryFunction};
+use crate::disasm::{binary_name, unary_name};
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// How many executed instructions fell into each opcode family, see `VirtualMachine::enable_opcode_stats`. This is
+/// finer than `InsnStats`: branches are split by whether they were taken, and unary and binary instructions by
+/// function.
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct OpcodeStats {
+    /// Return, CPUID, Debug-dump, Time
+    pub special: u64,
+    pub compare_zero: u64,
+    pub store_data: u64,
+    pub load_data: u64,
+    pub load_instruction: u64,
+    pub store_instruction: u64,
+    pub load_imm_low: u64,
+    pub load_imm_high: u64,
+    pub load_data_offset: u64,
+    pub store_data_offset: u64,
+    pub compare: u64,
+    pub branch_taken: u64,
+    pub branch_not_taken: u64,
+    pub jump_imm: u64,
+    pub jump_reg: u64,
+    /// Indexed by the function bits minus 8, see `get_unary`.
+    unary: [u64; 8],
+    /// Indexed by the function bits, see `get_binary`.
+    binary: [u64; 16],
+}
+
+impl OpcodeStats {
+    #[must_use]
+    pub fn new() -> OpcodeStats {
+        OpcodeStats::default()
+    }
+
+    /// Counts an instruction that was actually executed, i.e. that advanced the time. `branch_taken` only matters
+    /// for branches.
+    pub(crate) fn record(&mut self, instruction: u16, branch_taken: bool) {
+        let Some(class) = InsnClass::of(instruction) else {
+            return;
+        };
+        let function = ((instruction >> 8) & 0xF) as usize;
+        let counter = match class {
+            InsnClass::Special => &mut self.special,
+            InsnClass::CompareZero => &mut self.compare_zero,
+            InsnClass::StoreData => &mut self.store_data,
+            InsnClass::LoadData => &mut self.load_data,
+            InsnClass::LoadInstruction => &mut self.load_instruction,
+            InsnClass::StoreInstruction => &mut self.store_instruction,
+            InsnClass::LoadImmLow => &mut self.load_imm_low,
+            InsnClass::LoadImmHigh => &mut self.load_imm_high,
+            // Executed unary instructions always have a valid function, i.e. the highest bit is set.
+            InsnClass::Unary => &mut self.unary[function & 0x7],
+            InsnClass::Binary => &mut self.binary[function],
+            InsnClass::LoadDataOffset => &mut self.load_data_offset,
+            InsnClass::StoreDataOffset => &mut self.store_data_offset,
+            InsnClass::Compare => &mut self.compare,
+            InsnClass::Branch if branch_taken => &mut self.branch_taken,
+            InsnClass::Branch => &mut self.branch_not_taken,
+            InsnClass::JumpImm => &mut self.jump_imm,
+            InsnClass::JumpReg => &mut self.jump_reg,
+        };
+        *counter += 1;
+    }
+
+    pub fn get_unary(&self, function: UnaryFunction) -> u64 {
+        self.unary[function as usize & 0x7]
+    }
+
+    pub fn get_binary(&self, function: BinaryFunction) -> u64 {
+        self.binary[function as usize]
+    }
+
+    /// Returns every counter with its name, e.g. ("branch-taken", 3) or ("div.u", 1), in a fixed order.
+    pub fn entries(&self) -> Vec<(&'static str, u64)> {
+        let mut entries = vec![
+            ("special", self.special),
+            ("compare-zero", self.compare_zero),
+            ("store-data", self.store_data),
+            ("load-data", self.load_data),
+            ("load-instruction", self.load_instruction),
+            ("store-instruction", self.store_instruction),
+            ("load-imm-low", self.load_imm_low),
+            ("load-imm-high", self.load_imm_high),
+            ("load-data-offset", self.load_data_offset),
+            ("store-data-offset", self.store_data_offset),
+            ("compare", self.compare),
+            ("branch-taken", self.branch_taken),
+            ("branch-not-taken", self.branch_not_taken),
+            ("jump-imm", self.jump_imm),
+            ("jump-reg", self.jump_reg),
+        ];
+        for (bits, &count) in (0x8..).zip(self.unary.iter()) {
+            let function = UnaryFunction::from_bits(bits).expect("all eight are valid");
+            entries.push((unary_name(function), count));
+        }
+        for (bits, &count) in (0x0..).zip(self.binary.iter()) {
+            entries.push((binary_name(BinaryFunction::from_bits(bits)), count));
+        }
+        entries
+    }
+
+    pub fn total(&self) -> u64 {
+        self.entries().iter().map(|(_, count)| count).sum()
+    }
+}
+
+impl Display for OpcodeStats {
+    /// One right-aligned count and name per line, most frequent first and ties by name, skipping counters that are
+    /// zero.
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let mut entries = self.entries();
+        entries.retain(|&(_, count)| count > 0);
+        entries.sort_by(|(lhs_name, lhs_count), (rhs_name, rhs_count)| {
+            rhs_count.cmp(lhs_count).then(lhs_name.cmp(rhs_name))
+        });
+        let width = entries
+            .first()
+            .map_or(0, |(_, count)| count.to_string().len());
+        for (name, count) in entries {
+            writeln!(f, "{:>width$} {}", count, name, width = width)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_opcode_stats {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StepResult, VirtualMachine};
+
+    #[test]
+    fn test_countdown() {
+        let instructions = tinyvm_asm! {
+            lw r1, 3;
+            lw r2, 0x10;
+            loop_start:
+            sw r2, r1;
+            add r1, r2;
+            decr r1, r1;
+            b r1, loop_start;
+            lw r3, 0x10;
+            lwd r3, r0;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert_eq!(vm.opcode_stats(), None);
+        vm.enable_opcode_stats();
+        vm.run(100);
+        assert_eq!(vm.get_halted(), Some(StepResult::Return(3)));
+
+        let stats = vm.opcode_stats().unwrap();
+        assert_eq!(stats.load_imm_low, 3);
+        assert_eq!(stats.store_data, 3);
+        assert_eq!(stats.get_binary(BinaryFunction::Add), 3);
+        assert_eq!(stats.get_unary(UnaryFunction::Decr), 3);
+        assert_eq!(stats.branch_taken, 2);
+        assert_eq!(stats.branch_not_taken, 1);
+        assert_eq!(stats.load_data, 1);
+        // Like the profile, this only counts instructions that advance the time, so not the final return.
+        assert_eq!(stats.special, 0);
+        assert_eq!(stats.total(), 16);
+        assert_eq!(
+            stats.to_string(),
+            "3 add\n3 decr\n3 load-imm-low\n3 store-data\n2 branch-taken\n1 branch-not-taken\n1 load-data\n"
+        );
+    }
+
+    #[test]
+    fn test_entries() {
+        let stats = OpcodeStats::new();
+        let entries = stats.entries();
+        assert_eq!(entries.len(), 15 + 8 + 16);
+        assert_eq!(entries[15], ("decr", 0));
+        assert_eq!(entries[22], ("mov", 0));
+        assert_eq!(entries[23], ("add", 0));
+        assert_eq!(entries[38], ("root", 0));
+        assert_eq!(stats.to_string(), "");
+    }
+}
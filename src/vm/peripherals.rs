@@ -0,0 +1,265 @@
+use crate::vm::run::run_vm_stepping;
+use crate::vm::{CpuFeatures, ProgramOutcome, StepResult, VirtualMachine};
+use std::collections::VecDeque;
+use std::time::Instant;
+
+/// `r0` for `ConsolePeripheral`: Writes the low byte of `r1` to the output.
+pub const SYSCALL_WRITE_BYTE: u16 = 1;
+/// `r0` for `ConsolePeripheral`: Reads the next word of the input into `r1`. Sets `r0` to `SYSCALL_END_OF_INPUT`
+/// instead if there is none left.
+pub const SYSCALL_READ_WORD: u16 = 2;
+/// `r0` for `ConsolePeripheral`: Sets `r1` and `r2` to the high and low word of the milliseconds since the
+/// peripheral was created, saturating at 0xFFFF_FFFF.
+pub const SYSCALL_ELAPSED_MS: u16 = 3;
+
+/// `r0` after a successful syscall.
+pub const SYSCALL_OK: u16 = 0;
+/// `r0` after `SYSCALL_READ_WORD` if there is no input left.
+pub const SYSCALL_END_OF_INPUT: u16 = 1;
+/// `r0` after a syscall with an unknown operation.
+pub const SYSCALL_UNKNOWN: u16 = 0xFFFF;
+
+/// The outside world of a program run by `run_vm_with_syscalls`: Whenever the program executes Debug-dump, the
+/// handler may inspect the registers and change `r0` to `r3`, and the program continues with the next instruction.
+/// Changes to other registers are discarded.
+///
+/// By convention, `r0` selects the operation and receives the status, and `r1` to `r3` hold the arguments and
+/// results, see `ConsolePeripheral` and the host calls in the ISA.
+pub trait SyscallHandler {
+    fn syscall(&mut self, registers: &mut [u16; 16]);
+}
+
+/// Text output, word input, and the host time, see the `SYSCALL_*` constants.
+#[derive(Debug, Clone)]
+pub struct ConsolePeripheral {
+    output: Vec<u8>,
+    input: VecDeque<u16>,
+    start: Instant,
+}
+
+impl Default for ConsolePeripheral {
+    fn default() -> ConsolePeripheral {
+        ConsolePeripheral {
+            output: Vec::new(),
+            input: VecDeque::new(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl ConsolePeripheral {
+    #[must_use]
+    pub fn new() -> ConsolePeripheral {
+        ConsolePeripheral::default()
+    }
+
+    /// Like `new`, but `SYSCALL_READ_WORD` returns these words, in order.
+    #[must_use]
+    pub fn with_input(input: impl IntoIterator<Item = u16>) -> ConsolePeripheral {
+        ConsolePeripheral {
+            input: input.into_iter().collect(),
+            ..ConsolePeripheral::default()
+        }
+    }
+
+    /// Returns everything written so far, and clears the buffer. Invalid UTF-8 becomes U+FFFD.
+    pub fn take_text(&mut self) -> String {
+        let bytes = std::mem::take(&mut self.output);
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+}
+
+impl SyscallHandler for ConsolePeripheral {
+    fn syscall(&mut self, registers: &mut [u16; 16]) {
+        registers[0] = match registers[0] {
+            SYSCALL_WRITE_BYTE => {
+                self.output.push(registers[1] as u8);
+                SYSCALL_OK
+            }
+            SYSCALL_READ_WORD => match self.input.pop_front() {
+                Some(word) => {
+                    registers[1] = word;
+                    SYSCALL_OK
+                }
+                None => SYSCALL_END_OF_INPUT,
+            },
+            SYSCALL_ELAPSED_MS => {
+                let elapsed_ms =
+                    u32::try_from(self.start.elapsed().as_millis()).unwrap_or(u32::MAX);
+                registers[1] = (elapsed_ms >> 16) as u16;
+                registers[2] = elapsed_ms as u16;
+                SYSCALL_OK
+            }
+            _ => SYSCALL_UNKNOWN,
+        };
+    }
+}
+
+/// Like `run_vm`, but lets `handler` serve every Debug-dump instruction as a syscall. The Debug-dump handler of the
+/// VM, if any, is called first. Loop detection starts over after each syscall, as the program may now see something
+/// new.
+///
+/// During the run, the VM advertises `CpuFeatures::SYSCALL`, so that the program can tell whether Debug-dump may
+/// change its registers.
+pub fn run_vm_with_syscalls(
+    vm: &mut VirtualMachine,
+    max_steps: u64,
+    handler: &mut dyn SyscallHandler,
+) -> ProgramOutcome {
+    let features = vm.get_features();
+    vm.set_features(features.with(CpuFeatures::SYSCALL));
+    let outcome = run_vm_stepping(vm, max_steps, |vm| {
+        let result = vm.step();
+        if result == StepResult::DebugDump {
+            let mut registers = *vm.get_registers();
+            handler.syscall(&mut registers);
+            for (index, &value) in registers[..4].iter().enumerate() {
+                vm.set_register(index as u16, value);
+            }
+            vm.restart_loop_detection();
+        }
+        result
+    });
+    vm.set_features(features);
+    outcome
+}
+
+#[cfg(test)]
+mod test_peripherals {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::Segment;
+
+    #[test]
+    fn test_hello_world() {
+        let mut data = Segment::new_zeroed();
+        for (i, byte) in "Hello, world!\n".bytes().enumerate() {
+            data[0x0100 + i as u16] = byte as u16;
+        }
+        let instructions = tinyvm_asm! {
+            lw r2, 0x100;
+            loop_start:
+            lwd r2, r1;
+            b r1, print;
+            ret;
+            print:
+            lw r0, 1;
+            debug_dump;
+            incr r2, r2;
+            j loop_start;
+        };
+        let mut console = ConsolePeripheral::new();
+        let mut vm = VirtualMachine::new(instructions, data);
+        let outcome = run_vm_with_syscalls(&mut vm, 1000, &mut console);
+        assert!(
+            matches!(outcome, ProgramOutcome::Returned { value: 0, .. }),
+            "{:?}",
+            outcome
+        );
+        assert_eq!(console.take_text(), "Hello, world!\n");
+        assert_eq!(console.take_text(), "");
+    }
+
+    #[test]
+    fn test_read_input() {
+        // Sums up the input.
+        let instructions = tinyvm_asm! {
+            read:
+            lw r0, 2;
+            debug_dump;
+            b r0, done;
+            add r1, r3;
+            j read;
+            done:
+            mov r0, r3;
+            ret;
+        };
+        let mut console = ConsolePeripheral::with_input([1, 20, 300]);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let outcome = run_vm_with_syscalls(&mut vm, 1000, &mut console);
+        assert!(
+            matches!(outcome, ProgramOutcome::Returned { value: 321, .. }),
+            "{:?}",
+            outcome
+        );
+    }
+
+    #[test]
+    fn test_other_operations() {
+        let mut console = ConsolePeripheral::new();
+        let mut registers = [0; 16];
+        registers[0] = SYSCALL_ELAPSED_MS;
+        registers[1] = 0x1234;
+        registers[2] = 0x5678;
+        console.syscall(&mut registers);
+        assert_eq!(registers[0], SYSCALL_OK);
+        // The test does not take 65 seconds.
+        assert_eq!(registers[1], 0);
+        assert!(registers[2] < 0x1000, "{:?}", registers);
+        registers[0] = 0x0042;
+        console.syscall(&mut registers);
+        assert_eq!(registers[0], SYSCALL_UNKNOWN);
+    }
+
+    struct Clobber;
+
+    impl SyscallHandler for Clobber {
+        fn syscall(&mut self, registers: &mut [u16; 16]) {
+            *registers = [0xAAAA; 16];
+        }
+    }
+
+    #[test]
+    fn test_contract() {
+        let instructions = tinyvm_asm! {
+            lw r0, 0;
+            cpuid;
+            mov r5, r0;
+            lw r4, 7;
+            debug_dump;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let features = vm.get_features();
+        let outcome = run_vm_with_syscalls(&mut vm, 100, &mut Clobber);
+        assert!(
+            matches!(outcome, ProgramOutcome::Returned { value: 0xAAAA, .. }),
+            "{:?}",
+            outcome
+        );
+        let registers = vm.get_registers();
+        assert_eq!(registers[0..4], [0xAAAA; 4]);
+        // Only r0 to r3 may change, and the program could tell that they would.
+        assert_eq!(registers[4], 7);
+        assert_eq!(
+            registers[5],
+            0x8000 | features.with(CpuFeatures::SYSCALL).bits()
+        );
+        assert_eq!(vm.get_features(), features);
+    }
+
+    #[test]
+    fn test_without_syscalls_debug_dump_is_ignored() {
+        let instructions = tinyvm_asm! {
+            lw r0, 1;
+            lw r1, 0x41;
+            debug_dump;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+        assert_eq!(
+            crate::vm::run_vm(&mut vm, 100),
+            ProgramOutcome::Returned { value: 1, steps: 3 }
+        );
+        let mut console = ConsolePeripheral::new();
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert_eq!(
+            run_vm_with_syscalls(&mut vm, 100, &mut console),
+            ProgramOutcome::Returned {
+                value: SYSCALL_OK,
+                steps: 3
+            }
+        );
+        assert_eq!(console.take_text(), "A");
+    }
+}
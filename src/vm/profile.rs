@@ -0,0 +1,227 @@
+use std::fmt::{Display, Formatter, Result};
+use std::ops::RangeInclusive;
+
+/// Executions per address, see `VirtualMachine::enable_profiling`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Profile {
+    counts: Box<[u64; 1 << 16]>,
+}
+
+impl Default for Profile {
+    fn default() -> Profile {
+        // Not Box::new, since the array would take 512 KiB of stack first.
+        let counts = vec![0; 1 << 16].into_boxed_slice();
+        Profile {
+            counts: counts.try_into().expect("has the right length"),
+        }
+    }
+}
+
+impl Profile {
+    pub(crate) fn record(&mut self, pc: u16) {
+        self.counts[pc as usize] += 1;
+    }
+
+    pub(crate) fn counts(&self) -> &[u64; 1 << 16] {
+        &self.counts
+    }
+
+    /// Up to `n` addresses with the highest nonzero counts, hottest first. Ties are ordered by address.
+    pub(crate) fn hottest(&self, n: usize) -> Vec<(u16, u64)> {
+        let mut hot: Vec<(u16, u64)> = (0..=0xFFFF)
+            .zip(self.counts.iter().copied())
+            .filter(|&(_, count)| count > 0)
+            .collect();
+        hot.sort_by(|(lhs_pc, lhs_count), (rhs_pc, rhs_count)| {
+            rhs_count.cmp(lhs_count).then(lhs_pc.cmp(rhs_pc))
+        });
+        hot.truncate(n);
+        hot
+    }
+}
+
+/// Which instructions have been executed, see `VirtualMachine::coverage`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct CoverageReport {
+    /// In ascending order.
+    pub addresses: Vec<u16>,
+    /// Maximal runs of consecutive covered addresses, in ascending order.
+    pub ranges: Vec<RangeInclusive<u16>>,
+}
+
+impl CoverageReport {
+    /// `addresses` must be in ascending order.
+    pub(crate) fn from_addresses(addresses: Vec<u16>) -> CoverageReport {
+        let mut ranges: Vec<RangeInclusive<u16>> = Vec::new();
+        for &address in &addresses {
+            match ranges.last_mut() {
+                Some(range) if range.end().checked_add(1) == Some(address) => {
+                    *range = *range.start()..=address;
+                }
+                _ => ranges.push(address..=address),
+            }
+        }
+        CoverageReport { addresses, ranges }
+    }
+
+    /// The number of covered addresses.
+    #[must_use]
+    pub fn count(&self) -> usize {
+        self.addresses.len()
+    }
+
+    #[must_use]
+    pub fn is_covered(&self, address: u16) -> bool {
+        self.addresses.binary_search(&address).is_ok()
+    }
+}
+
+/// A one-line summary like `5 instructions covered: 0000-0003, 0005`.
+impl Display for CoverageReport {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let noun = if self.count() == 1 {
+            "instruction"
+        } else {
+            "instructions"
+        };
+        write!(f, "{} {} covered", self.count(), noun)?;
+        for (index, range) in self.ranges.iter().enumerate() {
+            f.write_str(if index == 0 { ": " } else { ", " })?;
+            if range.start() == range.end() {
+                write!(f, "{:04X}", range.start())?;
+            } else {
+                write!(f, "{:04X}-{:04X}", range.start(), range.end())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_profiling {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StepResult, StopReason, VirtualMachine};
+
+    fn tight_loop() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r1, 1000;
+            loop_start:
+            incr r2, r2;
+            decr r1, r1;
+            b r1, loop_start;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_hottest_ties() {
+        let mut profile = Profile::default();
+        for pc in [7, 3, 7, 5, 3] {
+            profile.record(pc);
+        }
+        assert_eq!(profile.hottest(2), vec![(3, 2), (7, 2)]);
+        assert_eq!(profile.hottest(10), vec![(3, 2), (7, 2), (5, 1)]);
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut vm = tight_loop();
+        assert!(matches!(vm.run(10_000).reason, StopReason::Returned(_)));
+        assert_eq!(vm.profile(), None);
+        assert_eq!(vm.hottest(3), vec![]);
+    }
+
+    #[test]
+    fn test_tight_loop() {
+        let mut vm = tight_loop();
+        vm.enable_profiling();
+        assert!(matches!(vm.run(10_000).reason, StopReason::Returned(_)));
+        let ret_pc = vm.get_program_counter();
+        let hottest = vm.hottest(3);
+        assert_eq!(
+            hottest,
+            vec![(ret_pc - 3, 1000), (ret_pc - 2, 1000), (ret_pc - 1, 1000)]
+        );
+        let profile = vm.profile().unwrap();
+        // The ret instruction halts, and is not counted.
+        assert_eq!(profile[ret_pc as usize], 0);
+        assert_eq!(profile.iter().sum::<u64>(), vm.get_time());
+    }
+
+    #[test]
+    fn test_breakpoint_not_counted() {
+        let mut vm = tight_loop();
+        vm.enable_profiling();
+        vm.add_breakpoint(0);
+        assert_eq!(vm.step(), StepResult::Breakpoint(0));
+        assert_eq!(vm.profile().unwrap()[0], 0);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.profile().unwrap()[0], 1);
+    }
+}
+
+#[cfg(test)]
+mod test_coverage {
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StepResult, StopReason, VirtualMachine};
+
+    fn skipping() -> VirtualMachine {
+        let instructions = tinyvm_asm! {
+            lw r1, 2;
+            j skip;
+            lw r2, 5;
+            skip:
+            decr r1, r1;
+            b r1, skip;
+            ret;
+            lw r3, 7;
+            ret;
+        };
+        VirtualMachine::new(instructions, Segment::new_zeroed())
+    }
+
+    #[test]
+    fn test_disabled_by_default() {
+        let mut vm = skipping();
+        vm.run(100);
+        assert_eq!(vm.coverage(), None);
+    }
+
+    #[test]
+    fn test_unreachable_tail() {
+        let mut vm = skipping();
+        vm.enable_coverage();
+        assert_eq!(vm.coverage().unwrap().count(), 0);
+        assert!(matches!(vm.run(100).reason, StopReason::Returned(_)));
+        let report = vm.coverage().unwrap();
+        assert_eq!(report.addresses, vec![0, 1, 3, 4, 5]);
+        assert_eq!(report.ranges, vec![0..=1, 3..=5]);
+        assert_eq!(report.count(), 5);
+        // The skipped instruction, and everything after the ret.
+        for address in [2, 6, 7, 0xFFFF] {
+            assert!(!report.is_covered(address));
+        }
+        assert!(report.is_covered(5));
+        assert_eq!(
+            report.to_string(),
+            "5 instructions covered: 0000-0001, 0003-0005"
+        );
+    }
+
+    #[test]
+    fn test_breakpoint_and_illegal() {
+        let mut vm = VirtualMachine::new(tinyvm_asm! { word 0xFFFF; }, Segment::new_zeroed());
+        vm.enable_coverage();
+        vm.add_breakpoint(0);
+        assert_eq!(vm.step(), StepResult::Breakpoint(0));
+        assert_eq!(vm.coverage().unwrap().count(), 0);
+        assert_eq!(vm.step(), StepResult::IllegalInstruction(0xFFFF));
+        assert_eq!(vm.coverage().unwrap().ranges, vec![0..=0]);
+        assert_eq!(
+            vm.coverage().unwrap().to_string(),
+            "1 instruction covered: 0000"
+        );
+    }
+}
@@ -0,0 +1,320 @@
+use crate::vm::SplitMix64;
+use getrandom::getrandom;
+use std::collections::VecDeque;
+use std::fmt::{Debug, Formatter, Result};
+use std::sync::{Arc, Mutex};
+
+/// Where `rnd` gets its numbers from, see `VirtualMachine::with_random_source`.
+pub trait RandomSource: Send {
+    /// Returns a number from 0 up to and including `upper`, ideally uniformly distributed. `None` means that no
+    /// randomness is available, which halts the VM with `StepResult::RandomnessUnavailable`.
+    ///
+    /// `rnd` with an upper bound of zero always yields zero without asking the source.
+    fn next_upto(&mut self, upper: u16) -> Option<u16>;
+}
+
+/// How often `rnd` asks the operating system for entropy before giving up.
+const RANDOM_ATTEMPTS: usize = 3;
+
+#[cfg(test)]
+thread_local! {
+    static FAIL_GETRANDOM: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Makes every entropy request on the current thread fail, to exercise `StepResult::RandomnessUnavailable`.
+#[cfg(test)]
+pub(crate) fn set_fail_getrandom(fail: bool) {
+    FAIL_GETRANDOM.with(|cell| cell.set(fail));
+}
+
+fn fill_random(bytes: &mut [u8]) -> bool {
+    #[cfg(test)]
+    if FAIL_GETRANDOM.with(|cell| cell.get()) {
+        return false;
+    }
+    getrandom(bytes).is_ok()
+}
+
+/// Returns `None` if the operating system cannot provide entropy, e.g. in a sandbox without /dev/urandom.
+fn os_random_u64() -> Option<u64> {
+    let mut bytes = [0u8; 8];
+    if !(0..RANDOM_ATTEMPTS).any(|_| fill_random(&mut bytes)) {
+        return None;
+    }
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn reduce(value: u64, upper: u16) -> u16 {
+    // Make a random u64, and do the modulo trick.
+    // This *does* create a disparity in probabilities, but it's at most (2**16) / (2**64) = 3.55e-13,
+    // so pretty darn unlikely to be noticed by anyone.
+    let modulus = (upper as u64) + 1;
+    (value % modulus) as u16
+}
+
+/// The operating system's entropy. This is the default, unless the VM was created by `new_with_seed`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct OsRandomSource;
+
+impl RandomSource for OsRandomSource {
+    fn next_upto(&mut self, upper: u16) -> Option<u16> {
+        Some(reduce(os_random_u64()?, upper))
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_upto(&mut self, upper: u16) -> Option<u16> {
+        Some(reduce(self.next_u64(), upper))
+    }
+}
+
+/// Not random at all: Returns `start`, `start + 1`, `start + 2`, and so on, each modulo `upper + 1`. Useful to
+/// write exact tests for programs that use `rnd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CountingSource {
+    next: u16,
+}
+
+impl CountingSource {
+    #[must_use]
+    pub fn new(start: u16) -> CountingSource {
+        CountingSource { next: start }
+    }
+}
+
+impl RandomSource for CountingSource {
+    fn next_upto(&mut self, upper: u16) -> Option<u16> {
+        let value = reduce(self.next as u64, upper);
+        self.next = self.next.wrapping_add(1);
+        Some(value)
+    }
+}
+
+/// Returns exactly the given values in order, see `VirtualMachine::replay_randomness`. Returns `None` once they are
+/// exhausted, and for a value that exceeds the upper bound.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplaySource {
+    values: VecDeque<u16>,
+}
+
+impl ReplaySource {
+    #[must_use]
+    pub fn new(values: Vec<u16>) -> ReplaySource {
+        ReplaySource {
+            values: values.into(),
+        }
+    }
+
+    /// Returns how many values have not been replayed yet.
+    #[must_use]
+    pub fn remaining(&self) -> usize {
+        self.values.len()
+    }
+}
+
+impl RandomSource for ReplaySource {
+    fn next_upto(&mut self, upper: u16) -> Option<u16> {
+        self.values.pop_front().filter(|&value| value <= upper)
+    }
+}
+
+/// A `RandomSource` that can be installed on several VMs, see `VirtualMachine::set_random_source`.
+///
+/// Clones share the same source, so a single source can feed several VMs, e.g. all moves of a game. Two VMs compare
+/// equal only if they share the same source.
+#[derive(Clone)]
+pub struct SharedRandomSource(Arc<Mutex<Box<dyn RandomSource>>>);
+
+impl SharedRandomSource {
+    pub fn new(source: Box<dyn RandomSource>) -> SharedRandomSource {
+        SharedRandomSource(Arc::new(Mutex::new(source)))
+    }
+
+    pub(crate) fn next_upto(&self, upper: u16) -> Option<u16> {
+        // A panicking source is the caller's problem, but it should not disable the source for good.
+        let mut source = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        source.next_upto(upper)
+    }
+}
+
+impl Debug for SharedRandomSource {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.write_str("SharedRandomSource")
+    }
+}
+
+impl PartialEq for SharedRandomSource {
+    fn eq(&self, other: &SharedRandomSource) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for SharedRandomSource {}
+
+#[cfg(test)]
+mod test_random {
+    use super::*;
+    use crate::vm::{Segment, StepResult, StopReason, VirtualMachine};
+
+    /// Pearson's chi-square statistic of `samples` draws of `rnd(upper_bound)`, assuming a uniform distribution.
+    fn chi_square(source: &mut dyn RandomSource, upper_bound: u16, samples: usize) -> f64 {
+        let mut counts = vec![0usize; upper_bound as usize + 1];
+        for _ in 0..samples {
+            let value = source.next_upto(upper_bound).unwrap();
+            counts[value as usize] += 1;
+        }
+        let expected = samples as f64 / counts.len() as f64;
+        counts
+            .iter()
+            .map(|&count| (count as f64 - expected).powi(2) / expected)
+            .sum()
+    }
+
+    fn check_uniform(source: &mut dyn RandomSource) {
+        // With 5 degrees of freedom, a statistic of 40 or more has a probability of about 1.5e-7. So for the
+        // operating system's entropy this test is technically flaky, but that should never matter in practice.
+        let statistic = chi_square(source, 5, 60_000);
+        assert!(statistic < 40.0, "chi-square statistic {}", statistic);
+        // And once more with a modulus that is not coprime to 256:
+        let statistic = chi_square(source, 7, 80_000);
+        assert!(statistic < 45.0, "chi-square statistic {}", statistic);
+    }
+
+    #[test]
+    fn test_uniform_os() {
+        check_uniform(&mut OsRandomSource);
+    }
+
+    #[test]
+    fn test_uniform_seeded() {
+        check_uniform(&mut SplitMix64::new(0));
+        check_uniform(&mut SplitMix64::new(0xDEAD_BEEF));
+    }
+
+    #[test]
+    fn test_counting() {
+        let mut source = CountingSource::new(0xFFFE);
+        assert_eq!(source.next_upto(0xFFFF), Some(0xFFFE));
+        assert_eq!(source.next_upto(0xFFFF), Some(0xFFFF));
+        assert_eq!(source.next_upto(0xFFFF), Some(0x0000));
+        assert_eq!(source.next_upto(2), Some(1));
+        assert_eq!(source.next_upto(2), Some(2));
+        assert_eq!(source.next_upto(2), Some(0));
+    }
+
+    #[test]
+    fn test_seeded_vm_is_reproducible() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x31FF; // lw r1, 0xFFFF
+        instructions[1] = 0x5E12; // rnd r2, r1
+        instructions[2] = 0x5E13; // rnd r3, r1
+        instructions[3] = 0x102A; // ret
+        let run = |seed| {
+            let mut vm =
+                VirtualMachine::new_with_seed(instructions.clone(), Segment::new_zeroed(), seed);
+            vm.run(10);
+            assert!(!vm.was_deterministic_so_far());
+            vm.get_registers()[2..4].to_vec()
+        };
+        assert_eq!(run(42), run(42));
+        assert_ne!(run(42), run(43));
+    }
+
+    #[test]
+    fn test_vm_with_random_source() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x3106; // lw r1, 6
+        instructions[1] = 0x5E12; // rnd r2, r1
+        instructions[2] = 0x5E13; // rnd r3, r1
+        instructions[3] = 0x5E04; // rnd r4, r0
+        instructions[4] = 0x5E15; // rnd r5, r1
+        let mut vm = VirtualMachine::with_random_source(
+            instructions,
+            Segment::new_zeroed(),
+            Box::new(CountingSource::new(5)),
+        );
+        vm.run(5);
+        // The upper bound of zero does not consume a number.
+        assert_eq!(vm.get_registers()[2..6], [5, 6, 0, 0]);
+        assert!(!vm.was_deterministic_so_far());
+        // But it is still a call.
+        assert_eq!(vm.random_calls(), 4);
+    }
+
+    #[test]
+    fn test_shared_between_vms() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x31FF; // lw r1, 0xFFFF
+        instructions[1] = 0x5E12; // rnd r2, r1
+        let source = SharedRandomSource::new(Box::new(CountingSource::new(100)));
+        let mut values = Vec::new();
+        for _ in 0..3 {
+            let mut vm = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+            vm.set_random_source(Some(source.clone()));
+            vm.run(2);
+            values.push(vm.get_registers()[2]);
+        }
+        assert_eq!(values, vec![100, 101, 102]);
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x31FF; // lw r1, 0xFFFF
+        instructions[1] = 0x5E12; // rnd r2, r1
+        instructions[2] = 0x5E03; // rnd r3, r0
+        instructions[3] = 0x5E14; // rnd r4, r1
+        instructions[4] = 0x5E15; // rnd r5, r1
+        let mut vm = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+        vm.record_randomness();
+        vm.run(5);
+        let trace = vm.take_random_trace();
+        assert_eq!(trace, [2, 4, 5].map(|r| vm.get_registers()[r]).to_vec());
+        assert!(vm.take_random_trace().is_empty());
+
+        let mut replayed = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+        replayed.replay_randomness(trace.clone());
+        replayed.run(5);
+        assert_eq!(replayed.get_registers(), vm.get_registers());
+
+        let mut exhausted = VirtualMachine::new(instructions, Segment::new_zeroed());
+        exhausted.replay_randomness(trace[..2].to_vec());
+        assert_eq!(exhausted.run(5).reason, StopReason::RandomnessUnavailable);
+        assert_eq!(exhausted.get_program_counter(), 4);
+    }
+
+    #[test]
+    fn test_replay_diverged() {
+        let mut source = ReplaySource::new(vec![3, 7, 2]);
+        assert_eq!(source.next_upto(5), Some(3));
+        assert_eq!(source.next_upto(5), None);
+        assert_eq!(source.remaining(), 1);
+        assert_eq!(source.next_upto(5), Some(2));
+        assert_eq!(source.next_upto(5), None);
+    }
+
+    struct Exhausted;
+
+    impl RandomSource for Exhausted {
+        fn next_upto(&mut self, _upper: u16) -> Option<u16> {
+            None
+        }
+    }
+
+    #[test]
+    fn test_unavailable() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0] = 0x5E12; // rnd r2, r1
+        instructions[1] = 0x3101; // lw r1, 1
+        instructions[2] = 0x5E12; // rnd r2, r1
+        let mut vm = VirtualMachine::with_random_source(
+            instructions,
+            Segment::new_zeroed(),
+            Box::new(Exhausted),
+        );
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.step(), StepResult::RandomnessUnavailable);
+        assert_eq!(vm.random_calls(), 1);
+    }
+}
@@ -0,0 +1,96 @@
+/// How many program counters `VirtualMachine::recent_pcs` remembers.
+pub const RECENT_PCS_LEN: usize = 32;
+
+/// The last `RECENT_PCS_LEN` program counters. Every address is stored twice, `RECENT_PCS_LEN` apart, so that the
+/// most recent ones are always a contiguous slice, ending just before `next + RECENT_PCS_LEN`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub(crate) struct RecentPcs {
+    pcs: [u16; 2 * RECENT_PCS_LEN],
+    next: usize,
+    len: usize,
+}
+
+impl Default for RecentPcs {
+    fn default() -> RecentPcs {
+        RecentPcs {
+            pcs: [0; 2 * RECENT_PCS_LEN],
+            next: 0,
+            len: 0,
+        }
+    }
+}
+
+impl RecentPcs {
+    pub(crate) fn push(&mut self, pc: u16) {
+        self.pcs[self.next] = pc;
+        self.pcs[self.next + RECENT_PCS_LEN] = pc;
+        self.next = (self.next + 1) % RECENT_PCS_LEN;
+        self.len = (self.len + 1).min(RECENT_PCS_LEN);
+    }
+
+    /// Oldest first.
+    pub(crate) fn as_slice(&self) -> &[u16] {
+        let end = self.next + RECENT_PCS_LEN;
+        &self.pcs[end - self.len..end]
+    }
+}
+
+#[cfg(test)]
+mod test_recent_pcs {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StepResult, VirtualMachine};
+
+    #[test]
+    fn test_push() {
+        let mut recent = RecentPcs::default();
+        assert!(recent.as_slice().is_empty());
+        recent.push(5);
+        recent.push(7);
+        assert_eq!(recent.as_slice(), &[5, 7]);
+        for pc in 0..100 {
+            recent.push(pc);
+        }
+        let expected = (100 - RECENT_PCS_LEN as u16..100).collect::<Vec<_>>();
+        assert_eq!(recent.as_slice(), expected.as_slice());
+    }
+
+    #[test]
+    fn test_across_branch() {
+        let instructions = tinyvm_asm! {
+            lw r1, 2;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            ill 0xFFFF;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert!(vm.recent_pcs().is_empty());
+        vm.enable_recent_pcs();
+        vm.run(100);
+        assert_eq!(
+            vm.get_halted(),
+            Some(StepResult::IllegalInstruction(0xFFFF))
+        );
+        // Includes the faulting instruction, but stepping the halted machine adds nothing.
+        vm.step();
+        assert_eq!(vm.recent_pcs(), &[0, 1, 2, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_across_wrap_around() {
+        let mut instructions = Segment::new_zeroed();
+        instructions[0xFFFE] = 0x5911; // incr r1
+        instructions[0xFFFF] = 0x5911; // incr r1
+        instructions[0x0000] = 0xB1FE; // j r1 - 0x02
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.enable_recent_pcs();
+        vm.set_register(1, 0xFFFE);
+        vm.set_program_counter(0xFFFE);
+        vm.run(4);
+        assert_eq!(vm.recent_pcs(), &[0xFFFE, 0xFFFF, 0x0000, 0xFFFE]);
+        // The second time around, r1 is 2, so the jump goes to itself forever.
+        vm.run(100);
+        assert_eq!(vm.recent_pcs(), &[0x0000; RECENT_PCS_LEN]);
+    }
+}
@@ -0,0 +1,128 @@
+/// A change of a watched register by the program, see `VirtualMachine::watch_register`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RegisterWatchHit {
+    /// `get_time` before the instruction was executed.
+    pub time: u64,
+    pub pc: u16,
+    pub register: u16,
+    pub old: u16,
+    pub new: u16,
+}
+
+/// The registers watched through `VirtualMachine::watch_register`, and their changes that the host has not taken
+/// yet.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub(crate) struct RegisterWatch {
+    /// One bit per register.
+    watched: u16,
+    hits: Vec<RegisterWatchHit>,
+}
+
+impl RegisterWatch {
+    pub(crate) fn watch(&mut self, index: u16) {
+        assert!(index < 16, "There are only 16 registers, not {}.", index);
+        self.watched |= 1 << index;
+    }
+
+    pub(crate) fn unwatch(&mut self, index: u16) {
+        assert!(index < 16, "There are only 16 registers, not {}.", index);
+        self.watched &= !(1 << index);
+    }
+
+    /// Whether any register is watched, i.e. whether the registers need to be compared at all.
+    pub(crate) fn is_active(&self) -> bool {
+        self.watched != 0
+    }
+
+    pub(crate) fn take_hits(&mut self) -> Vec<RegisterWatchHit> {
+        std::mem::take(&mut self.hits)
+    }
+
+    /// Compares the registers after executing the instruction at `pc` against `before`.
+    pub(crate) fn record(&mut self, before: &[u16; 16], after: &[u16; 16], time: u64, pc: u16) {
+        for (register, (&old, &new)) in before.iter().zip(after).enumerate() {
+            if self.watched & (1 << register) != 0 && old != new {
+                self.hits.push(RegisterWatchHit {
+                    time,
+                    pc,
+                    register: register as u16,
+                    old,
+                    new,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_register_watch {
+    use super::*;
+    use crate::tinyvm_asm;
+    use crate::vm::{Segment, StopReason, VirtualMachine};
+
+    #[test]
+    fn test_from_all_families() {
+        let instructions = tinyvm_asm! {
+            lw r1, 5;
+            incr r1, r1;
+            add r1, r1;
+            lw r2, 12;
+            compare 0b0100, r2, r1;
+            mov r1, r1;
+            cpuid;
+            lw r1, 7;
+            time;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.watch_register(1);
+        vm.set_register(1, 0x1234);
+        vm.set_register(1, 0);
+        assert_eq!(vm.run(100).reason, StopReason::Returned(0));
+        let hit = |time, pc, old, new| RegisterWatchHit {
+            time,
+            pc,
+            register: 1,
+            old,
+            new,
+        };
+        assert_eq!(
+            vm.take_register_watch_hits(),
+            vec![
+                // Load immediate, unary, binary, compare.
+                hit(0, 0, 0, 5),
+                hit(1, 1, 5, 6),
+                hit(2, 2, 6, 12),
+                hit(4, 4, 12, 1),
+                // The mov did not change anything. CPUID zeroes r1, and so does Time this early.
+                hit(6, 6, 1, 0),
+                hit(7, 7, 0, 7),
+                hit(8, 8, 7, 0),
+            ]
+        );
+        assert_eq!(vm.take_register_watch_hits(), vec![]);
+    }
+
+    #[test]
+    fn test_unwatch() {
+        let instructions = tinyvm_asm! {
+            lw r1, 1;
+            lw r2, 2;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        vm.watch_register(1);
+        vm.watch_register(2);
+        vm.unwatch_register(1);
+        vm.run(100);
+        let hits = vm.take_register_watch_hits();
+        assert_eq!(hits.len(), 1);
+        assert_eq!((hits[0].register, hits[0].new), (2, 2));
+    }
+
+    #[test]
+    #[should_panic(expected = "only 16 registers")]
+    fn test_out_of_range() {
+        VirtualMachine::new(Segment::new_zeroed(), Segment::new_zeroed()).watch_register(16);
+    }
+}
@@ -0,0 +1,510 @@
+use crate::vm::{Segment, StepResult, VirtualMachine};
+use std::iter::FusedIterator;
+
+/// How a program run by `run_program` ended. `steps` counts the executed instructions, i.e. `VirtualMachine::get_time`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ProgramOutcome {
+    /// The program executed the Return instruction with `value` in register 0.
+    Returned { value: u16, steps: u64 },
+    /// The program hit the illegal instruction `insn` at address `pc`.
+    Faulted { insn: u16, pc: u16, steps: u64 },
+    /// The program neither returned nor faulted within the budget.
+    OutOfBudget { steps: u64 },
+    /// The host could not provide entropy for the `rnd` instruction at address `pc`.
+    RandomnessUnavailable { pc: u16, steps: u64 },
+    /// The instruction at address `pc` has a breakpoint, see `VirtualMachine::add_breakpoint`. Running again
+    /// continues from there.
+    Breakpoint { pc: u16, steps: u64 },
+    /// The program would never return, because it repeats the same `period` instructions forever, see
+    /// `VirtualMachine::enable_loop_detection`.
+    LoopDetected { period: u64, steps: u64 },
+    /// The instruction at address `pc` tried to store to the read-only address `addr`, see
+    /// `VirtualMachine::protect_data`.
+    ProtectionFault { addr: u16, pc: u16, steps: u64 },
+}
+
+impl ProgramOutcome {
+    #[must_use]
+    pub fn steps(&self) -> u64 {
+        match self {
+            ProgramOutcome::Returned { steps, .. }
+            | ProgramOutcome::Faulted { steps, .. }
+            | ProgramOutcome::OutOfBudget { steps }
+            | ProgramOutcome::RandomnessUnavailable { steps, .. }
+            | ProgramOutcome::Breakpoint { steps, .. }
+            | ProgramOutcome::LoopDetected { steps, .. }
+            | ProgramOutcome::ProtectionFault { steps, .. } => *steps,
+        }
+    }
+}
+
+/// Why `VirtualMachine::run` stopped.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StopReason {
+    /// The program executed the Return instruction with this value in register 0.
+    Returned(u16),
+    IllegalInstruction(u16),
+    /// Only for `VirtualMachine::run_to_debug_dump`. The Debug-dump instruction has already been executed.
+    DebugDump,
+    RandomnessUnavailable,
+    /// The instruction at this address has a breakpoint, and was not executed yet.
+    Breakpoint(u16),
+    /// The program neither returned nor faulted within the budget.
+    OutOfBudget,
+    /// Only with `VirtualMachine::enable_loop_detection`. The machine is in exactly the same state as `period`
+    /// instructions ago, so it would repeat them forever instead of returning.
+    LoopDetected {
+        period: u64,
+    },
+    /// The instruction at `pc` tried to store to the read-only address `addr`, see
+    /// `VirtualMachine::protect_data`.
+    ProtectionFault {
+        addr: u16,
+        pc: u16,
+    },
+}
+
+/// How a call of `VirtualMachine::run` ended. `steps` counts the instructions executed by this call only, i.e. how
+/// far `VirtualMachine::get_time` advanced. With a cost model, it is the total cost of these instructions instead.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct RunOutcome {
+    pub steps: u64,
+    pub reason: StopReason,
+}
+
+impl VirtualMachine {
+    /// Executes up to `max_steps` instructions, and stops early if the machine halts or hits a breakpoint.
+    /// DebugDump is ignored. With a cost model, `max_steps` limits the total cost instead, and the run stops before
+    /// an instruction that costs more than what is left.
+    ///
+    /// Calling this on a machine that already halted executes nothing, and reports the same reason again.
+    pub fn run(&mut self, max_steps: u64) -> RunOutcome {
+        run_stepping(self, max_steps, false, VirtualMachine::step)
+    }
+
+    /// Like `run`, but also stops right after executing a Debug-dump instruction, so that the caller can inspect
+    /// the state. Call it again to continue.
+    pub fn run_to_debug_dump(&mut self, max_steps: u64) -> RunOutcome {
+        run_stepping(self, max_steps, true, VirtualMachine::step)
+    }
+
+    /// Runs the program from one Debug-dump instruction (0x102C) to the next, and returns the value of register 0 at
+    /// each of them. The ISA has no yield instruction, so this is only a convention for a program to report a
+    /// sequence of values. Each item may take up to `budget_per_item` steps. The iterator ends as soon as the program
+    /// halts or runs out of budget, see `DebugDumps::stop_reason`. In particular, the value of a Return is not an
+    /// item, but ends the iterator with `StopReason::Returned`.
+    ///
+    /// ```
+    /// let instructions = tinyvm::tinyvm_asm! {
+    ///     lw r1, 3;
+    ///     loop_start:
+    ///     add r1, r0;
+    ///     debug_dump;
+    ///     j loop_start;
+    /// };
+    /// let mut vm = tinyvm::VirtualMachine::new(instructions, tinyvm::Segment::new_zeroed());
+    /// let first: Vec<u16> = vm.debug_dumps(100).take(5).collect();
+    /// assert_eq!(first, vec![3, 6, 9, 12, 15]);
+    /// ```
+    pub fn debug_dumps(&mut self, budget_per_item: u64) -> DebugDumps<'_> {
+        DebugDumps {
+            vm: self,
+            budget_per_item,
+            stop_reason: None,
+        }
+    }
+}
+
+/// The value of register 0 at each Debug-dump instruction, see `VirtualMachine::debug_dumps`.
+#[derive(Debug)]
+pub struct DebugDumps<'a> {
+    vm: &'a mut VirtualMachine,
+    budget_per_item: u64,
+    stop_reason: Option<StopReason>,
+}
+
+impl DebugDumps<'_> {
+    /// Why the iterator ended, or `None` if it did not end yet. Never `StopReason::DebugDump`.
+    ///
+    /// ```
+    /// let instructions = tinyvm::tinyvm_asm! {
+    ///     lw r0, 7;
+    ///     debug_dump;
+    ///     ill 0xFFFF;
+    /// };
+    /// let mut vm = tinyvm::VirtualMachine::new(instructions, tinyvm::Segment::new_zeroed());
+    /// let mut dumps = vm.debug_dumps(100);
+    /// assert_eq!(dumps.by_ref().collect::<Vec<_>>(), vec![7]);
+    /// assert_eq!(
+    ///     dumps.stop_reason(),
+    ///     Some(tinyvm::StopReason::IllegalInstruction(0xFFFF))
+    /// );
+    ///
+    /// let instructions = tinyvm::tinyvm_asm! {
+    ///     lw r0, 7;
+    ///     debug_dump;
+    ///     lw r0, 8;
+    ///     ret;
+    /// };
+    /// let mut vm = tinyvm::VirtualMachine::new(instructions, tinyvm::Segment::new_zeroed());
+    /// let mut dumps = vm.debug_dumps(100);
+    /// assert_eq!(dumps.by_ref().collect::<Vec<_>>(), vec![7]);
+    /// assert_eq!(dumps.stop_reason(), Some(tinyvm::StopReason::Returned(8)));
+    /// ```
+    pub fn stop_reason(&self) -> Option<StopReason> {
+        self.stop_reason
+    }
+
+    pub fn vm(&self) -> &VirtualMachine {
+        self.vm
+    }
+}
+
+impl Iterator for DebugDumps<'_> {
+    type Item = u16;
+
+    fn next(&mut self) -> Option<u16> {
+        if self.stop_reason.is_some() {
+            return None;
+        }
+        match self.vm.run_to_debug_dump(self.budget_per_item).reason {
+            StopReason::DebugDump => Some(self.vm.get_registers()[0]),
+            reason => {
+                self.stop_reason = Some(reason);
+                None
+            }
+        }
+    }
+}
+
+impl FusedIterator for DebugDumps<'_> {}
+
+/// Why a run stops after a step with this result, or `None` if it continues.
+fn stop_reason(result: StepResult, stop_on_debug_dump: bool) -> Option<StopReason> {
+    match result {
+        StepResult::Continue => None,
+        StepResult::DebugDump if !stop_on_debug_dump => None,
+        StepResult::DebugDump => Some(StopReason::DebugDump),
+        StepResult::IllegalInstruction(insn) => Some(StopReason::IllegalInstruction(insn)),
+        StepResult::Return(value) => Some(StopReason::Returned(value)),
+        StepResult::RandomnessUnavailable => Some(StopReason::RandomnessUnavailable),
+        StepResult::Breakpoint(pc) => Some(StopReason::Breakpoint(pc)),
+        StepResult::ProtectionFault { addr, pc } => Some(StopReason::ProtectionFault { addr, pc }),
+    }
+}
+
+/// Like `VirtualMachine::run`, but lets the caller wrap each step, e.g. to observe it.
+pub(crate) fn run_stepping(
+    vm: &mut VirtualMachine,
+    max_steps: u64,
+    stop_on_debug_dump: bool,
+    mut step: impl FnMut(&mut VirtualMachine) -> StepResult,
+) -> RunOutcome {
+    // Even a budget of zero reports why the machine halted earlier.
+    if let Some(reason) = vm
+        .get_halted()
+        .and_then(|halted| stop_reason(halted, false))
+    {
+        return RunOutcome { steps: 0, reason };
+    }
+    let start_time = vm.get_time();
+    // The time may wrap around during the run.
+    let elapsed = |vm: &VirtualMachine| vm.get_time().wrapping_sub(start_time);
+    let mut reason = StopReason::OutOfBudget;
+    vm.restart_loop_detection();
+    while elapsed(vm).saturating_add(vm.next_cost()) <= max_steps {
+        if let Some(stop) = stop_reason(step(vm), stop_on_debug_dump) {
+            reason = stop;
+            break;
+        }
+        if let Some(period) = vm.check_for_loop() {
+            reason = StopReason::LoopDetected { period };
+            break;
+        }
+    }
+    RunOutcome {
+        steps: elapsed(vm),
+        reason,
+    }
+}
+
+/// Runs the program until it returns or faults, but for at most `max_steps` steps. DebugDump is ignored.
+pub fn run_program(instructions: Segment, data: Segment, max_steps: u64) -> ProgramOutcome {
+    run_vm(&mut VirtualMachine::new(instructions, data), max_steps)
+}
+
+/// Like `run_program`, but on an existing VM, so that the final state can be inspected afterwards.
+pub fn run_vm(vm: &mut VirtualMachine, max_steps: u64) -> ProgramOutcome {
+    run_vm_stepping(vm, max_steps, VirtualMachine::step)
+}
+
+/// Like `run_vm`, but lets the caller wrap each step, e.g. to observe it.
+pub(crate) fn run_vm_stepping(
+    vm: &mut VirtualMachine,
+    max_steps: u64,
+    step: impl FnMut(&mut VirtualMachine) -> StepResult,
+) -> ProgramOutcome {
+    let outcome = run_stepping(vm, max_steps, false, step);
+    let steps = vm.get_time();
+    let pc = vm.get_program_counter();
+    match outcome.reason {
+        StopReason::Returned(value) => ProgramOutcome::Returned { value, steps },
+        StopReason::IllegalInstruction(insn) => ProgramOutcome::Faulted { insn, pc, steps },
+        StopReason::RandomnessUnavailable => ProgramOutcome::RandomnessUnavailable { pc, steps },
+        StopReason::Breakpoint(pc) => ProgramOutcome::Breakpoint { pc, steps },
+        StopReason::LoopDetected { period } => ProgramOutcome::LoopDetected { period, steps },
+        StopReason::ProtectionFault { addr, pc } => {
+            ProgramOutcome::ProtectionFault { addr, pc, steps }
+        }
+        // DebugDump does not stop this run.
+        StopReason::DebugDump | StopReason::OutOfBudget => ProgramOutcome::OutOfBudget { steps },
+    }
+}
+
+#[cfg(test)]
+mod test_run {
+    use super::*;
+
+    #[test]
+    fn test_returned() {
+        let instructions = Segment::from_prefix(&[
+            0x3042, // lw r0, 0x0042
+            0x102C, // debug-dump
+            0x102A, // ret
+        ]);
+        assert_eq!(
+            run_program(instructions, Segment::new_zeroed(), 10),
+            ProgramOutcome::Returned {
+                value: 0x0042,
+                steps: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_returned_reads_data() {
+        let instructions = Segment::from_prefix(&[
+            0x3105, // lw r1, 5
+            0x2110, // lw r0, r1
+            0x102A, // ret
+        ]);
+        let data = Segment::from_prefix(&[0, 0, 0, 0, 0, 0x1337]);
+        assert_eq!(
+            run_program(instructions, data, 3),
+            ProgramOutcome::Returned {
+                value: 0x1337,
+                steps: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_faulted() {
+        let instructions = Segment::from_prefix(&[
+            0x3000, // lw r0, 0
+            0x5911, // incr r1
+            0xFFFF, // illegal
+        ]);
+        assert_eq!(
+            run_program(instructions, Segment::new_zeroed(), 10),
+            ProgramOutcome::Faulted {
+                insn: 0xFFFF,
+                pc: 2,
+                steps: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_out_of_budget() {
+        let instructions = Segment::from_prefix(&[
+            0x5911, // incr r1
+            0xA800, // j -0x1
+        ]);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let outcome = run_vm(&mut vm, 101);
+        assert_eq!(outcome, ProgramOutcome::OutOfBudget { steps: 101 });
+        assert_eq!(outcome.steps(), 101);
+        assert_eq!(vm.get_registers()[1], 51);
+    }
+
+    #[test]
+    fn test_zero_budget() {
+        let instructions = Segment::from_prefix(&[0x102A]);
+        assert_eq!(
+            run_program(instructions, Segment::new_zeroed(), 0),
+            ProgramOutcome::OutOfBudget { steps: 0 }
+        );
+    }
+
+    #[test]
+    fn test_randomness_unavailable() {
+        let instructions = Segment::from_prefix(&[
+            0x3105, // lw r1, 5
+            0x3207, // lw r2, 7
+            0x5E02, // rnd r2, r0 (no entropy needed)
+            0x5E12, // rnd r2, r1
+            0x102A, // ret
+        ]);
+        crate::vm::set_fail_getrandom(true);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let outcome = run_vm(&mut vm, 10);
+        crate::vm::set_fail_getrandom(false);
+        assert_eq!(
+            outcome,
+            ProgramOutcome::RandomnessUnavailable { pc: 3, steps: 3 }
+        );
+        assert_eq!(vm.get_halted(), Some(StepResult::RandomnessUnavailable));
+        assert_eq!(vm.get_registers()[2], 0);
+        assert!(vm.was_deterministic_so_far());
+        // Halting is sticky, even once entropy is available again.
+        assert_eq!(vm.step(), StepResult::RandomnessUnavailable);
+        assert_eq!(vm.get_time(), 3);
+    }
+
+    #[test]
+    fn test_run_counts_steps_per_call() {
+        let instructions = Segment::from_prefix(&[
+            0x5911, // incr r1
+            0x102C, // debug-dump
+            0x5911, // incr r1
+            0x102A, // ret
+        ]);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert_eq!(
+            vm.run(2),
+            RunOutcome {
+                steps: 2,
+                reason: StopReason::OutOfBudget
+            }
+        );
+        assert_eq!(
+            vm.run(10),
+            RunOutcome {
+                steps: 1,
+                reason: StopReason::Returned(0)
+            }
+        );
+        assert_eq!(vm.get_time(), 3);
+        // Already halted, which does not need any budget.
+        for max_steps in [10, 0] {
+            assert_eq!(
+                vm.run(max_steps),
+                RunOutcome {
+                    steps: 0,
+                    reason: StopReason::Returned(0)
+                }
+            );
+        }
+        assert_eq!(
+            run_vm(&mut vm, 0),
+            ProgramOutcome::Returned { value: 0, steps: 3 }
+        );
+    }
+
+    #[test]
+    fn test_debug_dump_counts_but_return_does_not() {
+        let instructions = Segment::from_prefix(&[
+            0x102C, // debug-dump
+            0x102A, // ret
+        ]);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        // A Debug-dump is an ordinary step, so it takes one unit of time and budget.
+        assert_eq!(
+            vm.run_to_debug_dump(1),
+            RunOutcome {
+                steps: 1,
+                reason: StopReason::DebugDump
+            }
+        );
+        // Return halts the machine instead, and does not advance the time.
+        assert_eq!(
+            vm.run(1),
+            RunOutcome {
+                steps: 0,
+                reason: StopReason::Returned(0)
+            }
+        );
+        assert_eq!(vm.get_time(), 1);
+    }
+
+    #[test]
+    fn test_run_to_debug_dump() {
+        let instructions = Segment::from_prefix(&[
+            0x5911, // incr r1
+            0x102C, // debug-dump
+            0x5911, // incr r1
+            0xFFFF, // illegal
+        ]);
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        assert_eq!(
+            vm.run_to_debug_dump(10),
+            RunOutcome {
+                steps: 2,
+                reason: StopReason::DebugDump
+            }
+        );
+        assert_eq!(vm.get_program_counter(), 2);
+        assert_eq!(vm.get_registers()[1], 1);
+        assert_eq!(
+            vm.run_to_debug_dump(10),
+            RunOutcome {
+                steps: 1,
+                reason: StopReason::IllegalInstruction(0xFFFF)
+            }
+        );
+        assert_eq!(vm.get_time(), 3);
+    }
+
+    #[test]
+    fn test_debug_dumps_arithmetic_sequence() {
+        let instructions = crate::tinyvm_asm! {
+            lw r2, 7;
+            lw r1, 4;
+            loop_start:
+            mov r0, r2;
+            debug_dump;
+            incr r2, r2;
+            incr r2, r2;
+            incr r2, r2;
+            decr r1, r1;
+            b r1, loop_start;
+            ill 0xFFFF;
+        };
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let mut dumps = vm.debug_dumps(10);
+        assert_eq!(dumps.stop_reason(), None);
+        assert_eq!(dumps.by_ref().collect::<Vec<_>>(), vec![7, 10, 13, 16]);
+        assert_eq!(
+            dumps.stop_reason(),
+            Some(StopReason::IllegalInstruction(0xFFFF))
+        );
+        // Fused, even though running again would report the same fault.
+        assert_eq!(dumps.next(), None);
+        assert_eq!(dumps.vm().get_program_counter(), 9);
+    }
+
+    #[test]
+    fn test_debug_dumps_budget_per_item() {
+        let instructions = crate::tinyvm_asm! {
+            lw r0, 1;
+            debug_dump;
+            lw r1, 3;
+            loop_start:
+            decr r1, r1;
+            b r1, loop_start;
+            debug_dump;
+            ret;
+        };
+        let mut vm = VirtualMachine::new(instructions.clone(), Segment::new_zeroed());
+        let mut dumps = vm.debug_dumps(5);
+        assert_eq!(dumps.next(), Some(1));
+        assert_eq!(dumps.next(), None);
+        assert_eq!(dumps.stop_reason(), Some(StopReason::OutOfBudget));
+
+        let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+        let mut dumps = vm.debug_dumps(8);
+        assert_eq!(dumps.by_ref().count(), 2);
+        assert_eq!(dumps.stop_reason(), Some(StopReason::Returned(1)));
+    }
+}
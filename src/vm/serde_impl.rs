@@ -0,0 +1,199 @@
+use super::{CpuFeatures, Segment, SplitMix64, StepResult, VirtualMachine, PAGES, PAGE_WORDS};
+use serde::de::Error;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::sync::Arc;
+
+/// `count` repetitions of `word`. A segment is serialized as a list of these, so mostly-empty segments stay small,
+/// e.g. `[[4138,1],[0,65535]]` for a lone `ret`.
+#[derive(Serialize, Deserialize)]
+struct Run(u16, u32);
+
+impl Serialize for Segment {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut runs: Vec<Run> = Vec::new();
+        for word in self.words() {
+            match runs.last_mut() {
+                Some(run) if run.0 == word => run.1 += 1,
+                _ => runs.push(Run(word, 1)),
+            }
+        }
+        runs.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Segment {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Segment, D::Error> {
+        let runs = Vec::<Run>::deserialize(deserializer)?;
+        let total = runs.iter().map(|run| run.1 as u64).sum::<u64>();
+        if total != (PAGES * PAGE_WORDS) as u64 {
+            return Err(D::Error::custom(format!(
+                "Expected runs of 65536 words in total, got {} instead.",
+                total
+            )));
+        }
+        let mut segment = Segment::new_sparse();
+        let mut address = 0usize;
+        for Run(word, count) in runs {
+            if word != 0 {
+                for index in address..address + count as usize {
+                    segment[index as u16] = word;
+                }
+            }
+            address += count as usize;
+        }
+        Ok(segment)
+    }
+}
+
+/// The serialized form of a `VirtualMachine`: everything that determines how it continues.
+///
+/// Host-side instrumentation (breakpoints, watchpoints, tracer, debug-dump handler, write log, profile, history,
+/// recorded randomness), the random source, the MMIO handler, and the cost model are not part of it, and have to be set
+/// up again after deserializing.
+#[derive(Serialize)]
+struct VmStateRef<'a> {
+    registers: &'a [u16; 16],
+    program_counter: u16,
+    time: u64,
+    instructions: &'a Segment,
+    data: &'a Segment,
+    deterministic_so_far: bool,
+    random_calls: u64,
+    halted: Option<StepResult>,
+    features: CpuFeatures,
+    rng: &'a Option<SplitMix64>,
+    resuming_from_breakpoint: bool,
+}
+
+/// Owned counterpart of `VmStateRef`.
+#[derive(Deserialize)]
+struct VmState {
+    registers: [u16; 16],
+    program_counter: u16,
+    time: u64,
+    instructions: Segment,
+    data: Segment,
+    deterministic_so_far: bool,
+    /// Missing in states from before it was counted.
+    #[serde(default)]
+    random_calls: u64,
+    halted: Option<StepResult>,
+    features: CpuFeatures,
+    rng: Option<SplitMix64>,
+    resuming_from_breakpoint: bool,
+}
+
+impl Serialize for VirtualMachine {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        VmStateRef {
+            registers: &self.registers,
+            program_counter: self.program_counter,
+            time: self.time,
+            instructions: &self.instructions,
+            data: &self.data,
+            deterministic_so_far: self.deterministic_so_far,
+            random_calls: self.random_calls,
+            halted: self.halted,
+            features: self.features,
+            rng: &self.rng,
+            resuming_from_breakpoint: self.resuming_from_breakpoint,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for VirtualMachine {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<VirtualMachine, D::Error> {
+        let state = VmState::deserialize(deserializer)?;
+        let mut vm = VirtualMachine::new_shared(Arc::new(state.instructions), state.data);
+        vm.registers = state.registers;
+        vm.program_counter = state.program_counter;
+        vm.time = state.time;
+        vm.deterministic_so_far = state.deterministic_so_far;
+        vm.random_calls = state.random_calls;
+        vm.halted = state.halted;
+        vm.features = state.features;
+        vm.rng = state.rng;
+        vm.resuming_from_breakpoint = state.resuming_from_breakpoint;
+        Ok(vm)
+    }
+}
+
+#[cfg(test)]
+mod test_serde {
+    use super::*;
+    use crate::tinyvm_asm;
+
+    #[test]
+    fn test_segment_roundtrip() {
+        let mut segment = Segment::new_zeroed();
+        segment[0x0000] = 0x102A;
+        segment[0x0001] = 0x102A;
+        segment[0xFFFF] = 0x1234;
+        let json = serde_json::to_string(&segment).unwrap();
+        assert_eq!(json, "[[4138,2],[0,65533],[4660,1]]");
+        assert_eq!(serde_json::from_str::<Segment>(&json).unwrap(), segment);
+
+        let empty = Segment::new_sparse();
+        let json = serde_json::to_string(&empty).unwrap();
+        assert_eq!(json, "[[0,65536]]");
+        assert_eq!(serde_json::from_str::<Segment>(&json).unwrap(), empty);
+    }
+
+    #[test]
+    fn test_segment_reject() {
+        for json in [
+            "[]",
+            "[[0,65535]]",
+            "[[0,65536],[1,1]]",
+            "[[0,4294967295],[0,1]]",
+        ] {
+            let err = serde_json::from_str::<Segment>(json).unwrap_err();
+            assert!(
+                err.to_string().contains("Expected runs of 65536 words"),
+                "{:?} gave {}",
+                json,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn test_vm_roundtrip_mid_run() {
+        let instructions = tinyvm_asm! {
+            lw r1, 0xFF;
+            loop_start:
+            rnd r2, r1;
+            sw r3, r2;
+            incr r3, r3;
+            b r1, loop_start;
+        };
+        let mut vm = VirtualMachine::new_with_seed(instructions, Segment::new_zeroed(), 42);
+        vm.set_features(CpuFeatures::default().with(CpuFeatures::STORE_INSTRUCTION));
+        vm.run(1000);
+        assert_eq!(vm.get_time(), 1000);
+
+        let json = serde_json::to_string(&vm).unwrap();
+        let mut restored = serde_json::from_str::<VirtualMachine>(&json).unwrap();
+        assert_eq!(restored, vm);
+
+        vm.run(1000);
+        restored.run(1000);
+        assert_eq!(restored, vm);
+        assert_eq!(restored.get_time(), 2000);
+    }
+
+    #[test]
+    fn test_vm_roundtrip_halted() {
+        let mut vm = VirtualMachine::new(tinyvm_asm! { word 0xFFFF; }, Segment::new_zeroed());
+        vm.step();
+        let json = serde_json::to_string(&vm).unwrap();
+        assert!(json.contains(r#""halted":{"IllegalInstruction":65535}"#));
+        let restored = serde_json::from_str::<VirtualMachine>(&json).unwrap();
+        assert_eq!(
+            restored.get_halted(),
+            Some(StepResult::IllegalInstruction(0xFFFF))
+        );
+        assert_eq!(restored, vm);
+    }
+}
@@ -0,0 +1,80 @@
+use crate::vm::{StepResult, VirtualMachine};
+use std::fmt::{Debug, Formatter, Result};
+use std::sync::{Arc, Mutex};
+
+/// One call of `VirtualMachine::step`, as seen by a `Tracer`.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct TraceEvent {
+    pub pc: u16,
+    pub instruction: u16,
+    /// The registers before executing the instruction.
+    pub registers: [u16; 16],
+    pub result: StepResult,
+}
+
+/// A callback for every step of a VM, see `VirtualMachine::set_tracer`.
+///
+/// Clones share the same callback, so a single tracer can observe several VMs, e.g. all moves of a game.
+/// Tracers do not take part in comparisons, so installing one never changes whether two VMs are equal.
+#[derive(Clone)]
+pub struct Tracer(Arc<Mutex<dyn FnMut(TraceEvent) + Send>>);
+
+impl Tracer {
+    pub fn new(callback: impl FnMut(TraceEvent) + Send + 'static) -> Tracer {
+        Tracer(Arc::new(Mutex::new(callback)))
+    }
+
+    pub(crate) fn call(&self, event: TraceEvent) {
+        // A panicking callback is the caller's problem, but it should not disable tracing for good.
+        let mut callback = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        callback(event);
+    }
+}
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.write_str("Tracer")
+    }
+}
+
+impl PartialEq for Tracer {
+    fn eq(&self, _other: &Tracer) -> bool {
+        true
+    }
+}
+
+impl Eq for Tracer {}
+
+/// A callback for the Debug-dump instruction, see `VirtualMachine::set_debug_dump_handler`. The VM itself does not
+/// know how to present its state, so this is up to the host, e.g. with `connect4::describe_debug_dump`.
+///
+/// Like a `Tracer`, clones share the same callback, and handlers do not take part in comparisons.
+#[derive(Clone)]
+pub struct DebugDumpHandler(Arc<Mutex<DebugDumpCallback>>);
+
+type DebugDumpCallback = dyn FnMut(&VirtualMachine) + Send;
+
+impl DebugDumpHandler {
+    pub fn new(callback: impl FnMut(&VirtualMachine) + Send + 'static) -> DebugDumpHandler {
+        DebugDumpHandler(Arc::new(Mutex::new(callback)))
+    }
+
+    pub(crate) fn call(&self, vm: &VirtualMachine) {
+        let mut callback = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        callback(vm);
+    }
+}
+
+impl Debug for DebugDumpHandler {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        f.write_str("DebugDumpHandler")
+    }
+}
+
+impl PartialEq for DebugDumpHandler {
+    fn eq(&self, _other: &DebugDumpHandler) -> bool {
+        true
+    }
+}
+
+impl Eq for DebugDumpHandler {}
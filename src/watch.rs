@@ -0,0 +1,92 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+/// Returns the modification time of the file, or `None` if it cannot be determined (e.g. the file is missing).
+pub fn file_mtime(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Remembers the modification times of a set of files, and tells whether any of them changed since the last poll.
+///
+/// The function used to stat files is injected, so that the decision logic can be tested without touching the filesystem.
+pub struct Watcher<S> {
+    paths: Vec<PathBuf>,
+    last_seen: Vec<Option<SystemTime>>,
+    stat: S,
+}
+
+impl<S: FnMut(&Path) -> Option<SystemTime>> Watcher<S> {
+    pub fn new(paths: Vec<PathBuf>, mut stat: S) -> Watcher<S> {
+        let last_seen = paths.iter().map(|path| stat(path)).collect();
+        Watcher {
+            paths,
+            last_seen,
+            stat,
+        }
+    }
+
+    /// Stats all files again. Returns true if any modification time differs from the previous poll.
+    ///
+    /// A file appearing or disappearing also counts as a change.
+    pub fn poll(&mut self) -> bool {
+        let mut changed = false;
+        for (path, last_seen) in self.paths.iter().zip(self.last_seen.iter_mut()) {
+            let current = (self.stat)(path);
+            if current != *last_seen {
+                *last_seen = current;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod test_watcher {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn at(secs: u64) -> Option<SystemTime> {
+        Some(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn test_no_change() {
+        let mut watcher = Watcher::new(vec!["a".into(), "b".into()], |_: &Path| at(5));
+        assert!(!watcher.poll());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn test_change_reported_once() {
+        let times = RefCell::new(HashMap::from([("a", at(1)), ("b", at(2))]));
+        let stat = |path: &Path| times.borrow()[path.to_str().unwrap()];
+        let mut watcher = Watcher::new(vec!["a".into(), "b".into()], stat);
+        assert!(!watcher.poll());
+
+        times.borrow_mut().insert("b", at(3));
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        // Going back in time is still a change.
+        times.borrow_mut().insert("a", at(0));
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+    }
+
+    #[test]
+    fn test_disappear_and_reappear() {
+        let time = RefCell::new(at(7));
+        let mut watcher = Watcher::new(vec!["a".into()], |_: &Path| *time.borrow());
+
+        *time.borrow_mut() = None;
+        assert!(watcher.poll());
+        assert!(!watcher.poll());
+
+        *time.borrow_mut() = at(7);
+        assert!(watcher.poll());
+    }
+}
@@ -1,31 +1,41 @@
-use tinyvm::{Segment, StepResult, VirtualMachine};
+use std::sync::{Arc, Mutex};
+use tinyvm::{
+    encode_branch, encode_jump_imm, run_program, selftest, tinyvm_asm, CountingSource, FaultInfo,
+    ProgramBuilder, ProgramOutcome, Segment, StepResult, TraceEvent, Tracer, VirtualMachine,
+    BRANCH_MAX, BRANCH_MIN, JUMP_IMM_MAX, JUMP_IMM_MIN,
+};
 
 enum Expectation {
     ActualNumSteps(u64),
     Data(u16, u16),
+    Deterministic(bool),
     LastStep(StepResult),
     ProgramCounter(u16),
     Register(u16, u16),
 }
 
-fn segment_from_prefix(prefix: &[u16]) -> Segment {
-    let mut segment = Segment::new_zeroed();
-    for (i, &v) in prefix.iter().enumerate() {
-        segment[i as u16] = v;
-    }
-    segment
-}
-
 fn run_test(
     instruction_prefix: &[u16],
     data_prefix: &[u16],
     max_steps: usize,
     expectations: &[Expectation],
 ) {
-    let instruction_segment = segment_from_prefix(instruction_prefix);
-    let data_segment = segment_from_prefix(data_prefix);
+    run_test_segments(
+        Segment::from_prefix(instruction_prefix),
+        Segment::from_prefix(data_prefix),
+        max_steps,
+        expectations,
+    );
+}
 
+fn run_test_segments(
+    instruction_segment: Segment,
+    data_segment: Segment,
+    max_steps: usize,
+    expectations: &[Expectation],
+) {
     let mut vm = VirtualMachine::new(instruction_segment, data_segment);
+    vm.remember_initial_data();
 
     let mut last_step_result = StepResult::Continue;
     let mut actual_steps = 0;
@@ -41,6 +51,15 @@ fn run_test(
             StepResult::Return(_) => {
                 break;
             }
+            StepResult::RandomnessUnavailable => {
+                break;
+            }
+            StepResult::Breakpoint(_) => {
+                break;
+            }
+            StepResult::ProtectionFault { .. } => {
+                break;
+            }
         }
         actual_steps += 1;
         if actual_steps % 0x100_0000 == 0 {
@@ -53,7 +72,11 @@ fn run_test(
         }
     }
 
-    println!("Data segment: {:?}", vm.get_data());
+    let modified_data = vm.modified_data();
+    println!("{} data words modified:", modified_data.len());
+    for (address, initial, current) in modified_data {
+        println!("    {:04X}: {:04X} -> {:04X}", address, initial, current);
+    }
     println!(
         "Final state: registers={:?}, pc={:04X}, actual_steps={}",
         vm.get_registers(),
@@ -77,6 +100,10 @@ fn run_test(
                 );
                 assert_eq!(*expected_data, vm.get_data()[*address]);
             }
+            Expectation::Deterministic(expected_deterministic) => {
+                println!("Expecting deterministic to be {}", expected_deterministic);
+                assert_eq!(*expected_deterministic, vm.was_deterministic_so_far());
+            }
             Expectation::LastStep(expected_step_result) => {
                 println!("Expecting last step to be {:?}", expected_step_result);
                 assert_eq!(*expected_step_result, last_step_result);
@@ -181,6 +208,81 @@ fn test_late_illegal() {
     );
 }
 
+#[test]
+fn test_run_program_illegal() {
+    assert_eq!(
+        run_program(
+            Segment::from_prefix(&[0x3000, 0x0123]),
+            Segment::new_zeroed(),
+            2
+        ),
+        ProgramOutcome::Faulted {
+            insn: 0x0123,
+            pc: 1,
+            steps: 1
+        }
+    );
+}
+
+#[test]
+fn test_illegal_pc_stays() {
+    // One illegal or reserved instruction from each group of the instruction space.
+    for insn in [
+        0x0000, 0x1200, 0x1030, 0x2F12, 0x5012, 0x7123, 0xC000, 0xFFFF,
+    ] {
+        run_test(
+            &[0x3000, insn],
+            &[],
+            2,
+            &[
+                Expectation::ActualNumSteps(1),
+                Expectation::LastStep(StepResult::IllegalInstruction(insn)),
+                Expectation::ProgramCounter(1),
+            ],
+        );
+    }
+}
+
+#[test]
+fn test_step_after_illegal() {
+    for insn in [
+        0x0000, 0x1200, 0x1030, 0x2F12, 0x5012, 0x7123, 0xC000, 0xFFFF,
+    ] {
+        let mut vm = VirtualMachine::new(
+            Segment::from_prefix(&[0x3142, insn, 0x3243]),
+            Segment::new_zeroed(),
+        );
+        assert_eq!(vm.step(), StepResult::Continue);
+        assert_eq!(vm.get_halted(), None);
+        assert_eq!(vm.last_fault(), None);
+        for _ in 0..3 {
+            assert_eq!(vm.step(), StepResult::IllegalInstruction(insn));
+            assert_eq!(vm.get_halted(), Some(StepResult::IllegalInstruction(insn)));
+            assert_eq!(vm.last_fault(), Some(FaultInfo { pc: 1, insn }));
+            assert_eq!(vm.get_program_counter(), 1);
+            assert_eq!(vm.get_time(), 1);
+            assert_eq!(vm.get_registers()[1], 0x0042);
+            assert_eq!(vm.get_registers()[2], 0x0000);
+        }
+    }
+}
+
+#[test]
+fn test_step_after_return() {
+    let mut vm = VirtualMachine::new(
+        Segment::from_prefix(&[0x3042, 0x102A, 0x3043]),
+        Segment::new_zeroed(),
+    );
+    assert_eq!(vm.step(), StepResult::Continue);
+    for _ in 0..3 {
+        assert_eq!(vm.step(), StepResult::Return(0x0042));
+        assert_eq!(vm.get_halted(), Some(StepResult::Return(0x0042)));
+        assert_eq!(vm.get_program_counter(), 1);
+        assert_eq!(vm.get_time(), 1);
+        assert_eq!(vm.get_registers()[0], 0x0042);
+    }
+}
+
 // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x3xxx-load-immediate-low-sign-extended
 // The instruction is `0b0011 0101 1000 1110`. Then this instruction will write the value 0xFF8E into register 5.
 #[test]
@@ -247,6 +349,29 @@ fn test_return_value() {
     );
 }
 
+#[test]
+fn test_return_value_run_program() {
+    assert_eq!(
+        run_program(
+            Segment::from_prefix(&[0x3042, 0x102A]),
+            Segment::new_zeroed(),
+            2
+        ),
+        ProgramOutcome::Returned {
+            value: 0x0042,
+            steps: 1
+        }
+    );
+    assert_eq!(
+        run_program(
+            Segment::from_prefix(&[0x3042, 0x102A]),
+            Segment::new_zeroed(),
+            1
+        ),
+        ProgramOutcome::OutOfBudget { steps: 1 }
+    );
+}
+
 // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x102b-cpuid
 // The instruction is `0b0001 0000 0010 1011`, and register 0 contains the value 0x0000. Then this instruction might, in a bare-bones and conforming VM, overwrite the register 0 with the value 0x8000, and registers 1, 2, and 3 each with the value 0x0000.
 #[test]
@@ -259,7 +384,8 @@ fn test_cpuid_0() {
             Expectation::ActualNumSteps(1),
             Expectation::ProgramCounter(1),
             Expectation::LastStep(StepResult::Continue),
-            Expectation::Register(0, 0x8000),
+            // 0x8000 for conformance, 0x4000 for exp and root, 0x2000 for compare-to-zero, 0x0800 for rnd.
+            Expectation::Register(0, 0xE800),
             Expectation::Register(1, 0x0000),
             Expectation::Register(2, 0x0000),
             Expectation::Register(3, 0x0000),
@@ -297,7 +423,7 @@ fn test_cpuid_overwrite() {
             Expectation::ActualNumSteps(5),
             Expectation::ProgramCounter(5),
             Expectation::LastStep(StepResult::Continue),
-            Expectation::Register(0, 0x8000),
+            Expectation::Register(0, 0xE800),
             Expectation::Register(1, 0x0000),
             Expectation::Register(2, 0x0000),
             Expectation::Register(3, 0x0000),
@@ -388,11 +514,11 @@ fn test_time_jump() {
 fn test_time_long() {
     run_test(
         &[
-            0x37AB, // lw r7, 0xFFAB
-            0x5877, // decr r7
-            0x9780, // b r7 -0x1
-            0x102D, // time
-            0x102A, // ret
+            0x37AB,                        // lw r7, 0xFFAB
+            0x5877,                        // decr r7
+            encode_branch(7, -1).unwrap(), // b r7 -0x1
+            0x102D,                        // time
+            0x102A,                        // ret
         ],
         &[],
         0xF_FFFF, // More than enough; definitely not tight
@@ -408,6 +534,22 @@ fn test_time_long() {
     );
 }
 
+#[test]
+fn test_time_after_set_time() {
+    let instructions = tinyvm_asm! {
+        lw r5, 7;
+        time;
+        ret;
+    };
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+    vm.set_time(0x1234_5678_9ABC_DEF0);
+    vm.run(10);
+    assert_eq!(vm.get_halted(), Some(StepResult::Return(0x1234)));
+    // The load immediate before Time counts, too.
+    assert_eq!(vm.get_registers()[0..4], [0x1234, 0x5678, 0x9ABC, 0xDEF1]);
+    assert_eq!(vm.get_time(), 0x1234_5678_9ABC_DEF2);
+}
+
 #[test]
 #[ignore = "Test takes too long"]
 // Runs in 122.29s in debug mode, that's about 35 MHz in simulation. Whoa!
@@ -911,7 +1053,7 @@ fn test_jump_immediate_overflow() {
 fn test_jump_immediate_underflow() {
     run_test(
         &[
-            0xA830, // j -0x031
+            encode_jump_imm(-0x31).unwrap(), // j -0x031
         ],
         &[],
         1,
@@ -927,7 +1069,7 @@ fn test_jump_immediate_underflow() {
 fn test_jump_immediate_extreme_positive() {
     run_test(
         &[
-            0xA7FF, // j +0x801
+            encode_jump_imm(0x801).unwrap(), // j +0x801
         ],
         &[],
         1,
@@ -943,7 +1085,7 @@ fn test_jump_immediate_extreme_positive() {
 fn test_jump_immediate_extreme_negative() {
     run_test(
         &[
-            0xAFFF, // j -0x800
+            encode_jump_imm(-0x800).unwrap(), // j -0x800
         ],
         &[],
         1,
@@ -1002,11 +1144,12 @@ fn test_branch_doc2() {
 #[test]
 fn test_compare_doc() {
     run_test(
-        &[
-            0x3305, // lw r3, 0x0005
-            0x3407, // lw r4, 0x0007
-            0x8A34, // ne r4, r3 (or cmp.lg r4, r3)
-        ],
+        &ProgramBuilder::new()
+            .lw(3, 0x0005)
+            .lw(4, 0x0007)
+            .compare(0b1010, 3, 4) // ne
+            .build()
+            .unwrap(),
         &[],
         3,
         &[
@@ -1021,13 +1164,15 @@ fn test_compare_doc() {
 
 fn run_compare_test(a: u16, b: u16, flags: u16, result: u16) {
     run_test(
-        &[
-            0x3100 | (a & 0xFF),        // ↓
-            0x4100 | ((a >> 8) & 0xFF), // lw r1, a
-            0x3200 | (b & 0xFF),        // ↓
-            0x4200 | ((b >> 8) & 0xFF), // lw r2, b
-            0x8012 | (flags << 8),      // cmp.flags r2, r1
-        ],
+        // Always both halves, so that the step count does not depend on the values.
+        &ProgramBuilder::new()
+            .lw_low(1, a as u8)
+            .lhi(1, (a >> 8) as u8)
+            .lw_low(2, b as u8)
+            .lhi(2, (b >> 8) as u8)
+            .compare(flags, 1, 2)
+            .build()
+            .unwrap(),
         &[],
         5,
         &[
@@ -1224,6 +1369,125 @@ fn test_compare_greater_signed_negative() {
     run_compare_test(0xABCD, 0x1234, 0b0011, 0);
 }
 
+// https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x11xx-compare-to-zero
+// The instruction is `0b0001 0001 1001 0011`, and register 3 contains the value 0xFFFB. Then this instruction will write the value 0x0001 into register 3, because -5 is less than 0 when interpreted as signed.
+#[test]
+fn test_compare_zero_doc1() {
+    run_test(
+        &ProgramBuilder::new()
+            .lw(3, 0xFFFB)
+            .compare_zero(0b1001, 3) // lt.s
+            .build()
+            .unwrap(),
+        &[],
+        2,
+        &[
+            Expectation::ProgramCounter(2),
+            Expectation::ActualNumSteps(2),
+            Expectation::Register(3, 1),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
+// https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x11xx-compare-to-zero
+// The instruction is `0b0001 0001 0100 0111`, and register 7 contains the value 0x0005. Then this instruction will write the value 0x0000 into register 7, because 5 is not equal to 0.
+#[test]
+fn test_compare_zero_doc2() {
+    run_test(
+        &ProgramBuilder::new()
+            .lw(7, 0x0005)
+            .compare_zero(0b0100, 7) // eq
+            .build()
+            .unwrap(),
+        &[],
+        2,
+        &[
+            Expectation::ProgramCounter(2),
+            Expectation::ActualNumSteps(2),
+            Expectation::Register(7, 0),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
+fn run_compare_zero_test(a: u16, flags: u16, result: u16) {
+    run_test(
+        &[
+            0x3100 | (a & 0xFF),        // ↓
+            0x4100 | ((a >> 8) & 0xFF), // lw r1, a
+            0x3205,                     // lw r2, 5
+            0x1101 | (flags << 4),      // cmp.flags r1, #0
+        ],
+        &[],
+        4,
+        &[
+            Expectation::ProgramCounter(4),
+            Expectation::ActualNumSteps(4),
+            Expectation::Register(1, result),
+            // Must not touch any other register:
+            Expectation::Register(0, 0),
+            Expectation::Register(2, 5),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
+#[test]
+fn test_compare_zero_unsigned() {
+    // Flags are LEG0. Columns: positive, zero, "negative" (which is just a large number here).
+    for (flags, on_positive, on_zero, on_negative) in [
+        (0b0000, 0, 0, 0),
+        (0b0010, 1, 0, 1),
+        (0b0100, 0, 1, 0),
+        (0b0110, 1, 1, 1),
+        (0b1000, 0, 0, 0),
+        (0b1010, 1, 0, 1),
+        (0b1100, 0, 1, 0),
+        (0b1110, 1, 1, 1),
+    ] {
+        run_compare_zero_test(0x0005, flags, on_positive);
+        run_compare_zero_test(0x0000, flags, on_zero);
+        run_compare_zero_test(0xFFFB, flags, on_negative);
+    }
+}
+
+#[test]
+fn test_compare_zero_signed() {
+    // Flags are LEG1. Columns: positive, zero, negative.
+    for (flags, on_positive, on_zero, on_negative) in [
+        (0b0001, 0, 0, 0),
+        (0b0011, 1, 0, 0),
+        (0b0101, 0, 1, 0),
+        (0b0111, 1, 1, 0),
+        (0b1001, 0, 0, 1),
+        (0b1011, 1, 0, 1),
+        (0b1101, 0, 1, 1),
+        (0b1111, 1, 1, 1),
+    ] {
+        run_compare_zero_test(0x0005, flags, on_positive);
+        run_compare_zero_test(0x0000, flags, on_zero);
+        run_compare_zero_test(0xFFFB, flags, on_negative);
+        run_compare_zero_test(0x8000, flags, on_negative);
+        run_compare_zero_test(0x7FFF, flags, on_positive);
+    }
+}
+
+#[test]
+fn test_special_reserved() {
+    for insn in [0x1200, 0x122A, 0x1F00, 0x102E, 0x10FF] {
+        run_test(
+            &[insn],
+            &[],
+            1,
+            &[
+                Expectation::ActualNumSteps(0),
+                Expectation::LastStep(StepResult::IllegalInstruction(insn)),
+            ],
+        );
+    }
+}
+
 // https://github.com/BenWiederhake/tinyvm/blob/master/instruction-set-architecture.md#0x5xxx-unary-functions
 // The instruction is `0b0101 1010 0101 0110`, and register 5 contains the value 0x1234. Then this instruction will write the value 0xEDCB into register 6, because not(0x1234) = 0xEDCB.
 #[test]
@@ -1345,6 +1609,48 @@ fn test_unary_rnd() {
     // The other cases aren't easily testable
 }
 
+#[test]
+fn test_unary_rnd_zero_is_deterministic() {
+    run_test(
+        &[
+            0x5E12, // rnd r2, r1
+        ],
+        &[],
+        1,
+        &[
+            Expectation::Register(2, 0),
+            Expectation::Deterministic(true),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
+#[test]
+fn test_unary_rnd_nonzero_is_nondeterministic() {
+    run_test(
+        &ProgramBuilder::new().lw(1, 1).rnd(2, 1).build().unwrap(),
+        &[],
+        2,
+        &[
+            Expectation::Deterministic(false),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
+#[test]
+fn test_deterministic_without_rnd() {
+    run_test(
+        &ProgramBuilder::new().lw(1, 1).mov(2, 1).build().unwrap(),
+        &[],
+        2,
+        &[
+            Expectation::Deterministic(true),
+            Expectation::LastStep(StepResult::Continue),
+        ],
+    );
+}
+
 #[test]
 fn test_unary_rnd_inclusive() {
     run_test(
@@ -1370,27 +1676,41 @@ fn test_unary_rnd_inclusive() {
 
 #[test]
 fn test_unary_rnd_extreme() {
-    // This test is flaky, and fails with a probability of roughly 0.00305%.
-    // So if you see it fail twice in a row, something is broken for sure.
-    run_test(
-        &[
+    let mut vm = VirtualMachine::with_random_source(
+        Segment::from_prefix(&[
             0x31FF, // lw r1, 0xFFFF
             0x5E12, // rnd r2, r1
             0x8421, // eq r2 r1
             0x8420, // eq r2 r0
-        ],
-        &[],
-        4,
-        &[
-            Expectation::ProgramCounter(4),
-            Expectation::ActualNumSteps(4),
-            // Can't easily check register 2 here.
-            // So instead we use the VM to check that it is neither minimum nor maximum:
-            Expectation::Register(0, 0),
-            Expectation::Register(1, 0),
-            Expectation::LastStep(StepResult::Continue),
-        ],
+        ]),
+        Segment::new_zeroed(),
+        Box::new(CountingSource::new(0x1234)),
     );
+    for _ in 0..4 {
+        assert_eq!(vm.step(), StepResult::Continue);
+    }
+    // The full 16-bit range is available, and the value is neither minimum nor maximum:
+    assert_eq!(vm.get_registers()[2], 0x1234);
+    assert_eq!(vm.get_registers()[1], 0);
+    assert_eq!(vm.get_registers()[0], 0);
+    assert!(!vm.was_deterministic_so_far());
+}
+
+#[test]
+fn test_unary_rnd_extreme_bounds() {
+    for (start, expected) in [(0x0000, 0x0000), (0xFFFF, 0xFFFF)] {
+        let mut vm = VirtualMachine::with_random_source(
+            Segment::from_prefix(&[
+                0x31FF, // lw r1, 0xFFFF
+                0x5E12, // rnd r2, r1
+            ]),
+            Segment::new_zeroed(),
+            Box::new(CountingSource::new(start)),
+        );
+        vm.step();
+        vm.step();
+        assert_eq!(vm.get_registers()[2], expected);
+    }
 }
 
 #[test]
@@ -1707,27 +2027,129 @@ fn test_binary_sra() {
     run_binary_test(0x8000, 0x0012, 0b1101, 0xFFFF);
 }
 
-// FIXME: Implement and test "exp" instruction
-// FIXME: Implement and test "root" instruction
+#[test]
+fn test_binary_exp() {
+    // * If FFFF=1110, the computed function may be "exp" (signed exponentiation according to IEEE754 double-precision arithmetic, then rounded to the nearest integer, clamped between 0x8000 (-32768) and 0x7FFF (+32767)), e.g. fn(0x0003, 0x0005) = 0x00F3, fn(0xFFFF, 0x0002) = 0x0001
+    //     * If the result is positive or negative Infinity, it is clamped accordingly.
+    run_binary_test(0x0003, 0x0005, 0b1110, 0x00F3);
+    run_binary_test(0xFFFF, 0x0002, 0b1110, 0x0001);
+
+    // Exponent 0:
+    run_binary_test(0x0000, 0x0000, 0b1110, 0x0001);
+    run_binary_test(0x1234, 0x0000, 0b1110, 0x0001);
+    run_binary_test(0x8000, 0x0000, 0b1110, 0x0001);
+    // Base 0:
+    run_binary_test(0x0000, 0x0001, 0b1110, 0x0000);
+    run_binary_test(0x0000, 0x7FFF, 0b1110, 0x0000);
+    run_binary_test(0x0000, 0xFFFF, 0b1110, 0x7FFF);
+    run_binary_test(0x0000, 0x8000, 0b1110, 0x7FFF);
+    // Base 1 and -1:
+    run_binary_test(0x0001, 0x7FFF, 0b1110, 0x0001);
+    run_binary_test(0x0001, 0x8000, 0b1110, 0x0001);
+    run_binary_test(0xFFFF, 0x0003, 0b1110, 0xFFFF);
+    run_binary_test(0xFFFF, 0xFFFF, 0b1110, 0xFFFF);
+    run_binary_test(0xFFFF, 0x8000, 0b1110, 0x0001);
+    // Clamping:
+    run_binary_test(0x0002, 0x000E, 0b1110, 0x4000);
+    run_binary_test(0x0002, 0x000F, 0b1110, 0x7FFF);
+    run_binary_test(0x0002, 0x7FFF, 0b1110, 0x7FFF);
+    run_binary_test(0xFFFE, 0x000F, 0b1110, 0x8000);
+    run_binary_test(0xFFFE, 0x0011, 0b1110, 0x8000);
+    run_binary_test(0xFFFE, 0x0010, 0b1110, 0x7FFF);
+    run_binary_test(0x00B5, 0x0002, 0b1110, 0x7FF9);
+    run_binary_test(0x00B6, 0x0002, 0b1110, 0x7FFF);
+    // Negative exponents, rounded to the nearest integer (ties to even):
+    run_binary_test(0x0002, 0xFFFF, 0b1110, 0x0000);
+    run_binary_test(0xFFFE, 0xFFFF, 0b1110, 0x0000);
+    run_binary_test(0x0003, 0xFFFF, 0b1110, 0x0000);
+    run_binary_test(0x7FFF, 0x8000, 0b1110, 0x0000);
+}
+
+#[test]
+fn test_binary_root() {
+    // * If FFFF=1111, the computed function may be "root" (signed root according to IEEE754 double-precision arithmetic, then rounded to the nearest integer), e.g. fn(0x0009, 0x0002) = 0x0003, fn(0x0900, 0x0002) = 0x0030, fn(0x00F3, 0x0005) = 0x0003, fn(0x0002, 0x0002) = 0x0001, fn(0x1234, 0x0000) = 0x0001
+    //     * If the result is NaN, the written value may be arbitrary.
+    run_binary_test(0x0009, 0x0002, 0b1111, 0x0003);
+    run_binary_test(0x0900, 0x0002, 0b1111, 0x0030);
+    run_binary_test(0x00F3, 0x0005, 0b1111, 0x0003);
+    run_binary_test(0x0002, 0x0002, 0b1111, 0x0001);
+    run_binary_test(0x1234, 0x0000, 0b1111, 0x0001);
+
+    // Degree 0 is always 1:
+    run_binary_test(0x0000, 0x0000, 0b1111, 0x0001);
+    run_binary_test(0xFFFF, 0x0000, 0b1111, 0x0001);
+    // Radicand 0:
+    run_binary_test(0x0000, 0x0001, 0b1111, 0x0000);
+    run_binary_test(0x0000, 0x0002, 0b1111, 0x0000);
+    run_binary_test(0x0000, 0xFFFF, 0b1111, 0x7FFF);
+    // Degree 1 is the identity:
+    run_binary_test(0x1234, 0x0001, 0b1111, 0x1234);
+    run_binary_test(0x8000, 0x0001, 0b1111, 0x8000);
+    // Rounding:
+    run_binary_test(0x0003, 0x0002, 0b1111, 0x0002);
+    run_binary_test(0x7FFF, 0x0002, 0b1111, 0x00B5);
+    run_binary_test(0x7FFF, 0x000F, 0b1111, 0x0002);
+    run_binary_test(0x7FFF, 0x7FFF, 0b1111, 0x0001);
+    // Odd roots of negative numbers are negative:
+    run_binary_test(0xFFF8, 0x0003, 0b1111, 0xFFFE);
+    run_binary_test(0x8000, 0x000F, 0b1111, 0xFFFE);
+    run_binary_test(0xFFFF, 0x0007, 0b1111, 0xFFFF);
+    // We define even roots of negative numbers (NaN) as 0:
+    run_binary_test(0xFFFF, 0x0002, 0b1111, 0x0000);
+    run_binary_test(0x8000, 0x0010, 0b1111, 0x0000);
+    // Negative degrees:
+    run_binary_test(0x0001, 0xFFFF, 0b1111, 0x0001);
+    run_binary_test(0x0004, 0xFFFE, 0b1111, 0x0000);
+    run_binary_test(0x0002, 0xFFFF, 0b1111, 0x0000);
+    run_binary_test(0xFFFF, 0xFFFF, 0b1111, 0xFFFF);
+}
+
+#[test]
+fn test_offset_helpers_match_vm() {
+    for relative in JUMP_IMM_MIN..=JUMP_IMM_MAX {
+        if relative == 0 || relative == 1 {
+            continue;
+        }
+        run_test(
+            &[encode_jump_imm(relative).unwrap()],
+            &[],
+            1,
+            &[Expectation::ProgramCounter(relative as u16)],
+        );
+    }
+    for relative in BRANCH_MIN..=BRANCH_MAX {
+        if relative == 0 || relative == 1 {
+            continue;
+        }
+        run_test(
+            &[
+                0x3501, // lw r5, 1
+                encode_branch(5, relative).unwrap(),
+            ],
+            &[],
+            2,
+            &[Expectation::ProgramCounter((1 + relative) as u16)],
+        );
+    }
+}
 
 #[test]
 fn test_fibonacci() {
-    #[rustfmt::skip] // Would break the labels. See https://github.com/rust-lang/rustfmt/issues/5630
-    run_test(
-        &[
-            0x3018, // lw r0, 24
-            0x3101, // lw r1, 1
-                    // .label start:
-            0x6012, // add r1 r2
-            0x5800, // decr r0
-            0x2002, // sw r0, r2
-            0x6021, // add r2 r1
-            0x5800, // decr r0
-            0x2001, // sw r0, r1
-            0x9085, // b r0 start // (offset is -0x6)
-            0x102A, // ret
-        ],
-        &[],
+    run_test_segments(
+        tinyvm_asm! {
+            lw r0, 24;
+            lw r1, 1;
+            start:
+            add r1, r2;
+            decr r0, r0;
+            sw r0, r2;
+            add r2, r1;
+            decr r0, r0;
+            sw r0, r1;
+            b r0, start;
+            ret;
+        },
+        Segment::new_zeroed(),
         0xFFFF,
         &[
             Expectation::ActualNumSteps(2 + (24 / 2) * 7),
@@ -1763,3 +2185,68 @@ fn test_fibonacci() {
         ],
     );
 }
+
+#[test]
+fn test_trace_fibonacci() {
+    let mut instructions = Segment::new_zeroed();
+    for (i, &word) in selftest::FIBONACCI.instructions.iter().enumerate() {
+        instructions[i as u16] = word;
+    }
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+    let events_clone = Arc::clone(&events);
+    vm.set_tracer(Some(Tracer::new(move |event| {
+        events_clone.lock().unwrap().push(event)
+    })));
+    vm.run(0xFFFF);
+
+    let events = events.lock().unwrap();
+    // Every executed instruction, plus the final ret.
+    assert_eq!(events.len(), 2 + (24 / 2) * 7 + 1);
+    assert_eq!(
+        events[0],
+        TraceEvent {
+            pc: 0,
+            instruction: 0x3018,
+            registers: [0; 16],
+            result: StepResult::Continue,
+        }
+    );
+    // The first iteration of the loop: add r1, r2
+    assert_eq!(events[2].pc, 2);
+    assert_eq!(events[2].instruction, 0x6012);
+    assert_eq!(events[2].registers[0..3], [24, 1, 0]);
+    // The branch back to the loop start.
+    assert_eq!(events[8].pc, 8);
+    assert_eq!(events[9].pc, 2);
+    let last = events.last().unwrap();
+    assert_eq!(last.pc, 9);
+    assert_eq!(last.result, StepResult::Return(0));
+}
+
+#[test]
+fn test_selftest_programs() {
+    // The self-test programs are shipped in the binary, so check them with this harness, too.
+    for program in selftest::PROGRAMS {
+        println!("Running self-test program {}", program.name);
+        let expectations = program
+            .checks
+            .iter()
+            .map(|check| match *check {
+                selftest::Check::Data(address, value) => Expectation::Data(address, value),
+                selftest::Check::NumSteps(steps) => Expectation::ActualNumSteps(steps),
+                selftest::Check::ProgramCounter(pc) => Expectation::ProgramCounter(pc),
+                selftest::Check::Register(index, value) => Expectation::Register(index, value),
+                selftest::Check::Returned(value) => {
+                    Expectation::LastStep(StepResult::Return(value))
+                }
+            })
+            .collect::<Vec<_>>();
+        run_test(
+            program.instructions,
+            &[],
+            program.max_steps as usize,
+            &expectations,
+        );
+    }
+}
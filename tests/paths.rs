@@ -0,0 +1,38 @@
+// Makes sure that the public paths keep compiling. The actual behavior is tested elsewhere.
+
+#[test]
+fn test_root_paths() {
+    use tinyvm::{Segment, StepResult, VirtualMachine};
+
+    let mut instructions = Segment::new_zeroed();
+    instructions[0] = 0x102A; // ret
+    let mut vm = VirtualMachine::new(instructions, Segment::new_zeroed());
+    assert_eq!(vm.step(), StepResult::Return(0));
+}
+
+#[test]
+fn test_module_paths() {
+    let segment: tinyvm::vm::Segment = tinyvm::Segment::new_zeroed();
+    let vm: tinyvm::VirtualMachine = tinyvm::vm::VirtualMachine::new(segment.clone(), segment);
+    assert_eq!(vm.get_halted(), None::<tinyvm::vm::StepResult>);
+    let _ = tinyvm::vm::load::LoadOptions::default();
+}
+
+#[test]
+fn test_prelude() {
+    use tinyvm::prelude::*;
+
+    let mut instructions = Segment::new_zeroed();
+    instructions[0] = 0x102A; // ret
+    let mut game = Game::new(instructions.clone(), instructions, 100);
+    // Both always pick column 0, until it is full.
+    assert_eq!(
+        game.conclude(),
+        GameResult::Won(Player::Two, WinReason::FullColumn(0))
+    );
+    assert_eq!(game.get_state(), GameState::Ended(game.conclude()));
+    let _: Option<&Board> = Some(game.get_board());
+    let _: Option<AlgorithmResult> = None;
+    let _: Option<StepResult> = None;
+    let _: Option<VirtualMachine> = None;
+}